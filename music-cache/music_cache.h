@@ -12,48 +12,254 @@ extern "C" {
 // Opaque database handle from Rust.
 typedef struct opaque_Db db;
 
+// Opaque library watcher handle from Rust.
+typedef struct opaque_LibraryWatcher library_watcher;
+
 typedef enum KeyType {
     KeyType_Song = 0,
     KeyType_Album = 1,
     KeyType_LastScanTime = 2,
+    KeyType_Artist = 3,
+    KeyType_SearchToken = 4,
+    KeyType_SongPath = 5,
+    KeyType_LibraryRoot = 6,
+    KeyType_AlbumArt = 7,
+    KeyType_AlbumGrouping = 8,
+    KeyType_MusicBrainzId = 9,
+    KeyType_Playlist = 10,
+    KeyType_SmartPlaylist = 11,
+    KeyType_HashScheme = 12,
+    KeyType_SchemaVersion = 13,
+    KeyType_AlbumAlias = 14,
 } KeyType;
 
-#pragma pack(push, 1)
+// Keys are the same bytes on every platform, so they can be compared with
+// memcmp and kept across runs.
 typedef struct Key {
-    KeyType _tag;
-    uint64_t _id;
+    uint8_t _tag;  // KeyType, stored as a single byte to match the Rust layout.
+    uint8_t _id[8];  // Big-endian.
 } Key;
-#pragma pack(pop)
 
 typedef struct AlbumTags {
     char *artist;
     char *title;
     bool has_year;
     uint16_t year;
+    char *genre;
+    bool has_total_discs;
+    uint16_t total_discs;
 } AlbumTags;
 
 typedef struct SongTags {
     char *title;
+    char *artist;
+    char *genre;
     bool has_track_number;
     uint16_t track_number;
+    bool has_total_tracks;
+    uint16_t total_tracks;
+    bool has_disc_number;
+    uint16_t disc_number;
+    bool has_total_discs;
+    uint16_t total_discs;
+    char *composer;
+    char *comment;
 } SongTags;
 
+// Each value is only meaningful when its has_ flag is set. Bitrate is in kbps.
+typedef struct StreamInfo {
+    bool has_duration_ms;
+    uint64_t duration_ms;
+    bool has_bitrate;
+    uint32_t bitrate;
+    bool has_sample_rate;
+    uint32_t sample_rate;
+    bool has_bit_depth;
+    uint8_t bit_depth;
+    bool has_channels;
+    uint8_t channels;
+} StreamInfo;
+
+typedef struct MusicBrainzIds {
+    char *recording_id;
+    char *release_id;
+    char *artist_id;
+} MusicBrainzIds;
+
+// Gains in dB, peaks as a fraction of full scale.
+typedef struct ReplayGain {
+    bool has_track_gain;
+    float track_gain;
+    bool has_track_peak;
+    float track_peak;
+    bool has_album_gain;
+    float album_gain;
+    bool has_album_peak;
+    float album_peak;
+} ReplayGain;
+
 typedef struct Song {
     SongTags tags;
     char *relpath;
+    StreamInfo stream;
+    MusicBrainzIds musicbrainz;
+    ReplayGain replay_gain;
 } Song;
 
+// duration_ms totals the songs whose duration is known.
 typedef struct Album {
     AlbumTags tags;
     Song *songs;
     size_t song_count;
+    uint64_t duration_ms;
 } Album;
 
+typedef struct AlbumArt {
+    uint8_t *data;
+    size_t len;
+    char *mime_type;
+} AlbumArt;
+
 typedef struct AlbumTagsWithKey {
     Key key;
     AlbumTags tags;
 } AlbumTagsWithKey;
 
+typedef struct ArtistWithKey {
+    Key key;
+    char *name;
+    size_t album_count;
+} ArtistWithKey;
+
+// Ratings are 1 to 5 stars. last_played_ms is milliseconds since the Unix
+// epoch.
+typedef struct UserData {
+    bool has_rating;
+    uint8_t rating;
+    uint32_t play_count;
+    uint32_t skip_count;
+    bool has_last_played_ms;
+    uint64_t last_played_ms;
+    bool favourite;
+} UserData;
+
+typedef struct UserDataWithKey {
+    Key key;
+    UserData data;
+} UserDataWithKey;
+
+// Entries keep their place when their song leaves the library, with has_song
+// unset and song left empty. Times are milliseconds since the Unix epoch.
+typedef struct PlaylistEntry {
+    Key key;
+    bool has_song;
+    Song song;
+} PlaylistEntry;
+
+typedef struct Playlist {
+    char *name;
+    uint64_t created_ms;
+    uint64_t modified_ms;
+    PlaylistEntry *entries;
+    size_t entry_count;
+} Playlist;
+
+typedef struct PlaylistWithKey {
+    Key key;
+    char *name;
+    size_t entry_count;
+    uint64_t created_ms;
+    uint64_t modified_ms;
+} PlaylistWithKey;
+
+typedef struct SongWithKey {
+    Key key;
+    Song song;
+} SongWithKey;
+
+typedef struct SmartPlaylistWithKey {
+    Key key;
+    char *name;
+    char *query;
+    uint64_t created_ms;
+    uint64_t modified_ms;
+} SmartPlaylistWithKey;
+
+typedef struct SearchResults {
+    Key *songs;
+    size_t song_count;
+    Key *albums;
+    size_t album_count;
+    Key *artists;
+    size_t artist_count;
+} SearchResults;
+
+typedef struct LibraryRoot {
+    char *name;
+    char *path;
+} LibraryRoot;
+
+typedef struct ScanProgress {
+    size_t directories_walked;
+    size_t files_queued;
+    size_t files_tagged;
+    size_t songs_removed;
+    size_t errors;
+} ScanProgress;
+
+// Called with running totals as a scan progresses. Return false to cancel the scan.
+// When errors goes up, the file that failed is described by last_error_code and
+// last_error_message during the call.
+typedef bool (*scan_progress_callback)(const ScanProgress *progress,
+                                       void *user_data);
+
+// What a scan did. Files that failed are left as they were.
+typedef struct ScanSummary {
+    size_t added;
+    size_t updated;
+    size_t moved;
+    size_t removed;
+    size_t skipped;
+    size_t failed;
+} ScanSummary;
+
+// Why the last call on this thread returned false. Values are stable across releases.
+typedef enum ErrorCode {
+    ErrorCode_None = 0,
+    ErrorCode_NotFound = 1,
+    ErrorCode_Decode = 2,
+    ErrorCode_Storage = 3,
+    ErrorCode_Io = 4,
+    ErrorCode_TagRead = 5,
+    ErrorCode_InvalidKey = 6,
+    ErrorCode_Cancelled = 7,
+    ErrorCode_InvalidArgument = 8,
+    ErrorCode_Watch = 9,
+    ErrorCode_DatabaseTooNew = 10,
+} ErrorCode;
+
+ErrorCode last_error_code(void);
+
+// Owned by the library and valid until the next failing call on this thread.
+const char *last_error_message(void);
+
+typedef enum LibraryChangeKind {
+    LibraryChangeKind_SongAdded = 0,
+    LibraryChangeKind_SongUpdated = 1,
+    LibraryChangeKind_SongRemoved = 2,
+    LibraryChangeKind_Error = 3,
+    LibraryChangeKind_SongMoved = 4,
+} LibraryChangeKind;
+
+// Called from the watcher's own thread. song_key is NULL for errors, which are
+// described by last_error_code and last_error_message during the call.
+typedef void (*library_change_callback)(LibraryChangeKind kind,
+                                        const Key *song_key, void *user_data);
+
+// Dbs written by older versions are upgraded as they're opened, and dbs from
+// newer ones fail with ErrorCode_DatabaseTooNew. Dbs written with an older key
+// hash or key layout are rekeyed. Keys handed out before then no longer find
+// anything, and the next scan of each root re-reads its files.
 bool open_db(const char *path, db **out);
 
 void close_db(db *db);
@@ -62,14 +268,214 @@ bool album_tags_for_key(db *db, const Key *album_key, AlbumTags *out);
 
 bool album_for_key(db *db, const Key *album_key, Album *out);
 
+// Embedded or folder art, as stored. data is NULL when the album has none.
+bool album_art_for_key(db *db, const Key *album_key, AlbumArt *out);
+
+// The album's art scaled to fit a size by size square, made once per size and
+// cached. Art already that small, or that can't be decoded, comes back as
+// stored. Free with free_album_art.
+bool album_thumbnail_for_key(db *db, const Key *album_key, uint32_t size,
+                             AlbumArt *out);
+
+// Looks a song up by its MusicBrainz recording ID. relpath is the absolute
+// path of the file.
+bool song_by_mbid(db *db, const char *recording_id, Key *out_key, Song *out);
+
 bool scan_album_tags_sorted(db *db, AlbumTagsWithKey **out, size_t *out_len);
 
+bool scan_artists_sorted(db *db, ArtistWithKey **out, size_t *out_len);
+
+bool albums_for_artist(db *db, const Key *artist_key, AlbumTagsWithKey **out,
+                       size_t *out_len);
+
+bool search(db *db, const char *query, size_t limit, SearchResults *out);
+
+// out may be NULL, and is only written when the scan succeeds.
+bool scan_library_with_callback(db *db, const char *path,
+                                scan_progress_callback callback,
+                                void *user_data, ScanSummary *out);
+
+// Songs are stored relative to a named root, so pointing a root at a new path
+// keeps their keys. Scanning a directory outside every root adds one for it.
+// Roots can't nest: a path inside or holding another root fails with
+// ErrorCode_InvalidArgument.
+bool set_library_root(db *db, const char *name, const char *path);
+
+// Removes the root and its songs from the db. Files on disk are left alone.
+bool remove_library_root(db *db, const char *name);
+
+bool library_roots(db *db, LibraryRoot **out, size_t *out_len);
+
+// Which songs share an album.
+typedef enum AlbumGrouping {
+    // Albums differ whenever artist, title or year differ at all.
+    AlbumGrouping_Tags = 0,
+    // Title and album artist ignoring case, punctuation and a leading "the".
+    AlbumGrouping_NormalizedTags = 1,
+    // Every song in a directory is one album.
+    AlbumGrouping_Directory = 2,
+    // The MusicBrainz album ID, falling back to normalized tags.
+    AlbumGrouping_MusicBrainz = 3,
+} AlbumGrouping;
+
+// Songs move to their new albums on the next scan of each root.
+bool set_album_grouping(db *db, AlbumGrouping grouping);
+
+bool album_grouping(db *db, AlbumGrouping *out);
+
+// Folds albums split by inconsistent tags back together. `merged` is set to
+// how many albums were merged away.
+bool reconcile_albums(db *db, size_t *merged);
+
+// User data is kept apart from tags, so scans leave it alone, and it outlives
+// the songs and albums it's for. Keys are song or album keys. Songs and albums
+// with none get the defaults. The updating calls write the result to `out`
+// unless it's NULL.
+bool user_data_for_key(db *db, const Key *key, UserData *out);
+
+bool record_play(db *db, const Key *key, UserData *out);
+
+bool record_skip(db *db, const Key *key, UserData *out);
+
+// A rating of 0 clears it.
+bool set_rating(db *db, const Key *key, uint8_t rating, UserData *out);
+
+bool set_favourite(db *db, const Key *key, bool favourite, UserData *out);
+
+// Most played first, leaving out anything never played or no longer in the
+// library.
+bool most_played(db *db, size_t limit, UserDataWithKey **out, size_t *out_len);
+
+bool recently_played(db *db, size_t limit, UserDataWithKey **out,
+                     size_t *out_len);
+
+bool create_playlist(db *db, const char *name, Key *out_key);
+
+// Song relpaths are absolute paths.
+bool playlist_for_key(db *db, const Key *playlist_key, Playlist *out);
+
+// Sorted by name.
+bool playlists(db *db, PlaylistWithKey **out, size_t *out_len);
+
+bool rename_playlist(db *db, const Key *playlist_key, const char *name);
+
+bool delete_playlist(db *db, const Key *playlist_key);
+
+// Indexes count every entry, including those whose songs have left the
+// library. Inserting at the entry count appends.
+bool insert_into_playlist(db *db, const Key *playlist_key, size_t index,
+                          const Key *song_keys, size_t len);
+
+bool remove_from_playlist(db *db, const Key *playlist_key, size_t index);
+
+bool move_in_playlist(db *db, const Key *playlist_key, size_t from, size_t to);
+
+// Playlists are M3U8 or PLS, going by a .m3u8, .m3u or .pls extension.
+// Imported playlists are named after the file, leaving out entries that
+// aren't songs in the library. `unresolved` and `written` may be NULL.
+bool import_playlist(db *db, const char *path, Key *out_key,
+                     size_t *unresolved);
+
+bool export_playlist(db *db, const Key *playlist_key, const char *path,
+                     size_t *written);
+
+// Queries pick songs or albums by field, for example:
+//
+//   year = 1990..1999 and genre = rock and rating >= 4
+//   and not days_since_played < 30 sort by plays desc limit 50 offset 100
+//
+// Conditions are `field op value` joined with and, or, not and parentheses.
+// Text fields take = and != (ignoring case) or ~ (contains). Number fields
+// take =, !=, <, <=, >, >= or = with an inclusive range like 1990..1999.
+// Flag fields stand alone. Records missing a field never match a condition on
+// it. Values with spaces go in double quotes. An empty query matches
+// everything. Malformed queries fail with ErrorCode_InvalidArgument and a
+// message giving the position.
+//
+// Song fields: title, artist, album, album_artist, genre, composer and comment
+// are text. year, track, disc, duration (seconds), bitrate (kbps), rating,
+// plays, skips, days_since_played, date_added (ms since the Unix epoch) and
+// days_since_added are numbers. favourite is a flag.
+//
+// Album fields: title, artist and genre are text. year, track_count, duration,
+// rating, plays, days_since_played, date_added and days_since_added are
+// numbers. favourite is a flag. User data fields are the album's own.
+//
+// Pages follow on from `after`, the key of the last record of the previous
+// page, or start at the beginning when it's NULL. `offset` in the query skips
+// records past that. Song relpaths are absolute paths.
+bool query_songs(db *db, const char *query, const Key *after,
+                 SongWithKey **out, size_t *out_len);
+
+bool query_song_keys(db *db, const char *query, const Key *after, Key **out,
+                     size_t *out_len);
+
+bool query_albums(db *db, const char *query, const Key *after,
+                  AlbumTagsWithKey **out, size_t *out_len);
+
+bool query_album_keys(db *db, const char *query, const Key *after, Key **out,
+                      size_t *out_len);
+
+// When a song or album was first scanned, or 0 if that was before dates were
+// kept.
+bool date_added(db *db, const Key *key, uint64_t *out_added_ms);
+
+// Smart playlists store a query and run it each time their songs are asked
+// for.
+bool create_smart_playlist(db *db, const char *name, const char *query,
+                           Key *out_key);
+
+// Sorted by name.
+bool smart_playlists(db *db, SmartPlaylistWithKey **out, size_t *out_len);
+
+bool update_smart_playlist(db *db, const Key *playlist_key, const char *name,
+                           const char *query);
+
+bool delete_smart_playlist(db *db, const Key *playlist_key);
+
+bool smart_playlist_songs(db *db, const Key *playlist_key, SongWithKey **out,
+                          size_t *out_len);
+
+// Scans a root added with set_library_root, leaving songs in other roots alone.
+bool scan_library_root_with_callback(db *db, const char *name,
+                                     scan_progress_callback callback,
+                                     void *user_data, ScanSummary *out);
+
+bool watch_library(db *db, const char *path, uint64_t debounce_ms,
+                   library_change_callback callback, void *user_data,
+                   library_watcher **out);
+
+void stop_watching(library_watcher *watcher);
+
 void free_album_tags(AlbumTags *tags);
 
 void free_album(Album *album);
 
+// Songs in an album are freed with it.
+void free_song(Song *song);
+
 void free_album_tags_sorted(AlbumTagsWithKey *albums, size_t len);
 
+void free_artists_sorted(ArtistWithKey *artists, size_t len);
+
+void free_search_results(SearchResults *results);
+
+void free_library_roots(LibraryRoot *roots, size_t len);
+
+void free_user_data_sorted(UserDataWithKey *entries, size_t len);
+
+void free_album_art(AlbumArt *art);
+
+void free_playlist(Playlist *playlist);
+
+void free_playlists(PlaylistWithKey *playlists, size_t len);
+
+void free_songs_with_key(SongWithKey *songs, size_t len);
+
+void free_smart_playlists(SmartPlaylistWithKey *playlists, size_t len);
+
+void free_keys(Key *keys, size_t len);
+
 #ifdef __cplusplus
 }
 #endif
@@ -0,0 +1,262 @@
+use audiotags::{FlacTag, Id3v2Tag, Mp4Tag, Tag};
+use music_cache_derive::derive_data_model;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    vorbis_comments::{read_asf_comments, read_ogg_comments},
+    AudioTag, Error, ExtendedTags, Result,
+};
+
+#[derive_data_model]
+#[derive(Clone, Copy, Hash)]
+pub enum Container {
+    Mpeg,
+    Flac,
+    Mp4,
+    Ogg,
+    Wav,
+    Aiff,
+    Asf,
+}
+
+#[derive_data_model]
+#[derive(Clone, Copy, Hash)]
+pub enum Codec {
+    Mp3,
+    Flac,
+    Aac,
+    Alac,
+    Vorbis,
+    Opus,
+    Pcm,
+    Wma,
+}
+
+// The codec is None when the container doesn't say without parsing further than the first few bytes.
+#[derive_data_model]
+#[derive(Clone, Copy, Hash)]
+pub struct AudioFormat {
+    pub container: Container,
+    pub codec: Option<Codec>,
+}
+
+impl AudioFormat {
+    pub fn new(container: Container, codec: Option<Codec>) -> AudioFormat {
+        AudioFormat { container, codec }
+    }
+}
+
+// How many bytes from the start of a file detectors are given. Shorter files are passed whole.
+pub const SNIFF_LEN: usize = 64;
+
+// Recognises a format from the start of a file. Detectors added to ScanOptions are tried before the
+// built in MagicBytes, so they can handle formats it doesn't know or override ones it gets wrong.
+pub trait FormatDetector: Send + Sync {
+    fn detect(&self, header: &[u8]) -> Option<AudioFormat>;
+}
+
+pub struct MagicBytes;
+
+pub(crate) const ASF_HEADER_GUID: [u8; 16] = [
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+
+impl FormatDetector for MagicBytes {
+    fn detect(&self, header: &[u8]) -> Option<AudioFormat> {
+        let format = if header.starts_with(b"fLaC") {
+            AudioFormat::new(Container::Flac, Some(Codec::Flac))
+        } else if header.starts_with(b"ID3") || is_mp3_frame(header) {
+            AudioFormat::new(Container::Mpeg, Some(Codec::Mp3))
+        } else if header.get(4..8) == Some(b"ftyp") {
+            AudioFormat::new(Container::Mp4, None)
+        } else if header.starts_with(b"OggS") {
+            AudioFormat::new(Container::Ogg, ogg_codec(header))
+        } else if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WAVE") {
+            AudioFormat::new(Container::Wav, wav_codec(header))
+        } else if header.starts_with(b"FORM") && header.get(8..12) == Some(b"AIFF") {
+            AudioFormat::new(Container::Aiff, Some(Codec::Pcm))
+        } else if header.starts_with(b"FORM") && header.get(8..12) == Some(b"AIFC") {
+            AudioFormat::new(Container::Aiff, None)
+        } else if header.starts_with(&ASF_HEADER_GUID) {
+            AudioFormat::new(Container::Asf, Some(Codec::Wma))
+        } else {
+            return None;
+        };
+        Some(format)
+    }
+}
+
+// A frame sync followed by MPEG layer III. Files without an ID3 tag start straight at a frame.
+fn is_mp3_frame(header: &[u8]) -> bool {
+    matches!(header, [0xFF, second, ..] if second & 0xE0 == 0xE0 && (second >> 1) & 0x03 == 0x01)
+}
+
+// The first packet, after the page header and its segment table, identifies the codec.
+fn ogg_codec(header: &[u8]) -> Option<Codec> {
+    let segments = usize::from(*header.get(26)?);
+    let packet = header.get(27 + segments..)?;
+    if packet.starts_with(b"\x01vorbis") {
+        Some(Codec::Vorbis)
+    } else if packet.starts_with(b"OpusHead") {
+        Some(Codec::Opus)
+    } else if packet.starts_with(b"\x7FFLAC") {
+        Some(Codec::Flac)
+    } else {
+        None
+    }
+}
+
+// The fmt chunk nearly always comes first, and its format tag says whether the samples are plain PCM.
+fn wav_codec(header: &[u8]) -> Option<Codec> {
+    if header.get(12..16) != Some(b"fmt ") {
+        return None;
+    }
+    match u16::from_le_bytes([*header.get(20)?, *header.get(21)?]) {
+        0x0001 | 0x0003 | 0xFFFE => Some(Codec::Pcm),
+        _ => None,
+    }
+}
+
+// Decides which files a scan looks at and what they turn out to be. Extensions are compared case
+// insensitively, without the leading dot.
+#[derive(Clone)]
+pub struct AudioFormats {
+    extensions: HashSet<String>,
+    detectors: Vec<Arc<dyn FormatDetector>>,
+}
+
+impl Default for AudioFormats {
+    fn default() -> Self {
+        let extensions = [
+            "mp3", "flac", "m4a", "m4b", "ogg", "oga", "opus", "wav", "aif", "aiff", "aifc", "wma",
+        ];
+        AudioFormats {
+            extensions: extensions.into_iter().map(String::from).collect(),
+            detectors: Vec::new(),
+        }
+    }
+}
+
+impl AudioFormats {
+    pub fn with_extensions<S: AsRef<str>>(
+        mut self,
+        extensions: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.extensions = extensions
+            .into_iter()
+            .map(|extension| extension.as_ref().to_lowercase())
+            .collect();
+        self
+    }
+
+    pub fn with_detector(mut self, detector: Arc<dyn FormatDetector>) -> Self {
+        self.detectors.push(detector);
+        self
+    }
+
+    pub fn accepts(&self, path: &Path) -> bool {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| self.extensions.contains(&extension.to_lowercase()))
+    }
+
+    pub fn detect(&self, path: &Path) -> io::Result<Option<AudioFormat>> {
+        let mut header = Vec::with_capacity(SNIFF_LEN);
+        File::open(path)?
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut header)?;
+        Ok(self
+            .detectors
+            .iter()
+            .find_map(|detector| detector.detect(&header))
+            .or_else(|| MagicBytes.detect(&header)))
+    }
+}
+
+// Picks the tag reader from what the file contains rather than its extension. Files that couldn't
+// be identified fall back to going by their extension, without any extended tags.
+pub fn read_audio_tag(
+    path: &Path,
+    format: Option<AudioFormat>,
+) -> Result<(AudioTag, ExtendedTags)> {
+    let tag = match format.map(|format| format.container) {
+        Some(Container::Mpeg) => or_empty_id3(id3::Tag::read_from_path(path))
+            .map(id3_tags)
+            .map_err(Into::into),
+        Some(Container::Flac) => metaflac::Tag::read_from_path(path)
+            .map(flac_tags)
+            .map_err(Into::into),
+        Some(Container::Mp4) => mp4ameta::Tag::read_from_path(path)
+            .map(mp4_tags)
+            .map_err(Into::into),
+        // id3 finds the tag chunk itself from the file's header.
+        Some(Container::Wav | Container::Aiff) => or_empty_id3(id3::Tag::read_from_path(path))
+            .map(id3_tags)
+            .map_err(Into::into),
+        Some(Container::Ogg) => read_ogg_comments(path)
+            .map(comment_tags)
+            .map_err(audiotags::Error::IOError),
+        Some(Container::Asf) => read_asf_comments(path)
+            .map(comment_tags)
+            .map_err(audiotags::Error::IOError),
+        None => Tag::new()
+            .read_from_path(path)
+            .map(|tag| (tag, ExtendedTags::default())),
+    };
+    tag.map_err(|e| Error::tag_read(path, e))
+}
+
+fn id3_tags(tag: id3::Tag) -> (AudioTag, ExtendedTags) {
+    let extended = ExtendedTags::from_id3(&tag);
+    (Box::new(Id3v2Tag::from(tag)), extended)
+}
+
+fn flac_tags(tag: metaflac::Tag) -> (AudioTag, ExtendedTags) {
+    let comments = tag.vorbis_comments().map(|comments| &comments.comments);
+    let extended =
+        ExtendedTags::from_vorbis(comments.into_iter().flatten().flat_map(|(name, values)| {
+            values
+                .iter()
+                .map(move |value| (name.as_str(), value.as_str()))
+        }));
+    (Box::new(FlacTag::from(tag)), extended)
+}
+
+// Ogg and ASF tags come out as Vorbis comments, so audiotags reads them as it does FLAC's.
+fn comment_tags(comments: Vec<(String, String)>) -> (AudioTag, ExtendedTags) {
+    let extended = ExtendedTags::from_vorbis(
+        comments
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    );
+    let mut tag = metaflac::Tag::new();
+    let vorbis_comments = tag.vorbis_comments_mut();
+    for (name, value) in comments {
+        vorbis_comments
+            .comments
+            .entry(name.to_ascii_uppercase())
+            .or_default()
+            .push(value);
+    }
+    (Box::new(FlacTag::from(tag)), extended)
+}
+
+fn mp4_tags(tag: mp4ameta::Tag) -> (AudioTag, ExtendedTags) {
+    let extended = ExtendedTags::from_mp4(&tag);
+    (Box::new(Mp4Tag::from(tag)), extended)
+}
+
+// ID3 tags are optional. Plenty of MP3s have none at all, and WAV and AIFF files only sometimes
+// carry an ID3 chunk.
+fn or_empty_id3(tag: id3::Result<id3::Tag>) -> id3::Result<id3::Tag> {
+    match tag {
+        Err(e) if matches!(e.kind, id3::ErrorKind::NoTag) => Ok(id3::Tag::new()),
+        tag => tag,
+    }
+}
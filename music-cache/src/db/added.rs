@@ -0,0 +1,46 @@
+use std::time::{Duration, SystemTime};
+
+use crate::*;
+
+// When songs and albums first turned up, in milliseconds since the Unix epoch. Kept in a tree of its own
+// like user data, so a song that goes and comes back keeps its date. Songs stored before this was
+// recorded have none.
+pub(crate) const ADDED_TREE: &str = "added";
+
+pub(crate) fn added_tree<S: Store>(tree: &S) -> Result<S::Tree> {
+    tree.open_tree(ADDED_TREE)
+}
+
+// Leaves any earlier date alone.
+pub(crate) fn mark_added(tree: &impl Store, key: &Key) -> Result<()> {
+    let now = millis_since_epoch(SystemTime::now()).to_be_bytes();
+    // Failing the swap just means the key already had a date.
+    let _ = added_tree(tree)?.compare_and_swap(key, None, Some(&now[..]))?;
+    Ok(())
+}
+
+// Moves a date to another key, keeping the earlier one when both have a date.
+pub(crate) fn merge_added(tree: &impl Store, from: &Key, into: &Key) -> Result<()> {
+    let added = added_tree(tree)?;
+    let Some(from_bytes) = added.remove(from)? else {
+        return Ok(());
+    };
+    let from_ms = u64::from_be_bytes(from_bytes.as_slice().try_into()?);
+    let earliest = match added_ms(tree, into)? {
+        Some(into_ms) => into_ms.min(from_ms),
+        None => from_ms,
+    };
+    added.insert(into, earliest.to_be_bytes())?;
+    Ok(())
+}
+
+pub fn added_ms(tree: &impl Store, key: &Key) -> Result<Option<u64>> {
+    match added_tree(tree)?.get(key)? {
+        Some(bytes) => Ok(Some(u64::from_be_bytes(bytes.as_slice().try_into()?))),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn added_time(tree: &impl Store, key: &Key) -> Result<Option<SystemTime>> {
+    Ok(added_ms(tree, key)?.map(|ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms)))
+}
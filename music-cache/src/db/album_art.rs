@@ -0,0 +1,262 @@
+use music_cache_derive::derive_data_model;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::*;
+
+// Art lives in its own tree so scans over the main keyspace don't drag image data through memory.
+pub(crate) const ALBUM_ART_TREE: &str = "album_art";
+// Scaled down copies of the art, keyed by the art's key followed by their size.
+pub(crate) const ALBUM_THUMBNAIL_TREE: &str = "album_thumbnails";
+
+// Pictures are stored as found, embedded or on disk, and shared by every album using the same image.
+#[derive_data_model]
+#[derive(Clone)]
+pub struct AlbumArt {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+impl AlbumArt {
+    pub fn serialize(&self) -> Vec<u8> {
+        bitcode::encode(self)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<AlbumArt> {
+        Ok(bitcode::decode(bytes)?)
+    }
+
+    pub fn hash_key(&self) -> Key {
+        let mut hasher = KeyHasher::new();
+        hasher.write_bytes(&self.data);
+        hash_key(KeyType::AlbumArt, hasher)
+    }
+
+    pub(crate) fn from_picture(picture: audiotags::Picture) -> AlbumArt {
+        AlbumArt {
+            mime_type: picture.mime_type.into(),
+            data: picture.data.to_vec(),
+        }
+    }
+}
+
+pub(crate) fn album_art_tree<S: Store>(tree: &S) -> Result<S::Tree> {
+    tree.open_tree(ALBUM_ART_TREE)
+}
+
+pub(crate) fn album_thumbnail_tree<S: Store>(tree: &S) -> Result<S::Tree> {
+    tree.open_tree(ALBUM_THUMBNAIL_TREE)
+}
+
+// Names are matched case insensitively, and earlier ones win when a directory has several.
+const FOLDER_ART_NAMES: [&str; 4] = ["cover", "folder", "front", "album"];
+
+fn folder_art_mime_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        _ => None,
+    }
+}
+
+fn folder_art_rank(path: &Path) -> Option<usize> {
+    folder_art_mime_type(path)?;
+    let stem = path.file_stem()?.to_str()?.to_lowercase();
+    FOLDER_ART_NAMES.iter().position(|name| *name == stem)
+}
+
+// Picks the image standing in for the album out of the files in a directory.
+pub(crate) fn find_folder_art(paths: impl IntoIterator<Item = PathBuf>) -> Option<PathBuf> {
+    paths
+        .into_iter()
+        .filter_map(|path| Some((folder_art_rank(&path)?, path)))
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, path)| path)
+}
+
+pub(crate) fn folder_art_in(dir: &Path) -> Option<PathBuf> {
+    let entries = std::fs::read_dir(dir).ok()?;
+    find_folder_art(entries.flatten().map(|entry| entry.path()))
+}
+
+fn read_folder_art(path: &Path) -> Option<AlbumArt> {
+    Some(AlbumArt {
+        mime_type: folder_art_mime_type(path)?.to_string(),
+        data: std::fs::read(path).ok()?,
+    })
+}
+
+// A cover image beside a directory's files. The first of them to need it reads and stores it, and
+// the rest reuse its key rather than reading and hashing it again.
+pub(crate) struct FolderArt {
+    path: PathBuf,
+    // None until it's been read, then the key it was stored under, if it could be read.
+    key: Mutex<Option<Option<Key>>>,
+}
+
+impl FolderArt {
+    pub(crate) fn new(path: PathBuf) -> Arc<FolderArt> {
+        Arc::new(FolderArt {
+            path,
+            key: Mutex::new(None),
+        })
+    }
+
+    // The lock is held while reading, so files loaded in parallel wait for the one read.
+    pub(crate) fn store(&self, tree: &impl Store) -> Result<Option<Key>> {
+        let mut key = self.key.lock().unwrap();
+        if let Some(key) = &*key {
+            return Ok(key.clone());
+        }
+        // A cover that can't be read just leaves the album without one.
+        let stored = read_folder_art(&self.path)
+            .map(|art| store_album_art(tree, &art))
+            .transpose()?;
+        *key = Some(stored.clone());
+        Ok(stored)
+    }
+}
+
+// The same picture embedded in every track of an album is only written once.
+pub fn store_album_art(tree: &impl Store, art: &AlbumArt) -> Result<Key> {
+    let key = art.hash_key();
+    let art_tree = album_art_tree(tree)?;
+    if !art_tree.contains_key(&key)? {
+        art_tree.insert(&key, art.serialize())?;
+    }
+    Ok(key)
+}
+
+pub fn link_album_art(tree: &impl Store, album_key: &Key, art_key: &Key) -> Result<()> {
+    let art_key = *art_key.to_byte_key();
+    let mut error = None;
+    tree.fetch_and_update(album_key, |maybe_bytes| {
+        let bytes = maybe_bytes?;
+        match StoredAlbum::partial_deserialize_album(bytes) {
+            Ok(mut album) => {
+                album.art = Some(art_key);
+                Some(album.serialize())
+            }
+            Err(e) => {
+                error = Some(e);
+                Some(bytes.to_vec())
+            }
+        }
+    })?;
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn album_art_key(tree: &impl Store, album_key: &Key) -> Result<Option<ByteKey>> {
+    let bytes = tree
+        .get(album_key)?
+        .ok_or_else(|| Error::NotFound(album_key.clone()))?;
+    Ok(StoredAlbum::partial_deserialize_album(&bytes)?.art)
+}
+
+fn stored_album_art(tree: &impl Store, art_key: &ByteKey) -> Result<Option<AlbumArt>> {
+    match album_art_tree(tree)?.get(art_key)? {
+        Some(bytes) => Ok(Some(AlbumArt::deserialize(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn album_art(tree: &impl Store, album_key: &Key) -> Result<Option<AlbumArt>> {
+    match album_art_key(tree, album_key)? {
+        Some(art_key) => stored_album_art(tree, &art_key),
+        None => Ok(None),
+    }
+}
+
+fn thumbnail_key(art_key: &ByteKey, size: u32) -> Vec<u8> {
+    [&art_key[..], &size.to_be_bytes()].concat()
+}
+
+// Scaled to fit a square of `size` pixels, keeping its shape. PNGs stay PNGs so transparency
+// survives, and everything else becomes a JPEG. None when the art can't be decoded or is already
+// that small.
+fn scale_album_art(art: &AlbumArt, size: u32) -> Option<AlbumArt> {
+    let format = image::guess_format(&art.data).ok()?;
+    let image = image::load_from_memory_with_format(&art.data, format).ok()?;
+    if image.width() <= size && image.height() <= size {
+        return None;
+    }
+    let thumbnail = image.thumbnail(size, size);
+    let mut data = std::io::Cursor::new(Vec::new());
+    let mime_type = if format == image::ImageFormat::Png {
+        thumbnail
+            .write_to(&mut data, image::ImageFormat::Png)
+            .ok()?;
+        "image/png"
+    } else {
+        let thumbnail = image::DynamicImage::from(thumbnail.into_rgb8());
+        thumbnail
+            .write_to(&mut data, image::ImageFormat::Jpeg)
+            .ok()?;
+        "image/jpeg"
+    };
+    Some(AlbumArt {
+        mime_type: mime_type.to_string(),
+        data: data.into_inner(),
+    })
+}
+
+// Each size is made the first time it's asked for and kept until the art is pruned. Art that's
+// already small enough, or that can't be decoded, comes back as stored.
+pub(crate) fn album_thumbnail(
+    tree: &impl Store,
+    album_key: &Key,
+    size: u32,
+) -> Result<Option<AlbumArt>> {
+    let Some(art_key) = album_art_key(tree, album_key)? else {
+        return Ok(None);
+    };
+    let size = size.max(1);
+    let thumbnail_tree = album_thumbnail_tree(tree)?;
+    let key = thumbnail_key(&art_key, size);
+    if let Some(bytes) = thumbnail_tree.get(&key)? {
+        return Ok(Some(AlbumArt::deserialize(&bytes)?));
+    }
+    let Some(art) = stored_album_art(tree, &art_key)? else {
+        return Ok(None);
+    };
+    match scale_album_art(&art, size) {
+        Some(thumbnail) => {
+            thumbnail_tree.insert(&key, thumbnail.serialize())?;
+            Ok(Some(thumbnail))
+        }
+        None => Ok(Some(art)),
+    }
+}
+
+// Art outlives the albums that used it, so anything no album links to any more is dropped.
+pub fn prune_album_art(tree: &impl Store) -> Result<()> {
+    let mut linked = HashSet::new();
+    for entry in tree.scan_prefix(KeyType::Album) {
+        let (_, bytes) = entry?;
+        if let Some(art_key) = StoredAlbum::partial_deserialize_album(&bytes)?.art {
+            linked.insert(art_key);
+        }
+    }
+    let art_tree = album_art_tree(tree)?;
+    for entry in art_tree.iter() {
+        let (key, _) = entry?;
+        if !linked.contains(key.as_slice()) {
+            art_tree.remove(key)?;
+        }
+    }
+    let thumbnail_tree = album_thumbnail_tree(tree)?;
+    for entry in thumbnail_tree.iter() {
+        let (key, _) = entry?;
+        if !linked.contains(&key[..key.len().saturating_sub(4)]) {
+            thumbnail_tree.remove(key)?;
+        }
+    }
+    Ok(())
+}
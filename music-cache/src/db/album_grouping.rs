@@ -0,0 +1,219 @@
+use music_cache_derive::derive_data_model;
+use std::collections::HashMap;
+
+use crate::*;
+
+// Decides which songs share an album. Tags keeps albums apart whenever artist, title or year differ
+// at all, which splits albums whose tracks weren't tagged consistently; the others are more forgiving.
+// Changing it makes the next scan of every root re-read all its files, so songs move to their new albums.
+#[repr(C)]
+#[derive_data_model]
+#[derive(Clone, Copy, Default)]
+pub enum AlbumGrouping {
+    #[default]
+    Tags,
+    // Title and album artist ignoring case, punctuation and a leading "the". Year is left out.
+    NormalizedTags,
+    // Every song in a directory is one album, whatever it's tagged with.
+    Directory,
+    // The MusicBrainz release ID when a file has one, and normalized tags otherwise.
+    MusicBrainz,
+}
+
+pub fn album_grouping_key() -> Key {
+    hash_key(KeyType::AlbumGrouping, KeyHasher::new())
+}
+
+// Each grouping hashes a marker first, so keys from one can't collide with keys from another.
+fn grouping_hasher(grouping: AlbumGrouping) -> KeyHasher {
+    let mut hasher = KeyHasher::new();
+    hasher.write_u8(grouping as u8);
+    hasher
+}
+
+fn normalize_album_field(value: &str) -> String {
+    let normalized: String = value
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    let normalized = normalized.trim_start();
+    let normalized = normalized.strip_prefix("the ").unwrap_or(normalized);
+    normalized.split_whitespace().collect()
+}
+
+fn normalized_tags_key(album_tags: &AlbumTags) -> Key {
+    let mut hasher = grouping_hasher(AlbumGrouping::NormalizedTags);
+    hasher.write_option_str(
+        album_tags
+            .artist
+            .as_deref()
+            .map(normalize_album_field)
+            .as_deref(),
+    );
+    hasher.write_option_str(
+        album_tags
+            .title
+            .as_deref()
+            .map(normalize_album_field)
+            .as_deref(),
+    );
+    hash_key(KeyType::Album, hasher)
+}
+
+// The key of the album a song belongs to under the grouping.
+pub fn album_key(grouping: AlbumGrouping, album_tags: &AlbumTags, song: &Song) -> Key {
+    match grouping {
+        AlbumGrouping::Tags => album_tags.hash_key(),
+        AlbumGrouping::NormalizedTags => normalized_tags_key(album_tags),
+        AlbumGrouping::Directory => {
+            let mut hasher = grouping_hasher(grouping);
+            hasher.write_str(&song.root);
+            let path = song.path();
+            hasher.write_option_bytes(path.parent().map(|dir| dir.as_os_str().as_encoded_bytes()));
+            hash_key(KeyType::Album, hasher)
+        }
+        AlbumGrouping::MusicBrainz => match &song.musicbrainz.release_id {
+            Some(id) => {
+                let mut hasher = grouping_hasher(grouping);
+                hasher.write_str(&id.trim().to_lowercase());
+                hash_key(KeyType::Album, hasher)
+            }
+            None => normalized_tags_key(album_tags),
+        },
+    }
+}
+
+// An album merged into another leaves an alias behind, so songs that would still be grouped under it
+// join the album that took it in rather than splitting it off again.
+fn album_alias_key(album_key: &Key) -> Key {
+    album_key.with_key_type(KeyType::AlbumAlias)
+}
+
+// Follows merges to the album a song grouped under album_key now belongs to. The hop limit only guards
+// against a db with aliases that loop.
+pub(crate) fn resolve_album_key(tree: &impl Store, album_key: Key) -> Result<Key> {
+    let mut resolved = album_key;
+    for _ in 0..16 {
+        match tree.get(album_alias_key(&resolved))? {
+            Some(bytes) => resolved = Key::try_from(&bytes)?,
+            None => break,
+        }
+    }
+    Ok(resolved)
+}
+
+// Albums are near duplicates when their titles match once normalized, and their artists and years
+// match or are missing from one of them. Untitled albums are never merged.
+fn near_duplicates(a: &AlbumTags, b: &AlbumTags) -> bool {
+    let matches_or_missing = |a: Option<String>, b: Option<String>| match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    };
+    let title = |tags: &AlbumTags| tags.title.as_deref().map(normalize_album_field);
+    let artist = |tags: &AlbumTags| tags.artist.as_deref().map(normalize_album_field);
+    title(a).is_some()
+        && title(a) == title(b)
+        && matches_or_missing(artist(a), artist(b))
+        && (a.year.is_none() || b.year.is_none() || a.year == b.year)
+}
+
+fn fill_missing(tags: &mut AlbumTags, other: &AlbumTags) {
+    tags.artist = tags.artist.take().or_else(|| other.artist.clone());
+    tags.year = tags.year.or(other.year);
+    tags.genre = tags.genre.take().or_else(|| other.genre.clone());
+    tags.total_discs = tags.total_discs.or(other.total_discs);
+}
+
+// Folds albums that were split by inconsistent tags back together. The album with the most songs
+// keeps its key and takes in the others' songs, along with any tags or art it was missing. Returns
+// how many albums were merged away.
+pub(crate) fn merge_split_albums(tree: &impl Store) -> Result<usize> {
+    let mut by_title: HashMap<String, Vec<(Key, StoredAlbum)>> = HashMap::new();
+    for entry in tree.scan_prefix(KeyType::Album) {
+        let (key, bytes) = entry?;
+        let album = StoredAlbum::partial_deserialize_album(&bytes)?;
+        if let Some(title) = album.tags.title.as_deref().map(normalize_album_field) {
+            by_title
+                .entry(title)
+                .or_default()
+                .push((Key::try_from(&key)?, album));
+        }
+    }
+
+    let mut merged = 0;
+    for mut albums in by_title.into_values().filter(|albums| albums.len() > 1) {
+        albums.sort_by(|(a_key, a), (b_key, b)| {
+            b.song_keys
+                .len()
+                .cmp(&a.song_keys.len())
+                .then_with(|| a_key.to_byte_key().cmp(b_key.to_byte_key()))
+        });
+        while !albums.is_empty() {
+            let (survivor_key, mut survivor) = albums.remove(0);
+            let previous_tags = survivor.tags.clone();
+            let mut duplicates = Vec::new();
+            let mut index = 0;
+            while index < albums.len() {
+                if near_duplicates(&survivor.tags, &albums[index].1.tags) {
+                    let (key, album) = albums.remove(index);
+                    fill_missing(&mut survivor.tags, &album.tags);
+                    duplicates.push((key, album));
+                } else {
+                    index += 1;
+                }
+            }
+            if duplicates.is_empty() {
+                continue;
+            }
+            merge_albums(
+                tree,
+                &survivor_key,
+                &mut survivor,
+                &previous_tags,
+                duplicates,
+            )?;
+            merged += 1;
+        }
+    }
+    Ok(merged)
+}
+
+fn merge_albums(
+    tree: &impl Store,
+    survivor_key: &Key,
+    survivor: &mut StoredAlbum,
+    previous_tags: &AlbumTags,
+    duplicates: Vec<(Key, StoredAlbum)>,
+) -> Result<()> {
+    for (key, album) in duplicates {
+        survivor.art = survivor.art.or(album.art);
+        for song_key in album.song_keys {
+            if !survivor.song_keys.iter().any(|(_, key)| *key == song_key.1) {
+                survivor.song_keys.push(song_key);
+            }
+        }
+        tree.remove(&key)?;
+        update_search_index(tree, &key, Some(&album.tags), None)?;
+        unlink_album_from_artist(tree, &album.tags, &key)?;
+        merge_user_data(tree, &key, survivor_key)?;
+        merge_added(tree, &key, survivor_key)?;
+        tree.insert(album_alias_key(&key), &survivor_key.to_byte_key()[..])?;
+    }
+    // The survivor is an album in its own right again, even if it was once merged away.
+    tree.remove(album_alias_key(survivor_key))?;
+    survivor.song_keys.sort_by(|a, b| a.0.cmp(&b.0));
+    tree.insert(survivor_key, survivor.serialize())?;
+
+    update_search_index(
+        tree,
+        survivor_key,
+        Some(previous_tags),
+        Some(&survivor.tags),
+    )?;
+    if previous_tags.artist != survivor.tags.artist {
+        unlink_album_from_artist(tree, previous_tags, survivor_key)?;
+        link_album_to_artist(tree, &survivor.tags, survivor_key)?;
+    }
+    Ok(())
+}
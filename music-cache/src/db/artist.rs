@@ -0,0 +1,119 @@
+use music_cache_derive::derive_data_model;
+
+use crate::*;
+
+// Albums are grouped under their album artist. Names are compared case and whitespace insensitively
+// so "The Band" and "the  band" share one record, but the first spelling seen is kept for display.
+#[derive_data_model]
+pub struct Artist {
+    pub name: Option<String>,
+    pub album_keys: Vec<ByteKey>,
+}
+
+impl Artist {
+    pub fn serialize(&self) -> Vec<u8> {
+        bitcode::encode(self)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Artist> {
+        Ok(bitcode::decode(bytes)?)
+    }
+}
+
+pub fn normalize_artist_name(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn add_album_to_artist(
+    maybe_bytes: Option<&[u8]>,
+    name: &Option<String>,
+    album_key: ByteKey,
+) -> Result<Artist> {
+    let mut artist = match maybe_bytes {
+        Some(bytes) => Artist::deserialize(bytes)?,
+        None => Artist {
+            name: name.clone(),
+            album_keys: Vec::new(),
+        },
+    };
+    if let Err(index) = artist.album_keys.binary_search(&album_key) {
+        artist.album_keys.insert(index, album_key);
+    }
+    Ok(artist)
+}
+
+fn remove_album_from_artist(bytes: &[u8], album_key: ByteKey) -> Result<Option<Artist>> {
+    let mut artist = Artist::deserialize(bytes)?;
+    artist.album_keys.retain(|key| *key != album_key);
+    if artist.album_keys.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(artist))
+}
+
+pub fn link_album_to_artist(
+    tree: &impl Store,
+    album_tags: &AlbumTags,
+    album_key: &Key,
+) -> Result<()> {
+    let artist_key = artist_hash_key(album_tags.artist.as_deref());
+    let byte_key = *album_key.to_byte_key();
+    let mut error = None;
+    let previous = tree.fetch_and_update(&artist_key, |maybe_bytes| {
+        match add_album_to_artist(maybe_bytes, &album_tags.artist, byte_key) {
+            Ok(artist) => Some(artist.serialize()),
+            Err(e) => {
+                error = Some(e);
+                maybe_bytes.map(Vec::from)
+            }
+        }
+    })?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    if previous.is_none() {
+        let artist = Artist {
+            name: album_tags.artist.clone(),
+            album_keys: Vec::new(),
+        };
+        update_search_index(tree, &artist_key, None, Some(&artist))?;
+    }
+    Ok(())
+}
+
+pub fn unlink_album_from_artist(
+    tree: &impl Store,
+    album_tags: &AlbumTags,
+    album_key: &Key,
+) -> Result<()> {
+    let artist_key = artist_hash_key(album_tags.artist.as_deref());
+    let byte_key = *album_key.to_byte_key();
+    let mut error = None;
+    let mut removed = false;
+    let previous = tree.fetch_and_update(&artist_key, |maybe_bytes| {
+        let bytes = maybe_bytes?;
+        match remove_album_from_artist(bytes, byte_key) {
+            Ok(artist) => {
+                removed = artist.is_none();
+                artist.map(|artist| artist.serialize())
+            }
+            Err(e) => {
+                error = Some(e);
+                Some(bytes.to_vec())
+            }
+        }
+    })?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    if let (true, Some(bytes)) = (removed, previous) {
+        let artist = Artist::deserialize(&bytes)?;
+        update_search_index(tree, &artist_key, Some(&artist), None)?;
+    }
+    Ok(())
+}
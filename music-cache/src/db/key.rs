@@ -1,103 +1,133 @@
-use std::{
-    hash::{DefaultHasher, Hasher},
-    mem,
+use crate::{
+    mark_new_db, normalize_artist_name, Album, AlbumTags, Artist, Error, KeyHasher, Playlist,
+    Result, SmartPlaylist, Song, Store,
 };
-
-use crate::{Album, AlbumTags, Result, Song};
 use music_cache_derive::{derive_data_model, taggable};
-use sled::IVec;
 
 #[repr(u8)]
-#[taggable(Song, Album, AlbumTags)]
+#[taggable(Song, Album, AlbumTags, Artist, Playlist, SmartPlaylist)]
 #[derive_data_model]
+#[derive(Clone, Copy)]
 // Variant names should exactly match types they are keys for.
 pub enum KeyType {
     Song,
     Album,
     LastScanTime,
-}
-
-#[repr(C, packed)]
-pub struct Key {
-    _tag: KeyType,
-    _id: u64,
-}
-
-impl Clone for Key {
-    fn clone(&self) -> Self {
-        Key::from_byte_key_owned(*self.to_byte_key())
+    Artist,
+    SearchToken,
+    SongPath,
+    LibraryRoot,
+    AlbumArt,
+    AlbumGrouping,
+    MusicBrainzId,
+    Playlist,
+    SmartPlaylist,
+    HashScheme,
+    SchemaVersion,
+    AlbumAlias,
+}
+
+// A KeyType byte followed by the id as a big-endian u64. Keys are stored as these bytes, so sled
+// orders each type's keys by id and a db reads the same on any machine.
+#[repr(C)]
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Key(ByteKey);
+
+pub type ByteKey = [u8; 9];
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key")
+            .field("tag", &self.0[0])
+            .field("id", &self.id())
+            .finish()
+    }
+}
+
+impl KeyType {
+    const ALL: [KeyType; 15] = [
+        KeyType::Song,
+        KeyType::Album,
+        KeyType::LastScanTime,
+        KeyType::Artist,
+        KeyType::SearchToken,
+        KeyType::SongPath,
+        KeyType::LibraryRoot,
+        KeyType::AlbumArt,
+        KeyType::AlbumGrouping,
+        KeyType::MusicBrainzId,
+        KeyType::Playlist,
+        KeyType::SmartPlaylist,
+        KeyType::HashScheme,
+        KeyType::SchemaVersion,
+        KeyType::AlbumAlias,
+    ];
+
+    pub fn from_tag(tag: u8) -> Option<KeyType> {
+        KeyType::ALL.get(tag as usize).copied()
     }
 }
 
-impl PartialEq for Key {
-    fn eq(&self, other: &Self) -> bool {
-        self.to_byte_key() == other.to_byte_key()
+impl Key {
+    pub fn new(key_type: KeyType, id: u64) -> Key {
+        let mut bytes = [key_type as u8; 9];
+        bytes[1..].copy_from_slice(&id.to_be_bytes());
+        Key(bytes)
     }
-}
 
-impl std::hash::Hash for Key {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.to_byte_key().hash(state);
+    // None for keys handed in through the FFI with a tag this build doesn't know.
+    pub fn key_type(&self) -> Option<KeyType> {
+        KeyType::from_tag(self.0[0])
     }
-}
-
-impl Eq for Key {}
 
-pub type ByteKey = [u8; mem::size_of::<Key>()];
-
-impl Key {
-    pub fn to_byte_key(&self) -> &ByteKey {
-        unsafe { std::mem::transmute(self) }
+    pub fn id(&self) -> u64 {
+        let mut id = [0; 8];
+        id.copy_from_slice(&self.0[1..]);
+        u64::from_be_bytes(id)
     }
 
-    pub fn from_byte_key(byte_key: &ByteKey) -> &Key {
-        unsafe { std::mem::transmute(byte_key) }
+    pub fn with_key_type(&self, key_type: KeyType) -> Key {
+        Key::new(key_type, self.id())
     }
 
-    pub fn from_byte_key_owned(byte_key: ByteKey) -> Key {
-        unsafe { std::mem::transmute(byte_key) }
+    pub fn to_byte_key(&self) -> &ByteKey {
+        &self.0
     }
-}
 
-impl AsRef<Key> for ByteKey {
-    fn as_ref(&self) -> &Key {
-        unsafe { &*(self as *const Self as *const Key) }
+    pub fn from_byte_key(byte_key: ByteKey) -> Key {
+        Key(byte_key)
     }
 }
 
-impl From<&IVec> for &Key {
-    fn from(vec: &IVec) -> Self {
-        let bytes = vec.as_ref();
-        unsafe { &*(bytes.as_ptr() as *const Key) }
+// Keys read back out of a store, checked for length and tag.
+impl TryFrom<&[u8]> for Key {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Key> {
+        let byte_key: ByteKey = bytes.try_into()?;
+        KeyType::from_tag(byte_key[0]).ok_or(Error::InvalidKey)?;
+        Ok(Key(byte_key))
     }
 }
 
-impl From<IVec> for &Key {
-    fn from(vec: IVec) -> Self {
-        let bytes = vec.as_ref();
-        unsafe { &*(bytes.as_ptr() as *const Key) }
+impl TryFrom<&Vec<u8>> for Key {
+    type Error = Error;
+
+    fn try_from(bytes: &Vec<u8>) -> Result<Key> {
+        Key::try_from(bytes.as_slice())
     }
 }
 
 impl AsRef<[u8]> for Key {
     fn as_ref(&self) -> &[u8] {
-        unsafe {
-            std::slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>())
-        }
-    }
-}
-
-impl AsRef<Key> for [u8] {
-    fn as_ref(&self) -> &Key {
-        unsafe { &*(self as *const Self as *const Key) }
+        &self.0
     }
 }
 
 impl AsRef<[u8]> for KeyType {
     fn as_ref(&self) -> &[u8] {
-        unsafe {
-            std::slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>())
-        }
+        // KeyType is repr(u8), so it's its own tag byte.
+        unsafe { std::slice::from_raw_parts(self as *const Self as *const u8, 1) }
     }
 }
 
@@ -105,66 +135,53 @@ pub trait KeyDBHelpers {
     fn generate_key(&self, value: &dyn TaggableKeyType) -> Result<Key>;
 }
 
-impl KeyDBHelpers for sled::Db {
+impl<S: Store> KeyDBHelpers for S {
     fn generate_key(&self, value: &dyn TaggableKeyType) -> Result<Key> {
-        Ok(Key {
-            _tag: value.tag(),
-            _id: self.generate_id()?,
-        })
+        mark_new_db(self)?;
+        Ok(Key::new(value.tag(), self.generate_id()?))
     }
 }
 
-pub fn hash_key(key_type: KeyType, hasher: impl Hasher) -> Key {
-    let hash = hasher.finish();
-    Key {
-        _tag: key_type,
-        _id: hash,
-    }
+pub fn hash_key(key_type: KeyType, hasher: KeyHasher) -> Key {
+    Key::new(key_type, hasher.finish())
+}
+
+// Holds the HASH_SCHEME the db's keys were made with. Its id is fixed, so it stays put whatever the
+// scheme.
+pub fn hash_scheme_key() -> Key {
+    Key::new(KeyType::HashScheme, 0)
 }
 
 pub trait HashKeyGen {
     fn hash_key(&self) -> Key;
 }
 
-pub fn song_hash_key(relpath: &[u8]) -> Key {
-    let mut hasher = DefaultHasher::new();
-    hasher.write(relpath);
+// Songs stored before there were roots have an empty root and their absolute path.
+pub fn song_hash_key(root: &str, relpath: &[u8]) -> Key {
+    let mut hasher = KeyHasher::new();
+    hasher.write_str(root);
+    hasher.write_bytes(relpath);
     hash_key(KeyType::Song, hasher)
 }
 
+pub fn artist_hash_key(name: Option<&str>) -> Key {
+    let mut hasher = KeyHasher::new();
+    hasher.write_option_str(name.map(normalize_artist_name).as_deref());
+    hash_key(KeyType::Artist, hasher)
+}
+
 impl HashKeyGen for Song {
     fn hash_key(&self) -> Key {
-        song_hash_key(&self.relpath)
+        song_hash_key(&self.root, &self.relpath)
     }
 }
 
 impl HashKeyGen for AlbumTags {
     fn hash_key(&self) -> Key {
-        let mut hasher = DefaultHasher::new();
-
-        hasher.maybe_write(&self.artist);
-        hasher.maybe_write(&self.title);
-        hasher.maybe_write_u16(&self.year);
-
+        let mut hasher = KeyHasher::new();
+        hasher.write_option_str(self.artist.as_deref());
+        hasher.write_option_str(self.title.as_deref());
+        hasher.write_option_u16(self.year);
         hash_key(KeyType::Album, hasher)
     }
 }
-
-trait HashMaybeWrite {
-    fn maybe_write(&mut self, val: &Option<String>);
-    fn maybe_write_u16(&mut self, val: &Option<u16>);
-}
-
-impl HashMaybeWrite for DefaultHasher {
-    fn maybe_write(&mut self, value: &Option<String>) {
-        if let Some(string) = value {
-            self.write(string.as_bytes());
-        }
-    }
-
-    fn maybe_write_u16(&mut self, val: &Option<u16>) {
-        if let Some(val) = val {
-            self.write(val.to_ne_bytes().as_slice());
-        }
-    }
-}
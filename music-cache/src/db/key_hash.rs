@@ -0,0 +1,141 @@
+// The hash behind every key derived from what it identifies, as opposed to ids sled hands out. Keys
+// outlive the program that wrote them, so the hash can't depend on the toolchain or the machine the
+// way std's DefaultHasher does. Scheme 1 is SipHash-1-3 with a zero key, fed through the methods
+// below only: integers go in little-endian, byte strings with their length in front as a u64, and
+// options as a 0 or 1 byte before any value. Scheme 2 doesn't change the hash at all. It only marks
+// where key ids started being written big-endian rather than in the machine's byte order. Changing
+// the hash or the key layout means bumping HASH_SCHEME, which rekeys existing dbs when they're next
+// opened. See rekey.
+pub const HASH_SCHEME: u32 = 2;
+
+const BLOCK_LEN: usize = 8;
+
+#[derive(Clone)]
+pub struct KeyHasher {
+    v: [u64; 4],
+    tail: [u8; BLOCK_LEN],
+    tail_len: usize,
+    len: u64,
+}
+
+impl Default for KeyHasher {
+    fn default() -> Self {
+        KeyHasher {
+            v: [
+                0x736f_6d65_7073_6575,
+                0x646f_7261_6e64_6f6d,
+                0x6c79_6765_6e65_7261,
+                0x7465_6462_7974_6573,
+            ],
+            tail: [0; BLOCK_LEN],
+            tail_len: 0,
+            len: 0,
+        }
+    }
+}
+
+impl KeyHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn round(&mut self) {
+        let [v0, v1, v2, v3] = &mut self.v;
+        *v0 = v0.wrapping_add(*v1);
+        *v1 = v1.rotate_left(13) ^ *v0;
+        *v0 = v0.rotate_left(32);
+        *v2 = v2.wrapping_add(*v3);
+        *v3 = v3.rotate_left(16) ^ *v2;
+        *v0 = v0.wrapping_add(*v3);
+        *v3 = v3.rotate_left(21) ^ *v0;
+        *v2 = v2.wrapping_add(*v1);
+        *v1 = v1.rotate_left(17) ^ *v2;
+        *v2 = v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, block: [u8; BLOCK_LEN]) {
+        let m = u64::from_le_bytes(block);
+        self.v[3] ^= m;
+        self.round();
+        self.v[0] ^= m;
+    }
+
+    fn write_raw(&mut self, mut bytes: &[u8]) {
+        self.len = self.len.wrapping_add(bytes.len() as u64);
+        if self.tail_len > 0 {
+            let fill = (BLOCK_LEN - self.tail_len).min(bytes.len());
+            self.tail[self.tail_len..self.tail_len + fill].copy_from_slice(&bytes[..fill]);
+            self.tail_len += fill;
+            bytes = &bytes[fill..];
+            if self.tail_len < BLOCK_LEN {
+                return;
+            }
+            self.compress(self.tail);
+            self.tail_len = 0;
+        }
+        let mut blocks = bytes.chunks_exact(BLOCK_LEN);
+        for block in &mut blocks {
+            self.compress(block.try_into().unwrap());
+        }
+        let rest = blocks.remainder();
+        self.tail[..rest.len()].copy_from_slice(rest);
+        self.tail_len = rest.len();
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.write_raw(&[value]);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_raw(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_raw(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_raw(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.write_raw(bytes);
+    }
+
+    pub fn write_str(&mut self, value: &str) {
+        self.write_bytes(value.as_bytes());
+    }
+
+    pub fn write_option_bytes(&mut self, value: Option<&[u8]>) {
+        self.write_u8(value.is_some() as u8);
+        if let Some(value) = value {
+            self.write_bytes(value);
+        }
+    }
+
+    pub fn write_option_str(&mut self, value: Option<&str>) {
+        self.write_option_bytes(value.map(str::as_bytes));
+    }
+
+    pub fn write_option_u16(&mut self, value: Option<u16>) {
+        self.write_u8(value.is_some() as u8);
+        if let Some(value) = value {
+            self.write_u16(value);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        let mut hasher = self.clone();
+        let mut last = [0; BLOCK_LEN];
+        last[..hasher.tail_len].copy_from_slice(&hasher.tail[..hasher.tail_len]);
+        last[BLOCK_LEN - 1] = hasher.len as u8;
+        hasher.compress(last);
+        hasher.v[2] ^= 0xff;
+        for _ in 0..3 {
+            hasher.round();
+        }
+        let [v0, v1, v2, v3] = hasher.v;
+        v0 ^ v1 ^ v2 ^ v3
+    }
+}
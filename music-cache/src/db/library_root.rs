@@ -0,0 +1,116 @@
+use music_cache_derive::derive_data_model;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::*;
+
+// A directory songs are stored relative to. Keys are derived from the root's name rather than where
+// it's mounted, so pointing a root at a new path keeps every song it holds.
+#[derive_data_model]
+#[derive(Clone)]
+pub struct LibraryRoot {
+    pub name: String,
+    pub path: Vec<u8>,
+}
+
+impl LibraryRoot {
+    pub fn new(name: &str, path: &Path) -> LibraryRoot {
+        LibraryRoot {
+            name: name.to_string(),
+            path: path.as_os_str().as_encoded_bytes().to_vec(),
+        }
+    }
+
+    pub fn path(&self) -> PathBuf {
+        path_from_bytes(&self.path)
+    }
+
+    // None when the path isn't inside this root.
+    pub fn relpath(&self, path: &Path) -> Option<Vec<u8>> {
+        let relpath = path.strip_prefix(self.path()).ok()?;
+        Some(relpath.as_os_str().as_encoded_bytes().to_vec())
+    }
+
+    // Whether the path is inside this root or holds it.
+    pub fn overlaps(&self, path: &Path) -> bool {
+        let root_path = self.path();
+        path.starts_with(&root_path) || root_path.starts_with(path)
+    }
+
+    pub fn resolve(&self, relpath: &[u8]) -> PathBuf {
+        self.path().join(path_from_bytes(relpath))
+    }
+
+    // Songs stored before there were roots belong to whichever root their absolute path is under.
+    pub fn holds(&self, song: &Song) -> bool {
+        if song.root.is_empty() {
+            song.path().starts_with(self.path())
+        } else {
+            song.root == self.name
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bitcode::encode(self)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<LibraryRoot> {
+        Ok(bitcode::decode(bytes)?)
+    }
+}
+
+pub fn library_root_key(name: &str) -> Key {
+    let mut hasher = KeyHasher::new();
+    hasher.write_str(name);
+    hash_key(KeyType::LibraryRoot, hasher)
+}
+
+// Each root is scanned on its own, so each has its own last scan time. It's only a record of when;
+// scans go by each file's stamp.
+pub fn last_scan_time_key(name: &str) -> Key {
+    library_root_key(name).with_key_type(KeyType::LastScanTime)
+}
+
+// Scan times are stored as big-endian seconds then nanoseconds since the epoch.
+pub(crate) fn encode_scan_time(time: SystemTime) -> [u8; 12] {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut bytes = [0; 12];
+    bytes[..8].copy_from_slice(&since_epoch.as_secs().to_be_bytes());
+    bytes[8..].copy_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+    bytes
+}
+
+pub(crate) fn decode_scan_time(bytes: &[u8]) -> Result<SystemTime> {
+    let bytes: [u8; 12] = bytes.try_into()?;
+    let secs = u64::from_be_bytes(bytes[..8].try_into()?);
+    let nanos = u32::from_be_bytes(bytes[8..].try_into()?);
+    Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+// The root with the longest path containing `path`, along with the path relative to it.
+pub fn root_for_path(tree: &impl Store, path: &Path) -> Result<Option<(LibraryRoot, Vec<u8>)>> {
+    let mut best: Option<(LibraryRoot, Vec<u8>)> = None;
+    for root in tree.library_roots()? {
+        if let Some(relpath) = root.relpath(path) {
+            if best
+                .as_ref()
+                .is_none_or(|(best, _)| best.path.len() < root.path.len())
+            {
+                best = Some((root, relpath));
+            }
+        }
+    }
+    Ok(best)
+}
+
+// Scanning or watching a directory outside every root registers it as a root of its own, named after
+// its path. A directory holding a root is refused rather than made a root around it.
+pub fn root_for_dir(tree: &impl Store, dir: &Path) -> Result<LibraryRoot> {
+    let dir = std::path::absolute(dir).map_err(|e| Error::io(dir, e))?;
+    match root_for_path(tree, &dir)? {
+        Some((root, _)) => Ok(root),
+        None => tree.set_library_root(&dir.to_string_lossy(), &dir),
+    }
+}
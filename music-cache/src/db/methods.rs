@@ -1,7 +1,7 @@
 use music_cache_derive::derive_data_model;
-use sled::IVec;
 use std::{
     collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
     time::SystemTime,
 };
 
@@ -15,102 +15,206 @@ pub trait Methods<T> {
 
 impl Song {
     pub fn serialize(&self) -> Vec<u8> {
-        bitcode::encode(self)
+        encode_record(SONG_FORMAT, self)
     }
 
-    pub fn deserialize(bytes: IVec) -> Result<Song> {
-        Ok(bitcode::decode(bytes.as_ref())?)
+    pub fn deserialize(bytes: &[u8]) -> Result<Song> {
+        if let Some(song) = decode_tagged(bytes, SONG_FORMAT) {
+            return Ok(song);
+        }
+        // Songs from the first release, from before records were tagged with their format.
+        bitcode::decode::<BasicSong>(bytes)
+            .map(Into::into)
+            .map_err(|e| undecodable(bytes, SONG_FORMAT, e))
+    }
+}
+
+// The layouts the first release stored songs and albums in, before records were tagged with their
+// format. They stay as they were so its dbs can still be read.
+#[derive(bitcode::Decode)]
+struct BasicSongTags {
+    title: Option<String>,
+    track_number: Option<u16>,
+}
+
+impl From<BasicSongTags> for SongTags {
+    fn from(tags: BasicSongTags) -> Self {
+        SongTags {
+            title: tags.title,
+            track_number: tags.track_number,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(bitcode::Decode)]
+struct BasicAlbumTags {
+    artist: Option<String>,
+    title: Option<String>,
+    year: Option<u16>,
+}
+
+impl From<BasicAlbumTags> for AlbumTags {
+    fn from(tags: BasicAlbumTags) -> Self {
+        AlbumTags {
+            artist: tags.artist,
+            title: tags.title,
+            year: tags.year,
+            ..Default::default()
+        }
+    }
+}
+
+// Songs written with only a title and track number. The rest of their tags are read the next time
+// their file is tagged.
+#[derive(bitcode::Decode)]
+struct BasicSong {
+    tags: BasicSongTags,
+    relpath: Vec<u8>,
+}
+
+impl From<BasicSong> for Song {
+    fn from(song: BasicSong) -> Self {
+        Song::new(song.tags.into(), &song.relpath)
     }
 }
 
-impl Methods<Song> for sled::Db {
+impl<S: Store> Methods<Song> for S {
     fn insert_metadata(&self, song: &Song) -> Result<Key> {
         let key = song.hash_key();
-        self.insert(&key, song)?;
+        let previous = self.insert(&key, song.serialize())?;
+        let previous = previous
+            .map(|bytes| Song::deserialize(&bytes))
+            .transpose()?;
+        update_search_index(self, &key, previous.as_ref(), Some(song))?;
         Ok(key)
     }
 
     fn get_metadata(&self, key: &Key) -> Result<Song> {
-        let bytes = self.get(key)?.ok_or("Could not find song tags key in db")?;
-        Song::deserialize(bytes)
+        let bytes = self.get(key)?.ok_or_else(|| Error::NotFound(key.clone()))?;
+        Song::deserialize(&bytes)
     }
 }
 
 #[derive_data_model]
 pub struct StoredAlbum {
-    tags: AlbumTags,
-    pub song_keys: Vec<(Option<u16>, ByteKey)>, // TODO Maybe Benchmark, but albums are small so maintaining sort this way seems best.
+    pub tags: AlbumTags,
+    pub song_keys: Vec<(TrackOrder, ByteKey)>, // TODO Maybe Benchmark, but albums are small so maintaining sort this way seems best.
+    // Key into the album art tree. See album_art_for_key.
+    pub art: Option<ByteKey>,
 }
 
-impl From<StoredAlbum> for IVec {
-    fn from(album: StoredAlbum) -> Self {
-        album.serialize().into()
-    }
+// Albums written with only an artist, title and year, and sorted by track number alone. Their order
+// is rebuilt when they're loaded.
+#[derive(bitcode::Decode)]
+struct BasicStoredAlbum {
+    tags: BasicAlbumTags,
+    song_keys: Vec<(Option<u16>, ByteKey)>,
 }
 
-impl From<&Song> for IVec {
-    fn from(song: &Song) -> Self {
-        song.serialize().into()
+impl From<BasicStoredAlbum> for StoredAlbum {
+    fn from(album: BasicStoredAlbum) -> Self {
+        StoredAlbum {
+            tags: album.tags.into(),
+            song_keys: album
+                .song_keys
+                .into_iter()
+                .map(|(track_number, key)| {
+                    let order = TrackOrder {
+                        disc_number: None,
+                        track_number,
+                        relpath: Vec::new(),
+                    };
+                    (order, key)
+                })
+                .collect(),
+            art: None,
+        }
     }
 }
 
 impl StoredAlbum {
-    pub fn new(tags: AlbumTags, first_track: (Option<u16>, ByteKey)) -> Self {
+    pub fn new(tags: AlbumTags, first_track: (TrackOrder, ByteKey)) -> Self {
         Self {
             tags,
             song_keys: vec![first_track],
+            art: None,
         }
     }
 
     pub fn partial_deserialize_album(bytes: &[u8]) -> Result<StoredAlbum> {
-        Ok(bitcode::decode(bytes)?)
+        Self::deserialize_maybe_legacy(bytes).map(|(album, _)| album)
+    }
+
+    // The flag is set when the album came from the legacy format and its order needs rebuilding.
+    fn deserialize_maybe_legacy(bytes: &[u8]) -> Result<(StoredAlbum, bool)> {
+        if let Some(album) = decode_tagged(bytes, ALBUM_FORMAT) {
+            return Ok((album, false));
+        }
+        // Albums from the first release, from before records were tagged with their format.
+        match bitcode::decode::<BasicStoredAlbum>(bytes) {
+            Ok(basic) => Ok((basic.into(), true)),
+            Err(e) => Err(undecodable(bytes, ALBUM_FORMAT, e)),
+        }
     }
 
     pub fn serialize(&self) -> Vec<u8> {
-        bitcode::encode(self)
+        encode_record(ALBUM_FORMAT, self)
     }
 }
 
-fn deserialize_album(tree: &sled::Db, bytes: &[u8]) -> Result<Album> {
+fn deserialize_album(tree: &impl Store, bytes: &[u8]) -> Result<Album> {
     let stored_album = StoredAlbum::partial_deserialize_album(bytes)?;
     Ok(Album {
         tags: stored_album.tags,
         songs: stored_album
             .song_keys
             .iter()
-            .map(|(_, byte_key)| tree.get_metadata(byte_key.as_ref()))
+            .map(|(_, byte_key)| tree.get_metadata(&Key::from_byte_key(*byte_key)))
             .collect::<Result<Vec<Song>>>()?,
     })
 }
 
-impl Methods<Album> for sled::Db {
+impl<S: Store> Methods<Album> for S {
     fn insert_metadata(&self, album: &Album) -> Result<Key> {
         let key = album.tags.hash_key();
 
-        let stored_album = StoredAlbum {
+        let mut stored_album = StoredAlbum {
             tags: album.tags.clone(),
             song_keys: album
                 .songs
                 .iter()
                 .map(|song| {
                     self.insert_metadata(song)
-                        .map(|key| (song.tags.track_number, *key.to_byte_key()))
+                        .map(|key| (song.track_order(), *key.to_byte_key()))
                 })
-                .collect::<Result<Vec<(Option<u16>, ByteKey)>>>()?,
+                .collect::<Result<Vec<(TrackOrder, ByteKey)>>>()?,
+            art: None,
         };
-        self.insert(&key, stored_album)?;
+        stored_album.song_keys.sort_by(|a, b| a.0.cmp(&b.0));
+        let previous = self.insert(&key, stored_album.serialize())?;
+        let previous = previous
+            .map(|bytes| StoredAlbum::partial_deserialize_album(&bytes))
+            .transpose()?;
+        update_search_index(
+            self,
+            &key,
+            previous.as_ref().map(|album| &album.tags),
+            Some(&album.tags),
+        )?;
+        link_album_to_artist(self, &album.tags, &key)?;
         Ok(key)
     }
 
     fn get_metadata(&self, key: &Key) -> Result<Album> {
-        let bytes = self.get(key)?.ok_or("Could not find album key in db")?;
+        let bytes = self.get(key)?.ok_or_else(|| Error::NotFound(key.clone()))?;
         deserialize_album(self, bytes.as_ref())
     }
 }
 
 pub type AlbumKeyBySongKey = HashMap<Key, Key>;
 
-impl Methods<AlbumTags> for sled::Db {
+impl<S: Store> Methods<AlbumTags> for S {
     fn insert_metadata(&self, album_tags: &AlbumTags) -> Result<Key> {
         // Not recommended to use this method.
         let empty_album = Album {
@@ -121,25 +225,58 @@ impl Methods<AlbumTags> for sled::Db {
     }
 
     fn get_metadata(&self, key: &Key) -> Result<AlbumTags> {
-        let bytes = self.get(key)?.ok_or("Could not find album key in db")?;
+        let bytes = self.get(key)?.ok_or_else(|| Error::NotFound(key.clone()))?;
         Ok(StoredAlbum::partial_deserialize_album(bytes.as_ref())?.tags)
     }
 }
 
-pub fn scan_stored_albums(tree: &sled::Db) -> Result<AlbumKeyBySongKey> {
+fn migrate_track_order(tree: &impl Store, album_key: &Key, album: &mut StoredAlbum) -> Result<()> {
+    let mut song_keys = album
+        .song_keys
+        .iter()
+        .map(|(_, song_key)| {
+            let song: Song = tree.get_metadata(&Key::from_byte_key(*song_key))?;
+            Ok((song.track_order(), *song_key))
+        })
+        .collect::<Result<Vec<(TrackOrder, ByteKey)>>>()?;
+    song_keys.sort_by(|a, b| a.0.cmp(&b.0));
+    album.song_keys = song_keys;
+    tree.insert(album_key, album.serialize())?;
+    Ok(())
+}
+
+// Makes the next scan of every root re-read every file.
+pub(crate) fn forget_file_stamps(tree: &impl Store) -> Result<()> {
+    for entry in tree.scan_prefix(KeyType::Song) {
+        let (key, bytes) = entry?;
+        let mut song = Song::deserialize(&bytes)?;
+        if song.file.take().is_some() {
+            tree.insert(key, song.serialize())?;
+        }
+    }
+    Ok(())
+}
+
+// Every scan visits all albums here, so this is also where legacy albums get upgraded.
+pub fn scan_stored_albums(tree: &impl Store) -> Result<AlbumKeyBySongKey> {
     tree.scan_prefix(KeyType::Album)
         .flat_map(|e| {
             e.map(|(album_key, bytes)| {
-                StoredAlbum::partial_deserialize_album(bytes.as_ref()).map(|album| {
-                    let album_key: &Key = album_key.into();
-                    (album_key.clone(), album)
-                })
+                StoredAlbum::deserialize_maybe_legacy(bytes.as_ref()).and_then(
+                    |(mut album, legacy)| {
+                        let album_key = Key::try_from(&album_key)?;
+                        if legacy {
+                            migrate_track_order(tree, &album_key, &mut album)?;
+                        }
+                        Ok((album_key, album))
+                    },
+                )
             })
         })
         .try_fold(AlbumKeyBySongKey::new(), |mut map, value| {
             value.map(|(key, stored_album)| {
                 for (_, song_key) in stored_album.song_keys {
-                    map.insert(Key::from_byte_key_owned(song_key), key.clone());
+                    map.insert(Key::from_byte_key(song_key), key.clone());
                 }
                 map
             })
@@ -150,37 +287,74 @@ pub trait Helpers {
     fn scan_albums(&self) -> impl Iterator<Item = Result<Album>>;
     fn scan_songs(&self) -> impl Iterator<Item = Result<Song>>;
     fn scan_album_tags_sorted(&self) -> Result<Vec<(Key, AlbumTags)>>;
-    fn get_song_from_path(&self, relpath: &[u8]) -> Result<Lazy<'_, Song>>;
-    fn set_last_scan_time(&self) -> Result<()>;
-    fn get_last_scan_time(&self) -> Result<SystemTime>;
+    fn scan_artists_sorted(&self) -> Result<Vec<(Key, Artist)>>;
+    fn albums_for_artist(&self, artist_key: &Key) -> Result<Vec<(Key, AlbumTags)>>;
+    fn get_song_from_path(&self, path: &[u8]) -> Result<Lazy<'_, Song>>;
+    fn song_path(&self, song: &Song) -> Result<PathBuf>;
+    fn set_library_root(&self, name: &str, path: &Path) -> Result<LibraryRoot>;
+    fn get_library_root(&self, name: &str) -> Result<LibraryRoot>;
+    fn library_roots(&self) -> Result<Vec<LibraryRoot>>;
+    fn remove_library_root(&self, name: &str) -> Result<()>;
+    fn set_last_scan_time(&self, root: &str) -> Result<()>;
+    fn get_last_scan_time(&self, root: &str) -> Result<SystemTime>;
     fn scan_album_song_keys(&self) -> Result<HashSet<(Key, Key)>>;
+    fn search(&self, query: &str, limit: usize) -> Result<SearchResults>;
+    fn album_art_for_key(&self, album_key: &Key) -> Result<Option<AlbumArt>>;
+    fn album_thumbnail_for_key(&self, album_key: &Key, size: u32) -> Result<Option<AlbumArt>>;
+    fn set_album_grouping(&self, grouping: AlbumGrouping) -> Result<()>;
+    fn album_grouping(&self) -> Result<AlbumGrouping>;
+    fn reconcile_albums(&self) -> Result<usize>;
+    fn song_by_mbid(&self, recording_id: &str) -> Result<(Key, Song)>;
+    fn user_data(&self, key: &Key) -> Result<UserData>;
+    fn record_play(&self, key: &Key) -> Result<UserData>;
+    fn record_skip(&self, key: &Key) -> Result<UserData>;
+    fn set_rating(&self, key: &Key, rating: Option<u8>) -> Result<UserData>;
+    fn set_favourite(&self, key: &Key, favourite: bool) -> Result<UserData>;
+    fn most_played(&self, limit: usize) -> Result<Vec<(Key, UserData)>>;
+    fn recently_played(&self, limit: usize) -> Result<Vec<(Key, UserData)>>;
+    fn create_playlist(&self, name: &str) -> Result<Key>;
+    fn playlist(&self, key: &Key) -> Result<Playlist>;
+    fn playlists(&self) -> Result<Vec<(Key, Playlist)>>;
+    fn rename_playlist(&self, key: &Key, name: &str) -> Result<()>;
+    fn delete_playlist(&self, key: &Key) -> Result<()>;
+    fn insert_into_playlist(&self, key: &Key, index: usize, song_keys: &[Key]) -> Result<()>;
+    fn remove_from_playlist(&self, key: &Key, index: usize) -> Result<()>;
+    fn move_in_playlist(&self, key: &Key, from: usize, to: usize) -> Result<()>;
+    fn playlist_songs(&self, key: &Key) -> Result<Vec<(Key, Song)>>;
+    fn import_playlist(&self, path: &Path) -> Result<(Key, Vec<PathBuf>)>;
+    fn export_playlist(&self, key: &Key, path: &Path) -> Result<usize>;
+    fn query_songs(&self, query: &SongQuery) -> Result<Vec<(Key, Song)>>;
+    fn query_song_keys(&self, query: &SongQuery) -> Result<Vec<Key>>;
+    fn query_albums(&self, query: &AlbumQuery) -> Result<Vec<(Key, AlbumTags)>>;
+    fn query_album_keys(&self, query: &AlbumQuery) -> Result<Vec<Key>>;
+    fn date_added(&self, key: &Key) -> Result<Option<SystemTime>>;
+    fn create_smart_playlist(&self, name: &str, query: &str) -> Result<Key>;
+    fn smart_playlist(&self, key: &Key) -> Result<SmartPlaylist>;
+    fn smart_playlists(&self) -> Result<Vec<(Key, SmartPlaylist)>>;
+    fn update_smart_playlist(&self, key: &Key, name: &str, query: &str) -> Result<()>;
+    fn delete_smart_playlist(&self, key: &Key) -> Result<()>;
+    fn smart_playlist_songs(&self, key: &Key) -> Result<Vec<(Key, Song)>>;
 }
 
-impl Helpers for sled::Db {
+impl<S: Store> Helpers for S {
     fn scan_albums(&self) -> impl Iterator<Item = Result<Album>> {
         self.scan_prefix(KeyType::Album).map(|album_tag| {
-            album_tag
-                .map_err(|e| e.into())
-                .and_then(|(_, bytes)| deserialize_album(self, bytes.as_ref()))
+            album_tag.and_then(|(_, bytes)| deserialize_album(self, bytes.as_ref()))
         })
     }
 
     fn scan_songs(&self) -> impl Iterator<Item = Result<Song>> {
-        self.scan_prefix(KeyType::Song).map(|bytes| {
-            bytes
-                .map_err(|e| e.into())
-                .and_then(|(_, bytes)| Song::deserialize(bytes))
-        })
+        self.scan_prefix(KeyType::Song)
+            .map(|bytes| bytes.and_then(|(_, bytes)| Song::deserialize(&bytes)))
     }
 
     fn scan_album_tags_sorted(&self) -> Result<Vec<(Key, AlbumTags)>> {
         let mut albums: Vec<(Key, AlbumTags)> = self
             .scan_prefix(KeyType::Album)
             .map(|entry| {
-                entry.map_err(|e| e.into()).and_then(|(album_key, bytes)| {
-                    let album_key: &Key = (&album_key).into();
+                entry.and_then(|(album_key, bytes)| {
                     let tags = StoredAlbum::partial_deserialize_album(bytes.as_ref())?.tags;
-                    Ok((Key::from_byte_key_owned(*album_key.to_byte_key()), tags))
+                    Ok((Key::try_from(&album_key)?, tags))
                 })
             })
             .collect::<Result<_>>()?;
@@ -197,40 +371,359 @@ impl Helpers for sled::Db {
         Ok(albums)
     }
 
+    fn scan_artists_sorted(&self) -> Result<Vec<(Key, Artist)>> {
+        let mut artists: Vec<(Key, Artist)> = self
+            .scan_prefix(KeyType::Artist)
+            .map(|entry| {
+                entry.and_then(|(artist_key, bytes)| {
+                    let artist = Artist::deserialize(bytes.as_ref())?;
+                    Ok((Key::try_from(&artist_key)?, artist))
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        // Sort on the same folded name the artist keys are made from, so "abba" comes before "Zappa".
+        artists.sort_by_cached_key(|(key, artist)| {
+            (
+                artist.name.as_deref().map(normalize_artist_name),
+                *key.to_byte_key(),
+            )
+        });
+
+        Ok(artists)
+    }
+
+    fn albums_for_artist(&self, artist_key: &Key) -> Result<Vec<(Key, AlbumTags)>> {
+        let bytes = self
+            .get(artist_key)?
+            .ok_or_else(|| Error::NotFound(artist_key.clone()))?;
+        let mut albums: Vec<(Key, AlbumTags)> = Artist::deserialize(bytes.as_ref())?
+            .album_keys
+            .into_iter()
+            .map(|album_key| {
+                let album_key = Key::from_byte_key(album_key);
+                let tags: AlbumTags = self.get_metadata(&album_key)?;
+                Ok((album_key, tags))
+            })
+            .collect::<Result<_>>()?;
+
+        albums.sort_by(|(key_a, tags_a), (key_b, tags_b)| {
+            tags_a
+                .year
+                .cmp(&tags_b.year)
+                .then_with(|| key_a.to_byte_key().cmp(key_b.to_byte_key()))
+        });
+
+        Ok(albums)
+    }
+
     // get all album_key, song_key pairs in a hash set
     fn scan_album_song_keys(&self) -> Result<HashSet<(Key, Key)>> {
         // scan_stored_albums(&self).collect()
         unimplemented!()
     }
 
-    fn get_song_from_path(&self, relpath: &[u8]) -> Result<Lazy<'_, Song>> {
-        let key = song_hash_key(relpath);
-        Ok(Box::new(move || self.get_metadata(&key)))
+    fn search(&self, query: &str, limit: usize) -> Result<SearchResults> {
+        search_index(self, query, limit)
     }
 
-    fn set_last_scan_time(&self) -> Result<()> {
-        let last_scan_time = SystemTime::now();
-        let bytes: [u8; std::mem::size_of::<SystemTime>()] =
-            unsafe { std::mem::transmute(last_scan_time) };
-        self.insert(KeyType::LastScanTime, &bytes)?;
+    // None when none of the album's songs had art, embedded or beside them.
+    fn album_art_for_key(&self, album_key: &Key) -> Result<Option<AlbumArt>> {
+        album_art(self, album_key)
+    }
+
+    fn album_thumbnail_for_key(&self, album_key: &Key, size: u32) -> Result<Option<AlbumArt>> {
+        album_thumbnail(self, album_key, size)
+    }
+
+    // Takes an absolute path, which is looked up relative to the root that holds it.
+    fn get_song_from_path(&self, path: &[u8]) -> Result<Lazy<'_, Song>> {
+        let (root, relpath, key) = locate_song(self, path)?;
+        Ok(Box::new(move || {
+            check_song_location(&key, self.get_metadata(&key)?, &root, &relpath)
+        }))
+    }
+
+    fn song_path(&self, song: &Song) -> Result<PathBuf> {
+        if song.root.is_empty() {
+            return Ok(song.path());
+        }
+        Ok(self.get_library_root(&song.root)?.resolve(&song.relpath))
+    }
+
+    // Adds a root, or moves an existing one to a new path.
+    fn set_library_root(&self, name: &str, path: &Path) -> Result<LibraryRoot> {
+        let path = std::path::absolute(path).map_err(|e| Error::io(path, e))?;
+        if let Some(other) = self
+            .library_roots()?
+            .into_iter()
+            .find(|other| other.name != name && other.overlaps(&path))
+        {
+            return Err(Error::OverlappingRoot {
+                path,
+                root: other.name,
+            });
+        }
+        let root = LibraryRoot::new(name, &path);
+        self.insert(library_root_key(name), root.serialize())?;
+        Ok(root)
+    }
+
+    fn get_library_root(&self, name: &str) -> Result<LibraryRoot> {
+        let key = library_root_key(name);
+        let bytes = self.get(&key)?.ok_or(Error::NotFound(key))?;
+        LibraryRoot::deserialize(bytes.as_ref())
+    }
+
+    fn library_roots(&self) -> Result<Vec<LibraryRoot>> {
+        self.scan_prefix(KeyType::LibraryRoot)
+            .map(|entry| entry.and_then(|(_, bytes)| LibraryRoot::deserialize(bytes.as_ref())))
+            .collect()
+    }
+
+    // Takes every song in the root out of the db along with the root itself. The files are left alone.
+    fn remove_library_root(&self, name: &str) -> Result<()> {
+        let root = self.get_library_root(name)?;
+        for (song_key, album_key) in scan_stored_albums(self)? {
+            let song: Song = self.get_metadata(&song_key)?;
+            if root.holds(&song) {
+                remove_song(self, &album_key, &song_key)?;
+            }
+        }
+        self.remove(library_root_key(name))?;
+        self.remove(last_scan_time_key(name))?;
+        prune_album_art(self)
+    }
+
+    fn set_last_scan_time(&self, root: &str) -> Result<()> {
+        self.insert(
+            last_scan_time_key(root),
+            encode_scan_time(SystemTime::now()),
+        )?;
         Ok(())
     }
 
-    fn get_last_scan_time(&self) -> Result<SystemTime> {
-        let bytes = self
-            .update_and_fetch(KeyType::LastScanTime, |maybe_bytes| match maybe_bytes {
-                Some(bytes) => Some(bytes.into()),
-                None => Some(
-                    (unsafe {
-                        std::mem::transmute::<SystemTime, [u8; std::mem::size_of::<SystemTime>()]>(
-                            SystemTime::UNIX_EPOCH,
-                        )
-                    })
-                    .to_vec(),
-                ),
-            })?
-            .ok_or("Impossible; Could not find last scan time in db")?
-            .to_vec();
-        Ok(unsafe { *(bytes.as_ptr() as *const SystemTime) })
+    fn get_last_scan_time(&self, root: &str) -> Result<SystemTime> {
+        match self.get(last_scan_time_key(root))? {
+            Some(bytes) => decode_scan_time(&bytes),
+            None => Ok(SystemTime::UNIX_EPOCH),
+        }
+    }
+
+    // Forgetting every file's stamp makes the next scans re-read them all, which is what moves songs to
+    // the albums the new grouping puts them in.
+    fn set_album_grouping(&self, grouping: AlbumGrouping) -> Result<()> {
+        if self.album_grouping()? as u8 == grouping as u8 {
+            return Ok(());
+        }
+        self.insert(album_grouping_key(), bitcode::encode(&grouping))?;
+        forget_file_stamps(self)
+    }
+
+    fn album_grouping(&self) -> Result<AlbumGrouping> {
+        match self.get(album_grouping_key())? {
+            Some(bytes) => Ok(bitcode::decode(&bytes)?),
+            None => Ok(AlbumGrouping::default()),
+        }
+    }
+
+    fn reconcile_albums(&self) -> Result<usize> {
+        let merged = merge_split_albums(self)?;
+        prune_album_art(self)?;
+        Ok(merged)
+    }
+
+    // Looks a song up by its MusicBrainz recording ID, ignoring case.
+    fn song_by_mbid(&self, recording_id: &str) -> Result<(Key, Song)> {
+        song_by_recording_id(self, recording_id)
+    }
+
+    // Takes song or album keys, as do the rest of the user data methods.
+    fn user_data(&self, key: &Key) -> Result<UserData> {
+        read_user_data(self, key)
+    }
+
+    fn record_play(&self, key: &Key) -> Result<UserData> {
+        let now = millis_since_epoch(SystemTime::now());
+        update_user_data(self, key, |data| {
+            data.play_count = data.play_count.saturating_add(1);
+            data.last_played_ms = Some(now);
+        })
+    }
+
+    fn record_skip(&self, key: &Key) -> Result<UserData> {
+        update_user_data(self, key, |data| {
+            data.skip_count = data.skip_count.saturating_add(1);
+        })
+    }
+
+    // None clears the rating.
+    fn set_rating(&self, key: &Key, rating: Option<u8>) -> Result<UserData> {
+        if let Some(rating) = rating.filter(|rating| !(1..=MAX_RATING).contains(rating)) {
+            return Err(Error::InvalidRating(rating));
+        }
+        update_user_data(self, key, |data| data.rating = rating)
+    }
+
+    fn set_favourite(&self, key: &Key, favourite: bool) -> Result<UserData> {
+        update_user_data(self, key, |data| data.favourite = favourite)
+    }
+
+    // Ties go to whatever was played more recently.
+    fn most_played(&self, limit: usize) -> Result<Vec<(Key, UserData)>> {
+        user_data_sorted(self, limit, |data| {
+            (data.play_count > 0).then_some((data.play_count, data.last_played_ms))
+        })
+    }
+
+    fn recently_played(&self, limit: usize) -> Result<Vec<(Key, UserData)>> {
+        user_data_sorted(self, limit, |data| data.last_played_ms)
+    }
+
+    fn create_playlist(&self, name: &str) -> Result<Key> {
+        store_new_playlist(self, &Playlist::new(name))
+    }
+
+    fn playlist(&self, key: &Key) -> Result<Playlist> {
+        get_playlist(self, key)
+    }
+
+    // Sorted by name, ignoring case.
+    fn playlists(&self) -> Result<Vec<(Key, Playlist)>> {
+        scan_playlists(self)
+    }
+
+    fn rename_playlist(&self, key: &Key, name: &str) -> Result<()> {
+        update_playlist(self, key, |playlist| {
+            playlist.name = name.to_string();
+            Ok(())
+        })
+    }
+
+    fn delete_playlist(&self, key: &Key) -> Result<()> {
+        delete_playlist_record(self, key)
+    }
+
+    // An index equal to the length appends.
+    fn insert_into_playlist(&self, key: &Key, index: usize, song_keys: &[Key]) -> Result<()> {
+        let song_keys = check_song_keys(self, song_keys)?;
+        update_playlist(self, key, |playlist| {
+            check_index(index, playlist.song_keys.len() + 1)?;
+            playlist
+                .song_keys
+                .splice(index..index, song_keys.iter().copied());
+            Ok(())
+        })
+    }
+
+    // Indexes count entries whose songs have left the library, so those can be removed too.
+    fn remove_from_playlist(&self, key: &Key, index: usize) -> Result<()> {
+        update_playlist(self, key, |playlist| {
+            check_index(index, playlist.song_keys.len())?;
+            playlist.song_keys.remove(index);
+            Ok(())
+        })
+    }
+
+    fn move_in_playlist(&self, key: &Key, from: usize, to: usize) -> Result<()> {
+        update_playlist(self, key, |playlist| {
+            check_index(from, playlist.song_keys.len())?;
+            check_index(to, playlist.song_keys.len())?;
+            let song_key = playlist.song_keys.remove(from);
+            playlist.song_keys.insert(to, song_key);
+            Ok(())
+        })
+    }
+
+    // Only the songs still in the library.
+    fn playlist_songs(&self, key: &Key) -> Result<Vec<(Key, Song)>> {
+        let entries = playlist_entries(self, &self.playlist(key)?)?;
+        Ok(entries
+            .into_iter()
+            .filter_map(|(key, song)| Some((key, song?)))
+            .collect())
+    }
+
+    // Creates a playlist named after the file. Entries that aren't songs in the library are left out
+    // and handed back.
+    fn import_playlist(&self, path: &Path) -> Result<(Key, Vec<PathBuf>)> {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut playlist = Playlist::new(&name);
+        let mut unresolved = Vec::new();
+        for entry in read_playlist_file(path)? {
+            match song_at_path(self, entry.as_os_str().as_encoded_bytes()) {
+                Ok((song_key, _)) => playlist.song_keys.push(*song_key.to_byte_key()),
+                Err(Error::NotFound(_)) => unresolved.push(entry),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((store_new_playlist(self, &playlist)?, unresolved))
+    }
+
+    // Writes M3U8 or PLS going by the extension, leaving out songs no longer in the library. Returns
+    // how many songs were written.
+    fn export_playlist(&self, key: &Key, path: &Path) -> Result<usize> {
+        let format = PlaylistFormat::for_path(path)?;
+        let songs = self.playlist_songs(key)?;
+        let entries = songs
+            .iter()
+            .map(|(_, song)| Ok((self.song_path(song)?, song)))
+            .collect::<Result<Vec<_>>>()?;
+        write_playlist_file(path, format, &entries)?;
+        Ok(entries.len())
+    }
+
+    // Parse text queries with SongQuery::parse.
+    fn query_songs(&self, query: &SongQuery) -> Result<Vec<(Key, Song)>> {
+        run_song_query(self, query)
+    }
+
+    fn query_song_keys(&self, query: &SongQuery) -> Result<Vec<Key>> {
+        Ok(run_song_query(self, query)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    fn query_albums(&self, query: &AlbumQuery) -> Result<Vec<(Key, AlbumTags)>> {
+        run_album_query(self, query)
+    }
+
+    fn query_album_keys(&self, query: &AlbumQuery) -> Result<Vec<Key>> {
+        Ok(run_album_query(self, query)?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    // When a song or album was first scanned, or None if that was before dates were kept.
+    fn date_added(&self, key: &Key) -> Result<Option<SystemTime>> {
+        added_time(self, key)
+    }
+
+    // Fails with InvalidQuery rather than storing a query that can't run.
+    fn create_smart_playlist(&self, name: &str, query: &str) -> Result<Key> {
+        store_new_smart_playlist(self, &SmartPlaylist::new(name, query)?)
+    }
+
+    fn smart_playlist(&self, key: &Key) -> Result<SmartPlaylist> {
+        get_smart_playlist(self, key)
+    }
+
+    // Sorted by name, ignoring case.
+    fn smart_playlists(&self) -> Result<Vec<(Key, SmartPlaylist)>> {
+        scan_smart_playlists(self)
+    }
+
+    fn update_smart_playlist(&self, key: &Key, name: &str, query: &str) -> Result<()> {
+        replace_smart_playlist(self, key, name, query)
+    }
+
+    fn delete_smart_playlist(&self, key: &Key) -> Result<()> {
+        delete_smart_playlist_record(self, key)
+    }
+
+    fn smart_playlist_songs(&self, key: &Key) -> Result<Vec<(Key, Song)>> {
+        self.query_songs(&self.smart_playlist(key)?.song_query()?)
     }
 }
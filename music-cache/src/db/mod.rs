@@ -1,6 +1,59 @@
+mod store;
+pub use store::*;
+
 mod key;
 pub use key::*;
 
+mod key_hash;
+pub use key_hash::*;
+
+mod rekey;
+pub use rekey::*;
+
+mod schema;
+pub use schema::*;
+
+mod artist;
+pub use artist::*;
+
+mod search;
+pub use search::*;
+
+mod song_path;
+pub use song_path::*;
+
+mod library_root;
+pub use library_root::*;
+
+mod album_art;
+pub use album_art::*;
+
+mod album_grouping;
+pub use album_grouping::*;
+
+mod musicbrainz;
+pub use musicbrainz::*;
+
+mod user_data;
+pub use user_data::*;
+
+mod added;
+pub use added::*;
+
+mod playlist;
+pub use playlist::*;
+
+mod playlist_file;
+pub use playlist_file::*;
+
+mod query;
+pub use query::*;
+
+mod query_parse;
+
+mod smart_playlist;
+pub use smart_playlist::*;
+
 #[allow(clippy::unnecessary_to_owned)]
 // Unfortunately sled doesn't guarantee alignment, so push the value into a vector to ensure it's aligned, adding a copy.
 // I could maybe think about forking sled to have alignment. But that's a pretty big undertaking and I don't know what the tradeoffs are.
@@ -0,0 +1,43 @@
+use crate::*;
+
+// Finds songs by the MusicBrainz recording ID scrobblers and MusicBrainz lookups hand back. When
+// several songs share a recording, the one tagged last is found.
+pub fn recording_id_key(recording_id: &str) -> Key {
+    let mut hasher = KeyHasher::new();
+    hasher.write_str(&recording_id.trim().to_lowercase());
+    hash_key(KeyType::MusicBrainzId, hasher)
+}
+
+fn recording_id(song: Option<&Song>) -> Option<&str> {
+    song?.musicbrainz.recording_id.as_deref()
+}
+
+// Pass None for previous on insert and None for current on removal.
+pub fn update_recording_index(
+    tree: &impl Store,
+    song_key: &Key,
+    previous: Option<&Song>,
+    current: Option<&Song>,
+) -> Result<()> {
+    let song_key = &song_key.to_byte_key()[..];
+    if let Some(previous) = recording_id(previous) {
+        if recording_id(current) != Some(previous) {
+            // Another song may have taken the ID over since, and keeps it.
+            let _ = tree.compare_and_swap(recording_id_key(previous), Some(song_key), None)?;
+        }
+    }
+    if let Some(current) = recording_id(current) {
+        tree.insert(recording_id_key(current), song_key)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn song_by_recording_id(tree: &impl Store, recording_id: &str) -> Result<(Key, Song)> {
+    let index_key = recording_id_key(recording_id);
+    let Some(bytes) = tree.get(&index_key)? else {
+        return Err(Error::NotFound(index_key));
+    };
+    let song_key = Key::try_from(&bytes)?;
+    let song: Song = tree.get_metadata(&song_key)?;
+    Ok((song_key, song))
+}
@@ -0,0 +1,146 @@
+use music_cache_derive::derive_data_model;
+use std::time::SystemTime;
+
+use crate::*;
+
+// Songs are kept in order by key, so a song keeps its place when its file moves. Entries for songs
+// that were removed stay put and are skipped when listing, and come back if the song does.
+#[derive_data_model]
+#[derive(Clone)]
+pub struct Playlist {
+    pub name: String,
+    pub song_keys: Vec<ByteKey>,
+    // Milliseconds since the Unix epoch.
+    pub created_ms: u64,
+    pub modified_ms: u64,
+}
+
+impl Playlist {
+    pub fn new(name: &str) -> Playlist {
+        let now = millis_since_epoch(SystemTime::now());
+        Playlist {
+            name: name.to_string(),
+            song_keys: Vec::new(),
+            created_ms: now,
+            modified_ms: now,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bitcode::encode(self)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Playlist> {
+        Ok(bitcode::decode(bytes)?)
+    }
+
+    pub fn song_keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.song_keys.iter().map(|key| Key::from_byte_key(*key))
+    }
+}
+
+pub(crate) fn store_new_playlist(tree: &impl Store, playlist: &Playlist) -> Result<Key> {
+    let key = tree.generate_key(playlist)?;
+    tree.insert(&key, playlist.serialize())?;
+    Ok(key)
+}
+
+// Anything other than a playlist key isn't found, rather than failing to decode.
+fn check_playlist_key(key: &Key) -> Result<()> {
+    match key.key_type() {
+        Some(KeyType::Playlist) => Ok(()),
+        _ => Err(Error::NotFound(key.clone())),
+    }
+}
+
+pub(crate) fn get_playlist(tree: &impl Store, key: &Key) -> Result<Playlist> {
+    check_playlist_key(key)?;
+    let bytes = tree.get(key)?.ok_or_else(|| Error::NotFound(key.clone()))?;
+    Playlist::deserialize(&bytes)
+}
+
+// Applies the edit and bumps the modified time, unless the edit fails.
+pub(crate) fn update_playlist(
+    tree: &impl Store,
+    key: &Key,
+    edit: impl Fn(&mut Playlist) -> Result<()>,
+) -> Result<()> {
+    check_playlist_key(key)?;
+    let mut error = None;
+    let previous = tree.fetch_and_update(key, |maybe_bytes| {
+        let bytes = maybe_bytes?;
+        let edited = Playlist::deserialize(bytes).and_then(|mut playlist| {
+            edit(&mut playlist)?;
+            playlist.modified_ms = millis_since_epoch(SystemTime::now());
+            Ok(playlist)
+        });
+        match edited {
+            Ok(playlist) => Some(playlist.serialize()),
+            Err(e) => {
+                error = Some(e);
+                Some(bytes.to_vec())
+            }
+        }
+    })?;
+    if previous.is_none() {
+        return Err(Error::NotFound(key.clone()));
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+// Playlists only hold songs that are in the library when they're added.
+pub(crate) fn check_song_keys(tree: &impl Store, song_keys: &[Key]) -> Result<Vec<ByteKey>> {
+    song_keys
+        .iter()
+        .map(|key| match key.key_type() {
+            Some(KeyType::Song) if tree.contains_key(key)? => Ok(*key.to_byte_key()),
+            _ => Err(Error::NotFound(key.clone())),
+        })
+        .collect()
+}
+
+pub(crate) fn check_index(index: usize, len: usize) -> Result<()> {
+    if index < len {
+        Ok(())
+    } else {
+        Err(Error::InvalidIndex(index))
+    }
+}
+
+// Each entry with its song, or None where the song has left the library.
+pub(crate) fn playlist_entries(
+    tree: &impl Store,
+    playlist: &Playlist,
+) -> Result<Vec<(Key, Option<Song>)>> {
+    playlist
+        .song_keys()
+        .map(|key| match tree.get_metadata(&key) {
+            Ok(song) => Ok((key, Some(song))),
+            Err(Error::NotFound(_)) => Ok((key, None)),
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+pub(crate) fn delete_playlist_record(tree: &impl Store, key: &Key) -> Result<()> {
+    check_playlist_key(key)?;
+    match tree.remove(key)? {
+        Some(_) => Ok(()),
+        None => Err(Error::NotFound(key.clone())),
+    }
+}
+
+pub(crate) fn scan_playlists(tree: &impl Store) -> Result<Vec<(Key, Playlist)>> {
+    let mut playlists = tree
+        .scan_prefix(KeyType::Playlist)
+        .map(|entry| {
+            let (key, bytes) = entry?;
+            Ok((Key::try_from(&key)?, Playlist::deserialize(&bytes)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    playlists.sort_by_cached_key(|(_, playlist)| playlist.name.to_lowercase());
+    Ok(playlists)
+}
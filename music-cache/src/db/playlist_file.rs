@@ -0,0 +1,169 @@
+use std::{
+    fmt::Write,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::*;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    M3u8,
+    Pls,
+}
+
+impl PlaylistFormat {
+    // Plain .m3u files are read as UTF-8 too, which is what most players write these days.
+    pub fn from_path(path: &Path) -> Option<PlaylistFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "m3u8" | "m3u" => Some(PlaylistFormat::M3u8),
+            "pls" => Some(PlaylistFormat::Pls),
+            _ => None,
+        }
+    }
+
+    pub fn for_path(path: &Path) -> Result<PlaylistFormat> {
+        PlaylistFormat::from_path(path).ok_or_else(|| Error::UnknownPlaylistFormat(path.into()))
+    }
+}
+
+// One line of a playlist file. Relative entries are resolved against the playlist's directory, and
+// ".." is taken out so the path can be matched against library roots.
+fn entry_path(entry: &str, dir: &Path) -> PathBuf {
+    let path = match entry.strip_prefix("file://") {
+        Some(uri) => PathBuf::from(percent_decode(uri)),
+        None => PathBuf::from(entry),
+    };
+    let mut resolved = PathBuf::new();
+    for component in dir.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+// Invalid escapes are kept as they are, as are bytes that don't decode to UTF-8.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| value.to_string())
+}
+
+fn parse_m3u8(contents: &str, dir: &Path) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| entry_path(line, dir))
+        .collect()
+}
+
+// Entries are ordered by their FileN number rather than where they sit in the file.
+fn parse_pls(contents: &str, dir: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<(u32, PathBuf)> = contents
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.trim().split_once('=')?;
+            let (field, number) = name.trim().split_at_checked(4)?;
+            if !field.eq_ignore_ascii_case("file") {
+                return None;
+            }
+            Some((number.parse().ok()?, entry_path(value.trim(), dir)))
+        })
+        .collect();
+    entries.sort_by_key(|(number, _)| *number);
+    entries.into_iter().map(|(_, path)| path).collect()
+}
+
+pub fn read_playlist_file(path: &Path) -> Result<Vec<PathBuf>> {
+    let format = PlaylistFormat::for_path(path)?;
+    let contents = std::fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(&contents);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(match format {
+        PlaylistFormat::M3u8 => parse_m3u8(contents, dir),
+        PlaylistFormat::Pls => parse_pls(contents, dir),
+    })
+}
+
+// Players show "Artist - Title", falling back to the file name.
+fn entry_title(song: &Song, path: &Path) -> String {
+    let title = song.tags.title.as_deref().map(str::trim);
+    let artist = song.tags.artist.as_deref().map(str::trim);
+    match (artist, title) {
+        (Some(artist), Some(title)) => format!("{artist} - {title}"),
+        (None, Some(title)) => title.to_string(),
+        _ => path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    }
+}
+
+// Unknown lengths are written as -1, as both formats expect.
+fn entry_seconds(song: &Song) -> i64 {
+    song.stream
+        .duration_ms
+        .map_or(-1, |ms| ((ms + 500) / 1000) as i64)
+}
+
+fn write_m3u8(entries: &[(PathBuf, &Song)]) -> String {
+    let mut contents = String::from("#EXTM3U\n");
+    for (path, song) in entries {
+        let _ = writeln!(
+            contents,
+            "#EXTINF:{},{}\n{}",
+            entry_seconds(song),
+            entry_title(song, path),
+            path.display()
+        );
+    }
+    contents
+}
+
+fn write_pls(entries: &[(PathBuf, &Song)]) -> String {
+    let mut contents = String::from("[playlist]\n");
+    for (number, (path, song)) in (1..).zip(entries) {
+        let _ = writeln!(contents, "File{number}={}", path.display());
+        let _ = writeln!(contents, "Title{number}={}", entry_title(song, path));
+        let _ = writeln!(contents, "Length{number}={}", entry_seconds(song));
+    }
+    let _ = writeln!(contents, "NumberOfEntries={}\nVersion=2", entries.len());
+    contents
+}
+
+// Entries are written as absolute paths, so the file can be moved about.
+pub fn write_playlist_file(
+    path: &Path,
+    format: PlaylistFormat,
+    entries: &[(PathBuf, &Song)],
+) -> Result<()> {
+    let contents = match format {
+        PlaylistFormat::M3u8 => write_m3u8(entries),
+        PlaylistFormat::Pls => write_pls(entries),
+    };
+    std::fs::write(path, contents).map_err(|e| Error::io(path, e))
+}
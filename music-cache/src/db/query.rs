@@ -0,0 +1,757 @@
+use std::{cmp::Ordering, collections::HashMap, fmt, ops::Not, time::SystemTime};
+
+use crate::*;
+
+// Queries pick songs or albums by their fields, sort them and page through them. Build them in code or
+// parse their text form, which smart playlists are stored in. See query_parse.
+pub trait QueryField: Copy + Eq + fmt::Debug + 'static {
+    // Every field, with its name in the text form.
+    const FIELDS: &'static [(Self, &'static str)];
+    // Sorted on, ascending, after a query's own sort keys and before the key, so order is stable.
+    const TIEBREAK: &'static [Self];
+
+    fn kind(self) -> FieldKind;
+
+    fn name(self) -> &'static str {
+        Self::FIELDS
+            .iter()
+            .find(|(field, _)| *field == self)
+            .map(|(_, name)| *name)
+            .unwrap_or_default()
+    }
+
+    // Ignores case.
+    fn from_name(name: &str) -> Option<Self> {
+        Self::FIELDS
+            .iter()
+            .find(|(_, field_name)| field_name.eq_ignore_ascii_case(name))
+            .map(|(field, _)| *field)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldKind {
+    Text,
+    Number,
+    Flag,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SongField {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Composer,
+    Comment,
+    Year,
+    Track,
+    Disc,
+    // Seconds.
+    Duration,
+    // kbps.
+    Bitrate,
+    Rating,
+    Plays,
+    Skips,
+    // Whole days. Songs that were never played have no value.
+    DaysSincePlayed,
+    // Milliseconds since the Unix epoch, for sorting by when songs turned up.
+    DateAdded,
+    DaysSinceAdded,
+    Favourite,
+}
+
+impl QueryField for SongField {
+    const FIELDS: &'static [(SongField, &'static str)] = &[
+        (SongField::Title, "title"),
+        (SongField::Artist, "artist"),
+        (SongField::Album, "album"),
+        (SongField::AlbumArtist, "album_artist"),
+        (SongField::Genre, "genre"),
+        (SongField::Composer, "composer"),
+        (SongField::Comment, "comment"),
+        (SongField::Year, "year"),
+        (SongField::Track, "track"),
+        (SongField::Disc, "disc"),
+        (SongField::Duration, "duration"),
+        (SongField::Bitrate, "bitrate"),
+        (SongField::Rating, "rating"),
+        (SongField::Plays, "plays"),
+        (SongField::Skips, "skips"),
+        (SongField::DaysSincePlayed, "days_since_played"),
+        (SongField::DateAdded, "date_added"),
+        (SongField::DaysSinceAdded, "days_since_added"),
+        (SongField::Favourite, "favourite"),
+    ];
+    const TIEBREAK: &'static [SongField] = &[
+        SongField::AlbumArtist,
+        SongField::Album,
+        SongField::Disc,
+        SongField::Track,
+    ];
+
+    fn kind(self) -> FieldKind {
+        match self {
+            SongField::Title
+            | SongField::Artist
+            | SongField::Album
+            | SongField::AlbumArtist
+            | SongField::Genre
+            | SongField::Composer
+            | SongField::Comment => FieldKind::Text,
+            SongField::Favourite => FieldKind::Flag,
+            _ => FieldKind::Number,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlbumField {
+    Title,
+    Artist,
+    Genre,
+    Year,
+    TrackCount,
+    // Seconds, over the songs whose duration is known.
+    Duration,
+    // User data set on the album itself rather than its songs.
+    Rating,
+    Plays,
+    DaysSincePlayed,
+    DateAdded,
+    DaysSinceAdded,
+    Favourite,
+}
+
+impl QueryField for AlbumField {
+    const FIELDS: &'static [(AlbumField, &'static str)] = &[
+        (AlbumField::Title, "title"),
+        (AlbumField::Artist, "artist"),
+        (AlbumField::Genre, "genre"),
+        (AlbumField::Year, "year"),
+        (AlbumField::TrackCount, "track_count"),
+        (AlbumField::Duration, "duration"),
+        (AlbumField::Rating, "rating"),
+        (AlbumField::Plays, "plays"),
+        (AlbumField::DaysSincePlayed, "days_since_played"),
+        (AlbumField::DateAdded, "date_added"),
+        (AlbumField::DaysSinceAdded, "days_since_added"),
+        (AlbumField::Favourite, "favourite"),
+    ];
+    const TIEBREAK: &'static [AlbumField] =
+        &[AlbumField::Artist, AlbumField::Year, AlbumField::Title];
+
+    fn kind(self) -> FieldKind {
+        match self {
+            AlbumField::Title | AlbumField::Artist | AlbumField::Genre => FieldKind::Text,
+            AlbumField::Favourite => FieldKind::Flag,
+            _ => FieldKind::Number,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextOp {
+    // Text comparisons ignore case and surrounding whitespace.
+    Is,
+    IsNot,
+    Contains,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NumberOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// A record missing the field never matches, so "not" is how to take in records without it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition<F = SongField> {
+    Text { field: F, op: TextOp, value: String },
+    Number { field: F, op: NumberOp, value: i64 },
+    // Inclusive at both ends.
+    Range { field: F, min: i64, max: i64 },
+    Flag(F),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Filter<F = SongField> {
+    Condition(Condition<F>),
+    Not(Box<Filter<F>>),
+    And(Vec<Filter<F>>),
+    Or(Vec<Filter<F>>),
+}
+
+impl<F: QueryField> Filter<F> {
+    pub fn is(field: F, value: impl Into<String>) -> Self {
+        Self::text(field, TextOp::Is, value)
+    }
+
+    pub fn is_not(field: F, value: impl Into<String>) -> Self {
+        Self::text(field, TextOp::IsNot, value)
+    }
+
+    pub fn contains(field: F, value: impl Into<String>) -> Self {
+        Self::text(field, TextOp::Contains, value)
+    }
+
+    pub fn text(field: F, op: TextOp, value: impl Into<String>) -> Self {
+        Filter::Condition(Condition::Text {
+            field,
+            op,
+            value: value.into(),
+        })
+    }
+
+    pub fn compare(field: F, op: NumberOp, value: i64) -> Self {
+        Filter::Condition(Condition::Number { field, op, value })
+    }
+
+    pub fn range(field: F, min: i64, max: i64) -> Self {
+        Filter::Condition(Condition::Range { field, min, max })
+    }
+
+    pub fn flag(field: F) -> Self {
+        Filter::Condition(Condition::Flag(field))
+    }
+
+    pub fn and(self, other: Filter<F>) -> Self {
+        match self {
+            Filter::And(mut all) => {
+                all.push(other);
+                Filter::And(all)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    pub fn or(self, other: Filter<F>) -> Self {
+        match self {
+            Filter::Or(mut any) => {
+                any.push(other);
+                Filter::Or(any)
+            }
+            filter => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    fn uses(&self, fields: &[F]) -> bool {
+        match self {
+            Filter::Condition(
+                Condition::Text { field, .. }
+                | Condition::Number { field, .. }
+                | Condition::Range { field, .. }
+                | Condition::Flag(field),
+            ) => fields.contains(field),
+            Filter::Not(filter) => filter.uses(fields),
+            Filter::And(filters) | Filter::Or(filters) => {
+                filters.iter().any(|filter| filter.uses(fields))
+            }
+        }
+    }
+}
+
+impl<F> Not for Filter<F> {
+    type Output = Filter<F>;
+
+    fn not(self) -> Filter<F> {
+        Filter::Not(Box::new(self))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortKey<F = SongField> {
+    pub field: F,
+    pub descending: bool,
+}
+
+// A query without a filter matches everything. Pages are picked by offset, or by `after`, which takes
+// the key of the last record of the previous page and holds up better while the library changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Query<F> {
+    pub filter: Option<Filter<F>>,
+    pub sort: Vec<SortKey<F>>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub after: Option<Key>,
+}
+
+pub type SongQuery = Query<SongField>;
+pub type AlbumQuery = Query<AlbumField>;
+
+impl<F> Default for Query<F> {
+    fn default() -> Self {
+        Query {
+            filter: None,
+            sort: Vec::new(),
+            limit: None,
+            offset: 0,
+            after: None,
+        }
+    }
+}
+
+impl<F: QueryField> Query<F> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Narrows any filter already set.
+    pub fn filter(mut self, filter: Filter<F>) -> Self {
+        self.filter = Some(match self.filter.take() {
+            Some(existing) => existing.and(filter),
+            None => filter,
+        });
+        self
+    }
+
+    pub fn sort_by(mut self, field: F) -> Self {
+        self.sort.push(SortKey {
+            field,
+            descending: false,
+        });
+        self
+    }
+
+    pub fn sort_by_descending(mut self, field: F) -> Self {
+        self.sort.push(SortKey {
+            field,
+            descending: true,
+        });
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn after(mut self, key: Key) -> Self {
+        self.after = Some(key);
+        self
+    }
+
+    fn uses(&self, fields: &[F]) -> bool {
+        self.filter
+            .as_ref()
+            .is_some_and(|filter| filter.uses(fields))
+            || self.sort.iter().any(|key| fields.contains(&key.field))
+    }
+}
+
+// What a query reads a record's fields from.
+trait QueryRecord<F> {
+    fn key(&self) -> &Key;
+    fn text(&self, field: F) -> Option<&str>;
+    fn number(&self, field: F, now_ms: u64) -> Option<i64>;
+    fn flag(&self, field: F) -> bool;
+}
+
+enum FieldValue {
+    Text(Option<String>),
+    Number(Option<i64>),
+    Flag(bool),
+}
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+fn normalize_text(value: &str) -> String {
+    value.trim().to_lowercase()
+}
+
+fn days_since(ms: Option<u64>, now_ms: u64) -> Option<i64> {
+    ms.map(|ms| (now_ms.saturating_sub(ms) / DAY_MS) as i64)
+}
+
+fn value<F: QueryField>(record: &impl QueryRecord<F>, field: F, now_ms: u64) -> FieldValue {
+    match field.kind() {
+        FieldKind::Text => FieldValue::Text(record.text(field).map(normalize_text)),
+        FieldKind::Number => FieldValue::Number(record.number(field, now_ms)),
+        FieldKind::Flag => FieldValue::Flag(record.flag(field)),
+    }
+}
+
+fn matches<F: QueryField>(record: &impl QueryRecord<F>, filter: &Filter<F>, now_ms: u64) -> bool {
+    match filter {
+        Filter::Condition(condition) => meets(record, condition, now_ms),
+        Filter::Not(filter) => !matches(record, filter, now_ms),
+        Filter::And(filters) => filters.iter().all(|filter| matches(record, filter, now_ms)),
+        Filter::Or(filters) => filters.iter().any(|filter| matches(record, filter, now_ms)),
+    }
+}
+
+fn meets<F: QueryField>(
+    record: &impl QueryRecord<F>,
+    condition: &Condition<F>,
+    now_ms: u64,
+) -> bool {
+    match condition {
+        Condition::Text { field, op, value } => {
+            let Some(text) = record.text(*field).map(normalize_text) else {
+                return false;
+            };
+            let value = normalize_text(value);
+            match op {
+                TextOp::Is => text == value,
+                TextOp::IsNot => text != value,
+                TextOp::Contains => text.contains(&value),
+            }
+        }
+        Condition::Number { field, op, value } => {
+            let Some(number) = record.number(*field, now_ms) else {
+                return false;
+            };
+            match op {
+                NumberOp::Eq => number == *value,
+                NumberOp::Ne => number != *value,
+                NumberOp::Lt => number < *value,
+                NumberOp::Le => number <= *value,
+                NumberOp::Gt => number > *value,
+                NumberOp::Ge => number >= *value,
+            }
+        }
+        Condition::Range { field, min, max } => record
+            .number(*field, now_ms)
+            .is_some_and(|number| (*min..=*max).contains(&number)),
+        Condition::Flag(field) => record.flag(*field),
+    }
+}
+
+// Records missing a value go last whichever way the sort runs.
+fn compare_values(a: &FieldValue, b: &FieldValue, descending: bool) -> Ordering {
+    fn compare<T: Ord>(a: &Option<T>, b: &Option<T>, descending: bool) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) if descending => b.cmp(a),
+            (Some(a), Some(b)) => a.cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }
+    }
+    match (a, b) {
+        (FieldValue::Text(a), FieldValue::Text(b)) => compare(a, b, descending),
+        (FieldValue::Number(a), FieldValue::Number(b)) => compare(a, b, descending),
+        (FieldValue::Flag(a), FieldValue::Flag(b)) => compare(&Some(a), &Some(b), descending),
+        _ => Ordering::Equal,
+    }
+}
+
+// Filters, sorts and pages the records. `after` is the record the query's cursor points at.
+fn run_query<F: QueryField, R: QueryRecord<F>>(
+    records: Vec<R>,
+    query: &Query<F>,
+    after: Option<R>,
+    now_ms: u64,
+) -> Vec<R> {
+    let sort: Vec<SortKey<F>> = query
+        .sort
+        .iter()
+        .copied()
+        .chain(F::TIEBREAK.iter().map(|&field| SortKey {
+            field,
+            descending: false,
+        }))
+        .collect();
+    let keyed = |record: R| -> (Vec<FieldValue>, R) {
+        let values = sort
+            .iter()
+            .map(|key| value(&record, key.field, now_ms))
+            .collect();
+        (values, record)
+    };
+    let compare = |(a_values, a): &(Vec<FieldValue>, R), (b_values, b): &(Vec<FieldValue>, R)| {
+        a_values
+            .iter()
+            .zip(b_values)
+            .zip(&sort)
+            .map(|((a, b), key)| compare_values(a, b, key.descending))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.key().to_byte_key().cmp(b.key().to_byte_key()))
+    };
+
+    let mut records: Vec<(Vec<FieldValue>, R)> = records
+        .into_iter()
+        .filter(|record| {
+            query
+                .filter
+                .as_ref()
+                .is_none_or(|filter| matches(record, filter, now_ms))
+        })
+        .map(keyed)
+        .collect();
+    records.sort_by(compare);
+    let start = match after.map(keyed) {
+        Some(after) => records.partition_point(|record| compare(record, &after).is_le()),
+        None => 0,
+    };
+    records
+        .into_iter()
+        .skip(start.saturating_add(query.offset))
+        .take(query.limit.unwrap_or(usize::MAX))
+        .map(|(_, record)| record)
+        .collect()
+}
+
+struct SongRecord {
+    key: Key,
+    song: Song,
+    album: Option<AlbumTags>,
+    user_data: UserData,
+    added_ms: Option<u64>,
+}
+
+impl QueryRecord<SongField> for SongRecord {
+    fn key(&self) -> &Key {
+        &self.key
+    }
+
+    fn text(&self, field: SongField) -> Option<&str> {
+        let tags = &self.song.tags;
+        let album = self.album.as_ref();
+        match field {
+            SongField::Title => tags.title.as_deref(),
+            SongField::Artist => tags.artist.as_deref(),
+            SongField::Album => album.and_then(|album| album.title.as_deref()),
+            SongField::AlbumArtist => album.and_then(|album| album.artist.as_deref()),
+            SongField::Genre => tags
+                .genre
+                .as_deref()
+                .or_else(|| album.and_then(|album| album.genre.as_deref())),
+            SongField::Composer => tags.composer.as_deref(),
+            SongField::Comment => tags.comment.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn number(&self, field: SongField, now_ms: u64) -> Option<i64> {
+        let tags = &self.song.tags;
+        let stream = &self.song.stream;
+        match field {
+            SongField::Year => self.album.as_ref()?.year.map(i64::from),
+            SongField::Track => tags.track_number.map(i64::from),
+            SongField::Disc => tags.disc_number.map(i64::from),
+            SongField::Duration => stream.duration_ms.map(|ms| (ms / 1000) as i64),
+            SongField::Bitrate => stream.bitrate.map(i64::from),
+            SongField::Rating => self.user_data.rating.map(i64::from),
+            SongField::Plays => Some(i64::from(self.user_data.play_count)),
+            SongField::Skips => Some(i64::from(self.user_data.skip_count)),
+            SongField::DaysSincePlayed => days_since(self.user_data.last_played_ms, now_ms),
+            SongField::DateAdded => self.added_ms.map(|ms| ms as i64),
+            SongField::DaysSinceAdded => days_since(self.added_ms, now_ms),
+            _ => None,
+        }
+    }
+
+    fn flag(&self, _: SongField) -> bool {
+        self.user_data.favourite
+    }
+}
+
+const SONG_USER_DATA_FIELDS: [SongField; 5] = [
+    SongField::Rating,
+    SongField::Plays,
+    SongField::Skips,
+    SongField::DaysSincePlayed,
+    SongField::Favourite,
+];
+const SONG_ADDED_FIELDS: [SongField; 2] = [SongField::DateAdded, SongField::DaysSinceAdded];
+
+// Reads only what the query needs, and each album's tags once.
+struct SongLoader<'a, S: Store> {
+    tree: &'a S,
+    albums: HashMap<Key, Option<AlbumTags>>,
+    user_data: bool,
+    added: bool,
+}
+
+impl<S: Store> SongLoader<'_, S> {
+    fn load(&mut self, key: Key, album_key: &Key) -> Result<SongRecord> {
+        let album = match self.albums.get(album_key) {
+            Some(album) => album.clone(),
+            None => {
+                let album = match self.tree.get_metadata(album_key) {
+                    Ok(album) => Some(album),
+                    Err(Error::NotFound(_)) => None,
+                    Err(e) => return Err(e),
+                };
+                self.albums.insert(album_key.clone(), album.clone());
+                album
+            }
+        };
+        Ok(SongRecord {
+            song: self.tree.get_metadata(&key)?,
+            user_data: match self.user_data {
+                true => read_user_data(self.tree, &key)?,
+                false => UserData::default(),
+            },
+            added_ms: match self.added {
+                true => added_ms(self.tree, &key)?,
+                false => None,
+            },
+            album,
+            key,
+        })
+    }
+}
+
+pub(crate) fn run_song_query(tree: &impl Store, query: &SongQuery) -> Result<Vec<(Key, Song)>> {
+    let album_keys = scan_stored_albums(tree)?;
+    let mut loader = SongLoader {
+        tree,
+        albums: HashMap::new(),
+        user_data: query.uses(&SONG_USER_DATA_FIELDS),
+        added: query.uses(&SONG_ADDED_FIELDS),
+    };
+    let after = match &query.after {
+        Some(key) => {
+            let album_key = album_keys
+                .get(key)
+                .ok_or_else(|| Error::NotFound(key.clone()))?;
+            Some(loader.load(key.clone(), album_key)?)
+        }
+        None => None,
+    };
+    let records = album_keys
+        .iter()
+        .map(|(song_key, album_key)| loader.load(song_key.clone(), album_key))
+        .collect::<Result<Vec<_>>>()?;
+    let now_ms = millis_since_epoch(SystemTime::now());
+    Ok(run_query(records, query, after, now_ms)
+        .into_iter()
+        .map(|record| (record.key, record.song))
+        .collect())
+}
+
+struct AlbumRecord {
+    key: Key,
+    tags: AlbumTags,
+    track_count: usize,
+    duration_ms: Option<u64>,
+    user_data: UserData,
+    added_ms: Option<u64>,
+}
+
+impl QueryRecord<AlbumField> for AlbumRecord {
+    fn key(&self) -> &Key {
+        &self.key
+    }
+
+    fn text(&self, field: AlbumField) -> Option<&str> {
+        match field {
+            AlbumField::Title => self.tags.title.as_deref(),
+            AlbumField::Artist => self.tags.artist.as_deref(),
+            AlbumField::Genre => self.tags.genre.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn number(&self, field: AlbumField, now_ms: u64) -> Option<i64> {
+        match field {
+            AlbumField::Year => self.tags.year.map(i64::from),
+            AlbumField::TrackCount => Some(self.track_count as i64),
+            AlbumField::Duration => self.duration_ms.map(|ms| (ms / 1000) as i64),
+            AlbumField::Rating => self.user_data.rating.map(i64::from),
+            AlbumField::Plays => Some(i64::from(self.user_data.play_count)),
+            AlbumField::DaysSincePlayed => days_since(self.user_data.last_played_ms, now_ms),
+            AlbumField::DateAdded => self.added_ms.map(|ms| ms as i64),
+            AlbumField::DaysSinceAdded => days_since(self.added_ms, now_ms),
+            _ => None,
+        }
+    }
+
+    fn flag(&self, _: AlbumField) -> bool {
+        self.user_data.favourite
+    }
+}
+
+const ALBUM_USER_DATA_FIELDS: [AlbumField; 4] = [
+    AlbumField::Rating,
+    AlbumField::Plays,
+    AlbumField::DaysSincePlayed,
+    AlbumField::Favourite,
+];
+const ALBUM_ADDED_FIELDS: [AlbumField; 2] = [AlbumField::DateAdded, AlbumField::DaysSinceAdded];
+
+struct AlbumLoader<'a, S: Store> {
+    tree: &'a S,
+    duration: bool,
+    user_data: bool,
+    added: bool,
+}
+
+impl<S: Store> AlbumLoader<'_, S> {
+    fn load(&self, key: Key, album: StoredAlbum) -> Result<AlbumRecord> {
+        let duration_ms = match self.duration {
+            true => album
+                .song_keys
+                .iter()
+                .map(|(_, song_key)| {
+                    let song: Song = self.tree.get_metadata(&Key::from_byte_key(*song_key))?;
+                    Ok(song.stream.duration_ms)
+                })
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .flatten()
+                .reduce(|total, ms| total + ms),
+            false => None,
+        };
+        Ok(AlbumRecord {
+            user_data: match self.user_data {
+                true => read_user_data(self.tree, &key)?,
+                false => UserData::default(),
+            },
+            added_ms: match self.added {
+                true => added_ms(self.tree, &key)?,
+                false => None,
+            },
+            track_count: album.song_keys.len(),
+            tags: album.tags,
+            duration_ms,
+            key,
+        })
+    }
+}
+
+pub(crate) fn run_album_query(
+    tree: &impl Store,
+    query: &AlbumQuery,
+) -> Result<Vec<(Key, AlbumTags)>> {
+    let loader = AlbumLoader {
+        tree,
+        duration: query.uses(&[AlbumField::Duration]),
+        user_data: query.uses(&ALBUM_USER_DATA_FIELDS),
+        added: query.uses(&ALBUM_ADDED_FIELDS),
+    };
+    let load =
+        |key: Key, bytes: &[u8]| loader.load(key, StoredAlbum::partial_deserialize_album(bytes)?);
+    let after = match &query.after {
+        Some(key) => {
+            let bytes = tree.get(key)?.ok_or_else(|| Error::NotFound(key.clone()))?;
+            Some(load(key.clone(), &bytes)?)
+        }
+        None => None,
+    };
+    let records = tree
+        .scan_prefix(KeyType::Album)
+        .map(|entry| {
+            let (key, bytes) = entry?;
+            load(Key::try_from(&key)?, &bytes)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let now_ms = millis_since_epoch(SystemTime::now());
+    Ok(run_query(records, query, after, now_ms)
+        .into_iter()
+        .map(|record| (record.key, record.tags))
+        .collect())
+}
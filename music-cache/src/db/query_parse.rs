@@ -0,0 +1,336 @@
+use crate::*;
+
+// The text form of a Query, shared by smart playlists and every client:
+//
+//   query     := [filter] ["sort" "by" sort_key {"," sort_key}] ["limit" number] ["offset" number]
+//   filter    := all {"or" all}
+//   all       := unary {"and" unary}
+//   unary     := "not" unary | "(" filter ")" | condition
+//   condition := field op value | flag_field
+//   op        := "=" | "!=" | "<" | "<=" | ">" | ">=" | "~"
+//   sort_key  := field ["asc" | "desc"]
+//
+// Keywords and field names ignore case. Text values are bare words or double quoted, with \" and \\
+// escapes, and only take "=", "!=" and "~" (contains). Number fields take "=" with a range like
+// 1990..1999 as well. Songs and albums have their own fields. For example:
+//
+//   year = 1990..1999 and genre = rock and rating >= 4 and not days_since_played < 30
+//   sort by plays desc limit 50
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(&'static str),
+    Open,
+    Close,
+    Comma,
+}
+
+const OPS: [&str; 7] = ["!=", "<=", ">=", "=", "<", ">", "~"];
+
+fn invalid(position: usize, message: impl Into<String>) -> Error {
+    Error::InvalidQuery {
+        position,
+        message: message.into(),
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !matches!(c, '(' | ')' | ',' | '"' | '=' | '!' | '<' | '>' | '~')
+}
+
+// Positions are in chars, for pointing at the problem in what the user typed.
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let start = i;
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let token = match c {
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            '"' => {
+                let mut value = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(invalid(start, "Unterminated quote")),
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.get(i + 1), Some('"' | '\\')) => {
+                            value.push(chars[i + 1]);
+                            i += 2;
+                        }
+                        Some(c) => {
+                            value.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                Token::Quoted(value)
+            }
+            _ if is_word_char(c) => {
+                while chars.get(i + 1).is_some_and(|c| is_word_char(*c)) {
+                    i += 1;
+                }
+                Token::Word(chars[start..=i].iter().collect())
+            }
+            _ => {
+                let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+                let op = OPS
+                    .into_iter()
+                    .find(|op| rest.starts_with(op))
+                    .ok_or_else(|| invalid(start, format!("Unexpected '{c}'")))?;
+                i += op.len() - 1;
+                Token::Op(op)
+            }
+        };
+        tokens.push((start, token));
+        i += 1;
+    }
+    Ok(tokens)
+}
+
+// How deeply "not"s and parentheses can nest. Filters are parsed and matched recursively, so deeper
+// ones are refused rather than overflowing the stack.
+const MAX_DEPTH: usize = 256;
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    end: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |(position, _)| *position)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.next += 1;
+        token
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.at_keyword(keyword);
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(invalid(self.position(), format!("Expected '{keyword}'")))
+        }
+    }
+
+    fn at_clause(&self) -> bool {
+        self.peek().is_none()
+            || self.at_keyword("sort")
+            || self.at_keyword("limit")
+            || self.at_keyword("offset")
+    }
+
+    fn query<F: QueryField>(&mut self) -> Result<Query<F>> {
+        let mut query = Query::default();
+        if !self.at_clause() {
+            query.filter = Some(self.filter()?);
+        }
+        if self.eat_keyword("sort") {
+            self.expect_keyword("by")?;
+            loop {
+                query.sort.push(self.sort_key()?);
+                if self.peek() != Some(&Token::Comma) {
+                    break;
+                }
+                self.next += 1;
+            }
+        }
+        if self.eat_keyword("limit") {
+            query.limit = Some(self.count("Expected a limit")?);
+        }
+        if self.eat_keyword("offset") {
+            query.offset = self.count("Expected an offset")?;
+        }
+        match self.peek() {
+            None => Ok(query),
+            Some(_) => Err(invalid(self.position(), "Unexpected text")),
+        }
+    }
+
+    fn count(&mut self, expected: &str) -> Result<usize> {
+        let position = self.position();
+        match self.advance() {
+            Some(Token::Word(word)) => word.parse().map_err(|_| invalid(position, expected)),
+            _ => Err(invalid(position, expected)),
+        }
+    }
+
+    fn filter<F: QueryField>(&mut self) -> Result<Filter<F>> {
+        let mut any = vec![self.all()?];
+        while self.eat_keyword("or") {
+            any.push(self.all()?);
+        }
+        Ok(match any.len() {
+            1 => any.remove(0),
+            _ => Filter::Or(any),
+        })
+    }
+
+    fn all<F: QueryField>(&mut self) -> Result<Filter<F>> {
+        let mut all = vec![self.unary()?];
+        while self.eat_keyword("and") {
+            all.push(self.unary()?);
+        }
+        Ok(match all.len() {
+            1 => all.remove(0),
+            _ => Filter::And(all),
+        })
+    }
+
+    fn unary<F: QueryField>(&mut self) -> Result<Filter<F>> {
+        if self.depth == MAX_DEPTH {
+            return Err(invalid(self.position(), "Too deeply nested"));
+        }
+        self.depth += 1;
+        let filter = self.nested();
+        self.depth -= 1;
+        filter
+    }
+
+    fn nested<F: QueryField>(&mut self) -> Result<Filter<F>> {
+        if self.eat_keyword("not") {
+            return Ok(Filter::Not(Box::new(self.unary()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.next += 1;
+            let filter = self.filter()?;
+            let position = self.position();
+            if self.advance() != Some(Token::Close) {
+                return Err(invalid(position, "Expected ')'"));
+            }
+            return Ok(filter);
+        }
+        Ok(Filter::Condition(self.condition()?))
+    }
+
+    fn field<F: QueryField>(&mut self) -> Result<F> {
+        let position = self.position();
+        match self.advance() {
+            Some(Token::Word(word)) => F::from_name(&word)
+                .ok_or_else(|| invalid(position, format!("Unknown field '{word}'"))),
+            _ => Err(invalid(position, "Expected a field")),
+        }
+    }
+
+    fn condition<F: QueryField>(&mut self) -> Result<Condition<F>> {
+        let field: F = self.field()?;
+        if field.kind() == FieldKind::Flag {
+            return Ok(Condition::Flag(field));
+        }
+        let position = self.position();
+        let op = match self.advance() {
+            Some(Token::Op(op)) => op,
+            _ => return Err(invalid(position, "Expected a comparison")),
+        };
+        let value_position = self.position();
+        let value = match self.advance() {
+            Some(Token::Word(value) | Token::Quoted(value)) => value,
+            _ => return Err(invalid(value_position, "Expected a value")),
+        };
+        match field.kind() {
+            FieldKind::Text => {
+                let op = match op {
+                    "=" => TextOp::Is,
+                    "!=" => TextOp::IsNot,
+                    "~" => TextOp::Contains,
+                    _ => return Err(invalid(position, format!("Can't use '{op}' on text"))),
+                };
+                Ok(Condition::Text { field, op, value })
+            }
+            _ => {
+                let number = |text: &str| {
+                    text.parse::<i64>()
+                        .map_err(|_| invalid(value_position, "Expected a number"))
+                };
+                if let Some((min, max)) = value.split_once("..") {
+                    if op != "=" {
+                        return Err(invalid(position, "Ranges only go with '='"));
+                    }
+                    return Ok(Condition::Range {
+                        field,
+                        min: number(min)?,
+                        max: number(max)?,
+                    });
+                }
+                let op = match op {
+                    "=" => NumberOp::Eq,
+                    "!=" => NumberOp::Ne,
+                    "<" => NumberOp::Lt,
+                    "<=" => NumberOp::Le,
+                    ">" => NumberOp::Gt,
+                    ">=" => NumberOp::Ge,
+                    _ => return Err(invalid(position, format!("Can't use '{op}' on numbers"))),
+                };
+                Ok(Condition::Number {
+                    field,
+                    op,
+                    value: number(&value)?,
+                })
+            }
+        }
+    }
+
+    fn sort_key<F: QueryField>(&mut self) -> Result<SortKey<F>> {
+        let field = self.field()?;
+        let descending = if self.eat_keyword("desc") {
+            true
+        } else {
+            self.eat_keyword("asc");
+            false
+        };
+        Ok(SortKey { field, descending })
+    }
+}
+
+impl<F: QueryField> Query<F> {
+    // An empty query matches everything.
+    pub fn parse(source: &str) -> Result<Query<F>> {
+        Parser {
+            tokens: tokenize(source)?,
+            next: 0,
+            end: source.chars().count(),
+            depth: 0,
+        }
+        .query()
+    }
+}
+
+impl<F: QueryField> std::str::FromStr for Query<F> {
+    type Err = Error;
+
+    fn from_str(source: &str) -> Result<Query<F>> {
+        Query::parse(source)
+    }
+}
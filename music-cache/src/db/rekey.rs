@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+
+use crate::*;
+
+// Dbs whose keys were made under another HASH_SCHEME, or before the scheme was recorded, have every
+// hashed key recomputed from what it identifies and every reference to one rewritten, all in one
+// transaction. Going from scheme 1 to 2 the hashes come out the same and only the byte order of the
+// keys changes, but the rewrite is done the same way. Playlists keep the ids the store gave them,
+// written big-endian. Songs are keyed by where they are now, so moved songs lose their path
+// aliases. Stored fingerprints may have come from an older hash, so they're dropped along with the
+// file stamps, and the next scan of each root re-reads its files to fill them back in. User data
+// and added dates for songs that have left the library can't be matched up, and stay under their
+// old keys. Aliases left by merged albums are dropped, since the keys they stood for can't be
+// worked out again. Thumbnails are dropped too, and made again when they're next asked for. Runs
+// as part of upgrade_db. Returns whether the db was rekeyed.
+pub fn upgrade_hash_scheme(tree: &impl Store) -> Result<bool> {
+    let scheme = match tree.get(hash_scheme_key())? {
+        Some(bytes) => Some(u32::from_be_bytes(bytes.as_slice().try_into()?)),
+        None => None,
+    };
+    match scheme {
+        Some(HASH_SCHEME) => return Ok(false),
+        None if is_new_db(tree)? => {
+            tree.insert(hash_scheme_key(), HASH_SCHEME.to_be_bytes())?;
+            return Ok(false);
+        }
+        _ => {}
+    }
+    // Legacy albums are upgraded while their songs can still be found under the old keys.
+    scan_stored_albums(tree)?;
+    // The first release kept nothing under generated ids, so a db without a scheme has none to read
+    // back in native order.
+    let native_order = scheme.is_some_and(|scheme| scheme < BIG_ENDIAN_SCHEME);
+    Rekeying::plan(tree, native_order)?.apply(tree)?;
+    Ok(true)
+}
+
+// The first scheme to write key ids big-endian. Earlier ones wrote them in the byte order of
+// whatever machine made the db, which is assumed to be this one.
+const BIG_ENDIAN_SCHEME: u32 = 2;
+
+// Removals are kept apart from inserts so a key that's both freed and reused ends up inserted.
+#[derive(Default)]
+struct Rewrite {
+    removals: Vec<Vec<u8>>,
+    inserts: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Rewrite {
+    fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.removals.push(key.into());
+    }
+
+    fn insert(&mut self, key: impl AsRef<[u8]>, value: impl Into<Vec<u8>>) {
+        self.inserts.push((key.as_ref().to_vec(), value.into()));
+    }
+
+    fn batch(self) -> Batch {
+        let mut batch = Batch::default();
+        for key in self.removals {
+            batch.remove(key);
+        }
+        for (key, value) in self.inserts {
+            batch.insert(key, value);
+        }
+        batch
+    }
+}
+
+// Every key type whose ids come from the hash, and so gets rewritten wholesale.
+const HASHED_KEY_TYPES: [KeyType; 10] = [
+    KeyType::Song,
+    KeyType::Album,
+    KeyType::LastScanTime,
+    KeyType::Artist,
+    KeyType::SearchToken,
+    KeyType::SongPath,
+    KeyType::LibraryRoot,
+    KeyType::AlbumGrouping,
+    KeyType::MusicBrainzId,
+    KeyType::AlbumAlias,
+];
+
+#[derive(Default)]
+struct Rekeying {
+    renames: HashMap<Key, Key>,
+    main: Rewrite,
+    user_data: Rewrite,
+    added: Rewrite,
+    album_art: Rewrite,
+    thumbnails: Rewrite,
+}
+
+impl Rekeying {
+    fn plan(tree: &impl Store, native_order: bool) -> Result<Rekeying> {
+        let mut rekeying = Rekeying::default();
+        for key_type in HASHED_KEY_TYPES {
+            for entry in tree.scan_prefix(key_type) {
+                let (key, _) = entry?;
+                rekeying.main.remove(key);
+            }
+        }
+
+        let art_tree = album_art_tree(tree)?;
+        for entry in art_tree.iter() {
+            let (key, bytes) = entry?;
+            let new_key = AlbumArt::deserialize(&bytes)?.hash_key();
+            rekeying.rename(&Key::try_from(&key)?, &new_key);
+            rekeying.album_art.remove(key);
+            rekeying.album_art.insert(&new_key, bytes);
+        }
+        for entry in album_thumbnail_tree(tree)?.iter() {
+            rekeying.thumbnails.remove(entry?.0);
+        }
+
+        let songs = rekeying.plan_songs(tree)?;
+        let albums = rekeying.plan_albums(tree, &songs)?;
+        rekeying.plan_artists(tree)?;
+        rekeying.plan_settings(tree)?;
+
+        for (key, song) in &songs {
+            rekeying.main.insert(key, song.serialize());
+            rekeying.index(key, song);
+            if let Some(recording_id) = &song.musicbrainz.recording_id {
+                let index_key = recording_id_key(recording_id);
+                rekeying.main.insert(index_key, &key.to_byte_key()[..]);
+            }
+        }
+        for (key, album) in &albums {
+            rekeying.main.insert(key, album.serialize());
+            rekeying.index(key, &album.tags);
+        }
+
+        for entry in tree.scan_prefix(KeyType::Playlist) {
+            let (key, bytes) = entry?;
+            let mut playlist = Playlist::deserialize(&bytes)?;
+            for song_key in &mut playlist.song_keys {
+                *song_key = rekeying.renamed_byte_key(song_key);
+            }
+            let new_key = generated_key(&key, native_order)?;
+            rekeying.main.remove(key);
+            rekeying.main.insert(new_key, playlist.serialize());
+        }
+        if native_order {
+            for entry in tree.scan_prefix(KeyType::SmartPlaylist) {
+                let (key, bytes) = entry?;
+                let new_key = generated_key(&key, native_order)?;
+                rekeying.main.remove(key);
+                rekeying.main.insert(new_key, bytes);
+            }
+        }
+
+        let (renames, user_data, added) = (
+            &rekeying.renames,
+            &mut rekeying.user_data,
+            &mut rekeying.added,
+        );
+        for (side_tree, rewrite) in [
+            (user_data_tree(tree)?, user_data),
+            (added_tree(tree)?, added),
+        ] {
+            for entry in side_tree.iter() {
+                let (key, bytes) = entry?;
+                if let Some(new_key) = renames.get(&Key::try_from(&key)?) {
+                    rewrite.remove(key);
+                    rewrite.insert(new_key, bytes);
+                }
+            }
+        }
+
+        rekeying
+            .main
+            .insert(hash_scheme_key(), HASH_SCHEME.to_be_bytes());
+        Ok(rekeying)
+    }
+
+    fn rename(&mut self, old: &Key, new: &Key) {
+        self.renames.insert(old.clone(), new.clone());
+    }
+
+    fn renamed_byte_key(&self, byte_key: &ByteKey) -> ByteKey {
+        match self.renames.get(&Key::from_byte_key(*byte_key)) {
+            Some(key) => *key.to_byte_key(),
+            None => *byte_key,
+        }
+    }
+
+    fn index(&mut self, key: &Key, record: &impl Searchable) {
+        for (entry_key, weight) in search_entries(key, record) {
+            self.main.insert(entry_key, [weight]);
+        }
+    }
+
+    fn plan_songs(&mut self, tree: &impl Store) -> Result<HashMap<Key, Song>> {
+        let mut songs = HashMap::new();
+        for entry in tree.scan_prefix(KeyType::Song) {
+            let (key, bytes) = entry?;
+            let mut song = Song::deserialize(&bytes)?;
+            song.fingerprint = None;
+            song.file = None;
+            let new_key = song.hash_key();
+            self.rename(&Key::try_from(&key)?, &new_key);
+            songs.insert(new_key, song);
+        }
+        Ok(songs)
+    }
+
+    // Albums are keyed the way their first song would be stored now. Any that land on one key are
+    // merged.
+    fn plan_albums(
+        &mut self,
+        tree: &impl Store,
+        songs: &HashMap<Key, Song>,
+    ) -> Result<HashMap<Key, StoredAlbum>> {
+        // The grouping's own key is about to change, so it's looked up by type.
+        let grouping = match tree.scan_prefix(KeyType::AlbumGrouping).next() {
+            Some(entry) => bitcode::decode(&entry?.1)?,
+            None => AlbumGrouping::default(),
+        };
+        let mut albums: HashMap<Key, StoredAlbum> = HashMap::new();
+        for entry in tree.scan_prefix(KeyType::Album) {
+            let (key, bytes) = entry?;
+            let mut album = StoredAlbum::partial_deserialize_album(&bytes)?;
+            for (_, song_key) in &mut album.song_keys {
+                *song_key = self.renamed_byte_key(song_key);
+            }
+            album.art = album.art.map(|art_key| self.renamed_byte_key(&art_key));
+            let first_song = album
+                .song_keys
+                .first()
+                .and_then(|(_, song_key)| songs.get(&Key::from_byte_key(*song_key)));
+            let new_key = match first_song {
+                Some(song) => album_key(grouping, &album.tags, song),
+                None => album.tags.hash_key(),
+            };
+            self.rename(&Key::try_from(&key)?, &new_key);
+            match albums.get_mut(&new_key) {
+                Some(existing) => {
+                    existing.art = existing.art.or(album.art);
+                    for song_key in album.song_keys {
+                        if !existing.song_keys.iter().any(|(_, key)| *key == song_key.1) {
+                            existing.song_keys.push(song_key);
+                        }
+                    }
+                    existing.song_keys.sort_by(|a, b| a.0.cmp(&b.0));
+                }
+                None => {
+                    albums.insert(new_key, album);
+                }
+            }
+        }
+        Ok(albums)
+    }
+
+    fn plan_artists(&mut self, tree: &impl Store) -> Result<()> {
+        let mut artists: HashMap<Key, Artist> = HashMap::new();
+        for entry in tree.scan_prefix(KeyType::Artist) {
+            let (_, bytes) = entry?;
+            let artist = Artist::deserialize(&bytes)?;
+            let new_key = artist_hash_key(artist.name.as_deref());
+            let merged = artists.entry(new_key).or_insert_with(|| Artist {
+                name: artist.name.clone(),
+                album_keys: Vec::new(),
+            });
+            merged.album_keys.extend(
+                artist
+                    .album_keys
+                    .iter()
+                    .map(|album_key| self.renamed_byte_key(album_key)),
+            );
+        }
+        for (key, mut artist) in artists {
+            artist.album_keys.sort();
+            artist.album_keys.dedup();
+            self.index(&key, &artist);
+            self.main.insert(&key, artist.serialize());
+        }
+        Ok(())
+    }
+
+    // Roots and the album grouping. Last scan times are left out, see upgrade_hash_scheme.
+    fn plan_settings(&mut self, tree: &impl Store) -> Result<()> {
+        for entry in tree.scan_prefix(KeyType::LibraryRoot) {
+            let (_, bytes) = entry?;
+            let root = LibraryRoot::deserialize(&bytes)?;
+            self.main.insert(library_root_key(&root.name), bytes);
+        }
+        if let Some(entry) = tree.scan_prefix(KeyType::AlbumGrouping).next() {
+            self.main.insert(album_grouping_key(), entry?.1);
+        }
+        Ok(())
+    }
+
+    fn apply(self, tree: &impl Store) -> Result<()> {
+        tree.apply_batches(&[
+            (None, self.main.batch()),
+            (Some(USER_DATA_TREE), self.user_data.batch()),
+            (Some(ADDED_TREE), self.added.batch()),
+            (Some(ALBUM_ART_TREE), self.album_art.batch()),
+            (Some(ALBUM_THUMBNAIL_TREE), self.thumbnails.batch()),
+        ])
+    }
+}
+
+// Ids the store handed out can't be recomputed, so keys written in native order are read back that
+// way.
+fn generated_key(key: &Vec<u8>, native_order: bool) -> Result<Key> {
+    let key = Key::try_from(key)?;
+    match (native_order, key.key_type()) {
+        (true, Some(key_type)) => {
+            let id = u64::from_ne_bytes(key.to_byte_key()[1..].try_into()?);
+            Ok(Key::new(key_type, id))
+        }
+        _ => Ok(key),
+    }
+}
@@ -0,0 +1,132 @@
+use crate::*;
+
+// The layout of the db as a whole. Bumping it means adding a migration that brings older dbs up to it.
+// Builds refuse dbs with a version past theirs rather than misreading them.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// Songs and albums are stored as their format followed by their bitcode. A record type's format is
+// bumped whenever its fields change, and its deserialize keeps a decoder for each earlier format
+// that shipped so old records upgrade as they're read. Records from the first release have no
+// leading byte.
+// Songs gained file stamps in format 2, and format 1 never shipped.
+pub const SONG_FORMAT: u8 = 2;
+pub const ALBUM_FORMAT: u8 = 1;
+
+pub fn schema_version_key() -> Key {
+    Key::new(KeyType::SchemaVersion, 0)
+}
+
+struct Migration<S> {
+    // What the db is at once this has run.
+    version: u32,
+    run: fn(&S) -> Result<()>,
+}
+
+// In version order. Each runs once on dbs older than it, and must be safe to run again if interrupted,
+// since the version is only recorded after it finishes.
+fn migrations<S: Store>() -> [Migration<S>; 1] {
+    [Migration {
+        version: 1,
+        run: tag_records,
+    }]
+}
+
+// Runs every migration the db hasn't had. Returns whether any ran.
+pub fn upgrade_schema<S: Store>(tree: &S) -> Result<bool> {
+    let version = match tree.get(schema_version_key())? {
+        Some(bytes) => u32::from_be_bytes(bytes.as_slice().try_into()?),
+        None if is_new_db(tree)? => {
+            tree.insert(schema_version_key(), SCHEMA_VERSION.to_be_bytes())?;
+            return Ok(false);
+        }
+        None => 0,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(Error::DatabaseTooNew {
+            found: version,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    let mut upgraded = false;
+    for migration in migrations::<S>().iter().filter(|m| m.version > version) {
+        (migration.run)(tree)?;
+        tree.insert(schema_version_key(), migration.version.to_be_bytes())?;
+        upgraded = true;
+    }
+    Ok(upgraded)
+}
+
+// Brings a db up to date before it's used: its schema first, then its keys. Runs when a db is opened
+// through the FFI and before every scan. Callers opening a db themselves should call this before
+// anything else. Returns whether anything changed.
+pub fn upgrade_db(tree: &impl Store) -> Result<bool> {
+    let migrated = upgrade_schema(tree)?;
+    let rekeyed = upgrade_hash_scheme(tree)?;
+    Ok(migrated || rekeyed)
+}
+
+// Records what a new db is before its first generated id is written, so the ids aren't taken for an
+// older build's.
+pub(crate) fn mark_new_db(tree: &impl Store) -> Result<()> {
+    if is_new_db(tree)? {
+        tree.insert(schema_version_key(), SCHEMA_VERSION.to_be_bytes())?;
+        tree.insert(hash_scheme_key(), HASH_SCHEME.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+// Nothing stored yet besides the records saying what the db is, so there's nothing to upgrade.
+pub(crate) fn is_new_db(tree: &impl Store) -> Result<bool> {
+    let markers = [schema_version_key(), hash_scheme_key()];
+    for entry in tree.iter() {
+        let (key, _) = entry?;
+        if !markers
+            .iter()
+            .any(|marker| marker.as_ref() == key.as_slice())
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+pub(crate) fn encode_record(format: u8, record: &impl bitcode::Encode) -> Vec<u8> {
+    let mut bytes = vec![format];
+    bytes.extend(bitcode::encode(record));
+    bytes
+}
+
+// The record's bitcode, if it's tagged with `format` and decodes as T.
+pub(crate) fn decode_tagged<T: bitcode::DecodeOwned>(bytes: &[u8], format: u8) -> Option<T> {
+    match bytes.split_first() {
+        Some((&tag, body)) if tag == format => bitcode::decode(body).ok(),
+        _ => None,
+    }
+}
+
+// Why nothing could decode a record. A tag past the current format means a newer build wrote it.
+pub(crate) fn undecodable(bytes: &[u8], format: u8, e: bitcode::Error) -> Error {
+    match bytes.first() {
+        Some(&tag) if tag > format => Error::DatabaseTooNew {
+            found: tag.into(),
+            supported: format.into(),
+        },
+        _ => e.into(),
+    }
+}
+
+// Version 1: songs and albums gain their format tag. Untagged records still decode, so this only
+// spares every read working out which layout it has. Legacy albums are re-sorted on the way.
+fn tag_records<S: Store>(tree: &S) -> Result<()> {
+    for entry in tree.scan_prefix(KeyType::Song) {
+        let (key, bytes) = entry?;
+        tree.insert(key, Song::deserialize(&bytes)?.serialize())?;
+    }
+    scan_stored_albums(tree)?;
+    for entry in tree.scan_prefix(KeyType::Album) {
+        let (key, bytes) = entry?;
+        let album = StoredAlbum::partial_deserialize_album(&bytes)?;
+        tree.insert(key, album.serialize())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::*;
+
+// The search index lives alongside the records it points at. Each entry key is the SearchToken tag, a
+// folded token, a zero separator and the key of the record containing it, so a prefix scan over a
+// partial token finds every matching record. The value is a single byte giving the weight of the
+// field the token came from.
+
+const SEPARATOR: u8 = 0;
+const EXACT_MATCH_BONUS: u32 = 2;
+
+pub trait Searchable {
+    // Text fields worth indexing, paired with how strongly a match in that field should rank.
+    fn search_fields(&self) -> Vec<(&str, u8)>;
+}
+
+impl Searchable for Song {
+    fn search_fields(&self) -> Vec<(&str, u8)> {
+        [
+            (&self.tags.title, 3),
+            (&self.tags.artist, 2),
+            (&self.tags.composer, 1),
+            (&self.tags.genre, 1),
+        ]
+        .into_iter()
+        .filter_map(|(field, weight)| field.as_deref().map(|text| (text, weight)))
+        .collect()
+    }
+}
+
+impl Searchable for AlbumTags {
+    fn search_fields(&self) -> Vec<(&str, u8)> {
+        [(&self.title, 3), (&self.artist, 2), (&self.genre, 1)]
+            .into_iter()
+            .filter_map(|(field, weight)| field.as_deref().map(|text| (text, weight)))
+            .collect()
+    }
+}
+
+impl Searchable for Artist {
+    fn search_fields(&self) -> Vec<(&str, u8)> {
+        self.name
+            .as_deref()
+            .map(|name| (name, 3))
+            .into_iter()
+            .collect()
+    }
+}
+
+#[cfg_attr(feature = "integration-tests", derive(Debug, PartialEq, Eq))]
+#[derive(Default)]
+pub struct SearchResults {
+    pub songs: Vec<Key>,
+    pub albums: Vec<Key>,
+    pub artists: Vec<Key>,
+}
+
+// Lowercases and strips diacritics, so "Beyoncé" and "BEYONCE" index the same.
+pub fn fold_text(text: &str) -> String {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> {
+    fold_text(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+fn token_prefix(token: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(1 + token.len());
+    prefix.push(KeyType::SearchToken as u8);
+    prefix.extend_from_slice(token.as_bytes());
+    prefix
+}
+
+fn token_entry_key(token: &str, key: &Key) -> Vec<u8> {
+    let mut entry_key = token_prefix(token);
+    entry_key.push(SEPARATOR);
+    entry_key.extend_from_slice(key.to_byte_key());
+    entry_key
+}
+
+fn parse_token_entry_key(entry_key: &[u8]) -> Result<(&[u8], ByteKey)> {
+    let key_start = entry_key
+        .len()
+        .checked_sub(std::mem::size_of::<ByteKey>())
+        .filter(|&start| start >= 2 && entry_key[start - 1] == SEPARATOR)
+        .ok_or(Error::InvalidKey)?;
+    let byte_key = entry_key[key_start..].try_into()?;
+    Ok((&entry_key[1..key_start - 1], byte_key))
+}
+
+fn weighted_tokens(record: &impl Searchable) -> HashMap<String, u8> {
+    let mut tokens = HashMap::new();
+    for (text, weight) in record.search_fields() {
+        for token in tokenize(text) {
+            let best = tokens.entry(token).or_insert(weight);
+            *best = (*best).max(weight);
+        }
+    }
+    tokens
+}
+
+// Every index entry for a record, for writing the index out in one go.
+pub(crate) fn search_entries(key: &Key, record: &impl Searchable) -> Vec<(Vec<u8>, u8)> {
+    weighted_tokens(record)
+        .into_iter()
+        .map(|(token, weight)| (token_entry_key(&token, key), weight))
+        .collect()
+}
+
+// Brings the index for one record from its previous contents to its current ones, touching only
+// the tokens that changed. Pass None for previous on insert and None for current on removal.
+pub fn update_search_index<T: Searchable>(
+    tree: &impl Store,
+    key: &Key,
+    previous: Option<&T>,
+    current: Option<&T>,
+) -> Result<()> {
+    let old_tokens = previous.map(weighted_tokens).unwrap_or_default();
+    let new_tokens = current.map(weighted_tokens).unwrap_or_default();
+
+    for token in old_tokens.keys() {
+        if !new_tokens.contains_key(token) {
+            tree.remove(token_entry_key(token, key))?;
+        }
+    }
+    for (token, weight) in &new_tokens {
+        if old_tokens.get(token) != Some(weight) {
+            tree.insert(token_entry_key(token, key), &[*weight][..])?;
+        }
+    }
+    Ok(())
+}
+
+// Every query token has to match a record for it to be returned, either exactly or as the prefix of
+// one of its tokens, so results narrow as the user types. Exact matches and matches in heavier
+// fields rank first; ties are broken by key so the order is stable.
+pub fn search_index(tree: &impl Store, query: &str, limit: usize) -> Result<SearchResults> {
+    let mut terms: Vec<String> = tokenize(query).collect();
+    terms.sort();
+    terms.dedup();
+    if terms.is_empty() {
+        return Ok(SearchResults::default());
+    }
+
+    let mut totals: HashMap<ByteKey, (usize, u32)> = HashMap::new();
+    for term in &terms {
+        let mut best: HashMap<ByteKey, u32> = HashMap::new();
+        for entry in tree.scan_prefix(token_prefix(term)) {
+            let (entry_key, weight) = entry?;
+            let (token, byte_key) = parse_token_entry_key(&entry_key)?;
+            let weight = u32::from(*weight.first().ok_or(Error::InvalidKey)?);
+            let score = if token == term.as_bytes() {
+                weight * EXACT_MATCH_BONUS
+            } else {
+                weight
+            };
+            let best_score = best.entry(byte_key).or_default();
+            *best_score = (*best_score).max(score);
+        }
+        for (byte_key, score) in best {
+            let total = totals.entry(byte_key).or_default();
+            total.0 += 1;
+            total.1 += score;
+        }
+    }
+
+    let mut ranked: Vec<(ByteKey, u32)> = totals
+        .into_iter()
+        .filter(|(_, (matched, _))| *matched == terms.len())
+        .map(|(byte_key, (_, score))| (byte_key, score))
+        .collect();
+    ranked.sort_by(|(key_a, score_a), (key_b, score_b)| {
+        score_b.cmp(score_a).then_with(|| key_a.cmp(key_b))
+    });
+
+    let mut results = SearchResults::default();
+    for (byte_key, _) in ranked {
+        let key = Key::from_byte_key(byte_key);
+        let bucket = match key.key_type() {
+            Some(KeyType::Song) => &mut results.songs,
+            Some(KeyType::Album) => &mut results.albums,
+            Some(KeyType::Artist) => &mut results.artists,
+            _ => continue,
+        };
+        if bucket.len() < limit {
+            bucket.push(key);
+        }
+    }
+    Ok(results)
+}
@@ -0,0 +1,86 @@
+use music_cache_derive::derive_data_model;
+use std::time::SystemTime;
+
+use crate::*;
+
+// Holds the query as written, so it reads back the way the user typed it. The query is checked when
+// stored and run against the library each time the songs are asked for.
+#[derive_data_model]
+#[derive(Clone)]
+pub struct SmartPlaylist {
+    pub name: String,
+    pub query: String,
+    // Milliseconds since the Unix epoch.
+    pub created_ms: u64,
+    pub modified_ms: u64,
+}
+
+impl SmartPlaylist {
+    pub fn new(name: &str, query: &str) -> Result<SmartPlaylist> {
+        SongQuery::parse(query)?;
+        let now = millis_since_epoch(SystemTime::now());
+        Ok(SmartPlaylist {
+            name: name.to_string(),
+            query: query.to_string(),
+            created_ms: now,
+            modified_ms: now,
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bitcode::encode(self)
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<SmartPlaylist> {
+        Ok(bitcode::decode(bytes)?)
+    }
+
+    pub fn song_query(&self) -> Result<SongQuery> {
+        SongQuery::parse(&self.query)
+    }
+}
+
+pub(crate) fn store_new_smart_playlist(tree: &impl Store, playlist: &SmartPlaylist) -> Result<Key> {
+    let key = tree.generate_key(playlist)?;
+    tree.insert(&key, playlist.serialize())?;
+    Ok(key)
+}
+
+pub(crate) fn get_smart_playlist(tree: &impl Store, key: &Key) -> Result<SmartPlaylist> {
+    if !matches!(key.key_type(), Some(KeyType::SmartPlaylist)) {
+        return Err(Error::NotFound(key.clone()));
+    }
+    let bytes = tree.get(key)?.ok_or_else(|| Error::NotFound(key.clone()))?;
+    SmartPlaylist::deserialize(&bytes)
+}
+
+// Keeps the created time.
+pub(crate) fn replace_smart_playlist(
+    tree: &impl Store,
+    key: &Key,
+    name: &str,
+    query: &str,
+) -> Result<()> {
+    let mut playlist = SmartPlaylist::new(name, query)?;
+    playlist.created_ms = get_smart_playlist(tree, key)?.created_ms;
+    tree.insert(key, playlist.serialize())?;
+    Ok(())
+}
+
+pub(crate) fn delete_smart_playlist_record(tree: &impl Store, key: &Key) -> Result<()> {
+    get_smart_playlist(tree, key)?;
+    tree.remove(key)?;
+    Ok(())
+}
+
+pub(crate) fn scan_smart_playlists(tree: &impl Store) -> Result<Vec<(Key, SmartPlaylist)>> {
+    let mut playlists = tree
+        .scan_prefix(KeyType::SmartPlaylist)
+        .map(|entry| {
+            let (key, bytes) = entry?;
+            Ok((Key::try_from(&key)?, SmartPlaylist::deserialize(&bytes)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    playlists.sort_by_cached_key(|(_, playlist)| playlist.name.to_lowercase());
+    Ok(playlists)
+}
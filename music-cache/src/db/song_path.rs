@@ -0,0 +1,115 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::*;
+
+// Songs keep the key they were first stored under when their file moves, so a path doesn't always
+// hash to its song's key. A moved song gets an alias from its new path, and a new file that turns up
+// at a path whose hash a moved song still holds is given a key of its own, aliased the same way.
+pub fn path_alias_key(root: &str, relpath: &[u8]) -> Key {
+    song_hash_key(root, relpath).with_key_type(KeyType::SongPath)
+}
+
+fn salted_song_hash_key(root: &str, relpath: &[u8], salt: u32) -> Key {
+    let mut hasher = KeyHasher::new();
+    hasher.write_str(root);
+    hasher.write_bytes(relpath);
+    hasher.write_u32(salt);
+    hash_key(KeyType::Song, hasher)
+}
+
+// Moves the alias for a song from its previous location to its current one. Pass None for previous on
+// insert and None for current on removal.
+pub fn update_path_alias(
+    tree: &impl Store,
+    song_key: &Key,
+    previous: Option<&Song>,
+    current: Option<&Song>,
+) -> Result<()> {
+    if previous.map(|song| (&song.root, &song.relpath))
+        == current.map(|song| (&song.root, &song.relpath))
+    {
+        return Ok(());
+    }
+    if let Some(previous) = previous {
+        if previous.hash_key() != *song_key {
+            tree.remove(path_alias_key(&previous.root, &previous.relpath))?;
+        }
+    }
+    if let Some(current) = current {
+        if current.hash_key() != *song_key {
+            let alias_key = path_alias_key(&current.root, &current.relpath);
+            tree.insert(alias_key, &song_key.to_byte_key()[..])?;
+        }
+    }
+    Ok(())
+}
+
+pub fn resolve_song_key(tree: &impl Store, root: &str, relpath: &[u8]) -> Result<Key> {
+    match tree.get(path_alias_key(root, relpath))? {
+        Some(bytes) => Ok(Key::try_from(&bytes)?),
+        None => Ok(song_hash_key(root, relpath)),
+    }
+}
+
+// A snapshot of the aliases, for resolving many paths at once during a scan.
+#[derive(Default)]
+pub struct SongPaths {
+    aliases: HashMap<Key, Key>,
+    held: HashSet<Key>,
+}
+
+impl SongPaths {
+    pub fn load(tree: &impl Store) -> Result<Self> {
+        let mut paths = SongPaths::default();
+        for entry in tree.scan_prefix(KeyType::SongPath) {
+            let (alias_key, bytes) = entry?;
+            let alias_key = Key::try_from(&alias_key)?;
+            let song_key = Key::try_from(&bytes)?;
+            paths.held.insert(song_key.clone());
+            paths
+                .aliases
+                .insert(alias_key.with_key_type(KeyType::Song), song_key);
+        }
+        Ok(paths)
+    }
+
+    pub fn song_key(&self, root: &str, relpath: &[u8]) -> Key {
+        let path_key = song_hash_key(root, relpath);
+        if let Some(song_key) = self.aliases.get(&path_key) {
+            return song_key.clone();
+        }
+
+        let mut song_key = path_key;
+        let mut salt = 0;
+        while self.held.contains(&song_key) {
+            salt += 1;
+            song_key = salted_song_hash_key(root, relpath, salt);
+        }
+        song_key
+    }
+}
+
+// The root and relpath an absolute path is stored under, and the key its song would have.
+pub fn locate_song(tree: &impl Store, path: &[u8]) -> Result<(String, Vec<u8>, Key)> {
+    let (root, relpath) = match root_for_path(tree, &path_from_bytes(path))? {
+        Some((root, relpath)) => (root.name, relpath),
+        None => (String::new(), path.to_vec()),
+    };
+    let key = resolve_song_key(tree, &root, &relpath)?;
+    Ok((root, relpath, key))
+}
+
+// A moved song keeps the key its old path hashes to, so that path alone doesn't find it.
+pub fn check_song_location(key: &Key, song: Song, root: &str, relpath: &[u8]) -> Result<Song> {
+    if song.root == root && song.relpath == relpath {
+        Ok(song)
+    } else {
+        Err(Error::NotFound(key.clone()))
+    }
+}
+
+pub fn song_at_path(tree: &impl Store, path: &[u8]) -> Result<(Key, Song)> {
+    let (root, relpath, key) = locate_song(tree, path)?;
+    let song = check_song_location(&key, tree.get_metadata(&key)?, &root, &relpath)?;
+    Ok((key, song))
+}
@@ -0,0 +1,403 @@
+use sled::{
+    transaction::{ConflictableTransactionResult, TransactionError},
+    Transactional,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+
+use crate::*;
+
+pub type Entry = (Vec<u8>, Vec<u8>);
+pub type Entries<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+// An ordered map of bytes to bytes. Every store has a default tree, where everything keyed by Key
+// lives, and can open named trees beside it for data kept apart, like album art and user data.
+pub trait Tree: Send + Sync {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+
+    // These return the value the key had before.
+    fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>>;
+
+    // In key order.
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Entries<'_>;
+
+    // Sets the key to whatever `update` makes of its current value, None removing it, and returns the
+    // value it replaced. `update` may be called again if the value changes underneath it, so it should
+    // only depend on what it's passed.
+    fn fetch_and_update(
+        &self,
+        key: impl AsRef<[u8]>,
+        update: impl FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>>;
+
+    // Writes `new` only if the key still holds `old`, None meaning absent. Returns whether it did.
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool>;
+
+    fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        self.scan_prefix(b"")
+    }
+
+    // Like fetch_and_update, but returns the value it was set to.
+    fn update_and_fetch(
+        &self,
+        key: impl AsRef<[u8]>,
+        mut update: impl FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut updated = None;
+        self.fetch_and_update(key, |value| {
+            updated = update(value);
+            updated.clone()
+        })?;
+        Ok(updated)
+    }
+}
+
+// Where the cache keeps everything. Methods, Helpers and the scanner work over any store, so the same
+// library can live in sled on disk or in a MemoryStore that goes when it's dropped.
+pub trait Store: Send + Sync + 'static {
+    type Tree: Tree;
+
+    fn default_tree(&self) -> &Self::Tree;
+
+    // Created empty the first time it's opened.
+    fn open_tree(&self, name: &str) -> Result<Self::Tree>;
+
+    // Never hands out the same id twice.
+    fn generate_id(&self) -> Result<u64>;
+
+    // Applies every batch or none of them, and readers never see some applied without the rest. None
+    // names the default tree.
+    fn apply_batches(&self, batches: &[(Option<&str>, Batch)]) -> Result<()>;
+}
+
+// A store reads and writes its default tree directly.
+impl<S: Store> Tree for S {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.default_tree().get(key)
+    }
+
+    fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.default_tree().insert(key, value)
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.default_tree().remove(key)
+    }
+
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Entries<'_> {
+        self.default_tree().scan_prefix(prefix)
+    }
+
+    fn fetch_and_update(
+        &self,
+        key: impl AsRef<[u8]>,
+        update: impl FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.default_tree().fetch_and_update(key, update)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        self.default_tree().compare_and_swap(key, old, new)
+    }
+
+    fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool> {
+        self.default_tree().contains_key(key)
+    }
+}
+
+// The scanner and watcher share their store through an Arc, so callers tend to hold one that way too.
+impl<S: Store> Store for Arc<S> {
+    type Tree = S::Tree;
+
+    fn default_tree(&self) -> &S::Tree {
+        (**self).default_tree()
+    }
+
+    fn open_tree(&self, name: &str) -> Result<S::Tree> {
+        (**self).open_tree(name)
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        (**self).generate_id()
+    }
+
+    fn apply_batches(&self, batches: &[(Option<&str>, Batch)]) -> Result<()> {
+        (**self).apply_batches(batches)
+    }
+}
+
+// Writes for Store::apply_batches. A key written more than once ends up with its last write.
+#[derive(Default)]
+pub struct Batch {
+    writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn insert(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.writes
+            .push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) {
+        self.writes.push((key.as_ref().to_vec(), None));
+    }
+
+    fn to_sled(&self) -> sled::Batch {
+        let mut batch = sled::Batch::default();
+        for (key, value) in &self.writes {
+            match value {
+                Some(value) => batch.insert(&key[..], &value[..]),
+                None => batch.remove(&key[..]),
+            }
+        }
+        batch
+    }
+}
+
+impl Tree for sled::Tree {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::get(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let previous = sled::Tree::insert(self, key, value.as_ref())?;
+        Ok(previous.map(|value| value.to_vec()))
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(sled::Tree::remove(self, key)?.map(|value| value.to_vec()))
+    }
+
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Entries<'_> {
+        Box::new(sled::Tree::scan_prefix(self, prefix).map(|entry| {
+            let (key, value) = entry?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn fetch_and_update(
+        &self,
+        key: impl AsRef<[u8]>,
+        update: impl FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let previous = sled::Tree::fetch_and_update(self, key, update)?;
+        Ok(previous.map(|value| value.to_vec()))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        Ok(sled::Tree::compare_and_swap(self, key, old, new)?.is_ok())
+    }
+
+    fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(sled::Tree::contains_key(self, key)?)
+    }
+}
+
+impl Store for sled::Db {
+    type Tree = sled::Tree;
+
+    fn default_tree(&self) -> &sled::Tree {
+        self
+    }
+
+    fn open_tree(&self, name: &str) -> Result<sled::Tree> {
+        Ok(sled::Db::open_tree(self, name)?)
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        Ok(sled::Db::generate_id(self)?)
+    }
+
+    // Trees from one sled db can share a transaction, which is what makes this atomic.
+    fn apply_batches(&self, batches: &[(Option<&str>, Batch)]) -> Result<()> {
+        let trees = batches
+            .iter()
+            .map(|(name, _)| match name {
+                Some(name) => Store::open_tree(self, name),
+                None => Ok(self.default_tree().clone()),
+            })
+            .collect::<Result<Vec<sled::Tree>>>()?;
+        let sled_batches: Vec<sled::Batch> =
+            batches.iter().map(|(_, batch)| batch.to_sled()).collect();
+        trees[..]
+            .transaction(|trees| {
+                for (tree, batch) in trees.iter().zip(&sled_batches) {
+                    tree.apply_batch(batch)?;
+                }
+                ConflictableTransactionResult::<()>::Ok(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => Error::Storage(e),
+                TransactionError::Abort(()) => unreachable!("batches never abort"),
+            })
+    }
+}
+
+// Kept entirely in memory and gone once the last clone is dropped, for tests and for sessions that
+// shouldn't leave anything behind. Clones share their contents.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    tree: MemoryTree,
+    trees: Arc<Mutex<HashMap<String, MemoryTree>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryTree(Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>);
+
+impl MemoryTree {
+    // Nothing is left half written by a panic under the lock, so a poisoned one is still usable.
+    fn read(&self) -> RwLockReadGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>> {
+        self.0.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>> {
+        self.0.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn apply_writes(map: &mut BTreeMap<Vec<u8>, Vec<u8>>, batch: &Batch) {
+    for (key, value) in &batch.writes {
+        match value {
+            Some(value) => map.insert(key.clone(), value.clone()),
+            None => map.remove(key),
+        };
+    }
+}
+
+impl Tree for MemoryTree {
+    fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.read().get(key.as_ref()).cloned())
+    }
+
+    fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .write()
+            .insert(key.as_ref().to_vec(), value.as_ref().to_vec()))
+    }
+
+    fn remove(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        Ok(self.write().remove(key.as_ref()))
+    }
+
+    // A snapshot, so the tree can be written to while its entries are being worked through.
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Entries<'_> {
+        let prefix = prefix.as_ref();
+        let entries: Vec<Result<Entry>> = self
+            .read()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    // Runs `update` once, under the tree's lock, so it mustn't touch the tree itself.
+    fn fetch_and_update(
+        &self,
+        key: impl AsRef<[u8]>,
+        mut update: impl FnMut(Option<&[u8]>) -> Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut map = self.write();
+        let key = key.as_ref();
+        let previous = map.get(key).cloned();
+        match update(previous.as_deref()) {
+            Some(value) => map.insert(key.to_vec(), value),
+            None => map.remove(key),
+        };
+        Ok(previous)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut map = self.write();
+        let key = key.as_ref();
+        if map.get(key).map(Vec::as_slice) != old {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => map.insert(key.to_vec(), value.to_vec()),
+            None => map.remove(key),
+        };
+        Ok(true)
+    }
+
+    fn contains_key(&self, key: impl AsRef<[u8]>) -> Result<bool> {
+        Ok(self.read().contains_key(key.as_ref()))
+    }
+}
+
+impl Store for MemoryStore {
+    type Tree = MemoryTree;
+
+    fn default_tree(&self) -> &MemoryTree {
+        &self.tree
+    }
+
+    fn open_tree(&self, name: &str) -> Result<MemoryTree> {
+        let mut trees = self.trees.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(trees.entry(name.to_string()).or_default().clone())
+    }
+
+    fn generate_id(&self) -> Result<u64> {
+        Ok(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    // Every tree involved is locked for the whole apply. They're locked in name order, so two applies
+    // at once can't each be holding a tree the other is waiting on.
+    fn apply_batches(&self, batches: &[(Option<&str>, Batch)]) -> Result<()> {
+        let mut names: Vec<Option<&str>> = batches.iter().map(|(name, _)| *name).collect();
+        names.sort();
+        names.dedup();
+        let trees = names
+            .iter()
+            .map(|name| match name {
+                Some(name) => self.open_tree(name),
+                None => Ok(self.tree.clone()),
+            })
+            .collect::<Result<Vec<MemoryTree>>>()?;
+        let mut maps: Vec<_> = trees.iter().map(MemoryTree::write).collect();
+        for (name, batch) in batches {
+            let index = names
+                .binary_search(name)
+                .expect("every batch's tree is locked");
+            apply_writes(&mut maps[index], batch);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use music_cache_derive::derive_data_model;
+use std::time::{Duration, SystemTime};
+
+use crate::*;
+
+// User data lives in its own tree, keyed by song or album key, so scans never touch it. It also
+// outlives remove_song, so a song whose file comes back keeps its history.
+pub(crate) const USER_DATA_TREE: &str = "user_data";
+
+// Ratings are stars, from 1 to MAX_RATING.
+pub const MAX_RATING: u8 = 5;
+
+#[derive_data_model]
+#[derive(Clone, Default)]
+pub struct UserData {
+    pub rating: Option<u8>,
+    pub play_count: u32,
+    pub skip_count: u32,
+    // Milliseconds since the Unix epoch.
+    pub last_played_ms: Option<u64>,
+    pub favourite: bool,
+}
+
+impl UserData {
+    fn serialize(&self) -> Vec<u8> {
+        bitcode::encode(self)
+    }
+
+    fn deserialize(bytes: &[u8]) -> Result<UserData> {
+        Ok(bitcode::decode(bytes)?)
+    }
+
+    pub fn last_played(&self) -> Option<SystemTime> {
+        self.last_played_ms
+            .map(|ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms))
+    }
+}
+
+pub(crate) fn user_data_tree<S: Store>(tree: &S) -> Result<S::Tree> {
+    tree.open_tree(USER_DATA_TREE)
+}
+
+// Songs and albums that were never played or rated have the default.
+pub(crate) fn read_user_data(tree: &impl Store, key: &Key) -> Result<UserData> {
+    match user_data_tree(tree)?.get(key)? {
+        Some(bytes) => UserData::deserialize(&bytes),
+        None => Ok(UserData::default()),
+    }
+}
+
+// Moves user data to another key. Where both have some, counts are added together and the survivor's
+// rating wins.
+pub(crate) fn merge_user_data(tree: &impl Store, from: &Key, into: &Key) -> Result<()> {
+    let user_data = user_data_tree(tree)?;
+    let Some(from_bytes) = user_data.remove(from)? else {
+        return Ok(());
+    };
+    let from = UserData::deserialize(&from_bytes)?;
+    let merged = match user_data.get(into)? {
+        Some(bytes) => {
+            let into = UserData::deserialize(&bytes)?;
+            UserData {
+                rating: into.rating.or(from.rating),
+                play_count: into.play_count.saturating_add(from.play_count),
+                skip_count: into.skip_count.saturating_add(from.skip_count),
+                last_played_ms: into.last_played_ms.max(from.last_played_ms),
+                favourite: into.favourite || from.favourite,
+            }
+        }
+        None => from,
+    };
+    user_data.insert(into, merged.serialize())?;
+    Ok(())
+}
+
+// Only keys in the library can be given user data, though it stays once they're gone.
+pub(crate) fn update_user_data(
+    tree: &impl Store,
+    key: &Key,
+    update: impl Fn(&mut UserData),
+) -> Result<UserData> {
+    if !tree.contains_key(key)? {
+        return Err(Error::NotFound(key.clone()));
+    }
+    let mut error = None;
+    let updated = user_data_tree(tree)?.update_and_fetch(key, |maybe_bytes| {
+        let mut data = match maybe_bytes.map(UserData::deserialize).transpose() {
+            Ok(data) => data.unwrap_or_default(),
+            Err(e) => {
+                error = Some(e);
+                return maybe_bytes.map(<[u8]>::to_vec);
+            }
+        };
+        update(&mut data);
+        Some(data.serialize())
+    })?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+    match updated {
+        Some(bytes) => UserData::deserialize(&bytes),
+        None => Ok(UserData::default()),
+    }
+}
+
+pub(crate) fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Entries for songs and albums no longer in the library are left out, as are ones sort_key skips.
+// Highest first.
+pub(crate) fn user_data_sorted<T: Ord>(
+    tree: &impl Store,
+    limit: usize,
+    sort_key: impl Fn(&UserData) -> Option<T>,
+) -> Result<Vec<(Key, UserData)>> {
+    let mut entries = Vec::new();
+    for entry in user_data_tree(tree)?.iter() {
+        let (key, bytes) = entry?;
+        let data = UserData::deserialize(&bytes)?;
+        if sort_key(&data).is_some() && tree.contains_key(&key)? {
+            entries.push((Key::try_from(&key)?, data));
+        }
+    }
+    entries.sort_by_key(|(_, data)| std::cmp::Reverse(sort_key(data)));
+    entries.truncate(limit);
+    Ok(entries)
+}
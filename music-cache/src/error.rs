@@ -0,0 +1,146 @@
+use std::{fmt, io, path::PathBuf};
+
+use crate::{Key, MAX_RATING};
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound(Key),
+    Decode(bitcode::Error),
+    Storage(sled::Error),
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    TagRead {
+        path: PathBuf,
+        source: audiotags::Error,
+    },
+    // A stored key, or an index entry built from one, doesn't have the expected layout.
+    InvalidKey,
+    Cancelled,
+    Watch(notify::Error),
+    // Outside 1 to MAX_RATING.
+    InvalidRating(u8),
+    // Past the end of a playlist.
+    InvalidIndex(usize),
+    // Playlists are read and written as M3U8 or PLS, going by their extension.
+    UnknownPlaylistFormat(PathBuf),
+    // Position counts chars into the query.
+    InvalidQuery {
+        position: usize,
+        message: String,
+    },
+    // The db's schema version, or a record's format, is newer than this build knows.
+    DatabaseTooNew {
+        found: u32,
+        supported: u32,
+    },
+    // Roots can't nest, since songs under both would be stored twice.
+    OverlappingRoot {
+        path: PathBuf,
+        root: String,
+    },
+}
+
+impl Error {
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    // audiotags wraps plain I/O failures too, and those are more useful reported as such.
+    pub fn tag_read(path: impl Into<PathBuf>, source: audiotags::Error) -> Self {
+        match source {
+            audiotags::Error::IOError(source) | audiotags::Error::ReadError { source } => {
+                Error::io(path, source)
+            }
+            source => Error::TagRead {
+                path: path.into(),
+                source,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(key) => write!(f, "Could not find {key:?} in db"),
+            Error::Decode(e) => write!(f, "Could not decode stored value: {e}"),
+            Error::Storage(e) => write!(f, "Storage error: {e}"),
+            Error::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            Error::TagRead { path, source } => {
+                write!(f, "Could not read tags from {}: {source}", path.display())
+            }
+            Error::InvalidKey => write!(f, "Malformed key in db"),
+            Error::Cancelled => write!(f, "Library scan cancelled"),
+            Error::Watch(e) => write!(f, "Could not watch library: {e}"),
+            Error::InvalidRating(rating) => {
+                write!(f, "Rating {rating} is not between 1 and {MAX_RATING}")
+            }
+            Error::InvalidIndex(index) => {
+                write!(f, "Index {index} is past the end of the playlist")
+            }
+            Error::UnknownPlaylistFormat(path) => {
+                write!(f, "{} is not an M3U8 or PLS playlist", path.display())
+            }
+            Error::InvalidQuery { position, message } => {
+                write!(f, "Invalid query at {position}: {message}")
+            }
+            Error::DatabaseTooNew { found, supported } => write!(
+                f,
+                "Database is at version {found}, newer than the {supported} this build supports"
+            ),
+            Error::OverlappingRoot { path, root } => {
+                write!(f, "{} overlaps the library root {root}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(e) => Some(e),
+            Error::Storage(e) => Some(e),
+            Error::Io { source, .. } => Some(source),
+            Error::TagRead { source, .. } => Some(source),
+            Error::Watch(e) => Some(e),
+            Error::NotFound(_)
+            | Error::InvalidKey
+            | Error::Cancelled
+            | Error::InvalidRating(_)
+            | Error::InvalidIndex(_)
+            | Error::UnknownPlaylistFormat(_)
+            | Error::InvalidQuery { .. }
+            | Error::DatabaseTooNew { .. }
+            | Error::OverlappingRoot { .. } => None,
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Storage(e)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(e: notify::Error) -> Self {
+        Error::Watch(e)
+    }
+}
+
+impl From<bitcode::Error> for Error {
+    fn from(e: bitcode::Error) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<std::array::TryFromSliceError> for Error {
+    fn from(_: std::array::TryFromSliceError) -> Self {
+        Error::InvalidKey
+    }
+}
@@ -0,0 +1,194 @@
+use mp4ameta::DataIdent;
+use music_cache_derive::derive_data_model;
+
+// As Picard writes them. Recording IDs identify a song across every release it appears on, which is
+// what scrobblers want.
+#[derive_data_model]
+#[derive(Clone, Default, Hash)]
+pub struct MusicBrainzIds {
+    pub recording_id: Option<String>,
+    pub release_id: Option<String>,
+    pub artist_id: Option<String>,
+}
+
+// Gains are in hundredths of a dB and peaks in millionths of full scale, so songs can still be hashed
+// and compared. Use the accessors for the values players expect.
+#[derive_data_model]
+#[derive(Clone, Copy, Default, Hash)]
+pub struct ReplayGain {
+    pub track_gain: Option<i32>,
+    pub track_peak: Option<u32>,
+    pub album_gain: Option<i32>,
+    pub album_peak: Option<u32>,
+}
+
+impl ReplayGain {
+    pub fn track_gain_db(&self) -> Option<f32> {
+        self.track_gain.map(|gain| gain as f32 / 100.0)
+    }
+
+    pub fn track_peak(&self) -> Option<f32> {
+        self.track_peak.map(|peak| peak as f32 / 1_000_000.0)
+    }
+
+    pub fn album_gain_db(&self) -> Option<f32> {
+        self.album_gain.map(|gain| gain as f32 / 100.0)
+    }
+
+    pub fn album_peak(&self) -> Option<f32> {
+        self.album_peak.map(|peak| peak as f32 / 1_000_000.0)
+    }
+}
+
+// Taggers write gains like "-6.54 dB".
+fn parse_gain(value: &str) -> Option<i32> {
+    let value = value.trim().to_ascii_lowercase();
+    let gain: f64 = value
+        .strip_suffix("db")
+        .unwrap_or(&value)
+        .trim()
+        .parse()
+        .ok()?;
+    gain.is_finite().then(|| (gain * 100.0).round() as i32)
+}
+
+fn parse_peak(value: &str) -> Option<u32> {
+    let peak: f64 = value.trim().parse().ok()?;
+    (peak.is_finite() && peak >= 0.0).then(|| (peak * 1_000_000.0).round() as u32)
+}
+
+// Fields audiotags doesn't read, which taggers keep in ID3 TXXX frames, Vorbis comments and MP4
+// freeform atoms.
+#[derive(Default, Clone)]
+pub struct ExtendedTags {
+    pub musicbrainz: MusicBrainzIds,
+    pub replay_gain: ReplayGain,
+}
+
+// The name a field goes by in each format. Names are matched case insensitively, since taggers
+// disagree on the case of ReplayGain fields in particular.
+struct FieldNames {
+    id3: &'static str,
+    vorbis: &'static str,
+    mp4: &'static str,
+}
+
+// Picard puts the recording ID in a UFID frame rather than TXXX. See musicbrainz_ufid.
+const MUSICBRAINZ_RECORDING_ID: FieldNames = FieldNames {
+    id3: "MusicBrainz Track Id",
+    vorbis: "MUSICBRAINZ_TRACKID",
+    mp4: "MusicBrainz Track Id",
+};
+
+const MUSICBRAINZ_RELEASE_ID: FieldNames = FieldNames {
+    id3: "MusicBrainz Album Id",
+    vorbis: "MUSICBRAINZ_ALBUMID",
+    mp4: "MusicBrainz Album Id",
+};
+
+const MUSICBRAINZ_ARTIST_ID: FieldNames = FieldNames {
+    id3: "MusicBrainz Artist Id",
+    vorbis: "MUSICBRAINZ_ARTISTID",
+    mp4: "MusicBrainz Artist Id",
+};
+
+const REPLAYGAIN_TRACK_GAIN: FieldNames = FieldNames {
+    id3: "REPLAYGAIN_TRACK_GAIN",
+    vorbis: "REPLAYGAIN_TRACK_GAIN",
+    mp4: "replaygain_track_gain",
+};
+
+const REPLAYGAIN_TRACK_PEAK: FieldNames = FieldNames {
+    id3: "REPLAYGAIN_TRACK_PEAK",
+    vorbis: "REPLAYGAIN_TRACK_PEAK",
+    mp4: "replaygain_track_peak",
+};
+
+const REPLAYGAIN_ALBUM_GAIN: FieldNames = FieldNames {
+    id3: "REPLAYGAIN_ALBUM_GAIN",
+    vorbis: "REPLAYGAIN_ALBUM_GAIN",
+    mp4: "replaygain_album_gain",
+};
+
+const REPLAYGAIN_ALBUM_PEAK: FieldNames = FieldNames {
+    id3: "REPLAYGAIN_ALBUM_PEAK",
+    vorbis: "REPLAYGAIN_ALBUM_PEAK",
+    mp4: "replaygain_album_peak",
+};
+
+const MP4_FREEFORM_MEAN: &str = "com.apple.iTunes";
+
+const MUSICBRAINZ_UFID_OWNER: &[u8] = b"http://musicbrainz.org";
+
+impl ExtendedTags {
+    fn read(get: impl Fn(&FieldNames) -> Option<String>) -> ExtendedTags {
+        let get = |field: &FieldNames| get(field).filter(|value| !value.trim().is_empty());
+        ExtendedTags {
+            musicbrainz: MusicBrainzIds {
+                recording_id: get(&MUSICBRAINZ_RECORDING_ID),
+                release_id: get(&MUSICBRAINZ_RELEASE_ID),
+                artist_id: get(&MUSICBRAINZ_ARTIST_ID),
+            },
+            replay_gain: ReplayGain {
+                track_gain: get(&REPLAYGAIN_TRACK_GAIN).as_deref().and_then(parse_gain),
+                track_peak: get(&REPLAYGAIN_TRACK_PEAK).as_deref().and_then(parse_peak),
+                album_gain: get(&REPLAYGAIN_ALBUM_GAIN).as_deref().and_then(parse_gain),
+                album_peak: get(&REPLAYGAIN_ALBUM_PEAK).as_deref().and_then(parse_peak),
+            },
+        }
+    }
+
+    pub(crate) fn from_id3(tag: &id3::Tag) -> ExtendedTags {
+        let mut tags = ExtendedTags::read(|field| {
+            tag.extended_texts()
+                .find(|text| text.description.eq_ignore_ascii_case(field.id3))
+                .map(|text| text.value.clone())
+        });
+        if let Some(recording_id) = musicbrainz_ufid(tag) {
+            tags.musicbrainz.recording_id = Some(recording_id);
+        }
+        tags
+    }
+
+    // NAME=value pairs, whether from a FLAC metadata block or read out of Ogg and ASF tags.
+    pub(crate) fn from_vorbis<'a>(
+        comments: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> ExtendedTags {
+        let comments: Vec<_> = comments.into_iter().collect();
+        ExtendedTags::read(|field| {
+            comments
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(field.vorbis))
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    pub(crate) fn from_mp4(tag: &mp4ameta::Tag) -> ExtendedTags {
+        ExtendedTags::read(|field| {
+            tag.strings()
+                .find(|(ident, _)| match ident {
+                    DataIdent::Freeform { mean, name } => {
+                        mean == MP4_FREEFORM_MEAN && name.eq_ignore_ascii_case(field.mp4)
+                    }
+                    DataIdent::Fourcc(_) => false,
+                })
+                .map(|(_, value)| value.to_string())
+        })
+    }
+}
+
+// id3 doesn't parse UFID frames, which hold an owner string, a null and the identifier.
+fn musicbrainz_ufid(tag: &id3::Tag) -> Option<String> {
+    tag.frames()
+        .filter(|frame| frame.id() == "UFID")
+        .filter_map(|frame| frame.content().to_unknown().ok())
+        .find_map(|unknown| {
+            let data = &unknown.data;
+            let separator = data.iter().position(|byte| *byte == 0)?;
+            let (owner, identifier) = (&data[..separator], &data[separator + 1..]);
+            (owner == MUSICBRAINZ_UFID_OWNER)
+                .then(|| String::from_utf8(identifier.to_vec()).ok())
+                .flatten()
+                .filter(|id| !id.trim().is_empty())
+        })
+}
@@ -1,10 +1,21 @@
 use std::{
-    ffi::{CStr, CString},
+    cell::RefCell,
+    ffi::{c_void, CStr, CString},
     os::raw::c_char,
+    path::Path,
     ptr,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use crate::{Album, AlbumTags, Helpers, Key, Methods, Result, Song, SongTags};
+use crate::{
+    millis_since_epoch, playlist_entries, scan_library_root_with_options,
+    scan_library_with_options, upgrade_db, Album, AlbumArt, AlbumGrouping, AlbumTags, Artist,
+    CancellationToken, Error, Helpers, Key, LibraryChange, LibraryObserver, LibraryRoot,
+    LibraryWatcher, Methods, MusicBrainzIds, Playlist, Query, QueryField, ReplayGain, Result,
+    ScanEvent, ScanObserver, ScanOptions, ScanProgress, ScanReport, SearchResults, SmartPlaylist,
+    Song, SongTags, StreamInfo, UserData,
+};
 
 #[repr(C)]
 pub struct CAlbumTags {
@@ -12,19 +23,69 @@ pub struct CAlbumTags {
     pub title: *mut c_char,
     pub has_year: bool,
     pub year: u16,
+    pub genre: *mut c_char,
+    pub has_total_discs: bool,
+    pub total_discs: u16,
 }
 
 #[repr(C)]
 pub struct CSongTags {
     pub title: *mut c_char,
+    pub artist: *mut c_char,
+    pub genre: *mut c_char,
     pub has_track_number: bool,
     pub track_number: u16,
+    pub has_total_tracks: bool,
+    pub total_tracks: u16,
+    pub has_disc_number: bool,
+    pub disc_number: u16,
+    pub has_total_discs: bool,
+    pub total_discs: u16,
+    pub composer: *mut c_char,
+    pub comment: *mut c_char,
+}
+
+#[repr(C)]
+pub struct CStreamInfo {
+    pub has_duration_ms: bool,
+    pub duration_ms: u64,
+    pub has_bitrate: bool,
+    pub bitrate: u32,
+    pub has_sample_rate: bool,
+    pub sample_rate: u32,
+    pub has_bit_depth: bool,
+    pub bit_depth: u8,
+    pub has_channels: bool,
+    pub channels: u8,
+}
+
+#[repr(C)]
+pub struct CMusicBrainzIds {
+    pub recording_id: *mut c_char,
+    pub release_id: *mut c_char,
+    pub artist_id: *mut c_char,
+}
+
+// Gains in dB, peaks as a fraction of full scale.
+#[repr(C)]
+pub struct CReplayGain {
+    pub has_track_gain: bool,
+    pub track_gain: f32,
+    pub has_track_peak: bool,
+    pub track_peak: f32,
+    pub has_album_gain: bool,
+    pub album_gain: f32,
+    pub has_album_peak: bool,
+    pub album_peak: f32,
 }
 
 #[repr(C)]
 pub struct CSong {
     pub tags: CSongTags,
     pub relpath: *mut c_char,
+    pub stream: CStreamInfo,
+    pub musicbrainz: CMusicBrainzIds,
+    pub replay_gain: CReplayGain,
 }
 
 #[repr(C)]
@@ -32,6 +93,14 @@ pub struct CAlbum {
     pub tags: CAlbumTags,
     pub songs: *mut CSong,
     pub song_count: usize,
+    pub duration_ms: u64,
+}
+
+#[repr(C)]
+pub struct CAlbumArt {
+    pub data: *mut u8,
+    pub len: usize,
+    pub mime_type: *mut c_char,
 }
 
 #[repr(C)]
@@ -40,6 +109,182 @@ pub struct CAlbumTagsWithKey {
     pub tags: CAlbumTags,
 }
 
+#[repr(C)]
+pub struct CUserData {
+    pub has_rating: bool,
+    pub rating: u8,
+    pub play_count: u32,
+    pub skip_count: u32,
+    pub has_last_played_ms: bool,
+    pub last_played_ms: u64,
+    pub favourite: bool,
+}
+
+#[repr(C)]
+pub struct CUserDataWithKey {
+    pub key: Key,
+    pub data: CUserData,
+}
+
+// `song` is only filled in when `has_song` is set, which it isn't once the song has left the library.
+#[repr(C)]
+pub struct CPlaylistEntry {
+    pub key: Key,
+    pub has_song: bool,
+    pub song: CSong,
+}
+
+#[repr(C)]
+pub struct CPlaylist {
+    pub name: *mut c_char,
+    pub created_ms: u64,
+    pub modified_ms: u64,
+    pub entries: *mut CPlaylistEntry,
+    pub entry_count: usize,
+}
+
+#[repr(C)]
+pub struct CPlaylistWithKey {
+    pub key: Key,
+    pub name: *mut c_char,
+    pub entry_count: usize,
+    pub created_ms: u64,
+    pub modified_ms: u64,
+}
+
+#[repr(C)]
+pub struct CSongWithKey {
+    pub key: Key,
+    pub song: CSong,
+}
+
+#[repr(C)]
+pub struct CSmartPlaylistWithKey {
+    pub key: Key,
+    pub name: *mut c_char,
+    pub query: *mut c_char,
+    pub created_ms: u64,
+    pub modified_ms: u64,
+}
+
+#[repr(C)]
+pub struct CArtistWithKey {
+    pub key: Key,
+    pub name: *mut c_char,
+    pub album_count: usize,
+}
+
+#[repr(C)]
+pub struct CSearchResults {
+    pub songs: *mut Key,
+    pub song_count: usize,
+    pub albums: *mut Key,
+    pub album_count: usize,
+    pub artists: *mut Key,
+    pub artist_count: usize,
+}
+
+#[repr(C)]
+pub struct CLibraryRoot {
+    pub name: *mut c_char,
+    pub path: *mut c_char,
+}
+
+// What a scan did. Each failure is also passed to the progress callback as it happens.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct CScanSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub moved: usize,
+    pub removed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl From<&ScanReport> for CScanSummary {
+    fn from(report: &ScanReport) -> Self {
+        CScanSummary {
+            added: report.added,
+            updated: report.updated,
+            moved: report.moved,
+            removed: report.removed,
+            skipped: report.skipped,
+            failed: report.failures.len(),
+        }
+    }
+}
+
+// Codes are part of the C API, so existing values must never change.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    None = 0,
+    NotFound = 1,
+    Decode = 2,
+    Storage = 3,
+    Io = 4,
+    TagRead = 5,
+    InvalidKey = 6,
+    Cancelled = 7,
+    InvalidArgument = 8,
+    Watch = 9,
+    DatabaseTooNew = 10,
+}
+
+impl From<&Error> for ErrorCode {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::NotFound(_) => ErrorCode::NotFound,
+            Error::Decode(_) => ErrorCode::Decode,
+            Error::Storage(_) => ErrorCode::Storage,
+            Error::Io { .. } => ErrorCode::Io,
+            Error::TagRead { .. } => ErrorCode::TagRead,
+            Error::InvalidKey => ErrorCode::InvalidKey,
+            Error::Cancelled => ErrorCode::Cancelled,
+            Error::Watch(_) => ErrorCode::Watch,
+            Error::DatabaseTooNew { .. } => ErrorCode::DatabaseTooNew,
+            Error::InvalidRating(_)
+            | Error::InvalidIndex(_)
+            | Error::UnknownPlaylistFormat(_)
+            | Error::InvalidQuery { .. }
+            | Error::OverlappingRoot { .. } => ErrorCode::InvalidArgument,
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<(ErrorCode, CString)> = RefCell::new((ErrorCode::None, CString::default()));
+}
+
+fn set_last_error(code: ErrorCode, message: String) {
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = (code, message));
+}
+
+fn fail(error: Error) -> bool {
+    set_last_error((&error).into(), error.to_string());
+    false
+}
+
+fn invalid_argument(message: &str) -> bool {
+    set_last_error(ErrorCode::InvalidArgument, message.to_string());
+    false
+}
+
+#[no_mangle]
+/// The error behind the last call on this thread that returned false. Successful calls leave it alone.
+pub extern "C" fn last_error_code() -> ErrorCode {
+    LAST_ERROR.with(|last_error| last_error.borrow().0)
+}
+
+#[no_mangle]
+/// # Safety
+/// The message belongs to this thread and is valid until its next failing call. Never null.
+pub unsafe extern "C" fn last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().1.as_ptr())
+}
+
 fn c_string_from_option<T: Into<Vec<u8>>>(value: Option<T>) -> *mut c_char {
     value
         .and_then(|val| CString::new(val).ok())
@@ -47,28 +292,38 @@ fn c_string_from_option<T: Into<Vec<u8>>>(value: Option<T>) -> *mut c_char {
         .unwrap_or(ptr::null_mut())
 }
 
+fn c_number_from_option<T: Default>(value: Option<T>) -> (bool, T) {
+    value.map(|val| (true, val)).unwrap_or_default()
+}
+
 #[no_mangle]
 /// # Safety
 /// `path` is a UTF-8 string. Free with `close_db`.
 pub unsafe extern "C" fn open_db(path: *const c_char, out: *mut *mut sled::Db) -> bool {
     if out.is_null() {
-        return false;
+        return invalid_argument("Null argument");
     }
 
     *out = ptr::null_mut();
 
     if path.is_null() {
-        return false;
+        return invalid_argument("Null argument");
     }
 
     let path = match CStr::from_ptr(path).to_str() {
         Ok(path) => path,
-        Err(_) => return false,
+        Err(_) => return invalid_argument("Path is not valid UTF-8"),
     };
 
-    *out = match sled::open(path) {
+    let db = sled::open(path)
+        .map_err(Error::from)
+        .and_then(|db| upgrade_db(&db).map(|_| db));
+    *out = match db {
         Ok(db) => Box::into_raw(Box::new(db)),
-        Err(_) => ptr::null_mut(),
+        Err(e) => {
+            fail(e);
+            ptr::null_mut()
+        }
     };
 
     !(*out).is_null()
@@ -87,28 +342,112 @@ pub unsafe extern "C" fn close_db(db: *mut sled::Db) {
 
 impl From<AlbumTags> for CAlbumTags {
     fn from(tags: AlbumTags) -> Self {
-        let (has_year, year) = tags.year.map(|year| (true, year)).unwrap_or((false, 0u16));
+        let (has_year, year) = c_number_from_option(tags.year);
+        let (has_total_discs, total_discs) = c_number_from_option(tags.total_discs);
 
         CAlbumTags {
             artist: c_string_from_option(tags.artist),
             title: c_string_from_option(tags.title),
             has_year,
             year,
+            genre: c_string_from_option(tags.genre),
+            has_total_discs,
+            total_discs,
         }
     }
 }
 
 impl From<SongTags> for CSongTags {
     fn from(tags: SongTags) -> Self {
-        let (has_track_number, track_number) = tags
-            .track_number
-            .map(|track| (true, track))
-            .unwrap_or((false, 0u16));
+        let (has_track_number, track_number) = c_number_from_option(tags.track_number);
+        let (has_total_tracks, total_tracks) = c_number_from_option(tags.total_tracks);
+        let (has_disc_number, disc_number) = c_number_from_option(tags.disc_number);
+        let (has_total_discs, total_discs) = c_number_from_option(tags.total_discs);
 
         CSongTags {
             title: c_string_from_option(tags.title),
+            artist: c_string_from_option(tags.artist),
+            genre: c_string_from_option(tags.genre),
             has_track_number,
             track_number,
+            has_total_tracks,
+            total_tracks,
+            has_disc_number,
+            disc_number,
+            has_total_discs,
+            total_discs,
+            composer: c_string_from_option(tags.composer),
+            comment: c_string_from_option(tags.comment),
+        }
+    }
+}
+
+impl From<StreamInfo> for CStreamInfo {
+    fn from(stream: StreamInfo) -> Self {
+        let (has_duration_ms, duration_ms) = c_number_from_option(stream.duration_ms);
+        let (has_bitrate, bitrate) = c_number_from_option(stream.bitrate);
+        let (has_sample_rate, sample_rate) = c_number_from_option(stream.sample_rate);
+        let (has_bit_depth, bit_depth) = c_number_from_option(stream.bit_depth);
+        let (has_channels, channels) = c_number_from_option(stream.channels);
+
+        CStreamInfo {
+            has_duration_ms,
+            duration_ms,
+            has_bitrate,
+            bitrate,
+            has_sample_rate,
+            sample_rate,
+            has_bit_depth,
+            bit_depth,
+            has_channels,
+            channels,
+        }
+    }
+}
+
+impl From<MusicBrainzIds> for CMusicBrainzIds {
+    fn from(ids: MusicBrainzIds) -> Self {
+        CMusicBrainzIds {
+            recording_id: c_string_from_option(ids.recording_id),
+            release_id: c_string_from_option(ids.release_id),
+            artist_id: c_string_from_option(ids.artist_id),
+        }
+    }
+}
+
+impl From<ReplayGain> for CReplayGain {
+    fn from(gain: ReplayGain) -> Self {
+        let (has_track_gain, track_gain) = c_number_from_option(gain.track_gain_db());
+        let (has_track_peak, track_peak) = c_number_from_option(gain.track_peak());
+        let (has_album_gain, album_gain) = c_number_from_option(gain.album_gain_db());
+        let (has_album_peak, album_peak) = c_number_from_option(gain.album_peak());
+
+        CReplayGain {
+            has_track_gain,
+            track_gain,
+            has_track_peak,
+            track_peak,
+            has_album_gain,
+            album_gain,
+            has_album_peak,
+            album_peak,
+        }
+    }
+}
+
+impl From<UserData> for CUserData {
+    fn from(data: UserData) -> Self {
+        let (has_rating, rating) = c_number_from_option(data.rating);
+        let (has_last_played_ms, last_played_ms) = c_number_from_option(data.last_played_ms);
+
+        CUserData {
+            has_rating,
+            rating,
+            play_count: data.play_count,
+            skip_count: data.skip_count,
+            has_last_played_ms,
+            last_played_ms,
+            favourite: data.favourite,
         }
     }
 }
@@ -118,12 +457,16 @@ impl From<Song> for CSong {
         CSong {
             tags: song.tags.into(),
             relpath: c_string_from_option(Some(song.relpath)),
+            stream: song.stream.into(),
+            musicbrainz: song.musicbrainz.into(),
+            replay_gain: song.replay_gain.into(),
         }
     }
 }
 
 impl From<Album> for CAlbum {
     fn from(album: Album) -> Self {
+        let duration_ms = album.duration_ms();
         let mut songs: Box<[CSong]> = album
             .songs
             .into_iter()
@@ -142,6 +485,7 @@ impl From<Album> for CAlbum {
             tags: album.tags.into(),
             songs: songs_ptr,
             song_count,
+            duration_ms,
         }
     }
 }
@@ -155,7 +499,7 @@ pub unsafe extern "C" fn album_tags_for_key(
     out: *mut CAlbumTags,
 ) -> bool {
     if db.is_null() || album_key.is_null() || out.is_null() {
-        return false;
+        return invalid_argument("Null argument");
     }
 
     let album_tags: Result<AlbumTags> = (&*db).get_metadata(&*album_key);
@@ -164,10 +508,18 @@ pub unsafe extern "C" fn album_tags_for_key(
             *out = album_tags.into();
             true
         }
-        Err(_) => false,
+        Err(e) => fail(e),
     }
 }
 
+// C callers open files directly, so they're handed absolute paths rather than ones relative to a root.
+fn resolve_song_paths(db: &sled::Db, album: &mut Album) -> Result<()> {
+    for song in &mut album.songs {
+        song.relpath = db.song_path(song)?.into_os_string().into_encoded_bytes();
+    }
+    Ok(())
+}
+
 #[no_mangle]
 /// # Safety
 /// Free `out` with `free_album`.
@@ -177,7 +529,7 @@ pub unsafe extern "C" fn album_for_key(
     out: *mut CAlbum,
 ) -> bool {
     if db.is_null() || album_key.is_null() || out.is_null() {
-        return false;
+        return invalid_argument("Null argument");
     }
 
     let out_ref = &mut *out;
@@ -185,13 +537,129 @@ pub unsafe extern "C" fn album_for_key(
     let key = &*album_key;
     let db_ref = &*db;
 
-    let album: Result<Album> = db_ref.get_metadata(key);
+    let album: Result<Album> = db_ref.get_metadata(key).and_then(|mut album| {
+        resolve_song_paths(db_ref, &mut album)?;
+        Ok(album)
+    });
     match album {
         Ok(album) => {
             *out_ref = album.into();
             true
         }
-        Err(_) => false,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `recording_id` is a UTF-8 string. Free `out` with `free_song`. Its relpath is the absolute path
+/// of the file.
+pub unsafe extern "C" fn song_by_mbid(
+    db: *mut sled::Db,
+    recording_id: *const c_char,
+    out_key: *mut Key,
+    out: *mut CSong,
+) -> bool {
+    if db.is_null() || recording_id.is_null() || out_key.is_null() || out.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let recording_id = match CStr::from_ptr(recording_id).to_str() {
+        Ok(recording_id) => recording_id,
+        Err(_) => return invalid_argument("Recording ID is not valid UTF-8"),
+    };
+
+    let db_ref = &*db;
+    let song = db_ref
+        .song_by_mbid(recording_id)
+        .and_then(|(key, mut song)| {
+            song.relpath = db_ref
+                .song_path(&song)?
+                .into_os_string()
+                .into_encoded_bytes();
+            Ok((key, song))
+        });
+    match song {
+        Ok((key, song)) => {
+            *out_key = key;
+            *out = song.into();
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free `out` with `free_album_art`. `out->data` is NULL when the album has no art.
+pub unsafe extern "C" fn album_art_for_key(
+    db: *mut sled::Db,
+    album_key: *const Key,
+    out: *mut CAlbumArt,
+) -> bool {
+    if db.is_null() || album_key.is_null() || out.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    write_album_art(&mut *out, (&*db).album_art_for_key(&*album_key))
+}
+
+#[no_mangle]
+/// # Safety
+/// Free `out` with `free_album_art`. `out->data` is NULL when the album has no art.
+pub unsafe extern "C" fn album_thumbnail_for_key(
+    db: *mut sled::Db,
+    album_key: *const Key,
+    size: u32,
+    out: *mut CAlbumArt,
+) -> bool {
+    if db.is_null() || album_key.is_null() || out.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    write_album_art(&mut *out, (&*db).album_thumbnail_for_key(&*album_key, size))
+}
+
+fn write_album_art(out: &mut CAlbumArt, art: Result<Option<AlbumArt>>) -> bool {
+    (out.data, out.len) = (ptr::null_mut(), 0);
+    out.mime_type = ptr::null_mut();
+
+    match art {
+        Ok(Some(art)) => {
+            (out.data, out.len) = into_c_array(art.data);
+            out.mime_type = c_string_from_option(Some(art.mime_type));
+            true
+        }
+        Ok(None) => true,
+        Err(e) => fail(e),
+    }
+}
+
+fn into_c_array<C>(items: Vec<C>) -> (*mut C, usize) {
+    let mut items: Box<[C]> = items.into_boxed_slice();
+    let len = items.len();
+    if len == 0 {
+        return (ptr::null_mut(), 0);
+    }
+
+    let items_ptr = items.as_mut_ptr();
+    std::mem::forget(items);
+    (items_ptr, len)
+}
+
+unsafe fn write_c_array<T, C>(
+    items: Vec<T>,
+    convert: impl FnMut(T) -> C,
+    out: *mut *mut C,
+    out_len: *mut usize,
+) {
+    (*out, *out_len) = into_c_array(items.into_iter().map(convert).collect());
+}
+
+fn album_tags_with_key((key, tags): (Key, AlbumTags)) -> CAlbumTagsWithKey {
+    CAlbumTagsWithKey {
+        key,
+        tags: tags.into(),
     }
 }
 
@@ -204,7 +672,7 @@ pub unsafe extern "C" fn scan_album_tags_sorted(
     out_len: *mut usize,
 ) -> bool {
     if db.is_null() || out.is_null() || out_len.is_null() {
-        return false;
+        return invalid_argument("Null argument");
     }
 
     *out = ptr::null_mut();
@@ -212,104 +680,1459 @@ pub unsafe extern "C" fn scan_album_tags_sorted(
 
     let albums = match (&*db).scan_album_tags_sorted() {
         Ok(albums) => albums,
-        Err(_) => return false,
+        Err(e) => return fail(e),
     };
 
-    let mut albums: Box<[CAlbumTagsWithKey]> = albums
-        .into_iter()
-        .map(|(key, tags)| CAlbumTagsWithKey {
-            key,
-            tags: tags.into(),
-        })
-        .collect::<Vec<_>>()
-        .into_boxed_slice();
+    write_c_array(albums, album_tags_with_key, out, out_len);
+    true
+}
 
-    *out_len = albums.len();
-    if *out_len == 0 {
-        return true;
+#[no_mangle]
+/// # Safety
+/// Free with `free_artists_sorted`.
+pub unsafe extern "C" fn scan_artists_sorted(
+    db: *mut sled::Db,
+    out: *mut *mut CArtistWithKey,
+    out_len: *mut usize,
+) -> bool {
+    if db.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
     }
 
-    *out = albums.as_mut_ptr();
-    std::mem::forget(albums);
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    let artists = match (&*db).scan_artists_sorted() {
+        Ok(artists) => artists,
+        Err(e) => return fail(e),
+    };
 
+    write_c_array(
+        artists,
+        |(key, artist): (Key, Artist)| CArtistWithKey {
+            key,
+            name: c_string_from_option(artist.name),
+            album_count: artist.album_keys.len(),
+        },
+        out,
+        out_len,
+    );
     true
 }
 
-fn free_c_string(ptr: &mut *mut c_char) {
-    if !ptr.is_null() {
-        unsafe {
-            let _ = CString::from_raw(*ptr);
-        }
-        *ptr = ptr::null_mut();
+#[no_mangle]
+/// # Safety
+/// Free with `free_album_tags_sorted`.
+pub unsafe extern "C" fn albums_for_artist(
+    db: *mut sled::Db,
+    artist_key: *const Key,
+    out: *mut *mut CAlbumTagsWithKey,
+    out_len: *mut usize,
+) -> bool {
+    if db.is_null() || artist_key.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
     }
+
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    let albums = match (&*db).albums_for_artist(&*artist_key) {
+        Ok(albums) => albums,
+        Err(e) => return fail(e),
+    };
+
+    write_c_array(albums, album_tags_with_key, out, out_len);
+    true
 }
 
-fn free_album_tags_inner(tags: &mut CAlbumTags) {
-    free_c_string(&mut tags.artist);
-    free_c_string(&mut tags.title);
-    tags.has_year = false;
-    tags.year = 0;
+impl From<SearchResults> for CSearchResults {
+    fn from(results: SearchResults) -> Self {
+        let (songs, song_count) = into_c_array(results.songs);
+        let (albums, album_count) = into_c_array(results.albums);
+        let (artists, artist_count) = into_c_array(results.artists);
+
+        CSearchResults {
+            songs,
+            song_count,
+            albums,
+            album_count,
+            artists,
+            artist_count,
+        }
+    }
 }
 
 #[no_mangle]
 /// # Safety
-/// Free tags produced by `album_tags_for_key`.
-pub unsafe extern "C" fn free_album_tags(tags: *mut CAlbumTags) {
-    if tags.is_null() {
-        return;
+/// `query` is a UTF-8 string. Free `out` with `free_search_results`.
+pub unsafe extern "C" fn search(
+    db: *mut sled::Db,
+    query: *const c_char,
+    limit: usize,
+    out: *mut CSearchResults,
+) -> bool {
+    if db.is_null() || query.is_null() || out.is_null() {
+        return invalid_argument("Null argument");
     }
 
-    free_album_tags_inner(&mut *tags);
+    let query = match CStr::from_ptr(query).to_str() {
+        Ok(query) => query,
+        Err(_) => return invalid_argument("Query is not valid UTF-8"),
+    };
+
+    match (&*db).search(query, limit) {
+        Ok(results) => {
+            *out = results.into();
+            true
+        }
+        Err(e) => fail(e),
+    }
 }
 
-fn free_song_tags(tags: &mut CSongTags) {
-    free_c_string(&mut tags.title);
-    tags.has_track_number = false;
-    tags.track_number = 0;
+pub type ScanProgressCallback =
+    Option<unsafe extern "C" fn(progress: *const ScanProgress, user_data: *mut c_void) -> bool>;
+
+struct CallbackObserver {
+    callback: unsafe extern "C" fn(*const ScanProgress, *mut c_void) -> bool,
+    user_data: *mut c_void,
+    cancellation: CancellationToken,
+    // C callbacks aren't expected to be thread safe, so calls are made one at a time.
+    lock: Mutex<()>,
 }
 
-fn free_song(song: &mut CSong) {
-    free_song_tags(&mut song.tags);
-    free_c_string(&mut song.relpath);
+// The C caller owns user_data and only ever sees it from inside the serialized callback.
+unsafe impl Send for CallbackObserver {}
+unsafe impl Sync for CallbackObserver {}
+
+impl ScanObserver for CallbackObserver {
+    fn on_event(&self, event: &ScanEvent, progress: &ScanProgress) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        if let ScanEvent::Error(failure) = event {
+            // Set on the thread making the call, so the callback can read it back.
+            set_last_error(
+                (&failure.error).into(),
+                format!("{}: {}", failure.path.display(), failure.error),
+            );
+        }
+        if !unsafe { (self.callback)(progress, self.user_data) } {
+            self.cancellation.cancel();
+        }
+    }
 }
 
 #[no_mangle]
 /// # Safety
-/// Free albums produced by `album_for_key`.
-pub unsafe extern "C" fn free_album(album: *mut CAlbum) {
-    if album.is_null() {
-        return;
+/// `path` is a UTF-8 string. `callback` may be null; returning false from it cancels the scan. `out`
+/// may be null, and is only written when the scan succeeds.
+pub unsafe extern "C" fn scan_library_with_callback(
+    db: *mut sled::Db,
+    path: *const c_char,
+    callback: ScanProgressCallback,
+    user_data: *mut c_void,
+    out: *mut CScanSummary,
+) -> bool {
+    if db.is_null() || path.is_null() {
+        return invalid_argument("Null argument");
     }
 
-    let album = &mut *album;
-    free_album_tags_inner(&mut album.tags);
-
-    if album.songs.is_null() || album.song_count == 0 {
-        album.songs = ptr::null_mut();
-        album.song_count = 0;
-        return;
-    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return invalid_argument("Path is not valid UTF-8"),
+    };
 
-    let songs_ptr = std::ptr::slice_from_raw_parts_mut(album.songs, album.song_count);
-    let mut songs_box = Box::from_raw(songs_ptr);
-    for song in songs_box.iter_mut() {
-        free_song(song);
+    let options = callback_scan_options(callback, user_data);
+    match scan_library_with_options(Arc::new((*db).clone()), path, &options) {
+        Ok(report) => {
+            if !out.is_null() {
+                *out = (&report).into();
+            }
+            true
+        }
+        Err(e) => fail(e),
     }
-    album.songs = ptr::null_mut();
-    album.song_count = 0;
 }
 
 #[no_mangle]
 /// # Safety
-/// Free arrays produced by `scan_album_tags_sorted`.
-pub unsafe extern "C" fn free_album_tags_sorted(albums: *mut CAlbumTagsWithKey, len: usize) {
-    if albums.is_null() || len == 0 {
-        return;
+/// `name` is a UTF-8 string naming a root added with `set_library_root`. `callback` may be null;
+/// returning false from it cancels the scan. `out` may be null, and is only written when the scan
+/// succeeds.
+pub unsafe extern "C" fn scan_library_root_with_callback(
+    db: *mut sled::Db,
+    name: *const c_char,
+    callback: ScanProgressCallback,
+    user_data: *mut c_void,
+    out: *mut CScanSummary,
+) -> bool {
+    if db.is_null() || name.is_null() {
+        return invalid_argument("Null argument");
     }
 
-    let albums_ptr = std::ptr::slice_from_raw_parts_mut(albums, len);
-    let mut albums_box = Box::from_raw(albums_ptr);
-    for album in albums_box.iter_mut() {
-        free_album_tags_inner(&mut album.tags);
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return invalid_argument("Name is not valid UTF-8"),
+    };
+
+    let options = callback_scan_options(callback, user_data);
+    match scan_library_root_with_options(Arc::new((*db).clone()), name, &options) {
+        Ok(report) => {
+            if !out.is_null() {
+                *out = (&report).into();
+            }
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+fn callback_scan_options(callback: ScanProgressCallback, user_data: *mut c_void) -> ScanOptions {
+    let cancellation = CancellationToken::new();
+    let options = ScanOptions::new().with_cancellation(cancellation.clone());
+    match callback {
+        Some(callback) => options.with_observer(Arc::new(CallbackObserver {
+            callback,
+            user_data,
+            cancellation,
+            lock: Mutex::new(()),
+        })),
+        None => options,
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `name` and `path` are UTF-8 strings. Setting an existing root's path moves it without changing any
+/// song keys.
+pub unsafe extern "C" fn set_library_root(
+    db: *mut sled::Db,
+    name: *const c_char,
+    path: *const c_char,
+) -> bool {
+    if db.is_null() || name.is_null() || path.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return invalid_argument("Name is not valid UTF-8"),
+    };
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return invalid_argument("Path is not valid UTF-8"),
+    };
+
+    match (&*db).set_library_root(name, path) {
+        Ok(_) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `name` is a UTF-8 string. Songs in the root are removed from the db, but their files are left alone.
+pub unsafe extern "C" fn remove_library_root(db: *mut sled::Db, name: *const c_char) -> bool {
+    if db.is_null() || name.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return invalid_argument("Name is not valid UTF-8"),
+    };
+
+    match (&*db).remove_library_root(name) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free with `free_library_roots`.
+pub unsafe extern "C" fn library_roots(
+    db: *mut sled::Db,
+    out: *mut *mut CLibraryRoot,
+    out_len: *mut usize,
+) -> bool {
+    if db.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    let roots = match (&*db).library_roots() {
+        Ok(roots) => roots,
+        Err(e) => return fail(e),
+    };
+
+    write_c_array(
+        roots,
+        |root: LibraryRoot| CLibraryRoot {
+            name: c_string_from_option(Some(root.name)),
+            path: c_string_from_option(Some(root.path)),
+        },
+        out,
+        out_len,
+    );
+    true
+}
+
+#[no_mangle]
+/// # Safety
+/// `grouping` is one of the values declared in the header. Songs move to their new albums on the
+/// next scan of each root.
+pub unsafe extern "C" fn set_album_grouping(db: *mut sled::Db, grouping: AlbumGrouping) -> bool {
+    if db.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    match (&*db).set_album_grouping(grouping) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+pub unsafe extern "C" fn album_grouping(db: *mut sled::Db, out: *mut AlbumGrouping) -> bool {
+    if db.is_null() || out.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    match (&*db).album_grouping() {
+        Ok(grouping) => {
+            *out = grouping;
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `merged` is set to how many albums were folded into others.
+pub unsafe extern "C" fn reconcile_albums(db: *mut sled::Db, merged: *mut usize) -> bool {
+    if db.is_null() || merged.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *merged = 0;
+    match (&*db).reconcile_albums() {
+        Ok(count) => {
+            *merged = count;
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+// Writes the updated user data to `out` when it isn't null.
+unsafe fn write_user_data(
+    db: *mut sled::Db,
+    key: *const Key,
+    out: *mut CUserData,
+    update: impl FnOnce(&sled::Db, &Key) -> Result<UserData>,
+) -> bool {
+    if db.is_null() || key.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    match update(&*db, &*key) {
+        Ok(data) => {
+            if !out.is_null() {
+                *out = data.into();
+            }
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `key` is a song or album key. Songs and albums with no user data get the defaults.
+pub unsafe extern "C" fn user_data_for_key(
+    db: *mut sled::Db,
+    key: *const Key,
+    out: *mut CUserData,
+) -> bool {
+    if out.is_null() {
+        return invalid_argument("Null argument");
+    }
+    write_user_data(db, key, out, |db, key| db.user_data(key))
+}
+
+#[no_mangle]
+/// # Safety
+/// Counts a play now. `out` may be null.
+pub unsafe extern "C" fn record_play(
+    db: *mut sled::Db,
+    key: *const Key,
+    out: *mut CUserData,
+) -> bool {
+    write_user_data(db, key, out, |db, key| db.record_play(key))
+}
+
+#[no_mangle]
+/// # Safety
+/// `out` may be null.
+pub unsafe extern "C" fn record_skip(
+    db: *mut sled::Db,
+    key: *const Key,
+    out: *mut CUserData,
+) -> bool {
+    write_user_data(db, key, out, |db, key| db.record_skip(key))
+}
+
+#[no_mangle]
+/// # Safety
+/// `rating` is 1 to 5 stars, or 0 to clear it. `out` may be null.
+pub unsafe extern "C" fn set_rating(
+    db: *mut sled::Db,
+    key: *const Key,
+    rating: u8,
+    out: *mut CUserData,
+) -> bool {
+    let rating = (rating != 0).then_some(rating);
+    write_user_data(db, key, out, |db, key| db.set_rating(key, rating))
+}
+
+#[no_mangle]
+/// # Safety
+/// `out` may be null.
+pub unsafe extern "C" fn set_favourite(
+    db: *mut sled::Db,
+    key: *const Key,
+    favourite: bool,
+    out: *mut CUserData,
+) -> bool {
+    write_user_data(db, key, out, |db, key| db.set_favourite(key, favourite))
+}
+
+unsafe fn write_user_data_sorted(
+    db: *mut sled::Db,
+    out: *mut *mut CUserDataWithKey,
+    out_len: *mut usize,
+    query: impl FnOnce(&sled::Db) -> Result<Vec<(Key, UserData)>>,
+) -> bool {
+    if db.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    let entries = match query(&*db) {
+        Ok(entries) => entries,
+        Err(e) => return fail(e),
+    };
+
+    write_c_array(
+        entries,
+        |(key, data): (Key, UserData)| CUserDataWithKey {
+            key,
+            data: data.into(),
+        },
+        out,
+        out_len,
+    );
+    true
+}
+
+#[no_mangle]
+/// # Safety
+/// Free with `free_user_data_sorted`.
+pub unsafe extern "C" fn most_played(
+    db: *mut sled::Db,
+    limit: usize,
+    out: *mut *mut CUserDataWithKey,
+    out_len: *mut usize,
+) -> bool {
+    write_user_data_sorted(db, out, out_len, |db| db.most_played(limit))
+}
+
+#[no_mangle]
+/// # Safety
+/// Free with `free_user_data_sorted`.
+pub unsafe extern "C" fn recently_played(
+    db: *mut sled::Db,
+    limit: usize,
+    out: *mut *mut CUserDataWithKey,
+    out_len: *mut usize,
+) -> bool {
+    write_user_data_sorted(db, out, out_len, |db| db.recently_played(limit))
+}
+
+#[no_mangle]
+/// # Safety
+/// `name` is a UTF-8 string. The new playlist is empty.
+pub unsafe extern "C" fn create_playlist(
+    db: *mut sled::Db,
+    name: *const c_char,
+    out_key: *mut Key,
+) -> bool {
+    if db.is_null() || name.is_null() || out_key.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return invalid_argument("Name is not valid UTF-8"),
+    };
+
+    match (&*db).create_playlist(name) {
+        Ok(key) => {
+            *out_key = key;
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+fn missing_c_song() -> CSong {
+    CSong {
+        tags: SongTags::default().into(),
+        relpath: ptr::null_mut(),
+        stream: StreamInfo::default().into(),
+        musicbrainz: MusicBrainzIds::default().into(),
+        replay_gain: ReplayGain::default().into(),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free `out` with `free_playlist`. Song relpaths are absolute paths, as with `album_for_key`.
+pub unsafe extern "C" fn playlist_for_key(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+    out: *mut CPlaylist,
+) -> bool {
+    if db.is_null() || playlist_key.is_null() || out.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let db_ref = &*db;
+    let playlist = db_ref.playlist(&*playlist_key).and_then(|playlist| {
+        let mut entries = playlist_entries(db_ref, &playlist)?;
+        for song in entries.iter_mut().filter_map(|(_, song)| song.as_mut()) {
+            song.relpath = db_ref
+                .song_path(song)?
+                .into_os_string()
+                .into_encoded_bytes();
+        }
+        Ok((playlist, entries))
+    });
+    match playlist {
+        Ok((playlist, entries)) => {
+            let entries = entries
+                .into_iter()
+                .map(|(key, song)| CPlaylistEntry {
+                    key,
+                    has_song: song.is_some(),
+                    song: song.map_or_else(missing_c_song, CSong::from),
+                })
+                .collect();
+            let (entries, entry_count) = into_c_array(entries);
+            *out = CPlaylist {
+                name: c_string_from_option(Some(playlist.name)),
+                created_ms: playlist.created_ms,
+                modified_ms: playlist.modified_ms,
+                entries,
+                entry_count,
+            };
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Sorted by name. Free with `free_playlists`.
+pub unsafe extern "C" fn playlists(
+    db: *mut sled::Db,
+    out: *mut *mut CPlaylistWithKey,
+    out_len: *mut usize,
+) -> bool {
+    if db.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    let playlists = match (&*db).playlists() {
+        Ok(playlists) => playlists,
+        Err(e) => return fail(e),
+    };
+
+    write_c_array(
+        playlists,
+        |(key, playlist): (Key, Playlist)| CPlaylistWithKey {
+            key,
+            entry_count: playlist.song_keys.len(),
+            name: c_string_from_option(Some(playlist.name)),
+            created_ms: playlist.created_ms,
+            modified_ms: playlist.modified_ms,
+        },
+        out,
+        out_len,
+    );
+    true
+}
+
+#[no_mangle]
+/// # Safety
+/// `name` is a UTF-8 string.
+pub unsafe extern "C" fn rename_playlist(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+    name: *const c_char,
+) -> bool {
+    if db.is_null() || playlist_key.is_null() || name.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return invalid_argument("Name is not valid UTF-8"),
+    };
+
+    match (&*db).rename_playlist(&*playlist_key, name) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+pub unsafe extern "C" fn delete_playlist(db: *mut sled::Db, playlist_key: *const Key) -> bool {
+    if db.is_null() || playlist_key.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    match (&*db).delete_playlist(&*playlist_key) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `song_keys` points to `len` song keys, and may be null when `len` is 0. An `index` equal to the
+/// entry count appends.
+pub unsafe extern "C" fn insert_into_playlist(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+    index: usize,
+    song_keys: *const Key,
+    len: usize,
+) -> bool {
+    if db.is_null() || playlist_key.is_null() || (song_keys.is_null() && len != 0) {
+        return invalid_argument("Null argument");
+    }
+
+    let song_keys = if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(song_keys, len)
+    };
+    match (&*db).insert_into_playlist(&*playlist_key, index, song_keys) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `index` counts entries whose songs have left the library.
+pub unsafe extern "C" fn remove_from_playlist(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+    index: usize,
+) -> bool {
+    if db.is_null() || playlist_key.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    match (&*db).remove_from_playlist(&*playlist_key, index) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+pub unsafe extern "C" fn move_in_playlist(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+    from: usize,
+    to: usize,
+) -> bool {
+    if db.is_null() || playlist_key.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    match (&*db).move_in_playlist(&*playlist_key, from, to) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `path` is a UTF-8 string ending in .m3u8, .m3u or .pls. `unresolved` is set to how many entries
+/// weren't songs in the library, and may be null.
+pub unsafe extern "C" fn import_playlist(
+    db: *mut sled::Db,
+    path: *const c_char,
+    out_key: *mut Key,
+    unresolved: *mut usize,
+) -> bool {
+    if db.is_null() || path.is_null() || out_key.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return invalid_argument("Path is not valid UTF-8"),
+    };
+
+    match (&*db).import_playlist(path) {
+        Ok((key, missing)) => {
+            *out_key = key;
+            if !unresolved.is_null() {
+                *unresolved = missing.len();
+            }
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `path` is a UTF-8 string ending in .m3u8, .m3u or .pls. `written` is set to how many songs were
+/// written, and may be null.
+pub unsafe extern "C" fn export_playlist(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+    path: *const c_char,
+    written: *mut usize,
+) -> bool {
+    if db.is_null() || playlist_key.is_null() || path.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return invalid_argument("Path is not valid UTF-8"),
+    };
+
+    match (&*db).export_playlist(&*playlist_key, path) {
+        Ok(count) => {
+            if !written.is_null() {
+                *written = count;
+            }
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+// Songs come with absolute paths, as with `album_for_key`.
+unsafe fn write_songs_with_key(
+    db: *mut sled::Db,
+    out: *mut *mut CSongWithKey,
+    out_len: *mut usize,
+    query: impl FnOnce(&sled::Db) -> Result<Vec<(Key, Song)>>,
+) -> bool {
+    if db.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    let db_ref = &*db;
+    let songs = query(db_ref).and_then(|songs| {
+        songs
+            .into_iter()
+            .map(|(key, mut song)| {
+                song.relpath = db_ref
+                    .song_path(&song)?
+                    .into_os_string()
+                    .into_encoded_bytes();
+                Ok((key, song))
+            })
+            .collect::<Result<Vec<_>>>()
+    });
+    match songs {
+        Ok(songs) => {
+            write_c_array(
+                songs,
+                |(key, song): (Key, Song)| CSongWithKey {
+                    key,
+                    song: song.into(),
+                },
+                out,
+                out_len,
+            );
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+// Parses a query, paging on from `after` unless it's null.
+unsafe fn parse_c_query<F: QueryField>(query: &str, after: *const Key) -> Result<Query<F>> {
+    let mut query = Query::parse(query)?;
+    if !after.is_null() {
+        query.after = Some((*after).clone());
+    }
+    Ok(query)
+}
+
+unsafe fn write_keys(
+    db: *mut sled::Db,
+    out: *mut *mut Key,
+    out_len: *mut usize,
+    query: impl FnOnce(&sled::Db) -> Result<Vec<Key>>,
+) -> bool {
+    if db.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    match query(&*db) {
+        Ok(keys) => {
+            write_c_array(keys, |key| key, out, out_len);
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `query` is a UTF-8 string in the query language described in the header. `after` may be null, or
+/// the key of the last song of the previous page. Free with `free_songs_with_key`.
+pub unsafe extern "C" fn query_songs(
+    db: *mut sled::Db,
+    query: *const c_char,
+    after: *const Key,
+    out: *mut *mut CSongWithKey,
+    out_len: *mut usize,
+) -> bool {
+    if query.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let query = match CStr::from_ptr(query).to_str() {
+        Ok(query) => query,
+        Err(_) => return invalid_argument("Query is not valid UTF-8"),
+    };
+    write_songs_with_key(db, out, out_len, |db| {
+        db.query_songs(&parse_c_query(query, after)?)
+    })
+}
+
+#[no_mangle]
+/// # Safety
+/// As `query_songs`. Free with `free_keys`.
+pub unsafe extern "C" fn query_song_keys(
+    db: *mut sled::Db,
+    query: *const c_char,
+    after: *const Key,
+    out: *mut *mut Key,
+    out_len: *mut usize,
+) -> bool {
+    if query.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let query = match CStr::from_ptr(query).to_str() {
+        Ok(query) => query,
+        Err(_) => return invalid_argument("Query is not valid UTF-8"),
+    };
+    write_keys(db, out, out_len, |db| {
+        db.query_song_keys(&parse_c_query(query, after)?)
+    })
+}
+
+#[no_mangle]
+/// # Safety
+/// `query` is a UTF-8 string using the album fields described in the header. `after` may be null, or
+/// the key of the last album of the previous page. Free with `free_album_tags_sorted`.
+pub unsafe extern "C" fn query_albums(
+    db: *mut sled::Db,
+    query: *const c_char,
+    after: *const Key,
+    out: *mut *mut CAlbumTagsWithKey,
+    out_len: *mut usize,
+) -> bool {
+    if db.is_null() || query.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    let query = match CStr::from_ptr(query).to_str() {
+        Ok(query) => query,
+        Err(_) => return invalid_argument("Query is not valid UTF-8"),
+    };
+    let albums = match parse_c_query(query, after).and_then(|query| (&*db).query_albums(&query)) {
+        Ok(albums) => albums,
+        Err(e) => return fail(e),
+    };
+
+    write_c_array(albums, album_tags_with_key, out, out_len);
+    true
+}
+
+#[no_mangle]
+/// # Safety
+/// As `query_albums`. Free with `free_keys`.
+pub unsafe extern "C" fn query_album_keys(
+    db: *mut sled::Db,
+    query: *const c_char,
+    after: *const Key,
+    out: *mut *mut Key,
+    out_len: *mut usize,
+) -> bool {
+    if query.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let query = match CStr::from_ptr(query).to_str() {
+        Ok(query) => query,
+        Err(_) => return invalid_argument("Query is not valid UTF-8"),
+    };
+    write_keys(db, out, out_len, |db| {
+        db.query_album_keys(&parse_c_query(query, after)?)
+    })
+}
+
+#[no_mangle]
+/// # Safety
+/// `key` is a song or album key. Writes 0 to `out_added_ms` when the date isn't known.
+pub unsafe extern "C" fn date_added(
+    db: *mut sled::Db,
+    key: *const Key,
+    out_added_ms: *mut u64,
+) -> bool {
+    if db.is_null() || key.is_null() || out_added_ms.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    match (&*db).date_added(&*key) {
+        Ok(added) => {
+            *out_added_ms = added.map_or(0, millis_since_epoch);
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `name` and `query` are UTF-8 strings.
+pub unsafe extern "C" fn create_smart_playlist(
+    db: *mut sled::Db,
+    name: *const c_char,
+    query: *const c_char,
+    out_key: *mut Key,
+) -> bool {
+    if db.is_null() || name.is_null() || query.is_null() || out_key.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return invalid_argument("Name is not valid UTF-8"),
+    };
+    let query = match CStr::from_ptr(query).to_str() {
+        Ok(query) => query,
+        Err(_) => return invalid_argument("Query is not valid UTF-8"),
+    };
+
+    match (&*db).create_smart_playlist(name, query) {
+        Ok(key) => {
+            *out_key = key;
+            true
+        }
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Sorted by name. Free with `free_smart_playlists`.
+pub unsafe extern "C" fn smart_playlists(
+    db: *mut sled::Db,
+    out: *mut *mut CSmartPlaylistWithKey,
+    out_len: *mut usize,
+) -> bool {
+    if db.is_null() || out.is_null() || out_len.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *out = ptr::null_mut();
+    *out_len = 0;
+
+    let playlists = match (&*db).smart_playlists() {
+        Ok(playlists) => playlists,
+        Err(e) => return fail(e),
+    };
+
+    write_c_array(
+        playlists,
+        |(key, playlist): (Key, SmartPlaylist)| CSmartPlaylistWithKey {
+            key,
+            name: c_string_from_option(Some(playlist.name)),
+            query: c_string_from_option(Some(playlist.query)),
+            created_ms: playlist.created_ms,
+            modified_ms: playlist.modified_ms,
+        },
+        out,
+        out_len,
+    );
+    true
+}
+
+#[no_mangle]
+/// # Safety
+/// `name` and `query` are UTF-8 strings.
+pub unsafe extern "C" fn update_smart_playlist(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+    name: *const c_char,
+    query: *const c_char,
+) -> bool {
+    if db.is_null() || playlist_key.is_null() || name.is_null() || query.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    let name = match CStr::from_ptr(name).to_str() {
+        Ok(name) => name,
+        Err(_) => return invalid_argument("Name is not valid UTF-8"),
+    };
+    let query = match CStr::from_ptr(query).to_str() {
+        Ok(query) => query,
+        Err(_) => return invalid_argument("Query is not valid UTF-8"),
+    };
+
+    match (&*db).update_smart_playlist(&*playlist_key, name, query) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+pub unsafe extern "C" fn delete_smart_playlist(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+) -> bool {
+    if db.is_null() || playlist_key.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    match (&*db).delete_smart_playlist(&*playlist_key) {
+        Ok(()) => true,
+        Err(e) => fail(e),
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free with `free_songs_with_key`.
+pub unsafe extern "C" fn smart_playlist_songs(
+    db: *mut sled::Db,
+    playlist_key: *const Key,
+    out: *mut *mut CSongWithKey,
+    out_len: *mut usize,
+) -> bool {
+    if playlist_key.is_null() {
+        return invalid_argument("Null argument");
+    }
+    write_songs_with_key(db, out, out_len, |db| {
+        db.smart_playlist_songs(&*playlist_key)
+    })
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LibraryChangeKind {
+    SongAdded = 0,
+    SongUpdated = 1,
+    SongRemoved = 2,
+    Error = 3,
+    SongMoved = 4,
+}
+
+pub type LibraryChangeCallback = Option<
+    unsafe extern "C" fn(kind: LibraryChangeKind, song_key: *const Key, user_data: *mut c_void),
+>;
+
+struct CallbackLibraryObserver {
+    callback: unsafe extern "C" fn(LibraryChangeKind, *const Key, *mut c_void),
+    user_data: *mut c_void,
+}
+
+// Only the watcher's worker thread ever calls the callback.
+unsafe impl Send for CallbackLibraryObserver {}
+unsafe impl Sync for CallbackLibraryObserver {}
+
+impl LibraryObserver for CallbackLibraryObserver {
+    fn on_change(&self, change: &LibraryChange) {
+        let (kind, song_key): (_, *const Key) = match change {
+            LibraryChange::SongAdded(key) => (LibraryChangeKind::SongAdded, *key),
+            LibraryChange::SongUpdated(key) => (LibraryChangeKind::SongUpdated, *key),
+            LibraryChange::SongRemoved(key) => (LibraryChangeKind::SongRemoved, *key),
+            LibraryChange::SongMoved(key) => (LibraryChangeKind::SongMoved, *key),
+            LibraryChange::Error(failure) => {
+                // The callback runs on the worker thread, so this is what it sees as the last error.
+                set_last_error(
+                    (&failure.error).into(),
+                    format!("{}: {}", failure.path.display(), failure.error),
+                );
+                (LibraryChangeKind::Error, ptr::null())
+            }
+        };
+        unsafe { (self.callback)(kind, song_key, self.user_data) };
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// `path` is a UTF-8 string. `callback` may be null. Stop and free with `stop_watching`.
+pub unsafe extern "C" fn watch_library(
+    db: *mut sled::Db,
+    path: *const c_char,
+    debounce_ms: u64,
+    callback: LibraryChangeCallback,
+    user_data: *mut c_void,
+    out: *mut *mut LibraryWatcher,
+) -> bool {
+    if db.is_null() || path.is_null() || out.is_null() {
+        return invalid_argument("Null argument");
+    }
+
+    *out = ptr::null_mut();
+
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => Path::new(path),
+        Err(_) => return invalid_argument("Path is not valid UTF-8"),
+    };
+
+    let debounce = Duration::from_millis(debounce_ms);
+    let watcher = match LibraryWatcher::start(Arc::new((*db).clone()), path, debounce) {
+        Ok(watcher) => watcher,
+        Err(e) => return fail(e),
+    };
+    if let Some(callback) = callback {
+        watcher.subscribe(Arc::new(CallbackLibraryObserver {
+            callback,
+            user_data,
+        }));
+    }
+
+    *out = Box::into_raw(Box::new(watcher));
+    true
+}
+
+#[no_mangle]
+/// # Safety
+/// Only stop watchers from `watch_library`. No callbacks are made once this returns.
+pub unsafe extern "C" fn stop_watching(watcher: *mut LibraryWatcher) {
+    if watcher.is_null() {
+        return;
+    }
+
+    Box::from_raw(watcher).stop();
+}
+
+fn free_c_string(ptr: &mut *mut c_char) {
+    if !ptr.is_null() {
+        unsafe {
+            let _ = CString::from_raw(*ptr);
+        }
+        *ptr = ptr::null_mut();
+    }
+}
+
+fn free_album_tags_inner(tags: &mut CAlbumTags) {
+    free_c_string(&mut tags.artist);
+    free_c_string(&mut tags.title);
+    tags.has_year = false;
+    tags.year = 0;
+    free_c_string(&mut tags.genre);
+    tags.has_total_discs = false;
+    tags.total_discs = 0;
+}
+
+#[no_mangle]
+/// # Safety
+/// Free tags produced by `album_tags_for_key`.
+pub unsafe extern "C" fn free_album_tags(tags: *mut CAlbumTags) {
+    if tags.is_null() {
+        return;
+    }
+
+    free_album_tags_inner(&mut *tags);
+}
+
+fn free_song_tags(tags: &mut CSongTags) {
+    free_c_string(&mut tags.title);
+    free_c_string(&mut tags.artist);
+    free_c_string(&mut tags.genre);
+    tags.has_track_number = false;
+    tags.track_number = 0;
+    tags.has_total_tracks = false;
+    tags.total_tracks = 0;
+    tags.has_disc_number = false;
+    tags.disc_number = 0;
+    tags.has_total_discs = false;
+    tags.total_discs = 0;
+    free_c_string(&mut tags.composer);
+    free_c_string(&mut tags.comment);
+}
+
+fn free_song_inner(song: &mut CSong) {
+    free_song_tags(&mut song.tags);
+    free_c_string(&mut song.relpath);
+    song.stream = StreamInfo::default().into();
+    free_c_string(&mut song.musicbrainz.recording_id);
+    free_c_string(&mut song.musicbrainz.release_id);
+    free_c_string(&mut song.musicbrainz.artist_id);
+    song.replay_gain = ReplayGain::default().into();
+}
+
+#[no_mangle]
+/// # Safety
+/// Free songs produced by `song_by_mbid`. Songs in an album are freed with it.
+pub unsafe extern "C" fn free_song(song: *mut CSong) {
+    if song.is_null() {
+        return;
+    }
+
+    free_song_inner(&mut *song);
+}
+
+#[no_mangle]
+/// # Safety
+/// Free albums produced by `album_for_key`.
+pub unsafe extern "C" fn free_album(album: *mut CAlbum) {
+    if album.is_null() {
+        return;
+    }
+
+    let album = &mut *album;
+    free_album_tags_inner(&mut album.tags);
+    album.duration_ms = 0;
+
+    if album.songs.is_null() || album.song_count == 0 {
+        album.songs = ptr::null_mut();
+        album.song_count = 0;
+        return;
+    }
+
+    let songs_ptr = std::ptr::slice_from_raw_parts_mut(album.songs, album.song_count);
+    let mut songs_box = Box::from_raw(songs_ptr);
+    for song in songs_box.iter_mut() {
+        free_song_inner(song);
+    }
+    album.songs = ptr::null_mut();
+    album.song_count = 0;
+}
+
+#[no_mangle]
+/// # Safety
+/// Free arrays produced by `scan_album_tags_sorted`, `albums_for_artist` or `query_albums`.
+pub unsafe extern "C" fn free_album_tags_sorted(albums: *mut CAlbumTagsWithKey, len: usize) {
+    if albums.is_null() || len == 0 {
+        return;
+    }
+
+    let albums_ptr = std::ptr::slice_from_raw_parts_mut(albums, len);
+    let mut albums_box = Box::from_raw(albums_ptr);
+    for album in albums_box.iter_mut() {
+        free_album_tags_inner(&mut album.tags);
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free arrays produced by `query_song_keys` or `query_album_keys`.
+pub unsafe extern "C" fn free_keys(keys: *mut Key, len: usize) {
+    if keys.is_null() || len == 0 {
+        return;
+    }
+
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(keys, len)));
+}
+
+#[no_mangle]
+/// # Safety
+/// Free arrays produced by `scan_artists_sorted`.
+pub unsafe extern "C" fn free_artists_sorted(artists: *mut CArtistWithKey, len: usize) {
+    if artists.is_null() || len == 0 {
+        return;
+    }
+
+    let artists_ptr = std::ptr::slice_from_raw_parts_mut(artists, len);
+    let mut artists_box = Box::from_raw(artists_ptr);
+    for artist in artists_box.iter_mut() {
+        free_c_string(&mut artist.name);
+        artist.album_count = 0;
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free arrays produced by `most_played` or `recently_played`.
+pub unsafe extern "C" fn free_user_data_sorted(entries: *mut CUserDataWithKey, len: usize) {
+    if entries.is_null() || len == 0 {
+        return;
+    }
+
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+        entries, len,
+    )));
+}
+
+#[no_mangle]
+/// # Safety
+/// Free playlists produced by `playlist_for_key`.
+pub unsafe extern "C" fn free_playlist(playlist: *mut CPlaylist) {
+    if playlist.is_null() {
+        return;
+    }
+
+    let playlist = &mut *playlist;
+    free_c_string(&mut playlist.name);
+    playlist.created_ms = 0;
+    playlist.modified_ms = 0;
+
+    if !playlist.entries.is_null() && playlist.entry_count != 0 {
+        let entries_ptr =
+            std::ptr::slice_from_raw_parts_mut(playlist.entries, playlist.entry_count);
+        let mut entries_box = Box::from_raw(entries_ptr);
+        for entry in entries_box.iter_mut() {
+            free_song_inner(&mut entry.song);
+        }
+    }
+    playlist.entries = ptr::null_mut();
+    playlist.entry_count = 0;
+}
+
+#[no_mangle]
+/// # Safety
+/// Free arrays produced by `playlists`.
+pub unsafe extern "C" fn free_playlists(playlists: *mut CPlaylistWithKey, len: usize) {
+    if playlists.is_null() || len == 0 {
+        return;
+    }
+
+    let playlists_ptr = std::ptr::slice_from_raw_parts_mut(playlists, len);
+    let mut playlists_box = Box::from_raw(playlists_ptr);
+    for playlist in playlists_box.iter_mut() {
+        free_c_string(&mut playlist.name);
+        playlist.entry_count = 0;
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free arrays produced by `query_songs` or `smart_playlist_songs`.
+pub unsafe extern "C" fn free_songs_with_key(songs: *mut CSongWithKey, len: usize) {
+    if songs.is_null() || len == 0 {
+        return;
+    }
+
+    let songs_ptr = std::ptr::slice_from_raw_parts_mut(songs, len);
+    let mut songs_box = Box::from_raw(songs_ptr);
+    for entry in songs_box.iter_mut() {
+        free_song_inner(&mut entry.song);
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free arrays produced by `smart_playlists`.
+pub unsafe extern "C" fn free_smart_playlists(playlists: *mut CSmartPlaylistWithKey, len: usize) {
+    if playlists.is_null() || len == 0 {
+        return;
+    }
+
+    let playlists_ptr = std::ptr::slice_from_raw_parts_mut(playlists, len);
+    let mut playlists_box = Box::from_raw(playlists_ptr);
+    for playlist in playlists_box.iter_mut() {
+        free_c_string(&mut playlist.name);
+        free_c_string(&mut playlist.query);
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free arrays produced by `library_roots`.
+pub unsafe extern "C" fn free_library_roots(roots: *mut CLibraryRoot, len: usize) {
+    if roots.is_null() || len == 0 {
+        return;
+    }
+
+    let roots_ptr = std::ptr::slice_from_raw_parts_mut(roots, len);
+    let mut roots_box = Box::from_raw(roots_ptr);
+    for root in roots_box.iter_mut() {
+        free_c_string(&mut root.name);
+        free_c_string(&mut root.path);
+    }
+}
+
+#[no_mangle]
+/// # Safety
+/// Free art produced by `album_art_for_key` or `album_thumbnail_for_key`.
+pub unsafe extern "C" fn free_album_art(art: *mut CAlbumArt) {
+    if art.is_null() {
+        return;
+    }
+
+    let art = &mut *art;
+    if !art.data.is_null() && art.len != 0 {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            art.data, art.len,
+        )));
+    }
+    art.data = ptr::null_mut();
+    art.len = 0;
+    free_c_string(&mut art.mime_type);
+}
+
+unsafe fn free_key_array(keys: &mut *mut Key, len: &mut usize) {
+    if !keys.is_null() && *len != 0 {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            *keys, *len,
+        )));
+    }
+    *keys = ptr::null_mut();
+    *len = 0;
+}
+
+#[no_mangle]
+/// # Safety
+/// Free results produced by `search`.
+pub unsafe extern "C" fn free_search_results(results: *mut CSearchResults) {
+    if results.is_null() {
+        return;
     }
+
+    let results = &mut *results;
+    free_key_array(&mut results.songs, &mut results.song_count);
+    free_key_array(&mut results.albums, &mut results.album_count);
+    free_key_array(&mut results.artists, &mut results.artist_count);
 }
@@ -0,0 +1,94 @@
+use music_cache_derive::derive_data_model;
+use std::{
+    fs::{File, Metadata},
+    io::{self, Read},
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+use crate::KeyHasher;
+
+const CHUNK_LEN: u64 = 1024 * 1024;
+
+// What a song's file looked like when it was last tagged. Scans skip files whose stamp still matches,
+// so a file copied in with an old modification time, or a clock that's drifted, doesn't fool them.
+// Where the file lives on disk isn't part of the match, since remounts and restores move it without
+// changing it.
+#[derive_data_model]
+#[derive(Clone, Copy, Hash)]
+pub struct FileStamp {
+    // Nanoseconds since the epoch. None where the filesystem doesn't keep one.
+    pub modified_ns: Option<u64>,
+    pub size: u64,
+    // None off unix. A file turning up under another id is checked against its content hash, if it
+    // has one, rather than re-read.
+    pub device: Option<u64>,
+    pub inode: Option<u64>,
+    // Of the whole file, so tags rewritten in place without a modification time bump can be caught. Only
+    // taken by scans with ScanOptions::with_content_check, which treat a stamp without one as changed.
+    pub content_hash: Option<u64>,
+}
+
+impl FileStamp {
+    // Hashing the content reads the whole file, so it's left out unless asked for.
+    pub fn read(path: &Path, hash_content: bool) -> io::Result<FileStamp> {
+        let metadata = path.metadata()?;
+        Ok(FileStamp {
+            content_hash: hash_content.then(|| content_hash(path)).transpose()?,
+            ..FileStamp::from_metadata(&metadata)
+        })
+    }
+
+    // Everything but the content hash, which takes reading the file.
+    pub fn from_metadata(metadata: &Metadata) -> FileStamp {
+        let modified_ns = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_nanos() as u64);
+        let (device, inode) = file_id(metadata);
+        FileStamp {
+            modified_ns,
+            size: metadata.len(),
+            device,
+            inode,
+            content_hash: None,
+        }
+    }
+
+    // Whether the file looks untouched, going by its modification time and size alone.
+    pub fn matches(&self, current: &FileStamp) -> bool {
+        (self.modified_ns, self.size) == (current.modified_ns, current.size)
+    }
+
+    // Whether it's still the same file on the same device.
+    pub fn same_file(&self, current: &FileStamp) -> bool {
+        (self.device, self.inode) == (current.device, current.inode)
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> (Option<u64>, Option<u64>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(metadata.dev()), Some(metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_: &Metadata) -> (Option<u64>, Option<u64>) {
+    (None, None)
+}
+
+// Read in fixed chunks so the hash doesn't depend on how the reads happen to come back.
+pub fn content_hash(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = KeyHasher::new();
+    let mut chunk = Vec::new();
+    loop {
+        chunk.clear();
+        (&mut file).take(CHUNK_LEN).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            return Ok(hasher.finish());
+        }
+        hasher.write_bytes(&chunk);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::KeyHasher;
+
+// Only the start of the audio is hashed, along with its length. That's plenty to tell songs apart
+// without reading whole files on every scan.
+const SAMPLE_LEN: u64 = 64 * 1024;
+
+const ID3V1_LEN: u64 = 128;
+
+// Identifies a file by its audio rather than its path or tags, so a song can be recognised after a
+// tagger has both moved and retagged it. None when there's no audio to go on.
+pub fn audio_fingerprint(path: &Path) -> io::Result<Option<u64>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let (start, end) = audio_range(&mut file, len)?;
+    if start >= end {
+        return Ok(None);
+    }
+
+    let ogg = read_at::<4>(&mut file, 0)? == Some(*b"OggS");
+    let mut sample = Vec::new();
+    file.seek(SeekFrom::Start(start))?;
+    file.take(SAMPLE_LEN.min(end - start))
+        .read_to_end(&mut sample)?;
+    if ogg {
+        sample = ogg_packet_data(&sample);
+    }
+
+    let mut hasher = KeyHasher::new();
+    hasher.write_u64(end - start);
+    hasher.write_bytes(&sample);
+    Ok(Some(hasher.finish()))
+}
+
+fn read_at<const N: usize>(file: &mut File, offset: u64) -> io::Result<Option<[u8; N]>> {
+    let mut buf = [0; N];
+    file.seek(SeekFrom::Start(offset))?;
+    match file.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf)),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// The byte range holding audio frames, leaving out any tags that editing would change.
+pub(crate) fn audio_range(file: &mut File, len: u64) -> io::Result<(u64, u64)> {
+    let Some(header) = read_at::<10>(file, 0)? else {
+        return Ok((0, len));
+    };
+
+    if header.starts_with(b"fLaC") {
+        return Ok((flac_audio_start(file, len)?, len));
+    }
+    if &header[4..8] == b"ftyp" {
+        return mp4_media_data(file, len);
+    }
+    if header.starts_with(b"OggS") {
+        return Ok((ogg_audio_start(file, len)?, len));
+    }
+    let form = read_at::<4>(file, 8)?;
+    if header.starts_with(b"RIFF") && form == Some(*b"WAVE") {
+        return chunk_data(file, len, b"data", u32::from_le_bytes);
+    }
+    if header.starts_with(b"FORM") && matches!(form.as_ref(), Some(b"AIFF" | b"AIFC")) {
+        return chunk_data(file, len, b"SSND", u32::from_be_bytes);
+    }
+
+    let start = if header.starts_with(b"ID3") {
+        let size = header[6..10]
+            .iter()
+            .fold(0u64, |size, byte| (size << 7) | u64::from(byte & 0x7f));
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        10 + size + footer
+    } else {
+        0
+    };
+    let end = match len.checked_sub(ID3V1_LEN) {
+        Some(trailer) if read_at::<3>(file, trailer)? == Some(*b"TAG") => trailer,
+        _ => len,
+    };
+    Ok((start, end))
+}
+
+// Metadata blocks follow the marker, each with a one byte type, whose top bit marks the last block,
+// and a 24 bit length.
+fn flac_audio_start(file: &mut File, len: u64) -> io::Result<u64> {
+    let mut offset = 4;
+    while let Some(header) = read_at::<4>(file, offset)? {
+        let block_len = u64::from_be_bytes([0, 0, 0, 0, 0, header[1], header[2], header[3]]);
+        offset += 4 + block_len;
+        if header[0] & 0x80 != 0 {
+            return Ok(offset);
+        }
+    }
+    Ok(len)
+}
+
+// The audio lives in the top level mdat atom, while tags live in moov and can move around it.
+fn mp4_media_data(file: &mut File, len: u64) -> io::Result<(u64, u64)> {
+    let mut offset = 0;
+    while let Some(header) = read_at::<8>(file, offset)? {
+        let (mut size, mut header_len) = (
+            u64::from(u32::from_be_bytes([
+                header[0], header[1], header[2], header[3],
+            ])),
+            8,
+        );
+        if size == 1 {
+            let Some(large_size) = read_at::<8>(file, offset + 8)? else {
+                break;
+            };
+            (size, header_len) = (u64::from_be_bytes(large_size), 16);
+        } else if size == 0 {
+            size = len - offset;
+        }
+        // Sizes come straight from the file, so one can point anywhere.
+        let Some(end) = offset.checked_add(size).filter(|_| size >= header_len) else {
+            break;
+        };
+        if &header[4..8] == b"mdat" {
+            return Ok((offset + header_len, end.min(len)));
+        }
+        if end > len {
+            break;
+        }
+        offset = end;
+    }
+    Ok((0, len))
+}
+
+// Each page has a 27 byte header ending in its segment count, then a table of segment lengths. The
+// codec's header packets, comments included, sit on pages of their own with a granule position of
+// zero, and audio starts on the first page past them.
+const OGG_PAGE_HEADER_LEN: usize = 27;
+
+fn ogg_audio_start(file: &mut File, len: u64) -> io::Result<u64> {
+    let mut offset = 0;
+    while let Some(header) = read_at::<OGG_PAGE_HEADER_LEN>(file, offset)? {
+        if !header.starts_with(b"OggS") {
+            break;
+        }
+        if header[6..14] != [0; 8] {
+            return Ok(offset);
+        }
+        let mut segments = vec![0; usize::from(header[26])];
+        file.read_exact(&mut segments)?;
+        let body_len: u64 = segments.iter().map(|&segment| u64::from(segment)).sum();
+        offset += (OGG_PAGE_HEADER_LEN + segments.len()) as u64 + body_len;
+    }
+    Ok(len)
+}
+
+// Page headers carry a sequence number and checksum, which change on every page once a longer comment
+// spills onto another page. Only what's in the pages goes into the fingerprint.
+fn ogg_packet_data(pages: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(pages.len());
+    let mut rest = pages;
+    while let Some(&segment_count) = rest.get(OGG_PAGE_HEADER_LEN - 1) {
+        let body_start = OGG_PAGE_HEADER_LEN + usize::from(segment_count);
+        let Some(segments) = rest.get(OGG_PAGE_HEADER_LEN..body_start) else {
+            break;
+        };
+        let body_len: usize = segments.iter().map(|&segment| usize::from(segment)).sum();
+        let body_end = (body_start + body_len).min(rest.len());
+        data.extend_from_slice(&rest[body_start..body_end]);
+        rest = &rest[body_end..];
+    }
+    data
+}
+
+// WAV and AIFF files are a list of chunks after a 12 byte header, each an id and a length, padded to
+// an even size. Tags live in id3 and LIST chunks of their own, so only the sample data chunk counts.
+fn chunk_data(
+    file: &mut File,
+    len: u64,
+    id: &[u8; 4],
+    chunk_len: fn([u8; 4]) -> u32,
+) -> io::Result<(u64, u64)> {
+    let mut offset = 12;
+    while let Some(header) = read_at::<8>(file, offset)? {
+        let size = u64::from(chunk_len([header[4], header[5], header[6], header[7]]));
+        if &header[..4] == id {
+            return Ok((offset + 8, (offset + 8 + size).min(len)));
+        }
+        offset += 8 + size + (size & 1);
+    }
+    Ok((0, len))
+}
@@ -1,24 +1,51 @@
 #![allow(clippy::missing_errors_doc)]
 
-use std::error::Error;
-
 #[cfg(any(test, feature = "integration-tests"))]
 pub mod tests {
     pub mod common;
     pub use common::*;
 }
 
+pub mod error;
+pub use error::*;
+
 pub mod db;
 pub use db::*;
 
 pub mod music_metadata;
 pub use music_metadata::*;
 
+pub mod extended_tags;
+pub use extended_tags::*;
+
 pub mod ffi;
 pub use ffi::*;
 
+pub mod audio_format;
+pub use audio_format::*;
+
+mod vorbis_comments;
+
+pub mod fingerprint;
+pub use fingerprint::*;
+
+pub mod file_stamp;
+pub use file_stamp::*;
+
+pub mod stream_info;
+pub use stream_info::*;
+
 pub mod library_scan;
 pub use library_scan::*;
 
-pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+pub mod library_watch;
+pub use library_watch::*;
+
+pub mod scan_options;
+pub use scan_options::*;
+
+pub mod scan_report;
+pub use scan_report::*;
+
+pub type Result<T> = std::result::Result<T, Error>;
 pub type Lazy<'a, T> = Box<dyn FnOnce() -> Result<T> + 'a>;
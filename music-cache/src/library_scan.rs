@@ -1,5 +1,4 @@
 use crate::methods::scan_stored_albums;
-use audiotags::Tag;
 use jwalk::WalkDir;
 use rayon::prelude::*;
 use std::{
@@ -7,65 +6,159 @@ use std::{
     collections::LinkedList,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
-    time::SystemTime,
 };
 
 use crate::{
-    song_hash_key, AlbumTags, ByteKey, HashKeyGen, Helpers, Key, Result, Song, SongTags,
-    StoredAlbum,
+    album_key, audio_fingerprint, content_hash, find_folder_art, link_album_art,
+    link_album_to_artist, mark_added, prune_album_art, read_audio_tag, read_stream_info,
+    resolve_album_key, root_for_dir, store_album_art, unlink_album_from_artist, update_path_alias,
+    update_recording_index, update_search_index, upgrade_db, AlbumArt, AlbumGrouping, AlbumTags,
+    AudioFormats, ByteKey, Error, FileStamp, FolderArt, HashKeyGen, Helpers, Key, LibraryRoot,
+    Methods, Result, ScanEvent, ScanFailure, ScanOptions, ScanPhase, ScanReport, ScanTracker, Song,
+    SongPaths, SongTags, Store, StoredAlbum, Tree,
 };
 
-fn process_tags(path: &Path, relpath: &[u8]) -> Result<Option<(Song, AlbumTags)>> {
-    let tags = Tag::new().read_from_path(path);
-    if tags.is_err() {
-        return Ok(None);
+// Compares the file with the stamp its song was stored with. Checking content as well catches tags
+// rewritten in place by something that put the modification time back. A file that's only moved on
+// disk, as after a remount or a restore, is checked by content where its stamp has a hash, and keeps
+// its song with the stamp brought up to date.
+fn is_unchanged(
+    tree: &impl Store,
+    song_key: &Key,
+    path: &Path,
+    check_content: bool,
+) -> Result<bool> {
+    let mut song: Song = match tree.get_metadata(song_key) {
+        Ok(song) => song,
+        Err(Error::NotFound(_)) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let Some(stored) = song.file else {
+        return Ok(false);
+    };
+    let metadata = path.metadata().map_err(|e| Error::io(path, e))?;
+    let current = FileStamp::from_metadata(&metadata);
+    if !stored.matches(&current) {
+        return Ok(false);
+    }
+    let same_file = stored.same_file(&current);
+    let hash = if check_content || (!same_file && stored.content_hash.is_some()) {
+        let hash = content_hash(path).map_err(|e| Error::io(path, e))?;
+        if stored.content_hash != Some(hash) {
+            return Ok(false);
+        }
+        Some(hash)
+    } else {
+        stored.content_hash
     };
-    let audio_tags = tags?;
+    if !same_file {
+        song.file = Some(FileStamp {
+            content_hash: hash,
+            ..current
+        });
+        tree.insert(song_key, song.serialize())?;
+    }
+    Ok(true)
+}
+
+// Along with any picture embedded in the file.
+fn process_tags(
+    path: &Path,
+    root: &str,
+    relpath: &[u8],
+    formats: &AudioFormats,
+    check_content: bool,
+) -> Result<(Song, AlbumTags, Option<AlbumArt>)> {
+    // Stamped first, so a file that changes while it's being read looks changed to the next scan.
+    let stamp = FileStamp::read(path, check_content).map_err(|e| Error::io(path, e))?;
+    let format = formats.detect(path).map_err(|e| Error::io(path, e))?;
+    let (audio_tags, extended) = read_audio_tag(path, format)?;
     let song_tags = SongTags::read(&audio_tags);
     let album_tags = AlbumTags::read(&audio_tags);
-    let song = Song::new(song_tags, relpath);
-    Ok(Some((song, album_tags)))
+    let art = audio_tags.album_cover().map(AlbumArt::from_picture);
+    let mut song = Song::new(song_tags, relpath);
+    song.root = root.to_string();
+    song.format = format;
+    song.stream = read_stream_info(path, format)?;
+    song.fingerprint = audio_fingerprint(path).map_err(|e| Error::io(path, e))?;
+    song.musicbrainz = extended.musicbrainz;
+    song.replay_gain = extended.replay_gain;
+    song.file = Some(stamp);
+    Ok((song, album_tags, art))
+}
+
+pub(crate) struct FileToLoad {
+    pub(crate) path: PathBuf,
+    // Relative to the root being scanned.
+    pub(crate) relpath: Vec<u8>,
+    pub(crate) song_key: Key,
+    // The album the song was stored under before this load, if it was already known.
+    pub(crate) album_key: Option<Key>,
+    // Set when the song was stored under another path, which this file replaces.
+    pub(crate) moved: bool,
+    // A cover image beside the file, used when the file has no art of its own. Shared by the other
+    // files in its directory.
+    pub(crate) folder_art: Option<Arc<FolderArt>>,
+}
+
+enum FileAction {
+    Load(FileToLoad),
+    Unchanged,
+    Ignored,
 }
 
 // This performs an update_and_fetch inside an update_and_fetch.
-// This is dangerous because sled will call update repeatedly if the underlying value changes whilst running.
+// This is dangerous because the store may call update repeatedly if the underlying value changes whilst running.
 // However, it's theoretically not possible because only one song is ever accessed at a time.
 // It is possible to write to the same album key simultaneously but album update doesn't alter any state.
 // At some point I should benchmark if the decision to do it in this absurd way is worth it over a more sensible transaction.
 // For now I'm assuming it is because it lives in a very hot path of the load logic and it halves the number of lookups.
+#[allow(clippy::too_many_arguments)]
 fn process_file(
+    tree: &impl Store,
     path: &Path,
-    last_scan_time: &SystemTime,
+    root: &LibraryRoot,
+    formats: &AudioFormats,
+    check_content: bool,
     song_keys: &Arc<Mutex<HashMap<Key, Key>>>,
-) -> Result<Option<(PathBuf, Key)>> {
-    let path_bytes = path.as_os_str().as_encoded_bytes();
-    let song_key = song_hash_key(path_bytes);
+    song_paths: &SongPaths,
+    folder_art: Option<&Arc<FolderArt>>,
+) -> Result<FileAction> {
+    // The walk starts inside the root, so everything it finds has a path relative to it.
+    let Some(relpath) = root.relpath(path) else {
+        return Ok(FileAction::Ignored);
+    };
+    let song_key = song_paths.song_key(&root.name, &relpath);
 
-    if song_keys.lock().unwrap().remove(&song_key).is_some()
-        && path.metadata().and_then(|m| m.modified()).unwrap() < *last_scan_time
-    {
-        return Ok(None);
+    let album_key = song_keys.lock().unwrap().remove(&song_key);
+    if album_key.is_some() && is_unchanged(tree, &song_key, path, check_content)? {
+        return Ok(FileAction::Unchanged);
     }
 
-    if let Some(ext) = path.extension() {
-        // TODO Implement resilient check function equivalent
-        if ext == "mp3" || ext == "flac" || ext == "m4a" {
-            return Ok(Some((path.to_path_buf(), song_key)));
-        }
+    if formats.accepts(path) {
+        return Ok(FileAction::Load(FileToLoad {
+            path: path.to_path_buf(),
+            relpath,
+            song_key,
+            album_key,
+            moved: false,
+            folder_art: folder_art.cloned(),
+        }));
     }
 
-    Ok(None)
+    Ok(FileAction::Ignored)
 }
 
 fn add_song_to_album(bytes: &[u8], song: &Song, song_key: ByteKey) -> Result<StoredAlbum> {
     let mut album = StoredAlbum::partial_deserialize_album(bytes)?;
+    // A rescanned song may have moved within the album, so drop its old position first.
+    album.song_keys.retain(|(_, key)| *key != song_key);
+    let order = song.track_order();
     let index = album
         .song_keys
-        .binary_search_by(|probe| probe.0.cmp(&song.tags.track_number))
+        .binary_search_by(|probe| probe.0.cmp(&order))
         .unwrap_or_else(|x| x);
-    album
-        .song_keys
-        .insert(index, (song.tags.track_number, song_key));
+    album.song_keys.insert(index, (order, song_key));
     Ok(album)
 }
 
@@ -74,89 +167,445 @@ fn find_remove_song_from_album(bytes: &[u8], song_key: ByteKey) -> Result<Option
     if album.song_keys.len() == 1 {
         return Ok(None);
     }
-    album.song_keys.retain(|&(_, key)| key != song_key);
+    album.song_keys.retain(|(_, key)| *key != song_key);
     Ok(Some(album))
 }
 
-pub fn remove_song_from_album(tree: &sled::Db, album_key: &Key, song_key: &Key) -> Result<()> {
+pub fn remove_song_from_album(tree: &impl Store, album_key: &Key, song_key: &Key) -> Result<()> {
     let byte_key = *song_key.to_byte_key();
-    tree.update_and_fetch(album_key, |maybe_bytes| {
-        maybe_bytes.and_then(|bytes| find_remove_song_from_album(bytes, byte_key).unwrap())
+    let mut error = None;
+    let previous = tree.fetch_and_update(album_key, |maybe_bytes| {
+        let bytes = maybe_bytes?;
+        match find_remove_song_from_album(bytes, byte_key) {
+            Ok(album) => album.map(|album| album.serialize()),
+            Err(e) => {
+                error = Some(e);
+                Some(bytes.to_vec())
+            }
+        }
     })?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    // The album was deleted along with its last song, so the artist no longer owns it.
+    if let Some(bytes) = previous {
+        if find_remove_song_from_album(&bytes, byte_key)?.is_none() {
+            let album = StoredAlbum::partial_deserialize_album(&bytes)?;
+            update_search_index(tree, album_key, Some(&album.tags), None)?;
+            unlink_album_from_artist(tree, &album.tags, album_key)?;
+        }
+    }
     Ok(())
 }
 
-pub fn remove_song(tree: &sled::Db, album_key: &Key, song_key: &Key) -> Result<()> {
+pub fn remove_song(tree: &impl Store, album_key: &Key, song_key: &Key) -> Result<()> {
     remove_song_from_album(tree, album_key, song_key)?;
-    tree.remove(song_key)?;
+    if let Some(bytes) = tree.remove(song_key)? {
+        let song = Song::deserialize(&bytes)?;
+        update_search_index(tree, song_key, Some(&song), None)?;
+        update_path_alias(tree, song_key, Some(&song), None)?;
+        update_recording_index(tree, song_key, Some(&song), None)?;
+    }
     Ok(())
 }
 
 pub fn album_upsert(
-    tree: &sled::Db,
+    tree: &impl Store,
+    album_tags: &AlbumTags,
+    song: &Song,
+    song_key: &Key,
+) -> Result<()> {
+    album_upsert_with_key(tree, &album_tags.hash_key(), album_tags, song, song_key)
+}
+
+// For album keys from a grouping other than the default. See album_key.
+pub fn album_upsert_with_key(
+    tree: &impl Store,
+    album_key: &Key,
     album_tags: &AlbumTags,
     song: &Song,
     song_key: &Key,
 ) -> Result<()> {
-    let album_key = album_tags.hash_key();
     let byte_key = *song_key.to_byte_key();
-    tree.update_and_fetch(album_key, |maybe_bytes| {
-        let new_album = if let Some(bytes) = maybe_bytes {
-            add_song_to_album(bytes, song, byte_key).unwrap()
-        } else {
-            StoredAlbum::new(album_tags.clone(), (song.tags.track_number, byte_key))
+    let mut error = None;
+    let previous = tree.fetch_and_update(album_key, |maybe_bytes| {
+        let new_album = match maybe_bytes {
+            Some(bytes) => match add_song_to_album(bytes, song, byte_key) {
+                Ok(album) => album,
+                Err(e) => {
+                    error = Some(e);
+                    return Some(bytes.to_vec());
+                }
+            },
+            None => StoredAlbum::new(album_tags.clone(), (song.track_order(), byte_key)),
         };
-        Some(new_album)
+        Some(new_album.serialize())
     })?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    if previous.is_none() {
+        update_search_index(tree, album_key, None, Some(album_tags))?;
+        link_album_to_artist(tree, album_tags, album_key)?;
+        mark_added(tree, album_key)?;
+    }
     Ok(())
 }
 
-fn apply_process_file(tree: &sled::Db, info: &(PathBuf, Key)) -> Result<()> {
-    let (path, song_key) = info;
-    let path_bytes = path.as_os_str().as_encoded_bytes();
+fn store_song(
+    tree: &impl Store,
+    song: &Song,
+    album_key: &Key,
+    album_tags: &AlbumTags,
+    art_key: Option<&Key>,
+    song_key: &Key,
+    previous_album_key: Option<&Key>,
+) -> Result<()> {
+    album_upsert_with_key(tree, album_key, album_tags, song, song_key)?;
+    // Whichever song was tagged last decides the album's art, so a new cover reaches the album as soon
+    // as any of its files is retagged.
+    if let Some(art_key) = art_key {
+        link_album_art(tree, album_key, art_key)?;
+    }
+    // Retagging can move a song to another album, which the old one then shouldn't still list.
+    if let Some(previous_album_key) = previous_album_key {
+        if previous_album_key != album_key {
+            remove_song_from_album(tree, previous_album_key, song_key)?;
+        }
+    }
+    let previous = tree.insert(song_key, song.serialize())?;
+    let previous = previous
+        .map(|bytes| Song::deserialize(&bytes))
+        .transpose()?;
+    if previous.is_none() {
+        mark_added(tree, song_key)?;
+    }
+    update_path_alias(tree, song_key, previous.as_ref(), Some(song))?;
+    update_recording_index(tree, song_key, previous.as_ref(), Some(song))?;
+    update_search_index(tree, song_key, previous.as_ref(), Some(song))
+}
 
-    if let Some((song, album_tags)) = process_tags(path, path_bytes)? {
-        album_upsert(tree, &album_tags, &song, song_key)?;
-        tree.insert(song_key, &song)?;
+// Returns the key of the album the song was stored under.
+pub(crate) fn load_file(
+    tree: &impl Store,
+    root: &LibraryRoot,
+    formats: &AudioFormats,
+    grouping: AlbumGrouping,
+    check_content: bool,
+    file: &FileToLoad,
+) -> std::result::Result<Key, Box<ScanFailure>> {
+    let (song, album_tags, art) = process_tags(
+        &file.path,
+        &root.name,
+        &file.relpath,
+        formats,
+        check_content,
+    )
+    .map_err(|e| Box::new(ScanFailure::new(file.path.clone(), ScanPhase::ReadTags, e)))?;
+    let store_failure = |e| Box::new(ScanFailure::new(file.path.clone(), ScanPhase::Store, e));
+    let art_key = match (art, &file.folder_art) {
+        (Some(art), _) => store_album_art(tree, &art).map(Some),
+        (None, Some(folder_art)) => folder_art.store(tree),
+        (None, None) => Ok(None),
     }
+    .map_err(store_failure)?;
+    let album_key = album_key(grouping, &album_tags, &song);
+    let album_key = resolve_album_key(tree, album_key).map_err(store_failure)?;
 
-    Ok(())
+    store_song(
+        tree,
+        &song,
+        &album_key,
+        &album_tags,
+        art_key.as_ref(),
+        &file.song_key,
+        file.album_key.as_ref(),
+    )
+    .map_err(store_failure)?;
+    Ok(album_key)
+}
+
+fn apply_process_file(
+    tree: &impl Store,
+    root: &LibraryRoot,
+    tracker: &ScanTracker,
+    grouping: AlbumGrouping,
+    file: &FileToLoad,
+) {
+    match load_file(
+        tree,
+        root,
+        tracker.formats(),
+        grouping,
+        tracker.checks_content(),
+        file,
+    ) {
+        Ok(_) if file.moved => {
+            tracker.song_moved();
+            tracker.notify(ScanEvent::FileTagged(&file.path));
+        }
+        Ok(_) => {
+            tracker.song_stored(file.album_key.is_some());
+            tracker.notify(ScanEvent::FileTagged(&file.path));
+        }
+        Err(failure) => tracker.fail(*failure),
+    }
+}
+
+// A song that disappeared and a file that appeared with the same audio are taken to be one song that
+// moved, so the file is loaded under the song's key and keeps whatever is attached to it. Songs stored
+// without a fingerprint, and fingerprints shared by several files, can't be told apart and are left
+// as a removal and an addition. Matched songs are taken out of removals.
+pub(crate) fn match_moves(
+    tree: &impl Store,
+    removals: &mut Vec<(Key, Key)>,
+    files: &mut [FileToLoad],
+) {
+    if removals.is_empty() || files.iter().all(|file| file.album_key.is_some()) {
+        return;
+    }
+
+    // Each fingerprint maps to its removal, or None when more than one song has it.
+    let mut missing: HashMap<u64, Option<usize>> = HashMap::new();
+    for (index, (song_key, _)) in removals.iter().enumerate() {
+        let song: Result<Song> = tree.get_metadata(song_key);
+        if let Some(fingerprint) = song.ok().and_then(|song| song.fingerprint) {
+            missing
+                .entry(fingerprint)
+                .and_modify(|removal| *removal = None)
+                .or_insert(Some(index));
+        }
+    }
+    if missing.is_empty() {
+        return;
+    }
+
+    let appeared: Vec<(usize, u64)> = files
+        .par_iter()
+        .enumerate()
+        .filter(|(_, file)| file.album_key.is_none())
+        .filter_map(|(index, file)| {
+            let fingerprint = audio_fingerprint(&file.path).ok().flatten()?;
+            Some((index, fingerprint))
+        })
+        .collect();
+    let mut appeared_counts: HashMap<u64, usize> = HashMap::new();
+    for (_, fingerprint) in &appeared {
+        *appeared_counts.entry(*fingerprint).or_default() += 1;
+    }
+
+    let mut moved = Vec::new();
+    for (index, fingerprint) in appeared {
+        if appeared_counts[&fingerprint] != 1 {
+            continue;
+        }
+        if let Some(Some(removal)) = missing.get(&fingerprint) {
+            let (song_key, album_key) = removals[*removal].clone();
+            let file = &mut files[index];
+            file.song_key = song_key;
+            file.album_key = Some(album_key);
+            file.moved = true;
+            moved.push(*removal);
+        }
+    }
+
+    moved.sort_unstable();
+    for removal in moved.into_iter().rev() {
+        removals.swap_remove(removal);
+    }
+}
+
+pub(crate) fn walk_failure(e: jwalk::Error, dir: &Path) -> ScanFailure {
+    let path = e.path().unwrap_or(dir).to_path_buf();
+    let message = e.to_string();
+    let source = e
+        .into_io_error()
+        .unwrap_or_else(|| std::io::Error::other(message));
+    ScanFailure::new(path.clone(), ScanPhase::Walk, Error::io(path, source))
+}
+
+pub(crate) fn stored_song_path(tree: &impl Store, song_key: &Key) -> Option<PathBuf> {
+    let song: Song = tree.get_metadata(song_key).ok()?;
+    tree.song_path(&song).ok()
+}
+
+pub fn scan_library<S: Store>(tree: Arc<S>, dir: &Path) -> Result<ScanReport> {
+    scan_library_with_options(tree, dir, &ScanOptions::default())
+}
+
+pub fn scan_library_root<S: Store>(tree: Arc<S>, name: &str) -> Result<ScanReport> {
+    scan_library_root_with_options(tree, name, &ScanOptions::default())
 }
 
-pub fn scan_library(tree: Arc<sled::Db>, dir: &Path) -> Result<()> {
-    let last_scan_time = Arc::new(tree.get_last_scan_time()?);
+// Problems with individual files are collected into the returned report and the scan carries on.
+// Only failures that make the whole scan meaningless, like a missing library directory or an
+// unreadable database, are returned as errors.
+// A cancelled scan stops walking and tagging as soon as it notices, and skips the removal pass and
+// the last scan time update. Files it didn't get to keep their old stamps, so the next scan picks
+// them up. Known files are skipped when they still match the stamp their song was stored with.
+// A directory inside a root only sweeps away missing songs beneath it, and leaves the root's last
+// scan time alone since the rest of the root wasn't looked at.
+pub fn scan_library_with_options<S: Store>(
+    tree: Arc<S>,
+    dir: &Path,
+    options: &ScanOptions,
+) -> Result<ScanReport> {
+    // If the library is missing, e.g. an unmounted drive, carrying on would remove every song.
+    std::fs::metadata(dir).map_err(|e| Error::io(dir, e))?;
+    upgrade_db(&tree)?;
+    let dir = std::path::absolute(dir).map_err(|e| Error::io(dir, e))?;
+    let root = root_for_dir(&tree, &dir)?;
+    scan_directory(tree, root, &dir, options)
+}
+
+// Scans a root registered with Helpers::set_library_root. Songs in other roots are left alone.
+pub fn scan_library_root_with_options<S: Store>(
+    tree: Arc<S>,
+    name: &str,
+    options: &ScanOptions,
+) -> Result<ScanReport> {
+    upgrade_db(&tree)?;
+    let root = tree.get_library_root(name)?;
+    let dir = root.path();
+    std::fs::metadata(&dir).map_err(|e| Error::io(&dir, e))?;
+    scan_directory(tree, root, &dir, options)
+}
+
+fn scan_directory<S: Store>(
+    tree: Arc<S>,
+    root: LibraryRoot,
+    dir: &Path,
+    options: &ScanOptions,
+) -> Result<ScanReport> {
+    let root = Arc::new(root);
+    let walk_root = Arc::clone(&root);
+
+    let tracker = Arc::new(ScanTracker::new(options));
+    let grouping = tree.album_grouping()?;
+    let walk_tree = Arc::clone(&tree);
 
     let song_keys = Arc::new(Mutex::new(scan_stored_albums(&tree)?));
     let final_song_keys = Arc::clone(&song_keys);
+    let song_paths = Arc::new(SongPaths::load(&tree)?);
 
     let files_to_load = Arc::new(Mutex::new(LinkedList::new()));
 
     let final_files_to_load = Arc::clone(&files_to_load);
 
-    for _ in WalkDir::new(dir).process_read_dir(move |_, _, _, children| {
-        let last_scan_time = Arc::clone(&last_scan_time);
+    let walk_tracker = Arc::clone(&tracker);
+    let walk = WalkDir::new(dir).process_read_dir(move |depth, dir_path, _, children| {
+        if walk_tracker.is_cancelled() {
+            children.clear();
+            return;
+        }
+        // jwalk reads the root itself through a pseudo read with no depth, which isn't a directory walk.
+        if depth.is_some() {
+            walk_tracker.notify(ScanEvent::DirectoryWalked(dir_path));
+        }
+
         let song_keys = Arc::clone(&song_keys);
-        for dir_entry_result in children {
-            let dir_entry = dir_entry_result.as_ref().unwrap();
+        let song_paths = Arc::clone(&song_paths);
+        let folder_art = find_folder_art(
+            children
+                .iter()
+                .flatten()
+                .filter(|dir_entry| dir_entry.file_type.is_file())
+                .map(|dir_entry| dir_entry.path()),
+        )
+        .map(FolderArt::new);
+        // Errors are left in place so the walk iterator yields them below.
+        for dir_entry in children.iter().flatten() {
             if dir_entry.file_type.is_file() {
-                if let Some(file_to_load) =
-                    process_file(&dir_entry.path(), &last_scan_time, &song_keys).unwrap()
-                {
-                    files_to_load.lock().unwrap().push_back(file_to_load);
+                let path = dir_entry.path();
+                match process_file(
+                    &walk_tree,
+                    &path,
+                    &walk_root,
+                    walk_tracker.formats(),
+                    walk_tracker.checks_content(),
+                    &song_keys,
+                    &song_paths,
+                    folder_art.as_ref(),
+                ) {
+                    Ok(FileAction::Load(file_to_load)) => {
+                        walk_tracker.notify(ScanEvent::FileQueued(&file_to_load.path));
+                        files_to_load.lock().unwrap().push_back(file_to_load);
+                    }
+                    Ok(FileAction::Unchanged) => walk_tracker.file_skipped(),
+                    Ok(FileAction::Ignored) => {}
+                    Err(e) => walk_tracker.fail(ScanFailure::new(path, ScanPhase::Metadata, e)),
                 }
             }
         }
-    }) {}
+    });
 
-    let files_to_load_list = final_files_to_load.lock().unwrap();
-    files_to_load_list
-        .par_iter()
-        .for_each(|file_to_load| apply_process_file(&tree, file_to_load).unwrap());
+    let mut unreadable_paths = Vec::new();
+    for entry in walk {
+        if let Err(e) = entry {
+            let failure = walk_failure(e, dir);
+            unreadable_paths.push(failure.path.clone());
+            tracker.fail(failure);
+        }
+    }
 
-    for (song_key, album_key) in final_song_keys.lock().unwrap().iter() {
-        remove_song(&tree, album_key, song_key)?;
+    if tracker.is_cancelled() {
+        return Err(Error::Cancelled);
     }
 
-    tree.set_last_scan_time()?;
-    Ok(())
+    // Only songs this scan looked for can have gone missing. That leaves out other roots, the rest of
+    // this one when scanning a directory inside it, and anything the walk couldn't read.
+    let looked_for = |song_key: &Key| {
+        let song: Result<Song> = tree.get_metadata(song_key);
+        let Ok(song) = song else {
+            return false;
+        };
+        root.holds(&song)
+            && tree.song_path(&song).is_ok_and(|path| {
+                path.starts_with(dir) && !unreadable_paths.iter().any(|dir| path.starts_with(dir))
+            })
+    };
+    let mut removals: Vec<(Key, Key)> = std::mem::take(&mut *final_song_keys.lock().unwrap())
+        .into_iter()
+        .filter(|(song_key, _)| looked_for(song_key))
+        .collect();
+    let mut files_to_load_list: Vec<FileToLoad> =
+        std::mem::take(&mut *final_files_to_load.lock().unwrap())
+            .into_iter()
+            .collect();
+    match_moves(&tree, &mut removals, &mut files_to_load_list);
+
+    files_to_load_list.par_iter().for_each(|file_to_load| {
+        if !tracker.is_cancelled() {
+            apply_process_file(&tree, &root, &tracker, grouping, file_to_load);
+        }
+    });
+
+    if tracker.is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+
+    for (song_key, album_key) in &removals {
+        match remove_song(&tree, album_key, song_key) {
+            Ok(()) => tracker.notify(ScanEvent::SongRemoved(song_key)),
+            Err(e) => tracker.fail(ScanFailure::new(
+                stored_song_path(&tree, song_key).unwrap_or_default(),
+                ScanPhase::Remove,
+                e,
+            )),
+        }
+    }
+
+    // Normalized keys can't tell albums apart by year, nor bring back together ones already split
+    // before the grouping was chosen, so near duplicates are folded into one another as well.
+    if matches!(grouping, AlbumGrouping::NormalizedTags) {
+        tree.reconcile_albums()?;
+    }
+    prune_album_art(&tree)?;
+
+    if dir == root.path() {
+        tree.set_last_scan_time(&root.name)?;
+    }
+    Ok(tracker.take_report())
 }
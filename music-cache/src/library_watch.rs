@@ -0,0 +1,343 @@
+use jwalk::WalkDir;
+use notify::{
+    event::{AccessKind, AccessMode},
+    EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    folder_art_in, load_file, match_moves, methods::scan_stored_albums, prune_album_art,
+    remove_song, root_for_dir, stored_song_path, upgrade_db, walk_failure, AlbumGrouping,
+    AlbumKeyBySongKey, AudioFormats, Error, FileToLoad, FolderArt, Helpers, Key, LibraryRoot,
+    Result, ScanFailure, ScanPhase, SongPaths, Store,
+};
+
+pub enum LibraryChange<'a> {
+    SongAdded(&'a Key),
+    SongUpdated(&'a Key),
+    SongRemoved(&'a Key),
+    // The song's file moved, and the song kept its key.
+    SongMoved(&'a Key),
+    Error(&'a ScanFailure),
+}
+
+// Changes are applied on the watcher's own thread, and observers are called from there.
+pub trait LibraryObserver: Send + Sync {
+    fn on_change(&self, change: &LibraryChange);
+}
+
+type Observers = Arc<Mutex<Vec<Arc<dyn LibraryObserver>>>>;
+
+// Keeps the db in step with a library directory until stopped or dropped.
+// Events are held until the directory has been quiet for the debounce period, so a file that's still
+// being copied in is only tagged once. The paths they touched are then checked against what's on
+// disk rather than trusting the event kinds, and renames are recognised the same way a scan does.
+// Songs it loads are stamped like a scan's, so the next scan skips their files.
+pub struct LibraryWatcher {
+    watcher: Option<RecommendedWatcher>,
+    worker: Option<thread::JoinHandle<()>>,
+    observers: Observers,
+}
+
+impl LibraryWatcher {
+    pub fn start<S: Store>(tree: Arc<S>, dir: &Path, debounce: Duration) -> Result<Self> {
+        Self::start_with_formats(tree, dir, debounce, AudioFormats::default())
+    }
+
+    // Files are picked up and detected the same way a scan with these formats would.
+    pub fn start_with_formats<S: Store>(
+        tree: Arc<S>,
+        dir: &Path,
+        debounce: Duration,
+        formats: AudioFormats,
+    ) -> Result<Self> {
+        std::fs::metadata(dir).map_err(|e| Error::io(dir, e))?;
+        let dir = &std::path::absolute(dir).map_err(|e| Error::io(dir, e))?;
+        upgrade_db(&tree)?;
+        let root = root_for_dir(&tree, dir)?;
+
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+
+        let observers = Observers::default();
+        let worker_observers = Arc::clone(&observers);
+        let dir = dir.to_path_buf();
+        let worker = thread::spawn(move || {
+            watch_loop(
+                &tree,
+                &root,
+                &dir,
+                &formats,
+                &events,
+                debounce,
+                &worker_observers,
+            );
+        });
+
+        Ok(Self {
+            watcher: Some(watcher),
+            worker: Some(worker),
+            observers,
+        })
+    }
+
+    pub fn subscribe(&self, observer: Arc<dyn LibraryObserver>) {
+        self.observers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(observer);
+    }
+
+    // Changes still waiting out the debounce period are dropped; the next scan picks them up.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // Dropping the watcher closes the event channel, which ends the worker.
+        drop(self.watcher.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for LibraryWatcher {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn publish(observers: &Observers, change: LibraryChange) {
+    for observer in observers.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        observer.on_change(&change);
+    }
+}
+
+// Reading a file to tag it raises access events of its own, so only finished writes count.
+fn is_relevant(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) => false,
+        _ => true,
+    }
+}
+
+fn watch_loop(
+    tree: &impl Store,
+    root: &LibraryRoot,
+    dir: &Path,
+    formats: &AudioFormats,
+    events: &mpsc::Receiver<notify::Result<notify::Event>>,
+    debounce: Duration,
+    observers: &Observers,
+) {
+    let mut pending = BTreeSet::new();
+    let mut last_event = Instant::now();
+    loop {
+        let event = if pending.is_empty() {
+            events.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            events.recv_timeout(debounce.saturating_sub(last_event.elapsed()))
+        };
+
+        match event {
+            Ok(Ok(event)) => {
+                if is_relevant(&event.kind) {
+                    pending.extend(event.paths);
+                    last_event = Instant::now();
+                }
+            }
+            Ok(Err(e)) => {
+                let path = e
+                    .paths
+                    .first()
+                    .cloned()
+                    .unwrap_or_else(|| dir.to_path_buf());
+                let failure = ScanFailure::new(path, ScanPhase::Walk, e.into());
+                publish(observers, LibraryChange::Error(&failure));
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let paths = std::mem::take(&mut pending);
+                apply_changes(tree, root, dir, formats, paths, observers);
+            }
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+// Everything that appeared is gathered before anything is removed, so a file moved within the library
+// is matched up with its old song and keeps its key.
+fn apply_changes(
+    tree: &impl Store,
+    root: &LibraryRoot,
+    dir: &Path,
+    formats: &AudioFormats,
+    paths: BTreeSet<PathBuf>,
+    observers: &Observers,
+) {
+    let stored = scan_stored_albums(tree).and_then(|album_keys| {
+        let song_paths = SongPaths::load(tree)?;
+        Ok((album_keys, song_paths, tree.album_grouping()?))
+    });
+    let (mut album_keys, song_paths, grouping) = match stored {
+        Ok(stored) => stored,
+        Err(e) => {
+            let failure = ScanFailure::new(dir.to_path_buf(), ScanPhase::Store, e);
+            return publish(observers, LibraryChange::Error(&failure));
+        }
+    };
+
+    let mut appeared = BTreeSet::new();
+    let mut removals = Vec::new();
+    for path in paths {
+        match std::fs::metadata(&path) {
+            // A directory that appears in one go, like a moved in album, raises no events for its files.
+            Ok(metadata) if metadata.is_dir() => {
+                for entry in WalkDir::new(&path) {
+                    match entry {
+                        Ok(entry) if entry.file_type.is_file() => {
+                            appeared.insert(entry.path());
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            let failure = walk_failure(e, &path);
+                            publish(observers, LibraryChange::Error(&failure));
+                        }
+                    }
+                }
+            }
+            Ok(_) => {
+                appeared.insert(path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                removals.extend(removed_songs(
+                    tree,
+                    root,
+                    &path,
+                    &mut album_keys,
+                    &song_paths,
+                ));
+            }
+            Err(e) => {
+                let failure =
+                    ScanFailure::new(path.clone(), ScanPhase::Metadata, Error::io(path, e));
+                publish(observers, LibraryChange::Error(&failure));
+            }
+        }
+    }
+
+    let mut folder_arts = HashMap::new();
+    let mut files: Vec<FileToLoad> = appeared
+        .into_iter()
+        .filter(|path| formats.accepts(path))
+        .filter_map(|path| {
+            let relpath = root.relpath(&path)?;
+            let song_key = song_paths.song_key(&root.name, &relpath);
+            let folder_art = path.parent().and_then(|dir| {
+                folder_arts
+                    .entry(dir.to_path_buf())
+                    .or_insert_with(|| folder_art_in(dir).map(FolderArt::new))
+                    .clone()
+            });
+            Some(FileToLoad {
+                album_key: album_keys.get(&song_key).cloned(),
+                song_key,
+                path,
+                relpath,
+                moved: false,
+                folder_art,
+            })
+        })
+        .collect();
+    match_moves(tree, &mut removals, &mut files);
+
+    for file in &files {
+        // The watcher only loads files it saw change, so it has no use for their content hashes.
+        match load_file(tree, root, formats, grouping, false, file) {
+            Ok(_) => {
+                let change = if file.moved {
+                    LibraryChange::SongMoved(&file.song_key)
+                } else if file.album_key.is_some() {
+                    LibraryChange::SongUpdated(&file.song_key)
+                } else {
+                    LibraryChange::SongAdded(&file.song_key)
+                };
+                publish(observers, change);
+            }
+            Err(failure) => publish(observers, LibraryChange::Error(&failure)),
+        }
+    }
+
+    for (song_key, album_key) in &removals {
+        match remove_song(tree, album_key, song_key) {
+            Ok(()) => publish(observers, LibraryChange::SongRemoved(song_key)),
+            Err(e) => {
+                let path = stored_song_path(tree, song_key).unwrap_or_default();
+                let failure = ScanFailure::new(path, ScanPhase::Remove, e);
+                publish(observers, LibraryChange::Error(&failure));
+            }
+        }
+    }
+
+    // Loaded songs can split an album the way they would in a scan, so they're folded back together
+    // the same way.
+    if matches!(grouping, AlbumGrouping::NormalizedTags) && !files.is_empty() {
+        if let Err(e) = tree.reconcile_albums() {
+            let failure = ScanFailure::new(dir.to_path_buf(), ScanPhase::Store, e);
+            publish(observers, LibraryChange::Error(&failure));
+        }
+    }
+
+    // Removed songs may have taken the last album using a cover with them.
+    if !removals.is_empty() {
+        if let Err(e) = prune_album_art(tree) {
+            let failure = ScanFailure::new(dir.to_path_buf(), ScanPhase::Remove, e);
+            publish(observers, LibraryChange::Error(&failure));
+        }
+    }
+}
+
+// A removed directory is only reported as itself, so everything stored beneath it goes too.
+fn removed_songs(
+    tree: &impl Store,
+    root: &LibraryRoot,
+    path: &Path,
+    album_keys: &mut AlbumKeyBySongKey,
+    song_paths: &SongPaths,
+) -> Vec<(Key, Key)> {
+    let song_key = root
+        .relpath(path)
+        .map(|relpath| song_paths.song_key(&root.name, &relpath))
+        .filter(|song_key| album_keys.contains_key(song_key));
+    let song_keys: Vec<Key> = if let Some(song_key) = song_key {
+        vec![song_key]
+    } else {
+        album_keys
+            .keys()
+            .filter(|song_key| {
+                stored_song_path(tree, song_key)
+                    .is_some_and(|song_path| song_path.starts_with(path))
+            })
+            .cloned()
+            .collect()
+    };
+
+    song_keys
+        .into_iter()
+        .filter_map(|song_key| {
+            let album_key = album_keys.remove(&song_key)?;
+            Some((song_key, album_key))
+        })
+        .collect()
+}
@@ -1,30 +1,96 @@
 use music_cache_derive::derive_data_model;
-use std::path::Path;
+#[cfg(unix)]
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::{AudioFormat, FileStamp, MusicBrainzIds, ReplayGain, StreamInfo};
 
 #[derive_data_model]
 #[cfg_attr(any(test, feature = "integration-tests"), derive(Clone))]
 #[derive(Hash)]
 pub struct Song {
     pub tags: SongTags,
+    // Name of the LibraryRoot relpath is relative to. Songs stored before there were roots have an empty
+    // root and an absolute relpath.
+    pub root: String,
     // converting a path to a utf8 string might not be valid and there's no Archive instance for PathBuf so just store it as bytes.
     pub relpath: Vec<u8>,
+    // Identifies the file's audio so the song can be recognised after it moves. See audio_fingerprint.
+    pub fingerprint: Option<u64>,
+    // What the file's contents turned out to be, whatever its extension says.
+    pub format: Option<AudioFormat>,
+    // Read from the audio frames rather than the tags. See read_stream_info.
+    pub stream: StreamInfo,
+    pub musicbrainz: MusicBrainzIds,
+    pub replay_gain: ReplayGain,
+    // The file as it was when it was last tagged. None makes the next scan re-read it.
+    pub file: Option<FileStamp>,
 }
 
 impl Song {
     pub fn new(tags: SongTags, relpath: &[u8]) -> Song {
         Song {
             tags,
+            root: String::new(),
             relpath: Vec::from(relpath),
+            fingerprint: None,
+            format: None,
+            stream: StreamInfo::default(),
+            musicbrainz: MusicBrainzIds::default(),
+            replay_gain: ReplayGain::default(),
+            file: None,
         }
     }
+
+    // Relative to the song's root. See Helpers::song_path for where the file actually is.
+    pub fn path(&self) -> PathBuf {
+        path_from_bytes(&self.relpath)
+    }
+}
+
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
 }
 
 #[derive_data_model]
 #[cfg_attr(any(test, feature = "integration-tests"), derive(Clone))]
-#[derive(Hash)]
+#[derive(Hash, Default)]
 pub struct SongTags {
     pub title: Option<String>,
+    pub artist: Option<String>,
+    pub genre: Option<String>,
     pub track_number: Option<u16>,
+    pub total_tracks: Option<u16>,
+    pub disc_number: Option<u16>,
+    pub total_discs: Option<u16>,
+    pub composer: Option<String>,
+    pub comment: Option<String>,
+}
+
+// Play order within an album. Ties between songs with the same disc and track fall back to the path
+// so the order is stable no matter which file the scanner happened to reach first.
+#[derive(bitcode::Encode, bitcode::Decode, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TrackOrder {
+    pub disc_number: Option<u16>,
+    pub track_number: Option<u16>,
+    pub relpath: Vec<u8>,
+}
+
+impl Song {
+    pub fn track_order(&self) -> TrackOrder {
+        TrackOrder {
+            disc_number: self.tags.disc_number,
+            track_number: self.tags.track_number,
+            relpath: self.relpath.clone(),
+        }
+    }
 }
 
 #[cfg_attr(feature = "integration-tests", derive(Debug, PartialEq, Eq))]
@@ -33,12 +99,24 @@ pub struct Album {
     pub songs: Vec<Song>,
 }
 
+impl Album {
+    // Songs whose duration isn't known count for nothing.
+    pub fn duration_ms(&self) -> u64 {
+        self.songs
+            .iter()
+            .filter_map(|song| song.stream.duration_ms)
+            .sum()
+    }
+}
+
 #[derive_data_model]
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct AlbumTags {
     pub artist: Option<String>,
     pub title: Option<String>,
     pub year: Option<u16>,
+    pub genre: Option<String>,
+    pub total_discs: Option<u16>,
 }
 
 pub type AudioTag = Box<dyn audiotags::AudioTag + Send + Sync>;
@@ -49,6 +127,8 @@ impl AlbumTags {
             artist: tag.album_artist().map(ToString::to_string),
             title: tag.album_title().map(ToString::to_string),
             year: tag.year().and_then(|y| y.try_into().ok()),
+            genre: tag.genre().map(ToString::to_string),
+            total_discs: tag.total_discs(),
         }
     }
 }
@@ -57,7 +137,14 @@ impl SongTags {
     pub fn read(tag: &AudioTag) -> SongTags {
         SongTags {
             title: tag.title().map(ToString::to_string),
+            artist: tag.artist().map(ToString::to_string),
+            genre: tag.genre().map(ToString::to_string),
             track_number: tag.track_number(),
+            total_tracks: tag.total_tracks(),
+            disc_number: tag.disc_number(),
+            total_discs: tag.total_discs(),
+            composer: tag.composer().map(ToString::to_string),
+            comment: tag.comment().map(ToString::to_string),
         }
     }
 }
@@ -66,7 +153,14 @@ impl Song {
     pub fn read(tag: &AudioTag, relpath: &Path) -> Song {
         Song {
             tags: SongTags::read(tag),
+            root: String::new(),
             relpath: relpath.to_path_buf().into_os_string().into_encoded_bytes(),
+            fingerprint: None,
+            format: None,
+            stream: StreamInfo::default(),
+            musicbrainz: MusicBrainzIds::default(),
+            replay_gain: ReplayGain::default(),
+            file: None,
         }
     }
 }
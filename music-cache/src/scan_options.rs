@@ -0,0 +1,186 @@
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{AudioFormats, Key, ScanFailure, ScanReport};
+
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Running totals for a scan, passed alongside every event.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct ScanProgress {
+    pub directories_walked: usize,
+    pub files_queued: usize,
+    pub files_tagged: usize,
+    pub songs_removed: usize,
+    pub errors: usize,
+}
+
+pub enum ScanEvent<'a> {
+    DirectoryWalked(&'a Path),
+    FileQueued(&'a Path),
+    FileTagged(&'a Path),
+    SongRemoved(&'a Key),
+    Error(&'a ScanFailure),
+}
+
+// Both scan phases run in parallel, so observers are called from several threads at once.
+pub trait ScanObserver: Send + Sync {
+    fn on_event(&self, event: &ScanEvent, progress: &ScanProgress);
+}
+
+#[derive(Clone, Default)]
+pub struct ScanOptions {
+    pub observer: Option<Arc<dyn ScanObserver>>,
+    pub cancellation: CancellationToken,
+    pub formats: AudioFormats,
+    // Hash every known file whose metadata is unchanged too. Much slower, since each file is read in
+    // full, but it catches tags edited in place by tools that restore the modification time.
+    pub content_check: bool,
+}
+
+impl ScanOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_observer(mut self, observer: Arc<dyn ScanObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = cancellation;
+        self
+    }
+
+    pub fn with_formats(mut self, formats: AudioFormats) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    pub fn with_content_check(mut self, content_check: bool) -> Self {
+        self.content_check = content_check;
+        self
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ScanTracker {
+    options: ScanOptions,
+    directories_walked: AtomicUsize,
+    files_queued: AtomicUsize,
+    files_tagged: AtomicUsize,
+    songs_removed: AtomicUsize,
+    errors: AtomicUsize,
+    songs_added: AtomicUsize,
+    songs_updated: AtomicUsize,
+    songs_moved: AtomicUsize,
+    files_skipped: AtomicUsize,
+    failures: Mutex<Vec<ScanFailure>>,
+}
+
+impl ScanTracker {
+    pub(crate) fn new(options: &ScanOptions) -> Self {
+        Self {
+            options: options.clone(),
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn formats(&self) -> &AudioFormats {
+        &self.options.formats
+    }
+
+    pub(crate) fn checks_content(&self) -> bool {
+        self.options.content_check
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.options.cancellation.is_cancelled()
+    }
+
+    pub(crate) fn notify(&self, event: ScanEvent) {
+        let counter = match event {
+            ScanEvent::DirectoryWalked(_) => &self.directories_walked,
+            ScanEvent::FileQueued(_) => &self.files_queued,
+            ScanEvent::FileTagged(_) => &self.files_tagged,
+            ScanEvent::SongRemoved(_) => &self.songs_removed,
+            ScanEvent::Error(_) => &self.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(observer) = &self.options.observer {
+            observer.on_event(&event, &self.progress());
+        }
+    }
+
+    pub(crate) fn fail(&self, failure: ScanFailure) {
+        self.notify(ScanEvent::Error(&failure));
+        self.failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(failure);
+    }
+
+    pub(crate) fn song_stored(&self, known: bool) {
+        let counter = if known {
+            &self.songs_updated
+        } else {
+            &self.songs_added
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn song_moved(&self) {
+        self.songs_moved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn file_skipped(&self) {
+        self.files_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn take_report(&self) -> ScanReport {
+        let mut failures =
+            std::mem::take(&mut *self.failures.lock().unwrap_or_else(|e| e.into_inner()));
+        failures.sort_by(|a, b| a.path.cmp(&b.path));
+        ScanReport {
+            added: self.songs_added.load(Ordering::Relaxed),
+            updated: self.songs_updated.load(Ordering::Relaxed),
+            moved: self.songs_moved.load(Ordering::Relaxed),
+            removed: self.songs_removed.load(Ordering::Relaxed),
+            skipped: self.files_skipped.load(Ordering::Relaxed),
+            failures,
+        }
+    }
+
+    pub(crate) fn progress(&self) -> ScanProgress {
+        ScanProgress {
+            directories_walked: self.directories_walked.load(Ordering::Relaxed),
+            files_queued: self.files_queued.load(Ordering::Relaxed),
+            files_tagged: self.files_tagged.load(Ordering::Relaxed),
+            songs_removed: self.songs_removed.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+
+use crate::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanPhase {
+    Walk,
+    Metadata,
+    ReadTags,
+    Store,
+    Remove,
+}
+
+#[derive(Debug)]
+pub struct ScanFailure {
+    pub path: PathBuf,
+    pub phase: ScanPhase,
+    pub error: Error,
+}
+
+impl ScanFailure {
+    pub fn new(path: PathBuf, phase: ScanPhase, error: Error) -> Self {
+        Self { path, phase, error }
+    }
+}
+
+// What a scan did. Files that fail are listed in failures and otherwise left alone, so a song that
+// can no longer be read keeps its previous record rather than being removed.
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    pub added: usize,
+    pub updated: usize,
+    pub moved: usize,
+    pub removed: usize,
+    pub skipped: usize,
+    pub failures: Vec<ScanFailure>,
+}
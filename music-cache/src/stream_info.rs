@@ -0,0 +1,230 @@
+use music_cache_derive::derive_data_model;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::{fingerprint::audio_range, AudioFormat, Container, Error, Result};
+
+// Properties of the audio itself rather than its tags. Each is None when the file doesn't say, and
+// all of them are for formats there's no reader for. Bitrate is the average over the whole stream in
+// kbps.
+#[derive_data_model]
+#[derive(Clone, Copy, Hash, Default)]
+pub struct StreamInfo {
+    pub duration_ms: Option<u64>,
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u8>,
+    pub channels: Option<u8>,
+}
+
+// A stream that can't be made sense of still leaves the song's tags worth storing, so only IO errors
+// fail the file.
+pub fn read_stream_info(path: &Path, format: Option<AudioFormat>) -> Result<StreamInfo> {
+    let info = match format.map(|format| format.container) {
+        Some(Container::Mpeg) => mp3_stream_info(path).map_err(|e| Error::io(path, e))?,
+        Some(Container::Flac) => match metaflac::Tag::read_from_path(path) {
+            Ok(tag) => flac_stream_info(path, &tag)?,
+            Err(metaflac::Error {
+                kind: metaflac::ErrorKind::Io(e),
+                ..
+            }) => return Err(Error::io(path, e)),
+            Err(_) => StreamInfo::default(),
+        },
+        Some(Container::Mp4) => match mp4ameta::Tag::read_from_path(path) {
+            Ok(tag) => mp4_stream_info(&tag),
+            Err(mp4ameta::Error {
+                kind: mp4ameta::ErrorKind::Io(e),
+                ..
+            }) => return Err(Error::io(path, e)),
+            Err(_) => StreamInfo::default(),
+        },
+        _ => StreamInfo::default(),
+    };
+    Ok(info)
+}
+
+fn bitrate(audio_len: u64, duration_ms: u64) -> Option<u32> {
+    let kbps = (audio_len * 8).checked_div(duration_ms)?;
+    u32::try_from(kbps).ok()
+}
+
+fn flac_stream_info(path: &Path, tag: &metaflac::Tag) -> Result<StreamInfo> {
+    let Some(stream) = tag.get_streaminfo() else {
+        return Ok(StreamInfo::default());
+    };
+    // A sample count of zero means the encoder didn't know it.
+    let duration_ms = (stream.total_samples * 1000)
+        .checked_div(u64::from(stream.sample_rate))
+        .filter(|&duration_ms| duration_ms > 0);
+    let audio_len = audio_len(path).map_err(|e| Error::io(path, e))?;
+    Ok(StreamInfo {
+        duration_ms,
+        bitrate: duration_ms.and_then(|duration_ms| bitrate(audio_len, duration_ms)),
+        sample_rate: Some(stream.sample_rate),
+        bit_depth: Some(stream.bits_per_sample),
+        channels: Some(stream.num_channels),
+    })
+}
+
+// mp4 records the average bitrate in bps, and leaves bit depth to the codec.
+fn mp4_stream_info(tag: &mp4ameta::Tag) -> StreamInfo {
+    StreamInfo {
+        duration_ms: tag
+            .duration()
+            .and_then(|duration| u64::try_from(duration.as_millis()).ok()),
+        bitrate: tag.avg_bitrate().map(|bitrate| bitrate / 1000),
+        sample_rate: tag.sample_rate().map(|sample_rate| sample_rate.hz()),
+        bit_depth: None,
+        channels: tag.channel_config().map(|config| config.channel_count()),
+    }
+}
+
+fn audio_len(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let (start, end) = audio_range(&mut file, len)?;
+    Ok(end.saturating_sub(start))
+}
+
+// How far past the tag to look for the first frame, for files with padding or junk in between.
+const MP3_SEARCH_LEN: u64 = 64 * 1024;
+
+const MPEG1_BITRATES: [u32; 15] = [
+    0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+];
+const MPEG2_BITRATES: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+const MPEG1_SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+struct Mp3Frame {
+    mpeg1: bool,
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u8,
+    len: usize,
+}
+
+impl Mp3Frame {
+    // Only layer III, and not free format, whose frames can't be measured from the header.
+    fn parse(header: &[u8]) -> Option<Mp3Frame> {
+        let &[0xFF, b1, b2, b3, ..] = header else {
+            return None;
+        };
+        if b1 & 0xE0 != 0xE0 || (b1 >> 1) & 0x03 != 0x01 {
+            return None;
+        }
+        let (mpeg1, rate_divisor) = match (b1 >> 3) & 0x03 {
+            0b11 => (true, 1),
+            0b10 => (false, 2),
+            0b00 => (false, 4),
+            _ => return None,
+        };
+        let bitrates = if mpeg1 {
+            &MPEG1_BITRATES
+        } else {
+            &MPEG2_BITRATES
+        };
+        let bitrate = *bitrates.get(usize::from(b2 >> 4)).filter(|&&b| b > 0)?;
+        let sample_rate = MPEG1_SAMPLE_RATES.get(usize::from((b2 >> 2) & 0x03))? / rate_divisor;
+        let padding = usize::from((b2 >> 1) & 0x01);
+        let channels = if b3 >> 6 == 0b11 { 1 } else { 2 };
+        let samples_per_byte = if mpeg1 { 144 } else { 72 };
+        let len = (samples_per_byte * bitrate * 1000 / sample_rate) as usize + padding;
+        Some(Mp3Frame {
+            mpeg1,
+            bitrate,
+            sample_rate,
+            channels,
+            len,
+        })
+    }
+
+    fn samples(&self) -> u64 {
+        if self.mpeg1 {
+            1152
+        } else {
+            576
+        }
+    }
+
+    // The first frame of a VBR file holds a Xing (or Info) or VBRI header instead of audio, giving the
+    // frame count and, usually, the stream length.
+    fn vbr_header(&self, frame: &[u8]) -> Option<(u64, Option<u64>)> {
+        let side_info = match (self.mpeg1, self.channels) {
+            (true, 1) | (false, 2) => 17,
+            (true, _) => 32,
+            (false, _) => 9,
+        };
+        let xing = frame.get(4 + side_info..)?;
+        if xing.starts_with(b"Xing") || xing.starts_with(b"Info") {
+            let flags = be_u32(xing, 4)?;
+            let mut offset = 8;
+            let mut field = |present: bool| {
+                let value = present.then(|| be_u32(xing, offset)).flatten();
+                offset += if present { 4 } else { 0 };
+                value
+            };
+            let frames = field(flags & 0x01 != 0)?;
+            let bytes = field(flags & 0x02 != 0);
+            return Some((u64::from(frames), bytes.map(u64::from)));
+        }
+        let vbri = frame.get(36..)?;
+        if vbri.starts_with(b"VBRI") {
+            let bytes = be_u32(vbri, 10)?;
+            let frames = be_u32(vbri, 14)?;
+            return Some((u64::from(frames), Some(u64::from(bytes))));
+        }
+        None
+    }
+}
+
+fn be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// A frame only counts when another starts right after it, since a sync pattern on its own turns up in
+// tags and audio data often enough.
+fn first_mp3_frame(buf: &[u8]) -> Option<(usize, Mp3Frame)> {
+    (0..buf.len()).find_map(|offset| {
+        let frame = Mp3Frame::parse(&buf[offset..])?;
+        Mp3Frame::parse(buf.get(offset + frame.len..)?)?;
+        Some((offset, frame))
+    })
+}
+
+fn mp3_stream_info(path: &Path) -> io::Result<StreamInfo> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let (start, end) = audio_range(&mut file, len)?;
+    let mut buf = Vec::new();
+    file.seek(SeekFrom::Start(start))?;
+    file.take(MP3_SEARCH_LEN.min(end.saturating_sub(start)))
+        .read_to_end(&mut buf)?;
+
+    let Some((offset, frame)) = first_mp3_frame(&buf) else {
+        return Ok(StreamInfo::default());
+    };
+    let audio_len = end - start - offset as u64;
+    let (duration_ms, bitrate) = match frame.vbr_header(&buf[offset..]) {
+        Some((frames, bytes)) => {
+            let duration_ms = frames * frame.samples() * 1000 / u64::from(frame.sample_rate);
+            let bitrate = self::bitrate(bytes.unwrap_or(audio_len), duration_ms);
+            (Some(duration_ms).filter(|&d| d > 0), bitrate)
+        }
+        // Without one the file is taken to be constant bitrate throughout.
+        None => (
+            (audio_len * 8).checked_div(u64::from(frame.bitrate)),
+            Some(frame.bitrate),
+        ),
+    };
+    Ok(StreamInfo {
+        duration_ms,
+        bitrate,
+        sample_rate: Some(frame.sample_rate),
+        bit_depth: None,
+        channels: Some(frame.channels),
+    })
+}
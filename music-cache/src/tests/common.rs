@@ -1,5 +1,8 @@
 use fake::{
-    faker::{lorem::en::Sentence, name::en::Name},
+    faker::{
+        lorem::en::{Sentence, Word},
+        name::en::Name,
+    },
     *,
 };
 
@@ -13,9 +16,18 @@ pub trait Arbitrary {
 
 impl Arbitrary for SongTags {
     fn arbitrary() -> Self {
+        let track_number: Option<u16> = (0..20).fake();
+        let disc_number: Option<u16> = (1..4).fake();
         Self {
             title: Sentence(1..10).fake(),
-            track_number: (0..20).fake(),
+            artist: Name().fake(),
+            genre: Word().fake(),
+            track_number,
+            total_tracks: track_number.and((20..30).fake()),
+            disc_number,
+            total_discs: disc_number.and((4..6).fake()),
+            composer: Name().fake(),
+            comment: Sentence(1..10).fake(),
         }
     }
 }
@@ -24,7 +36,60 @@ impl Arbitrary for Song {
     fn arbitrary() -> Self {
         Self {
             tags: SongTags::arbitrary(),
+            root: String::new(),
             relpath: (0..16).map(|_| Faker.fake::<u8>()).collect(),
+            fingerprint: Faker.fake(),
+            format: None,
+            stream: StreamInfo::arbitrary(),
+            musicbrainz: MusicBrainzIds::arbitrary(),
+            replay_gain: ReplayGain::arbitrary(),
+            file: None,
+        }
+    }
+}
+
+fn arbitrary_mbid() -> String {
+    let uuid: u128 = Faker.fake();
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        uuid >> 96,
+        (uuid >> 80) & 0xffff,
+        (uuid >> 64) & 0xffff,
+        (uuid >> 48) & 0xffff,
+        uuid & 0xffff_ffff_ffff
+    )
+}
+
+impl Arbitrary for MusicBrainzIds {
+    fn arbitrary() -> Self {
+        let maybe_mbid = || Faker.fake::<bool>().then(arbitrary_mbid);
+        Self {
+            recording_id: maybe_mbid(),
+            release_id: maybe_mbid(),
+            artist_id: maybe_mbid(),
+        }
+    }
+}
+
+impl Arbitrary for ReplayGain {
+    fn arbitrary() -> Self {
+        Self {
+            track_gain: (-2000..1000).fake(),
+            track_peak: (0..1_200_000).fake(),
+            album_gain: (-2000..1000).fake(),
+            album_peak: (0..1_200_000).fake(),
+        }
+    }
+}
+
+impl Arbitrary for StreamInfo {
+    fn arbitrary() -> Self {
+        Self {
+            duration_ms: (1000..600_000).fake(),
+            bitrate: (32..320).fake(),
+            sample_rate: Faker.fake(),
+            bit_depth: Faker.fake(),
+            channels: (1..8).fake(),
         }
     }
 }
@@ -35,15 +100,19 @@ impl Arbitrary for AlbumTags {
             artist: Name().fake(),
             title: Sentence(1..10).fake(),
             year: (1900..3022).fake(),
+            genre: Word().fake(),
+            total_discs: (1..4).fake(),
         }
     }
 }
 
 impl Arbitrary for Album {
     fn arbitrary() -> Self {
+        let mut songs: Vec<Song> = (0..(2..20).fake()).map(|_| Song::arbitrary()).collect();
+        songs.sort_by_key(Song::track_order);
         Self {
             tags: AlbumTags::arbitrary(),
-            songs: (0..(2..20).fake()).map(|_| Song::arbitrary()).collect(),
+            songs,
         }
     }
 }
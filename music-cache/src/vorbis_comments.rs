@@ -0,0 +1,235 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    mem,
+    path::Path,
+};
+
+use crate::ASF_HEADER_GUID;
+
+const OGG_PAGE_HEADER_LEN: usize = 27;
+
+const ASF_HEADER_LEN: usize = 30;
+
+const ASF_OBJECT_HEADER_LEN: usize = 24;
+
+const ASF_CONTENT_DESCRIPTION_GUID: [u8; 16] = [
+    0x33, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+
+const ASF_EXTENDED_CONTENT_DESCRIPTION_GUID: [u8; 16] = [
+    0x40, 0xA4, 0xD0, 0xD2, 0x07, 0xE3, 0xD2, 0x11, 0x97, 0xF0, 0x00, 0xA0, 0xC9, 0x5E, 0xA8, 0x50,
+];
+
+// ASF attributes by the Vorbis comment Picard would write them as. Names are matched case
+// insensitively, like Vorbis comment names.
+const ASF_ATTRIBUTES: &[(&str, &str)] = &[
+    ("WM/AlbumTitle", "ALBUM"),
+    ("WM/AlbumArtist", "ALBUMARTIST"),
+    ("WM/TrackNumber", "TRACKNUMBER"),
+    ("WM/PartOfSet", "DISCNUMBER"),
+    ("WM/Year", "YEAR"),
+    ("WM/Genre", "GENRE"),
+    ("WM/Composer", "COMPOSER"),
+    ("MusicBrainz/Track Id", "MUSICBRAINZ_TRACKID"),
+    ("MusicBrainz/Album Id", "MUSICBRAINZ_ALBUMID"),
+    ("MusicBrainz/Artist Id", "MUSICBRAINZ_ARTISTID"),
+    ("replaygain_track_gain", "REPLAYGAIN_TRACK_GAIN"),
+    ("replaygain_track_peak", "REPLAYGAIN_TRACK_PEAK"),
+    ("replaygain_album_gain", "REPLAYGAIN_ALBUM_GAIN"),
+    ("replaygain_album_peak", "REPLAYGAIN_ALBUM_PEAK"),
+];
+
+// Vorbis, Opus and FLAC streams in Ogg all keep their tags as Vorbis comments in the stream's second
+// packet. A stream that ends or can't be made sense of before then has no tags.
+pub(crate) fn read_ogg_comments(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let packets = ogg_packets(&mut file, 2)?;
+    let [first, second] = packets.as_slice() else {
+        return Ok(Vec::new());
+    };
+    let comments = if first.starts_with(b"\x01vorbis") {
+        second.strip_prefix(b"\x03vorbis")
+    } else if first.starts_with(b"OpusHead") {
+        second.strip_prefix(b"OpusTags")
+    } else if first.starts_with(b"\x7FFLAC") {
+        // A FLAC metadata block, with a one byte type and three byte length before the comments.
+        second
+            .get(4..)
+            .filter(|_| second.first().is_some_and(|&kind| kind & 0x7F == 4))
+    } else {
+        None
+    };
+    Ok(comments.and_then(vorbis_comments).unwrap_or_default())
+}
+
+// Reassembles the first packets of the stream the file starts with, skipping the pages of any other
+// stream multiplexed with it. Fewer come back when the file ends first.
+fn ogg_packets(file: &mut impl Read, count: usize) -> io::Result<Vec<Vec<u8>>> {
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut serial = None;
+    let mut header = [0; OGG_PAGE_HEADER_LEN];
+    while packets.len() < count && read_or_eof(file, &mut header)? && header.starts_with(b"OggS") {
+        let mut segments = vec![0; usize::from(header[26])];
+        let body_len = if read_or_eof(file, &mut segments)? {
+            segments.iter().map(|&segment| usize::from(segment)).sum()
+        } else {
+            break;
+        };
+        let mut body = vec![0; body_len];
+        if !read_or_eof(file, &mut body)? {
+            break;
+        }
+        let page_serial = [header[14], header[15], header[16], header[17]];
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+        let mut rest = body.as_slice();
+        for &segment in &segments {
+            let (data, after) = rest.split_at(usize::from(segment));
+            packet.extend_from_slice(data);
+            rest = after;
+            // A segment shorter than the most a lacing value can say ends its packet.
+            if segment < 255 {
+                packets.push(mem::take(&mut packet));
+            }
+        }
+    }
+    packets.truncate(count);
+    Ok(packets)
+}
+
+fn read_or_eof(file: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// A vendor string and then NAME=value comments, each preceded by its length. Ogg Vorbis adds a framing
+// bit after the last one, which is left alone.
+fn vorbis_comments(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut rest = data;
+    let vendor_len = take_u32_le(&mut rest)?;
+    take(&mut rest, vendor_len)?;
+    let count = take_u32_le(&mut rest)?;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = take_u32_le(&mut rest)?;
+        let comment = String::from_utf8_lossy(take(&mut rest, len)?);
+        if let Some((name, value)) = comment.split_once('=') {
+            comments.push((name.to_string(), value.to_string()));
+        }
+    }
+    Some(comments)
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let taken = data.get(..len)?;
+    *data = &data[len..];
+    Some(taken)
+}
+
+fn take_u32_le(data: &mut &[u8]) -> Option<usize> {
+    let bytes = take(data, 4)?;
+    usize::try_from(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).ok()
+}
+
+fn take_u16_le(data: &mut &[u8]) -> Option<usize> {
+    let bytes = take(data, 2)?;
+    Some(usize::from(u16::from_le_bytes([bytes[0], bytes[1]])))
+}
+
+// WMA files keep the title and artist in the header's content description object, and everything
+// else as attributes in its extended content description. They're read as the Vorbis comments they
+// correspond to. A header that can't be made sense of has no tags.
+pub(crate) fn read_asf_comments(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let mut file = File::open(path)?;
+    let mut header = [0; ASF_HEADER_LEN];
+    if !read_or_eof(&mut file, &mut header)? || !header.starts_with(&ASF_HEADER_GUID) {
+        return Ok(Vec::new());
+    }
+    let header_len = u64::from_le_bytes(header[16..24].try_into().unwrap_or_default());
+    let mut objects = Vec::new();
+    file.take(header_len.saturating_sub(ASF_HEADER_LEN as u64))
+        .read_to_end(&mut objects)?;
+
+    let mut comments = Vec::new();
+    let mut rest = objects.as_slice();
+    while let Some(object_header) = take(&mut rest, ASF_OBJECT_HEADER_LEN) {
+        let size = u64::from_le_bytes(object_header[16..].try_into().unwrap_or_default());
+        let data_len = usize::try_from(size)
+            .ok()
+            .and_then(|size| size.checked_sub(ASF_OBJECT_HEADER_LEN));
+        let Some(data) = data_len.and_then(|len| take(&mut rest, len)) else {
+            break;
+        };
+        if object_header[..16] == ASF_CONTENT_DESCRIPTION_GUID {
+            comments.extend(asf_content_description(data).unwrap_or_default());
+        } else if object_header[..16] == ASF_EXTENDED_CONTENT_DESCRIPTION_GUID {
+            comments.extend(asf_extended_content_description(data).unwrap_or_default());
+        }
+    }
+    Ok(comments)
+}
+
+// The lengths of the title, author, copyright, description and rating, followed by each of them.
+fn asf_content_description(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut rest = data;
+    let mut lens = [0; 5];
+    for len in &mut lens {
+        *len = take_u16_le(&mut rest)?;
+    }
+    let mut comments = Vec::new();
+    for (len, name) in lens.into_iter().zip(["TITLE", "ARTIST", "", "COMMENT", ""]) {
+        let value = utf16_string(take(&mut rest, len)?);
+        if !name.is_empty() && !value.is_empty() {
+            comments.push((name.to_string(), value));
+        }
+    }
+    Some(comments)
+}
+
+// A count of attributes, each a name, a value type and a value. Numbers are kept as their decimal
+// text, as Vorbis comments would have them.
+fn asf_extended_content_description(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut rest = data;
+    let count = take_u16_le(&mut rest)?;
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let name_len = take_u16_le(&mut rest)?;
+        let name = utf16_string(take(&mut rest, name_len)?);
+        let value_type = take_u16_le(&mut rest)?;
+        let value_len = take_u16_le(&mut rest)?;
+        let value = take(&mut rest, value_len)?;
+        let value = match (value_type, value.len()) {
+            (0, _) => utf16_string(value),
+            (3, 4) => u32::from_le_bytes(value.try_into().ok()?).to_string(),
+            (4, 8) => u64::from_le_bytes(value.try_into().ok()?).to_string(),
+            (5, 2) => u16::from_le_bytes(value.try_into().ok()?).to_string(),
+            _ => continue,
+        };
+        let vorbis_name = ASF_ATTRIBUTES
+            .iter()
+            .find(|(asf_name, _)| asf_name.eq_ignore_ascii_case(&name))
+            .map(|(_, vorbis_name)| vorbis_name);
+        if let Some(vorbis_name) = vorbis_name {
+            comments.push((vorbis_name.to_string(), value));
+        }
+    }
+    Some(comments)
+}
+
+// Null terminated UTF-16LE.
+fn utf16_string(data: &[u8]) -> String {
+    let units = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect::<String>()
+        .trim_end_matches('\0')
+        .to_string()
+}
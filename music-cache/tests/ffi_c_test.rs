@@ -4,6 +4,9 @@ use music_cache::{
 };
 use std::ffi::CString;
 
+mod fs_utils;
+use fs_utils::{add_extended_text, add_musicbrainz_ufid, embed_picture, SkeletonFileTree};
+
 extern "C" {
     fn ffi_open_db_round_trip(path: *const std::os::raw::c_char) -> bool;
     fn ffi_open_db_rejects_null_path() -> bool;
@@ -19,6 +22,64 @@ extern "C" {
         expected: *const ffi::CAlbumTags,
     ) -> bool;
     fn ffi_expect_scan_album_tags_sorted(db: *mut std::ffi::c_void, expected_len: usize) -> bool;
+    fn ffi_scan_library_counting(
+        db: *mut std::ffi::c_void,
+        path: *const std::os::raw::c_char,
+        cancel_after: usize,
+        files_tagged: *mut usize,
+        summary: *mut ffi::CScanSummary,
+        failure_code: *mut ErrorCode,
+    ) -> bool;
+    fn ffi_expect_search(
+        db: *mut std::ffi::c_void,
+        query: *const std::os::raw::c_char,
+        expected_song: *const Key,
+    ) -> bool;
+    fn ffi_expect_scan_artists_sorted(db: *mut std::ffi::c_void, expected_len: usize) -> bool;
+    fn ffi_expect_albums_for_artist(
+        db: *mut std::ffi::c_void,
+        artist_key: *const Key,
+        expected_len: usize,
+    ) -> bool;
+    fn ffi_expect_last_error(db: *mut std::ffi::c_void, missing_key: *const Key) -> bool;
+    fn ffi_expect_album_art(
+        db: *mut std::ffi::c_void,
+        album_key: *const Key,
+        expected: *const u8,
+        expected_len: usize,
+        expected_mime_type: *const std::os::raw::c_char,
+    ) -> bool;
+    fn ffi_library_root_round_trip(
+        db: *mut std::ffi::c_void,
+        path: *const std::os::raw::c_char,
+        albums_scanned: *mut usize,
+    ) -> bool;
+    fn ffi_expect_song_by_mbid(
+        db: *mut std::ffi::c_void,
+        recording_id: *const std::os::raw::c_char,
+        expected_key: *const Key,
+        expected: *const ffi::CSong,
+    ) -> bool;
+    fn ffi_user_data_round_trip(db: *mut std::ffi::c_void, song_key: *const Key) -> bool;
+    fn ffi_query_songs_round_trip(
+        db: *mut std::ffi::c_void,
+        played_key: *const Key,
+        song_count: usize,
+    ) -> bool;
+    fn ffi_playlist_round_trip(
+        db: *mut std::ffi::c_void,
+        playlist_key: *const Key,
+        song_key: *const Key,
+        gone_key: *const Key,
+        export_path: *const std::os::raw::c_char,
+        unknown_path: *const std::os::raw::c_char,
+    ) -> bool;
+    fn ffi_album_grouping_round_trip(db: *mut std::ffi::c_void, merged: *mut usize) -> bool;
+    fn ffi_watch_library_start_stop(
+        db: *mut std::ffi::c_void,
+        path: *const std::os::raw::c_char,
+        missing_path: *const std::os::raw::c_char,
+    ) -> bool;
 }
 
 #[test]
@@ -103,3 +164,364 @@ fn ffi_album_round_trip() -> Result {
 
     Ok(())
 }
+
+#[test]
+fn ffi_artists_round_trip() -> Result {
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+
+    for _ in 0..10 {
+        db.insert_metadata(&Album::arbitrary())?;
+    }
+
+    let artists = db.scan_artists_sorted()?;
+
+    assert!(unsafe {
+        ffi_expect_scan_artists_sorted(&db as *const _ as *mut std::ffi::c_void, artists.len())
+    });
+
+    for (artist_key, artist) in &artists {
+        assert!(unsafe {
+            ffi_expect_albums_for_artist(
+                &db as *const _ as *mut std::ffi::c_void,
+                artist_key as *const Key,
+                artist.album_keys.len(),
+            )
+        });
+    }
+
+    Ok(())
+}
+
+#[test]
+fn ffi_search_round_trip() -> Result {
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+
+    let mut song = Song::arbitrary();
+    song.tags.title = Some("Searchable Title".into());
+    let song_key = db.insert_metadata(&song)?;
+    let query = CString::new("searchable")?;
+
+    assert!(unsafe {
+        ffi_expect_search(
+            &db as *const _ as *mut std::ffi::c_void,
+            query.as_ptr(),
+            &song_key as *const Key,
+        )
+    });
+
+    Ok(())
+}
+
+#[test]
+fn ffi_scan_library_with_callback() -> Result {
+    let music_dir = tempfile::tempdir()?;
+    let tree = SkeletonFileTree {
+        dirs: vec![],
+        files: 4,
+    };
+    tree.generate_file_structure(music_dir.path())?;
+    let path = CString::new(music_dir.path().to_str().expect("temp path is valid utf-8"))?;
+
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+    let db_ptr = &db as *const _ as *mut std::ffi::c_void;
+
+    let mut files_tagged = 0;
+    let mut summary = ffi::CScanSummary::default();
+    let mut failure_code = ErrorCode::None;
+    assert!(!unsafe {
+        ffi_scan_library_counting(
+            db_ptr,
+            path.as_ptr(),
+            1,
+            &mut files_tagged,
+            &mut summary,
+            &mut failure_code,
+        )
+    });
+    assert_eq!(last_error_code(), ErrorCode::Cancelled);
+    assert_eq!(db.scan_songs().count(), 0);
+
+    // Not really a flac, so its tags can't be read.
+    std::fs::write(music_dir.path().join("junk.flac"), b"not a flac")?;
+    assert!(unsafe {
+        ffi_scan_library_counting(
+            db_ptr,
+            path.as_ptr(),
+            0,
+            &mut files_tagged,
+            &mut summary,
+            &mut failure_code,
+        )
+    });
+    assert_eq!(files_tagged, 4);
+    assert_eq!(db.scan_songs().count(), 4);
+    assert_eq!(
+        summary,
+        ffi::CScanSummary {
+            added: 4,
+            failed: 1,
+            ..Default::default()
+        }
+    );
+    assert_eq!(failure_code, ErrorCode::TagRead);
+
+    Ok(())
+}
+
+#[test]
+fn ffi_reports_last_error() -> Result {
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+
+    let missing_key = AlbumTags::arbitrary().hash_key();
+    assert!(unsafe {
+        ffi_expect_last_error(
+            &db as *const _ as *mut std::ffi::c_void,
+            &missing_key as *const Key,
+        )
+    });
+
+    Ok(())
+}
+
+#[test]
+fn ffi_watch_library_start_stop_via_shim() -> Result {
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path().join("db"))?;
+    let path = CString::new(temp_dir.path().to_str().expect("temp path is valid utf-8"))?;
+    let missing_path = CString::new(
+        temp_dir
+            .path()
+            .join("missing")
+            .to_str()
+            .expect("temp path is valid utf-8"),
+    )?;
+
+    assert!(unsafe {
+        ffi_watch_library_start_stop(
+            &db as *const _ as *mut std::ffi::c_void,
+            path.as_ptr(),
+            missing_path.as_ptr(),
+        )
+    });
+
+    Ok(())
+}
+
+#[test]
+fn ffi_album_art_round_trip() -> Result {
+    let music_dir = tempfile::tempdir()?;
+    let all_tags = SkeletonFileTree {
+        dirs: vec![],
+        files: 1,
+    }
+    .generate_file_structure(music_dir.path())?;
+    let album_key = all_tags[0].0.hash_key();
+
+    let temp_dir = tempfile::tempdir()?;
+    let db = std::sync::Arc::new(sled::open(temp_dir.path())?);
+    let db_ptr = &*db as *const _ as *mut std::ffi::c_void;
+    scan_library(std::sync::Arc::clone(&db), music_dir.path())?;
+    assert!(unsafe {
+        ffi_expect_album_art(db_ptr, &album_key, std::ptr::null(), 0, std::ptr::null())
+    });
+
+    // File timestamps come from a coarser clock than SystemTime::now, so give them a moment to pass it.
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let cover = b"\x89PNG cover";
+    embed_picture(&music_dir.path().join("0.mp3"), "image/png", cover)?;
+    scan_library(std::sync::Arc::clone(&db), music_dir.path())?;
+    let mime_type = CString::new("image/png")?;
+    assert!(unsafe {
+        ffi_expect_album_art(
+            db_ptr,
+            &album_key,
+            cover.as_ptr(),
+            cover.len(),
+            mime_type.as_ptr(),
+        )
+    });
+
+    Ok(())
+}
+
+#[test]
+fn ffi_library_root_round_trip_via_shim() -> Result {
+    let music_dir = tempfile::tempdir()?;
+    SkeletonFileTree {
+        dirs: vec![],
+        files: 3,
+    }
+    .generate_file_structure(music_dir.path())?;
+    let path = CString::new(music_dir.path().to_str().expect("temp path is valid utf-8"))?;
+
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+
+    let mut albums_scanned = 0;
+    assert!(unsafe {
+        ffi_library_root_round_trip(
+            &db as *const _ as *mut std::ffi::c_void,
+            path.as_ptr(),
+            &mut albums_scanned,
+        )
+    });
+    assert_eq!(albums_scanned, 1);
+    assert_eq!(db.scan_songs().count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn ffi_album_grouping_round_trip_via_shim() -> Result {
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+
+    let tags = AlbumTags::arbitrary();
+    let split = AlbumTags {
+        year: None,
+        ..tags.clone()
+    };
+    for tags in [&tags, &tags, &split] {
+        let song = Song::arbitrary();
+        album_upsert(&db, tags, &song, &db.insert_metadata(&song)?)?;
+    }
+
+    let mut merged = 0;
+    assert!(unsafe {
+        ffi_album_grouping_round_trip(&db as *const _ as *mut std::ffi::c_void, &mut merged)
+    });
+    assert_eq!(
+        merged,
+        usize::from(tags.year.is_some() && tags.title.is_some())
+    );
+    assert!(matches!(db.album_grouping()?, AlbumGrouping::Directory));
+
+    Ok(())
+}
+
+#[test]
+fn ffi_song_by_mbid_via_shim() -> Result {
+    let music_dir = tempfile::tempdir()?;
+    let all_tags = SkeletonFileTree {
+        dirs: vec![],
+        files: 1,
+    }
+    .generate_file_structure(music_dir.path())?;
+    let path = all_tags[0].1.path();
+    let recording_id = "b1a9c0e9-d987-4042-ae91-78d6a3267d69";
+    add_musicbrainz_ufid(&path, recording_id)?;
+    add_extended_text(&path, "REPLAYGAIN_TRACK_GAIN", "-7.25 dB")?;
+
+    let temp_dir = tempfile::tempdir()?;
+    let db = std::sync::Arc::new(sled::open(temp_dir.path())?);
+    scan_library(std::sync::Arc::clone(&db), music_dir.path())?;
+
+    let (key, mut song) = db.song_by_mbid(recording_id)?;
+    song.relpath = db.song_path(&song)?.into_os_string().into_encoded_bytes();
+    let mut expected: ffi::CSong = song.into();
+    let recording_id = CString::new(recording_id)?;
+
+    assert!(unsafe {
+        ffi_expect_song_by_mbid(
+            &*db as *const _ as *mut std::ffi::c_void,
+            recording_id.as_ptr(),
+            &key,
+            &expected,
+        )
+    });
+    assert!(expected.replay_gain.has_track_gain);
+    assert_eq!(expected.replay_gain.track_gain, -7.25);
+
+    unsafe { free_song(&mut expected) };
+
+    Ok(())
+}
+
+#[test]
+fn ffi_user_data_round_trip_via_shim() -> Result {
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+    let song_key = db.insert_metadata(&Song::arbitrary())?;
+
+    assert!(unsafe {
+        ffi_user_data_round_trip(&db as *const _ as *mut std::ffi::c_void, &song_key)
+    });
+    let data = db.user_data(&song_key)?;
+    assert_eq!(
+        (data.rating, data.play_count, data.favourite),
+        (Some(4), 1, true)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ffi_playlist_round_trip_via_shim() -> Result {
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+    // Arbitrary paths can hold a nul byte, which C strings can't.
+    let song = Song {
+        relpath: b"kept.mp3".to_vec(),
+        ..Song::arbitrary()
+    };
+    let song_key = db.insert_metadata(&song)?;
+    let gone_key = db.insert_metadata(&Song::arbitrary())?;
+    let playlist_key = db.create_playlist("mixtape")?;
+    db.insert_into_playlist(&playlist_key, 0, &[song_key.clone(), gone_key.clone()])?;
+    db.remove(&gone_key)?;
+
+    let export_path = temp_dir.path().join("mixtape.m3u8");
+    let c_path =
+        |path: &std::path::Path| CString::new(path.to_str().expect("temp path is valid utf-8"));
+    let (export_c_path, unknown_c_path) = (
+        c_path(&export_path)?,
+        c_path(&temp_dir.path().join("mixtape.txt"))?,
+    );
+    assert!(unsafe {
+        ffi_playlist_round_trip(
+            &db as *const _ as *mut std::ffi::c_void,
+            &playlist_key,
+            &song_key,
+            &gone_key,
+            export_c_path.as_ptr(),
+            unknown_c_path.as_ptr(),
+        )
+    });
+    let playlist = db.playlist(&playlist_key)?;
+    assert_eq!(playlist.name, "renamed");
+    assert_eq!(
+        playlist.song_keys().collect::<Vec<_>>(),
+        [song_key.clone(), song_key]
+    );
+    assert!(std::fs::read_to_string(&export_path)?.starts_with("#EXTM3U\n"));
+
+    Ok(())
+}
+
+#[test]
+fn ffi_query_songs_round_trip_via_shim() -> Result {
+    let temp_dir = tempfile::tempdir()?;
+    let db = sled::open(temp_dir.path())?;
+    let mut album = Album::arbitrary();
+    album.songs.push(Song::arbitrary());
+    // Arbitrary paths can hold a nul byte, which C strings can't.
+    album.songs[0].relpath = b"played.mp3".to_vec();
+    db.insert_metadata(&album)?;
+    let played_key = album.songs[0].hash_key();
+    db.record_play(&played_key)?;
+
+    assert!(unsafe {
+        ffi_query_songs_round_trip(
+            &db as *const _ as *mut std::ffi::c_void,
+            &played_key,
+            album.songs.len(),
+        )
+    });
+    assert!(db.smart_playlists()?.is_empty());
+
+    Ok(())
+}
@@ -3,6 +3,23 @@
 #include <stdint.h>
 #include <string.h>
 
+static bool strings_match(const char *actual, const char *expected) {
+  return (expected == NULL && actual == NULL) ||
+         (expected != NULL && actual != NULL && strcmp(actual, expected) == 0);
+}
+
+static bool numbers_match(bool has_actual, uint64_t actual, bool has_expected,
+                          uint64_t expected) {
+  return (!has_expected && !has_actual) ||
+         (has_expected && has_actual && actual == expected);
+}
+
+static bool gains_match(bool has_actual, float actual, bool has_expected,
+                        float expected) {
+  return (!has_expected && !has_actual) ||
+         (has_expected && has_actual && actual == expected);
+}
+
 bool ffi_open_db_round_trip(const char *path) {
   db *handle = NULL;
   if (!open_db(path, &handle) || handle == NULL) {
@@ -28,21 +45,17 @@ bool ffi_expect_album_tags(db *db, const Key *album_key,
 
   bool result = album_tags_for_key(db, album_key, &tags);
 
-  result &= (expected->artist == NULL && tags.artist == NULL) ||
-            (expected->artist != NULL && tags.artist != NULL &&
-             strcmp(tags.artist, expected->artist) == 0);
-
-  result &= (expected->title == NULL && tags.title == NULL) ||
-            (expected->title != NULL && tags.title != NULL &&
-             strcmp(tags.title, expected->title) == 0);
-
-  result &=
-      (!expected->has_year && !tags.has_year) ||
-      (expected->has_year && tags.has_year && tags.year == expected->year);
+  result &= strings_match(tags.artist, expected->artist);
+  result &= strings_match(tags.title, expected->title);
+  result &= numbers_match(tags.has_year, tags.year, expected->has_year,
+                          expected->year);
+  result &= strings_match(tags.genre, expected->genre);
+  result &= numbers_match(tags.has_total_discs, tags.total_discs,
+                          expected->has_total_discs, expected->total_discs);
 
   free_album_tags(&tags);
 
-  result &= tags.artist == NULL && tags.title == NULL;
+  result &= tags.artist == NULL && tags.title == NULL && tags.genre == NULL;
 
   return result;
 }
@@ -52,18 +65,63 @@ bool ffi_expect_song_tags(const Song *song, const Song *expected) {
     return false;
   }
 
-  bool result = (expected->tags.title == NULL && song->tags.title == NULL) ||
-                (expected->tags.title != NULL && song->tags.title != NULL &&
-                 strcmp(song->tags.title, expected->tags.title) == 0);
+  const SongTags *tags = &song->tags;
+  const SongTags *expected_tags = &expected->tags;
+
+  bool result = strings_match(tags->title, expected_tags->title);
+  result &= strings_match(tags->artist, expected_tags->artist);
+  result &= strings_match(tags->genre, expected_tags->genre);
+  result &= numbers_match(tags->has_track_number, tags->track_number,
+                          expected_tags->has_track_number,
+                          expected_tags->track_number);
+  result &= numbers_match(tags->has_total_tracks, tags->total_tracks,
+                          expected_tags->has_total_tracks,
+                          expected_tags->total_tracks);
+  result &= numbers_match(tags->has_disc_number, tags->disc_number,
+                          expected_tags->has_disc_number,
+                          expected_tags->disc_number);
+  result &= numbers_match(tags->has_total_discs, tags->total_discs,
+                          expected_tags->has_total_discs,
+                          expected_tags->total_discs);
+  result &= strings_match(tags->composer, expected_tags->composer);
+  result &= strings_match(tags->comment, expected_tags->comment);
 
-  result &=
-      (!expected->tags.has_track_number && !song->tags.has_track_number) ||
-      (expected->tags.has_track_number && song->tags.has_track_number &&
-       song->tags.track_number == expected->tags.track_number);
+  result &= strings_match(song->relpath, expected->relpath);
 
-  result &= (expected->relpath == NULL && song->relpath == NULL) ||
-            (expected->relpath != NULL && song->relpath != NULL &&
-             strcmp(song->relpath, expected->relpath) == 0);
+  const StreamInfo *stream = &song->stream;
+  const StreamInfo *expected_stream = &expected->stream;
+  result &= numbers_match(stream->has_duration_ms, stream->duration_ms,
+                          expected_stream->has_duration_ms,
+                          expected_stream->duration_ms);
+  result &= numbers_match(stream->has_bitrate, stream->bitrate,
+                          expected_stream->has_bitrate,
+                          expected_stream->bitrate);
+  result &= numbers_match(stream->has_sample_rate, stream->sample_rate,
+                          expected_stream->has_sample_rate,
+                          expected_stream->sample_rate);
+  result &= numbers_match(stream->has_bit_depth, stream->bit_depth,
+                          expected_stream->has_bit_depth,
+                          expected_stream->bit_depth);
+  result &= numbers_match(stream->has_channels, stream->channels,
+                          expected_stream->has_channels,
+                          expected_stream->channels);
+
+  const MusicBrainzIds *ids = &song->musicbrainz;
+  const MusicBrainzIds *expected_ids = &expected->musicbrainz;
+  result &= strings_match(ids->recording_id, expected_ids->recording_id);
+  result &= strings_match(ids->release_id, expected_ids->release_id);
+  result &= strings_match(ids->artist_id, expected_ids->artist_id);
+
+  const ReplayGain *gain = &song->replay_gain;
+  const ReplayGain *expected_gain = &expected->replay_gain;
+  result &= gains_match(gain->has_track_gain, gain->track_gain,
+                        expected_gain->has_track_gain, expected_gain->track_gain);
+  result &= gains_match(gain->has_track_peak, gain->track_peak,
+                        expected_gain->has_track_peak, expected_gain->track_peak);
+  result &= gains_match(gain->has_album_gain, gain->album_gain,
+                        expected_gain->has_album_gain, expected_gain->album_gain);
+  result &= gains_match(gain->has_album_peak, gain->album_peak,
+                        expected_gain->has_album_peak, expected_gain->album_peak);
 
   return result;
 }
@@ -79,6 +137,7 @@ bool ffi_expect_album(db *db, const Key *album_key, const Album *expected) {
   result &= ffi_expect_album_tags(db, album_key, &expected->tags);
 
   result &= expected->song_count == album.song_count;
+  result &= expected->duration_ms == album.duration_ms;
 
   if (expected->song_count > 0 && album.song_count > 0) {
     if (expected->songs == NULL || album.songs == NULL) {
@@ -122,3 +181,406 @@ bool ffi_expect_scan_album_tags_sorted(db *db, size_t expected_len) {
 
   return result;
 }
+
+bool ffi_expect_scan_artists_sorted(db *db, size_t expected_len) {
+  if (db == NULL) {
+    return false;
+  }
+
+  ArtistWithKey *artists = NULL;
+  size_t len = 0;
+
+  bool result = scan_artists_sorted(db, &artists, &len);
+  result &= len == expected_len;
+  result &= (len == 0) == (artists == NULL);
+
+  for (size_t i = 0; i < len; ++i) {
+    result &= artists[i].key._tag == KeyType_Artist;
+    result &= artists[i].album_count > 0;
+  }
+
+  if (artists != NULL) {
+    free_artists_sorted(artists, len);
+  }
+
+  return result;
+}
+
+bool ffi_expect_albums_for_artist(db *db, const Key *artist_key,
+                                  size_t expected_len) {
+  if (db == NULL || artist_key == NULL) {
+    return false;
+  }
+
+  AlbumTagsWithKey *albums = NULL;
+  size_t len = 0;
+
+  bool result = albums_for_artist(db, artist_key, &albums, &len);
+  result &= len == expected_len;
+  result &= (len == 0) == (albums == NULL);
+
+  for (size_t i = 0; i < len; ++i) {
+    result &= albums[i].key._tag == KeyType_Album;
+  }
+
+  if (albums != NULL) {
+    free_album_tags_sorted(albums, len);
+  }
+
+  return result;
+}
+
+bool ffi_expect_search(db *db, const char *query, const Key *expected_song) {
+  if (db == NULL || query == NULL || expected_song == NULL) {
+    return false;
+  }
+
+  SearchResults results = {0};
+  bool result = search(db, query, 10, &results);
+
+  result &= results.song_count == 1 && results.songs != NULL &&
+            memcmp(&results.songs[0], expected_song, sizeof(Key)) == 0;
+  result &= results.album_count == 0 && results.albums == NULL;
+
+  free_search_results(&results);
+
+  result &= results.songs == NULL && results.song_count == 0;
+
+  return result;
+}
+
+typedef struct ScanCounter {
+  size_t cancel_after;
+  size_t events;
+  size_t files_tagged;
+  size_t errors;
+  ErrorCode failure_code;
+} ScanCounter;
+
+static bool count_scan_progress(const ScanProgress *progress,
+                                void *user_data) {
+  ScanCounter *counter = user_data;
+  counter->events += 1;
+  if (progress->files_tagged > counter->files_tagged) {
+    counter->files_tagged = progress->files_tagged;
+  }
+  if (progress->errors > counter->errors) {
+    counter->errors = progress->errors;
+    counter->failure_code = last_error_code();
+  }
+  return counter->cancel_after == 0 || counter->events < counter->cancel_after;
+}
+
+// Scans `path`, cancelling after `cancel_after` progress events (0 never
+// cancels). `failure_code` is the code the callback saw for the last failure.
+bool ffi_scan_library_counting(db *db, const char *path, size_t cancel_after,
+                               size_t *files_tagged, ScanSummary *summary,
+                               ErrorCode *failure_code) {
+  ScanCounter counter = {cancel_after, 0, 0, 0, ErrorCode_None};
+  bool result = scan_library_with_callback(db, path, count_scan_progress,
+                                           &counter, summary);
+  *files_tagged = counter.files_tagged;
+  *failure_code = counter.failure_code;
+  return result;
+}
+
+static bool art_matches(AlbumArt *art, const uint8_t *expected,
+                        size_t expected_len, const char *expected_mime_type) {
+  bool result = art->len == expected_len;
+  result &= strings_match(art->mime_type, expected_mime_type);
+  if (expected == NULL) {
+    result &= art->data == NULL;
+  } else {
+    result &= art->data != NULL && art->len == expected_len &&
+              memcmp(art->data, expected, expected_len) == 0;
+  }
+
+  free_album_art(art);
+  result &= art->data == NULL && art->mime_type == NULL;
+  return result;
+}
+
+// The art is expected to be too small or too malformed to scale, so its
+// thumbnail is the art itself.
+bool ffi_expect_album_art(db *db, const Key *album_key,
+                          const uint8_t *expected, size_t expected_len,
+                          const char *expected_mime_type) {
+  AlbumArt art = {0};
+  bool result = album_art_for_key(db, album_key, &art);
+  result &= art_matches(&art, expected, expected_len, expected_mime_type);
+
+  AlbumArt thumbnail = {0};
+  result &= album_thumbnail_for_key(db, album_key, 64, &thumbnail);
+  result &= art_matches(&thumbnail, expected, expected_len, expected_mime_type);
+  return result;
+}
+
+// Looks up a key that isn't in the db, then passes a null argument, checking
+// the error reported for each.
+bool ffi_expect_last_error(db *db, const Key *missing_key) {
+  AlbumTags tags = {0};
+
+  bool result = !album_tags_for_key(db, missing_key, &tags);
+  result &= last_error_code() == ErrorCode_NotFound;
+  result &= strlen(last_error_message()) > 0;
+
+  result &= !album_tags_for_key(db, NULL, &tags);
+  result &= last_error_code() == ErrorCode_InvalidArgument;
+
+  return result;
+}
+
+// Starts and stops a watcher on `path`, and checks a missing directory is
+// refused with an I/O error.
+bool ffi_watch_library_start_stop(db *db, const char *path,
+                                  const char *missing_path) {
+  library_watcher *watcher = NULL;
+  if (!watch_library(db, path, 100, NULL, NULL, &watcher) || watcher == NULL) {
+    return false;
+  }
+  stop_watching(watcher);
+
+  watcher = NULL;
+  bool result = !watch_library(db, missing_path, 100, NULL, NULL, &watcher);
+  result &= watcher == NULL && last_error_code() == ErrorCode_Io;
+  return result;
+}
+
+// Adds a root at `path`, scans it, checks it's listed, then removes it along
+// with its songs.
+bool ffi_library_root_round_trip(db *db, const char *path,
+                                 size_t *albums_scanned) {
+  bool result = set_library_root(db, "music", path);
+  result &= scan_library_root_with_callback(db, "music", NULL, NULL, NULL);
+
+  LibraryRoot *roots = NULL;
+  size_t len = 0;
+  result &= library_roots(db, &roots, &len);
+  result &= len == 1 && roots != NULL;
+  if (len == 1 && roots != NULL) {
+    result &= strings_match(roots[0].name, "music");
+    result &= strings_match(roots[0].path, path);
+  }
+  free_library_roots(roots, len);
+
+  ScanCounter counter = {0, 0, 0, 0, ErrorCode_None};
+  result &= !scan_library_root_with_callback(db, "missing", count_scan_progress,
+                                             &counter, NULL);
+  result &= last_error_code() == ErrorCode_NotFound;
+
+  AlbumTagsWithKey *albums = NULL;
+  result &= scan_album_tags_sorted(db, &albums, albums_scanned);
+  free_album_tags_sorted(albums, *albums_scanned);
+
+  result &= remove_library_root(db, "music");
+  result &= library_roots(db, &roots, &len) && len == 0 && roots == NULL;
+  return result;
+}
+
+// Switches the db to grouping by directory, then folds split albums together.
+bool ffi_album_grouping_round_trip(db *db, size_t *merged) {
+  AlbumGrouping grouping = AlbumGrouping_Tags;
+  bool result = album_grouping(db, &grouping);
+  result &= grouping == AlbumGrouping_Tags;
+
+  result &= set_album_grouping(db, AlbumGrouping_Directory);
+  result &= album_grouping(db, &grouping);
+  result &= grouping == AlbumGrouping_Directory;
+
+  result &= !reconcile_albums(db, NULL);
+  result &= last_error_code() == ErrorCode_InvalidArgument;
+  result &= reconcile_albums(db, merged);
+  return result;
+}
+
+// Looks the song up by `recording_id`, then checks an unknown ID isn't found.
+bool ffi_expect_song_by_mbid(db *db, const char *recording_id,
+                             const Key *expected_key, const Song *expected) {
+  Key key = {0};
+  Song song = {0};
+  bool result = song_by_mbid(db, recording_id, &key, &song);
+  result &= memcmp(&key, expected_key, sizeof(Key)) == 0;
+  result &= ffi_expect_song_tags(&song, expected);
+
+  free_song(&song);
+  result &= song.relpath == NULL && song.musicbrainz.recording_id == NULL;
+  result &= !song.replay_gain.has_track_gain;
+
+  result &= !song_by_mbid(db, "not-a-recording", &key, &song);
+  result &= last_error_code() == ErrorCode_NotFound;
+  result &= !song_by_mbid(db, NULL, &key, &song);
+  result &= last_error_code() == ErrorCode_InvalidArgument;
+  return result;
+}
+
+// Rates, plays and favourites the song, then checks it tops the most played.
+bool ffi_user_data_round_trip(db *db, const Key *song_key) {
+  UserData data = {0};
+  bool result = user_data_for_key(db, song_key, &data);
+  result &= !data.has_rating && data.play_count == 0 && !data.favourite;
+
+  result &= set_rating(db, song_key, 4, NULL);
+  result &= !set_rating(db, song_key, 6, NULL);
+  result &= last_error_code() == ErrorCode_InvalidArgument;
+  result &= set_favourite(db, song_key, true, NULL);
+  result &= record_play(db, song_key, &data);
+  result &= data.has_rating && data.rating == 4 && data.play_count == 1;
+  result &= data.has_last_played_ms && data.favourite;
+
+  UserDataWithKey *entries = NULL;
+  size_t len = 0;
+  result &= most_played(db, 10, &entries, &len);
+  result &= len == 1 && entries != NULL;
+  if (len == 1 && entries != NULL) {
+    result &= memcmp(&entries[0].key, song_key, sizeof(Key)) == 0;
+    result &= entries[0].data.play_count == 1;
+  }
+  free_user_data_sorted(entries, len);
+
+  result &= recently_played(db, 0, &entries, &len);
+  result &= len == 0 && entries == NULL;
+  return result;
+}
+
+bool ffi_playlist_round_trip(db *db, const Key *playlist_key,
+                             const Key *song_key, const Key *gone_key,
+                             const char *export_path,
+                             const char *unknown_path) {
+  // The second entry's song has left the library.
+  Playlist playlist = {0};
+  bool result = playlist_for_key(db, playlist_key, &playlist);
+  result &= strings_match(playlist.name, "mixtape");
+  result &= playlist.entry_count == 2 && playlist.entries != NULL;
+  if (playlist.entry_count == 2 && playlist.entries != NULL) {
+    result &= memcmp(&playlist.entries[0].key, song_key, sizeof(Key)) == 0;
+    result &= playlist.entries[0].has_song;
+    result &= playlist.entries[0].song.relpath != NULL;
+    result &= memcmp(&playlist.entries[1].key, gone_key, sizeof(Key)) == 0;
+    result &= !playlist.entries[1].has_song;
+    result &= playlist.entries[1].song.relpath == NULL;
+  }
+  free_playlist(&playlist);
+  result &= playlist.name == NULL && playlist.entries == NULL;
+
+  result &= !insert_into_playlist(db, playlist_key, 0, gone_key, 1);
+  result &= last_error_code() == ErrorCode_NotFound;
+  result &= insert_into_playlist(db, playlist_key, 2, song_key, 1);
+  result &= move_in_playlist(db, playlist_key, 2, 0);
+  result &= remove_from_playlist(db, playlist_key, 2);
+  result &= !remove_from_playlist(db, playlist_key, 2);
+  result &= last_error_code() == ErrorCode_InvalidArgument;
+  result &= rename_playlist(db, playlist_key, "renamed");
+
+  size_t written = 0;
+  result &= export_playlist(db, playlist_key, export_path, &written);
+  result &= written == 2;
+  result &= !export_playlist(db, playlist_key, unknown_path, NULL);
+  result &= last_error_code() == ErrorCode_InvalidArgument;
+
+  Key scratch_key;
+  result &= create_playlist(db, "scratch", &scratch_key);
+  PlaylistWithKey *playlist_list = NULL;
+  size_t len = 0;
+  result &= playlists(db, &playlist_list, &len);
+  result &= len == 2 && playlist_list != NULL;
+  if (len == 2 && playlist_list != NULL) {
+    result &= strings_match(playlist_list[0].name, "renamed");
+    result &= playlist_list[0].entry_count == 2;
+    result &= strings_match(playlist_list[1].name, "scratch");
+    result &= playlist_list[1].entry_count == 0;
+  }
+  free_playlists(playlist_list, len);
+  result &= delete_playlist(db, &scratch_key);
+  result &= !playlist_for_key(db, &scratch_key, &playlist);
+  result &= last_error_code() == ErrorCode_NotFound;
+  return result;
+}
+
+bool ffi_query_songs_round_trip(db *db, const Key *played_key,
+                                size_t song_count) {
+  SongWithKey *songs = NULL;
+  size_t len = 0;
+  bool result = query_songs(db, "", NULL, &songs, &len);
+  result &= len == song_count && songs != NULL;
+  free_songs_with_key(songs, len);
+
+  result &= query_songs(db, "plays > 0 sort by title", NULL, &songs,
+                        &len);
+  result &= len == 1 && songs != NULL;
+  if (len == 1 && songs != NULL) {
+    result &= memcmp(&songs[0].key, played_key, sizeof(Key)) == 0;
+    result &= songs[0].song.relpath != NULL;
+  }
+  free_songs_with_key(songs, len);
+
+  result &= !query_songs(db, "plays >", NULL, &songs, &len);
+  result &= last_error_code() == ErrorCode_InvalidArgument;
+  result &= strstr(last_error_message(), "at 7") != NULL;
+  result &= len == 0 && songs == NULL;
+
+  Key *keys = NULL;
+  result &= query_song_keys(db, "sort by title", NULL, &keys, &len);
+  result &= len == song_count && keys != NULL;
+  if (len == song_count && keys != NULL) {
+    Key *rest = NULL;
+    size_t rest_len = 0;
+    result &= query_song_keys(db, "sort by title", &keys[0], &rest, &rest_len);
+    result &= rest_len == song_count - 1 && rest != NULL;
+    if (rest_len > 0 && rest != NULL) {
+      result &= memcmp(&rest[0], &keys[1], sizeof(Key)) == 0;
+    }
+    free_keys(rest, rest_len);
+    result &= query_songs(db, "sort by title limit 1 offset 1", NULL, &songs,
+                          &len);
+    result &= len == 1 && songs != NULL;
+    if (len == 1 && songs != NULL) {
+      result &= memcmp(&songs[0].key, &keys[1], sizeof(Key)) == 0;
+    }
+    free_songs_with_key(songs, len);
+  }
+  free_keys(keys, song_count);
+
+  AlbumTagsWithKey *albums = NULL;
+  result &= query_albums(db, "track_count >= 1", NULL, &albums, &len);
+  result &= len == 1 && albums != NULL;
+  free_album_tags_sorted(albums, len);
+  result &= query_album_keys(db, "", NULL, &keys, &len);
+  result &= len == 1 && keys != NULL;
+  if (len == 1 && keys != NULL) {
+    uint64_t added_ms = 1;
+    result &= date_added(db, &keys[0], &added_ms);
+    Key *rest = NULL;
+    size_t rest_len = 1;
+    result &= query_album_keys(db, "", &keys[0], &rest, &rest_len);
+    result &= rest_len == 0 && rest == NULL;
+  }
+  free_keys(keys, len);
+  result &= !query_albums(db, "album = x", NULL, &albums, &len);
+  result &= last_error_code() == ErrorCode_InvalidArgument;
+
+  Key playlist_key;
+  result &= !create_smart_playlist(db, "broken", "plays", &playlist_key);
+  result &= create_smart_playlist(db, "played", "plays >= 1", &playlist_key);
+  result &= smart_playlist_songs(db, &playlist_key, &songs, &len);
+  result &= len == 1;
+  free_songs_with_key(songs, len);
+
+  result &= update_smart_playlist(db, &playlist_key, "unplayed", "plays = 0");
+  SmartPlaylistWithKey *playlists = NULL;
+  result &= smart_playlists(db, &playlists, &len);
+  result &= len == 1 && playlists != NULL;
+  if (len == 1 && playlists != NULL) {
+    result &= memcmp(&playlists[0].key, &playlist_key, sizeof(Key)) == 0;
+    result &= strings_match(playlists[0].name, "unplayed");
+    result &= strings_match(playlists[0].query, "plays = 0");
+  }
+  free_smart_playlists(playlists, len);
+  result &= smart_playlist_songs(db, &playlist_key, &songs, &len);
+  result &= len == song_count - 1;
+  free_songs_with_key(songs, len);
+
+  result &= delete_smart_playlist(db, &playlist_key);
+  result &= !smart_playlist_songs(db, &playlist_key, &songs, &len);
+  result &= last_error_code() == ErrorCode_NotFound;
+  return result;
+}
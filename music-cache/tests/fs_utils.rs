@@ -4,9 +4,15 @@ use std::{fs::File, path::Path};
 
 use audiotags::Tag;
 
-use id3::{Tag as ID3Tag, TagLike, Version};
+use id3::{
+    frame::{Comment, Content, ExtendedText, Frame, Picture, PictureType, Unknown},
+    Tag as ID3Tag, TagLike, Version,
+};
 use music_cache::tests::common::*;
-use music_cache::{AlbumTags, Song, SongTags};
+use music_cache::{
+    AlbumTags, AudioFormat, Codec, Container, MusicBrainzIds, ReplayGain, Song, SongTags,
+    StreamInfo,
+};
 use tempfile::tempdir;
 
 #[test]
@@ -16,7 +22,7 @@ fn test_round_trip_dummy_mp3() -> Result {
     let temp_path = dir.path().join("music.mp3");
 
     let album = AlbumTags::arbitrary();
-    let song_tags = SongTags::arbitrary();
+    let song_tags = song_tags_for_album(&album);
 
     write_tags_to_path(&temp_path, &album, &song_tags)?;
     check_tags_from_path(&temp_path, &album, &song_tags)?;
@@ -34,7 +40,7 @@ impl SkeletonFileTree {
     pub fn generate_file_structure(
         &self,
         path: &Path,
-    ) -> music_cache::Result<Vec<(AlbumTags, Song)>> {
+    ) -> std::result::Result<Vec<(AlbumTags, Song)>, Box<dyn std::error::Error>> {
         let mut tags = Vec::new();
 
         for (i, dir) in self.dirs.iter().enumerate() {
@@ -43,14 +49,27 @@ impl SkeletonFileTree {
             let dir_tags = dir.generate_file_structure(&new_path)?;
             tags.extend(dir_tags);
         }
-        let album_tags = AlbumTags::arbitrary();
+        // Albums without any identifying tags share a key, so make sure each directory's album is distinct.
+        let mut album_tags = AlbumTags::arbitrary();
+        album_tags
+            .title
+            .get_or_insert_with(|| path.to_string_lossy().into_owned());
         for file in 0..self.files {
             let new_path = path.join(file.to_string() + ".mp3");
-            let song_tags = SongTags::arbitrary();
+            let song_tags = song_tags_for_album(&album_tags);
+            let frames = rand::random::<usize>() % 8 + 2;
+            std::fs::write(&new_path, dummy_audio(frames))?;
             write_tags_to_path(&new_path, &album_tags, &song_tags)?;
             let song = Song {
                 tags: song_tags,
+                root: String::new(),
                 relpath: new_path.into_os_string().into_encoded_bytes(),
+                fingerprint: None,
+                format: Some(AudioFormat::new(Container::Mpeg, Some(Codec::Mp3))),
+                stream: dummy_stream_info(frames),
+                musicbrainz: MusicBrainzIds::default(),
+                replay_gain: ReplayGain::default(),
+                file: None,
             };
             tags.push((album_tags.clone(), song));
         }
@@ -58,10 +77,47 @@ impl SkeletonFileTree {
     }
 }
 
+// A single file holds one genre and one disc count, so the song and album tags have to agree on them.
+fn song_tags_for_album(album_tags: &AlbumTags) -> SongTags {
+    let mut song_tags = SongTags::arbitrary();
+    song_tags.genre.clone_from(&album_tags.genre);
+    song_tags.total_discs = album_tags.total_discs;
+    if song_tags.total_discs.is_some() {
+        song_tags.disc_number = song_tags.disc_number.or(Some(1));
+    }
+    song_tags
+}
+
+// MPEG 1 layer III at 128kbps, 44.1kHz, joint stereo, no padding.
+pub const DUMMY_FRAME_HEADER: [u8; 4] = [0xFF, 0xFB, 0x90, 0x64];
+pub const DUMMY_FRAME_LEN: usize = 417;
+
+// Constant bitrate frames filled with noise, so each file has a fingerprint of its own and a length
+// that can be worked out from its size.
+pub fn dummy_audio(frames: usize) -> Vec<u8> {
+    (0..frames)
+        .flat_map(|_| {
+            let noise = (4..DUMMY_FRAME_LEN).map(|_| rand::random::<u8>());
+            DUMMY_FRAME_HEADER.into_iter().chain(noise)
+        })
+        .collect()
+}
+
+pub fn dummy_stream_info(frames: usize) -> StreamInfo {
+    StreamInfo {
+        duration_ms: Some((frames * DUMMY_FRAME_LEN * 8 / 128) as u64),
+        bitrate: Some(128),
+        sample_rate: Some(44100),
+        bit_depth: None,
+        channels: Some(2),
+    }
+}
+
 // id3 is here is because it allows writing to an empty file. audiotags does not.
 // would otherwise need to keep a dummy mp3 file and constantly copy it around.
-fn write_tags_to_path(path: &Path, album_tags: &AlbumTags, song_tags: &SongTags) -> Result {
-    File::create(path)?;
+pub fn write_tags_to_path(path: &Path, album_tags: &AlbumTags, song_tags: &SongTags) -> Result {
+    // Keeps whatever audio is already there. id3 replaces just the tag.
+    File::options().create(true).append(true).open(path)?;
 
     let mut tag = ID3Tag::new();
 
@@ -81,10 +137,79 @@ fn write_tags_to_path(path: &Path, album_tags: &AlbumTags, song_tags: &SongTags)
         tag.set_year(i32::from(*year));
     }
 
+    if let Some(artist) = &song_tags.artist {
+        tag.set_artist(artist);
+    }
+
+    if let Some(genre) = &song_tags.genre {
+        tag.set_genre(genre);
+    }
+
     if let Some(track_number) = &song_tags.track_number {
         tag.set_track(u32::from(*track_number));
     }
 
+    if let Some(total_tracks) = &song_tags.total_tracks {
+        tag.set_total_tracks(u32::from(*total_tracks));
+    }
+
+    if let Some(disc_number) = &song_tags.disc_number {
+        tag.set_disc(u32::from(*disc_number));
+    }
+
+    if let Some(total_discs) = &song_tags.total_discs {
+        tag.set_total_discs(u32::from(*total_discs));
+    }
+
+    if let Some(composer) = &song_tags.composer {
+        tag.add_frame(Frame::text("TCOM", composer.as_str()));
+    }
+
+    if let Some(comment) = &song_tags.comment {
+        tag.add_frame(Comment {
+            lang: "eng".to_string(),
+            description: String::new(),
+            text: comment.clone(),
+        });
+    }
+
+    tag.write_to_path(path, Version::Id3v24)?;
+    Ok(())
+}
+
+// Adds a front cover to the tag already written to the file.
+pub fn embed_picture(path: &Path, mime_type: &str, data: &[u8]) -> Result {
+    let mut tag = ID3Tag::read_from_path(path)?;
+    tag.add_frame(Picture {
+        mime_type: mime_type.to_string(),
+        picture_type: PictureType::CoverFront,
+        description: String::new(),
+        data: data.to_vec(),
+    });
+    tag.write_to_path(path, Version::Id3v24)?;
+    Ok(())
+}
+
+// Adds a TXXX frame, which is where taggers keep fields ID3 has no frame of its own for.
+pub fn add_extended_text(path: &Path, description: &str, value: &str) -> Result {
+    let mut tag = ID3Tag::read_from_path(path)?;
+    tag.add_frame(ExtendedText {
+        description: description.to_string(),
+        value: value.to_string(),
+    });
+    tag.write_to_path(path, Version::Id3v24)?;
+    Ok(())
+}
+
+// Picard keeps the recording ID in a UFID frame owned by MusicBrainz.
+pub fn add_musicbrainz_ufid(path: &Path, recording_id: &str) -> Result {
+    let mut tag = ID3Tag::read_from_path(path)?;
+    let data = [b"http://musicbrainz.org\0", recording_id.as_bytes()].concat();
+    let content = Content::Unknown(Unknown {
+        data,
+        version: Version::Id3v24,
+    });
+    tag.add_frame(Frame::with_content("UFID", content));
     tag.write_to_path(path, Version::Id3v24)?;
     Ok(())
 }
@@ -2,11 +2,18 @@ use music_cache::{
     tests::{common::Result, Arbitrary},
     *,
 };
-use std::{path::Path, sync::Arc, time::SystemTime};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
 use tempfile::*;
 
 mod fs_utils;
-use fs_utils::SkeletonFileTree;
+use fs_utils::{
+    add_extended_text, add_musicbrainz_ufid, dummy_audio, embed_picture, write_tags_to_path,
+    SkeletonFileTree, DUMMY_FRAME_LEN,
+};
 
 use rand::prelude::*;
 
@@ -39,7 +46,6 @@ fn test_my_library() -> Result {
 #[test]
 fn test_gen_file_tree() -> Result {
     // TODO we should add some junk files inside here too.
-    // And potentially images since album art is sometimes in the dir.
     let dir = tempdir()?;
 
     let tree: SkeletonFileTree = SkeletonFileTree {
@@ -70,10 +76,11 @@ fn test_gen_file_tree() -> Result {
 
     for (album_tags, song) in all_tags {
         let restored_song = tree.get_song_from_path(&song.relpath)?()?;
-        assert!(restored_song.tags == song.tags);
+        assert_eq!(restored_song.tags, song.tags);
+        assert_eq!(restored_song.stream, song.stream);
         let restored_album: Album = tree.get_metadata(&album_tags.hash_key())?;
         assert!(restored_album.songs.contains(&restored_song));
-        assert!(restored_album.tags == album_tags);
+        assert_eq!(restored_album.tags, album_tags);
     }
 
     Ok(())
@@ -94,6 +101,16 @@ fn test_db_round_trip() -> Result {
     Ok(())
 }
 
+#[test]
+fn test_db_missing_key() -> Result {
+    let dir = TempDir::new().unwrap();
+    let tree = sled::open(dir.path()).unwrap();
+    let key = Song::arbitrary().hash_key();
+    let missing: music_cache::Result<Song> = tree.get_metadata(&key);
+    assert!(matches!(missing, Err(Error::NotFound(missing_key)) if missing_key == key));
+    Ok(())
+}
+
 #[test]
 fn test_db_retrieve_song_by_path() -> Result {
     let dir = TempDir::new().unwrap();
@@ -146,31 +163,37 @@ fn test_scan_album_tags_sorted() -> Result {
             artist: Some("Beta Artist".into()),
             title: Some("beta".into()),
             year: Some(1985),
+            ..Default::default()
         },
         AlbumTags {
             artist: Some("alpha artist".into()),
             title: Some("alpha-new".into()),
             year: Some(2000),
+            ..Default::default()
         },
         AlbumTags {
             artist: Some("Alpha Artist".into()),
             title: Some("alpha-old".into()),
             year: Some(1990),
+            ..Default::default()
         },
         AlbumTags {
             artist: Some("123Numbers".into()),
             title: Some("numbers".into()),
             year: Some(1975),
+            ..Default::default()
         },
         AlbumTags {
             artist: None,
             title: Some("no-artist".into()),
             year: Some(1999),
+            ..Default::default()
         },
         AlbumTags {
             artist: Some("Alpha Artist".into()),
             title: Some("alpha-no-year".into()),
             year: None,
+            ..Default::default()
         },
     ];
 
@@ -203,11 +226,12 @@ fn test_scan_album_tags_sorted() -> Result {
 fn test_scan_time() -> Result {
     let dir = TempDir::new()?;
     let tree = sled::open(dir.path())?;
-    tree.set_last_scan_time()?;
-    let time = tree.get_last_scan_time()?;
-    tree.set_last_scan_time()?;
-    let new_time = tree.get_last_scan_time()?;
+    tree.set_last_scan_time("music")?;
+    let time = tree.get_last_scan_time("music")?;
+    tree.set_last_scan_time("music")?;
+    let new_time = tree.get_last_scan_time("music")?;
     assert!(new_time > time);
+    assert_eq!(tree.get_last_scan_time("nas")?, SystemTime::UNIX_EPOCH);
 
     Ok(())
 }
@@ -219,7 +243,8 @@ fn test_album_upsert() -> Result {
     let album_tags = AlbumTags::arbitrary();
     let mut songs: Vec<Song> = (0..10).map(|_| Song::arbitrary()).collect();
     for (i, song) in songs.iter_mut().enumerate() {
-        song.tags.track_number = Some(i as u16);
+        song.tags.disc_number = Some((i / 5) as u16 + 1);
+        song.tags.track_number = Some((i % 5) as u16 + 1);
     }
 
     let mut unordered_songs: Vec<(usize, &mut Song)> = songs.iter_mut().enumerate().collect();
@@ -279,3 +304,2404 @@ fn test_remove_song_from_album() -> Result {
 
     Ok(())
 }
+
+#[test]
+fn test_artist_index() -> Result {
+    let dir = TempDir::new()?;
+    let tree = sled::open(dir.path())?;
+
+    let albums = vec![
+        AlbumTags {
+            artist: Some("alpha artist".into()),
+            title: Some("alpha-new".into()),
+            year: Some(2000),
+            ..Default::default()
+        },
+        AlbumTags {
+            artist: Some("ALPHA  Artist".into()),
+            title: Some("alpha-old".into()),
+            year: Some(1990),
+            ..Default::default()
+        },
+        AlbumTags {
+            artist: Some("Beta Artist".into()),
+            title: Some("beta".into()),
+            year: Some(1985),
+            ..Default::default()
+        },
+    ];
+
+    let mut song_keys = Vec::new();
+    for tags in &albums {
+        let song = Song::arbitrary();
+        let song_key = tree.insert_metadata(&song)?;
+        album_upsert(&tree, tags, &song, &song_key)?;
+        song_keys.push((tags.hash_key(), song_key));
+    }
+
+    let artists: Vec<(Option<String>, usize)> = tree
+        .scan_artists_sorted()?
+        .into_iter()
+        .map(|(_, artist)| (artist.name, artist.album_keys.len()))
+        .collect();
+    assert_eq!(
+        artists,
+        vec![
+            (Some("alpha artist".into()), 2),
+            (Some("Beta Artist".into()), 1),
+        ]
+    );
+
+    let alpha_key = artist_hash_key(Some("ALPHA ARTIST"));
+    let alpha_albums: Vec<Option<String>> = tree
+        .albums_for_artist(&alpha_key)?
+        .into_iter()
+        .map(|(_, tags)| tags.title)
+        .collect();
+    assert_eq!(
+        alpha_albums,
+        vec![Some("alpha-old".into()), Some("alpha-new".into())]
+    );
+
+    for (album_key, song_key) in &song_keys[..2] {
+        remove_song(&tree, album_key, song_key)?;
+    }
+    assert!(tree.get(&alpha_key)?.is_none());
+    assert_eq!(tree.scan_artists_sorted()?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_album_upsert_orders_ties_by_path() -> Result {
+    let dir = TempDir::new()?;
+    let tree = sled::open(dir.path())?;
+    let album_tags = AlbumTags::arbitrary();
+
+    let mut songs: Vec<Song> = (0..4).map(|_| Song::arbitrary()).collect();
+    for (i, song) in songs.iter_mut().enumerate() {
+        song.tags.disc_number = Some(1);
+        song.tags.track_number = None;
+        song.relpath = format!("{}.mp3", 3 - i).into_bytes();
+    }
+
+    for song in &songs {
+        let song_key = tree.insert_metadata(song)?;
+        album_upsert(&tree, &album_tags, song, &song_key)?;
+        // Upserting the same song again, as a rescan would, must not duplicate it.
+        album_upsert(&tree, &album_tags, song, &song_key)?;
+    }
+
+    let restored_album: Album = tree.get_metadata(&album_tags.hash_key())?;
+    let paths: Vec<&[u8]> = restored_album
+        .songs
+        .iter()
+        .map(|song| song.relpath.as_slice())
+        .collect();
+    assert_eq!(
+        paths,
+        vec![&b"0.mp3"[..], &b"1.mp3"[..], &b"2.mp3"[..], &b"3.mp3"[..]]
+    );
+    Ok(())
+}
+
+// The tag layouts from before the full tag model was read.
+#[derive(bitcode::Encode)]
+struct BasicSongTags {
+    title: Option<String>,
+    track_number: Option<u16>,
+}
+
+#[derive(bitcode::Encode)]
+struct BasicAlbumTags {
+    artist: Option<String>,
+    title: Option<String>,
+    year: Option<u16>,
+}
+
+impl From<&AlbumTags> for BasicAlbumTags {
+    fn from(tags: &AlbumTags) -> Self {
+        BasicAlbumTags {
+            artist: tags.artist.clone(),
+            title: tags.title.clone(),
+            year: tags.year,
+        }
+    }
+}
+
+#[derive(bitcode::Encode)]
+struct BasicSong {
+    tags: BasicSongTags,
+    relpath: Vec<u8>,
+}
+
+impl From<&Song> for BasicSong {
+    fn from(song: &Song) -> Self {
+        BasicSong {
+            tags: BasicSongTags {
+                title: song.tags.title.clone(),
+                track_number: song.tags.track_number,
+            },
+            relpath: song.relpath.clone(),
+        }
+    }
+}
+
+#[derive(bitcode::Encode)]
+struct BasicStoredAlbum {
+    tags: BasicAlbumTags,
+    song_keys: Vec<(Option<u16>, ByteKey)>,
+}
+
+#[test]
+fn test_legacy_album_track_order_migration() -> Result {
+    let dir = TempDir::new()?;
+    let tree = sled::open(dir.path())?;
+    // Albums in this format were written with the artist/title/year tag layout.
+    let album_tags = AlbumTags {
+        genre: None,
+        total_discs: None,
+        ..AlbumTags::arbitrary()
+    };
+    let album_key = album_tags.hash_key();
+
+    let mut songs: Vec<Song> = (0..6).map(|_| Song::arbitrary()).collect();
+    for (i, song) in songs.iter_mut().enumerate() {
+        song.tags.disc_number = Some((i / 3) as u16 + 1);
+        song.tags.track_number = Some((i % 3) as u16 + 1);
+    }
+
+    // The legacy format sorted by track number alone, interleaving the two discs.
+    let mut legacy_keys = songs
+        .iter()
+        .map(|song| {
+            Ok((
+                song.tags.track_number,
+                *tree.insert_metadata(song)?.to_byte_key(),
+            ))
+        })
+        .collect::<music_cache::Result<Vec<(Option<u16>, ByteKey)>>>()?;
+    legacy_keys.sort_by_key(|(track_number, _)| *track_number);
+    let legacy = BasicStoredAlbum {
+        tags: BasicAlbumTags::from(&album_tags),
+        song_keys: legacy_keys,
+    };
+    tree.insert(&album_key, bitcode::encode(&legacy))?;
+
+    let song_keys = scan_stored_albums(&tree)?;
+    assert_eq!(song_keys.len(), songs.len());
+
+    let restored_album: Album = tree.get_metadata(&album_key)?;
+    assert_eq!(restored_album.tags, album_tags);
+    assert_eq!(restored_album.songs, songs);
+    Ok(())
+}
+
+#[test]
+fn test_basic_tag_layouts_decode() -> Result {
+    let dir = TempDir::new()?;
+    let tree = sled::open(dir.path())?;
+
+    let song = Song::arbitrary();
+    let song_key = song.hash_key();
+    tree.insert(&song_key, bitcode::encode(&BasicSong::from(&song)))?;
+    let stored: Song = tree.get_metadata(&song_key)?;
+    let expected_tags = SongTags {
+        title: song.tags.title.clone(),
+        track_number: song.tags.track_number,
+        ..Default::default()
+    };
+    assert_eq!(stored, Song::new(expected_tags, &song.relpath));
+
+    let album_tags = AlbumTags::arbitrary();
+    let album = BasicStoredAlbum {
+        tags: BasicAlbumTags::from(&album_tags),
+        song_keys: vec![(song.tags.track_number, *song_key.to_byte_key())],
+    };
+    tree.insert(album_tags.hash_key(), bitcode::encode(&album))?;
+    let stored: Album = tree.get_metadata(&album_tags.hash_key())?;
+    let expected_tags = AlbumTags {
+        artist: album_tags.artist.clone(),
+        title: album_tags.title.clone(),
+        year: album_tags.year,
+        ..Default::default()
+    };
+    assert_eq!(stored.tags, expected_tags);
+    assert_eq!(stored.songs.len(), 1);
+    Ok(())
+}
+
+// A db as the first release left it: untagged records under native-order keys, a bare scan time,
+// and nothing saying what schema or key scheme it has.
+#[test]
+fn test_upgrade_basic_db() -> Result {
+    let dir = TempDir::new()?;
+    let tree = sled::open(dir.path())?;
+    let native_key = |key_type: KeyType, id: u64| {
+        let mut key = vec![key_type as u8];
+        key.extend(id.to_ne_bytes());
+        key
+    };
+
+    let album_tags = AlbumTags {
+        genre: None,
+        total_discs: None,
+        ..AlbumTags::arbitrary()
+    };
+    let songs: Vec<Song> = (0..3u16)
+        .map(|i| {
+            let tags = SongTags {
+                title: Some(format!("Track {i}")),
+                track_number: Some(3 - i),
+                ..Default::default()
+            };
+            Song::new(tags, format!("Album/{i}.mp3").as_bytes())
+        })
+        .collect();
+    let mut song_keys = Vec::new();
+    for (id, song) in (1u64..).zip(&songs) {
+        let key = native_key(KeyType::Song, id);
+        tree.insert(&key, bitcode::encode(&BasicSong::from(song)))?;
+        song_keys.push((song.tags.track_number, key.as_slice().try_into()?));
+    }
+    song_keys.sort_by_key(|(track_number, _)| *track_number);
+    let album = BasicStoredAlbum {
+        tags: BasicAlbumTags::from(&album_tags),
+        song_keys,
+    };
+    tree.insert(native_key(KeyType::Album, 1), bitcode::encode(&album))?;
+    tree.insert([KeyType::LastScanTime as u8], [0u8; 16])?;
+
+    assert!(upgrade_db(&tree)?);
+    assert!(!upgrade_db(&tree)?);
+    for song in &songs {
+        assert_eq!(tree.get(song.hash_key())?.unwrap()[0], SONG_FORMAT);
+    }
+    assert_eq!(tree.get(album_tags.hash_key())?.unwrap()[0], ALBUM_FORMAT);
+    let stored: Album = tree.get_metadata(&album_tags.hash_key())?;
+    assert_eq!(stored.tags, album_tags);
+    let titles: Vec<_> = stored
+        .songs
+        .iter()
+        .map(|song| song.tags.title.as_deref())
+        .collect();
+    assert_eq!(titles, [Some("Track 2"), Some("Track 1"), Some("Track 0")]);
+    assert_eq!(tree.scan_songs().count(), songs.len());
+    assert!(tree.get([KeyType::LastScanTime as u8])?.is_none());
+    Ok(())
+}
+#[test]
+fn test_search() -> Result {
+    let dir = TempDir::new()?;
+    let tree = sled::open(dir.path())?;
+
+    let album_tags = AlbumTags {
+        artist: Some("Beyoncé".into()),
+        title: Some("I Am... Sasha Fierce".into()),
+        year: Some(2008),
+        ..Default::default()
+    };
+    let album_key = album_tags.hash_key();
+
+    let mut halo = Song::arbitrary();
+    halo.tags.title = Some("Halo".into());
+    halo.tags.artist = Some("Beyoncé".into());
+    let mut halogen = Song::arbitrary();
+    halogen.tags.title = Some("Halogen Lights".into());
+    halogen.tags.artist = Some("Someone Else".into());
+
+    let mut song_keys = Vec::new();
+    for song in [&halo, &halogen] {
+        let song_key = tree.insert_metadata(song)?;
+        album_upsert(&tree, &album_tags, song, &song_key)?;
+        song_keys.push(song_key);
+    }
+
+    let results = tree.search("BEYONCE", 10)?;
+    assert!(results.songs.contains(&song_keys[0]));
+    assert_eq!(results.albums, vec![album_key.clone()]);
+    assert_eq!(results.artists, vec![artist_hash_key(Some("Beyoncé"))]);
+
+    // Type-ahead prefixes match both songs, but the exact word ranks first.
+    let results = tree.search("halo", 10)?;
+    assert_eq!(results.songs, song_keys);
+    let results = tree.search("hal", 1)?;
+    assert_eq!(results.songs.len(), 1);
+
+    // Every query term has to match.
+    let results = tree.search("halo lights", 10)?;
+    assert_eq!(results.songs, vec![song_keys[1].clone()]);
+    assert!(tree.search("halo nothing", 10)?.songs.is_empty());
+
+    remove_song(&tree, &album_key, &song_keys[0])?;
+    let results = tree.search("halo", 10)?;
+    assert_eq!(results.songs, vec![song_keys[1].clone()]);
+
+    remove_song(&tree, &album_key, &song_keys[1])?;
+    assert_eq!(tree.search("beyonce", 10)?, SearchResults::default());
+    assert_eq!(tree.search("sasha", 10)?, SearchResults::default());
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct RecordingObserver {
+    last_progress: Mutex<ScanProgress>,
+    cancel_on_queue: Option<CancellationToken>,
+}
+
+impl ScanObserver for RecordingObserver {
+    fn on_event(&self, event: &ScanEvent, progress: &ScanProgress) {
+        let mut last_progress = self.last_progress.lock().unwrap();
+        // Events from different threads can arrive out of order, so keep the furthest along.
+        last_progress.directories_walked = last_progress
+            .directories_walked
+            .max(progress.directories_walked);
+        last_progress.files_queued = last_progress.files_queued.max(progress.files_queued);
+        last_progress.files_tagged = last_progress.files_tagged.max(progress.files_tagged);
+        last_progress.songs_removed = last_progress.songs_removed.max(progress.songs_removed);
+        last_progress.errors = last_progress.errors.max(progress.errors);
+
+        if let (ScanEvent::FileQueued(_), Some(cancellation)) = (event, &self.cancel_on_queue) {
+            cancellation.cancel();
+        }
+    }
+}
+
+fn single_album_file_tree(files: u8) -> SkeletonFileTree {
+    SkeletonFileTree {
+        dirs: vec![SkeletonFileTree {
+            dirs: vec![],
+            files,
+        }],
+        files: 0,
+    }
+}
+
+#[test]
+fn test_scan_progress() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(5).generate_file_structure(dir.path())?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+
+    let observer = Arc::new(RecordingObserver::default());
+    let options = ScanOptions::new().with_observer(observer.clone());
+    scan_library_with_options(Arc::clone(&tree), dir.path(), &options)?;
+
+    assert_eq!(
+        *observer.last_progress.lock().unwrap(),
+        ScanProgress {
+            directories_walked: 2,
+            files_queued: 5,
+            files_tagged: 5,
+            songs_removed: 0,
+            errors: 0,
+        }
+    );
+
+    let (_, removed_song) = &all_tags[0];
+    std::fs::remove_file(Path::new(std::str::from_utf8(&removed_song.relpath)?))?;
+
+    let observer = Arc::new(RecordingObserver::default());
+    let options = ScanOptions::new().with_observer(observer.clone());
+    scan_library_with_options(Arc::clone(&tree), dir.path(), &options)?;
+
+    let progress = *observer.last_progress.lock().unwrap();
+    assert_eq!(progress.files_queued, 0);
+    assert_eq!(progress.songs_removed, 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_scan_cancellation() -> Result {
+    let dir = tempdir()?;
+    single_album_file_tree(5).generate_file_structure(dir.path())?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+
+    let cancellation = CancellationToken::new();
+    let observer = Arc::new(RecordingObserver {
+        cancel_on_queue: Some(cancellation.clone()),
+        ..Default::default()
+    });
+    let options = ScanOptions::new()
+        .with_observer(observer.clone())
+        .with_cancellation(cancellation);
+
+    assert!(matches!(
+        scan_library_with_options(Arc::clone(&tree), dir.path(), &options),
+        Err(Error::Cancelled)
+    ));
+    assert_eq!(observer.last_progress.lock().unwrap().files_tagged, 0);
+    assert_eq!(tree.scan_songs().count(), 0);
+    let root = dir.path().to_string_lossy();
+    assert_eq!(tree.get_last_scan_time(&root)?, SystemTime::UNIX_EPOCH);
+
+    Ok(())
+}
+
+#[test]
+fn test_stream_info() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(dir.path())?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let (album_tags, _) = &all_tags[0];
+    let album: Album = tree.get_metadata(&album_tags.hash_key())?;
+    let expected: u64 = all_tags
+        .iter()
+        .filter_map(|(_, song)| song.stream.duration_ms)
+        .sum();
+    assert_eq!(album.duration_ms(), expected);
+
+    // A Xing header in place of the first frame's audio gives the length of a VBR stream.
+    let mut vbr = dummy_audio(2);
+    let xing = 4 + 32;
+    vbr[4..DUMMY_FRAME_LEN].fill(0);
+    vbr[xing..xing + 4].copy_from_slice(b"Xing");
+    vbr[xing + 4..xing + 8].copy_from_slice(&3u32.to_be_bytes());
+    vbr[xing + 8..xing + 12].copy_from_slice(&1000u32.to_be_bytes());
+    vbr[xing + 12..xing + 16].copy_from_slice(&400_000u32.to_be_bytes());
+    let path = dir.path().join("vbr.mp3");
+    std::fs::write(&path, &vbr)?;
+    let mp3 = AudioFormat::new(Container::Mpeg, Some(Codec::Mp3));
+    let stream = read_stream_info(&path, Some(mp3))?;
+    assert_eq!(stream.duration_ms, Some(1000 * 1152 * 1000 / 44100));
+    assert_eq!(stream.bitrate, Some(400_000 * 8 / 26122));
+    assert_eq!(stream.sample_rate, Some(44100));
+    assert_eq!(stream.channels, Some(2));
+
+    // 10 seconds of 16 bit stereo at 44.1kHz, taking up 100kB.
+    let mut flac = b"fLaC\x80\0\0\x22".to_vec();
+    flac.extend([0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+    let packed = (44100u64 << 44) | (1 << 41) | (15 << 36) | 441_000;
+    flac.extend(packed.to_be_bytes());
+    flac.extend([0; 16]);
+    flac.resize(flac.len() + 100_000, 0);
+    let path = dir.path().join("lossless.flac");
+    std::fs::write(&path, &flac)?;
+    let stream = read_stream_info(
+        &path,
+        Some(AudioFormat::new(Container::Flac, Some(Codec::Flac))),
+    )?;
+    assert_eq!(
+        stream,
+        StreamInfo {
+            duration_ms: Some(10_000),
+            bitrate: Some(80),
+            sample_rate: Some(44100),
+            bit_depth: Some(16),
+            channels: Some(2),
+        }
+    );
+
+    // Noise with no frames in it tells nothing about the stream.
+    let path = dir.path().join("noise.mp3");
+    std::fs::write(&path, [0x55; 4096])?;
+    assert_eq!(read_stream_info(&path, Some(mp3))?, StreamInfo::default());
+
+    Ok(())
+}
+
+#[test]
+fn test_album_art() -> Result {
+    let dir = tempdir()?;
+    let all_tags = SkeletonFileTree {
+        dirs: [2, 2, 1, 1, 1]
+            .into_iter()
+            .map(|files| SkeletonFileTree {
+                dirs: vec![],
+                files,
+            })
+            .collect(),
+        files: 0,
+    }
+    .generate_file_structure(dir.path())?;
+    let album_in = |name: &str| {
+        let (album_tags, _) = all_tags
+            .iter()
+            .find(|(_, song)| song.path().parent().unwrap().ends_with(name))
+            .unwrap();
+        album_tags.hash_key()
+    };
+    let songs_in = |name: &str| {
+        all_tags
+            .iter()
+            .map(|(_, song)| song.path())
+            .filter(|path| path.parent().unwrap().ends_with(name))
+            .collect::<Vec<_>>()
+    };
+
+    // Folder art, with cover preferred over folder whatever the case of its name.
+    std::fs::write(dir.path().join("0/folder.png"), b"folder")?;
+    std::fs::write(dir.path().join("0/Cover.JPG"), b"cover")?;
+    // Embedded art beats an image beside the file, and is stored once however many albums use it.
+    std::fs::write(dir.path().join("1/cover.jpg"), b"ignored")?;
+    for path in [songs_in("1"), songs_in("2")].concat() {
+        embed_picture(&path, "image/png", b"embedded")?;
+    }
+    let mut png = std::io::Cursor::new(Vec::new());
+    image::RgbaImage::new(64, 32).write_to(&mut png, image::ImageFormat::Png)?;
+    let png = png.into_inner();
+    std::fs::write(dir.path().join("4/cover.png"), &png)?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+
+    let art = |name: &str| -> music_cache::Result<Option<(String, Vec<u8>)>> {
+        let art = tree.album_art_for_key(&album_in(name))?;
+        Ok(art.map(|art| (art.mime_type, art.data)))
+    };
+    assert_eq!(art("0")?, Some(("image/jpeg".into(), b"cover".to_vec())));
+    assert_eq!(art("1")?, Some(("image/png".into(), b"embedded".to_vec())));
+    assert_eq!(art("2")?, art("1")?);
+    assert_eq!(art("3")?, None);
+    assert_eq!(tree.open_tree("album_art")?.len(), 3);
+
+    // Thumbnails are made once for each size asked for.
+    let thumbnail = tree.album_thumbnail_for_key(&album_in("4"), 16)?.unwrap();
+    assert_eq!(thumbnail.mime_type, "image/png");
+    let scaled = image::load_from_memory(&thumbnail.data)?;
+    assert_eq!((scaled.width(), scaled.height()), (16, 8));
+    let cached = tree.album_thumbnail_for_key(&album_in("4"), 16)?.unwrap();
+    assert_eq!(cached.data, thumbnail.data);
+    assert_eq!(tree.open_tree("album_thumbnails")?.len(), 1);
+    // Art that's already small enough, or can't be decoded, comes back as stored.
+    let unscaled = tree.album_thumbnail_for_key(&album_in("4"), 64)?.unwrap();
+    assert_eq!(unscaled.data, png);
+    let undecodable = tree.album_thumbnail_for_key(&album_in("0"), 16)?.unwrap();
+    assert_eq!(undecodable.data, b"cover");
+    assert!(tree.album_thumbnail_for_key(&album_in("3"), 16)?.is_none());
+    assert_eq!(tree.open_tree("album_thumbnails")?.len(), 1);
+
+    // Art no album uses any more goes with the last of its songs, along with its thumbnails.
+    for path in [songs_in("0"), songs_in("4")].concat() {
+        std::fs::remove_file(path)?;
+    }
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(tree.open_tree("album_art")?.len(), 1);
+    assert_eq!(tree.open_tree("album_thumbnails")?.len(), 0);
+    assert_eq!(art("1")?, Some(("image/png".into(), b"embedded".to_vec())));
+
+    Ok(())
+}
+
+fn album_tags(artist: Option<&str>, title: &str, year: Option<u16>) -> AlbumTags {
+    AlbumTags {
+        artist: artist.map(str::to_string),
+        title: Some(title.to_string()),
+        year,
+        ..AlbumTags::default()
+    }
+}
+
+#[test]
+fn test_reconcile_albums() -> Result {
+    let dir = TempDir::new()?;
+    let tree = sled::open(dir.path())?;
+    let full = album_tags(Some("The Beatles"), "Abbey Road", Some(1969));
+    let split = [
+        album_tags(Some("beatles"), "abbey road!", None),
+        album_tags(None, "ABBEY ROAD", Some(1969)),
+    ];
+    let apart = [
+        album_tags(Some("The Beatles"), "Abbey Road", Some(2019)),
+        album_tags(Some("Someone Else"), "Abbey Road", None),
+    ];
+    let add_songs = |tags: &AlbumTags, count: usize| -> Result {
+        for _ in 0..count {
+            let song = Song::arbitrary();
+            album_upsert(&tree, tags, &song, &tree.insert_metadata(&song)?)?;
+        }
+        Ok(())
+    };
+    add_songs(&full, 3)?;
+    for tags in split.iter().chain(&apart) {
+        add_songs(tags, 1)?;
+    }
+
+    assert_eq!(tree.reconcile_albums()?, 1);
+    let album: Album = tree.get_metadata(&full.hash_key())?;
+    assert_eq!(album.songs.len(), 5);
+    for tags in &split {
+        assert!(matches!(
+            Methods::<Album>::get_metadata(&tree, &tags.hash_key()),
+            Err(Error::NotFound(_))
+        ));
+    }
+    for tags in &apart {
+        let album: Album = tree.get_metadata(&tags.hash_key())?;
+        assert_eq!(album.songs.len(), 1);
+    }
+    assert_eq!(tree.scan_albums().count(), 3);
+    assert_eq!(tree.search("abbey", 10)?.albums.len(), 3);
+    assert_eq!(tree.scan_artists_sorted()?.len(), 2);
+
+    // Nothing is left to merge.
+    assert_eq!(tree.reconcile_albums()?, 0);
+    Ok(())
+}
+
+#[test]
+fn test_reconciled_albums_stay_merged() -> Result {
+    let dir = tempdir()?;
+    let full = album_tags(Some("The Beatles"), "Abbey Road", Some(1969));
+    let split = album_tags(None, "Abbey Road", None);
+    let files = [("1.mp3", &full), ("2.mp3", &full), ("3.mp3", &split)];
+    for (name, tags) in files {
+        let path = dir.path().join(name);
+        std::fs::write(&path, dummy_audio(2))?;
+        write_tags_to_path(&path, tags, &SongTags::default())?;
+    }
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(tree.scan_albums().count(), 2);
+    tree.record_play(&full.hash_key())?;
+    tree.record_play(&split.hash_key())?;
+    tree.set_rating(&split.hash_key(), Some(4))?;
+    let split_added = added_ms(&*tree, &split.hash_key())?;
+
+    // The merged album's plays, rating and added date go to the album that took it in.
+    assert_eq!(tree.reconcile_albums()?, 1);
+    let user_data = tree.user_data(&full.hash_key())?;
+    assert_eq!((user_data.play_count, user_data.rating), (2, Some(4)));
+    assert_eq!(tree.user_data(&split.hash_key())?, UserData::default());
+    assert!(added_ms(&*tree, &full.hash_key())? <= split_added);
+    assert_eq!(added_ms(&*tree, &split.hash_key())?, None);
+
+    // Touching the file that was split off doesn't split it off again.
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    write_tags_to_path(&dir.path().join("3.mp3"), &split, &SongTags::default())?;
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.updated, report.skipped), (1, 2), "{report:?}");
+    assert_eq!(tree.scan_albums().count(), 1);
+    let album: Album = tree.get_metadata(&full.hash_key())?;
+    assert_eq!(album.songs.len(), 3);
+    assert_eq!(tree.search("abbey", 10)?.albums, vec![full.hash_key()]);
+
+    Ok(())
+}
+
+#[test]
+fn test_album_grouping() -> Result {
+    let dir = tempdir()?;
+    let files = [
+        (
+            "a/1.mp3",
+            album_tags(Some("The Beatles"), "Abbey Road", Some(1969)),
+        ),
+        ("a/2.mp3", album_tags(Some("Beatles"), "Abbey Road", None)),
+        ("b/3.mp3", album_tags(Some("Someone"), "One", None)),
+        ("b/4.mp3", album_tags(Some("Someone Else"), "Another", None)),
+    ];
+    for (relpath, tags) in &files {
+        let path = dir.path().join(relpath);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, dummy_audio(2))?;
+        write_tags_to_path(&path, tags, &SongTags::default())?;
+    }
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    let album_sizes = || -> music_cache::Result<Vec<usize>> {
+        let mut sizes = tree
+            .scan_albums()
+            .map(|album| album.map(|album| album.songs.len()))
+            .collect::<music_cache::Result<Vec<_>>>()?;
+        sizes.sort();
+        Ok(sizes)
+    };
+
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert!(matches!(tree.album_grouping()?, AlbumGrouping::Tags));
+    assert_eq!(album_sizes()?, [1, 1, 1, 1]);
+
+    // Changing the grouping regroups songs on the next scan, though no file changed.
+    tree.set_album_grouping(AlbumGrouping::NormalizedTags)?;
+    assert!(matches!(
+        tree.album_grouping()?,
+        AlbumGrouping::NormalizedTags
+    ));
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(album_sizes()?, [1, 1, 2]);
+    assert_eq!(tree.scan_artists_sorted()?.len(), 3);
+
+    tree.set_album_grouping(AlbumGrouping::Directory)?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(album_sizes()?, [2, 2]);
+
+    // Files without an ID fall back to normalized tags.
+    add_extended_text(
+        &dir.path().join("b/3.mp3"),
+        "MusicBrainz Album Id",
+        "0b1f7a9e-a2b4-4f8d-9a3c-1f2e3d4c5b6a",
+    )?;
+    add_extended_text(
+        &dir.path().join("b/4.mp3"),
+        "MusicBrainz Album Id",
+        "0B1F7A9E-A2B4-4F8D-9A3C-1F2E3D4C5B6A",
+    )?;
+    tree.set_album_grouping(AlbumGrouping::MusicBrainz)?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(album_sizes()?, [2, 2]);
+    let albums = tree.scan_album_tags_sorted()?;
+    assert!(albums
+        .iter()
+        .any(|(_, tags)| tags.title.as_deref() == Some("Abbey Road")));
+
+    Ok(())
+}
+
+#[test]
+fn test_extended_tags() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(dir.path())?;
+    let paths: Vec<_> = all_tags.iter().map(|(_, song)| song.path()).collect();
+    let recording_id = "b1a9c0e9-d987-4042-ae91-78d6a3267d69";
+    add_musicbrainz_ufid(&paths[0], recording_id)?;
+    add_extended_text(&paths[0], "MusicBrainz Album Id", "release")?;
+    add_extended_text(&paths[0], "MusicBrainz Artist Id", "artist")?;
+    // Taggers disagree on the case of ReplayGain names.
+    add_extended_text(&paths[0], "replaygain_track_gain", "-6.54 dB")?;
+    add_extended_text(&paths[0], "REPLAYGAIN_TRACK_PEAK", "0.988547")?;
+    add_extended_text(&paths[0], "REPLAYGAIN_ALBUM_GAIN", "+1.2 dB")?;
+    add_extended_text(&paths[0], "REPLAYGAIN_ALBUM_PEAK", "nonsense")?;
+    // A TXXX recording ID from taggers that don't write UFID.
+    add_extended_text(
+        &paths[1],
+        "MusicBrainz Track Id",
+        "0f6f0f6f-0000-4000-8000-000000000001",
+    )?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+
+    let (key, song) = tree.song_by_mbid(&recording_id.to_uppercase())?;
+    assert_eq!(tree.song_path(&song)?, paths[0]);
+    assert_eq!(key, song.hash_key());
+    assert_eq!(
+        song.musicbrainz,
+        MusicBrainzIds {
+            recording_id: Some(recording_id.to_string()),
+            release_id: Some("release".to_string()),
+            artist_id: Some("artist".to_string()),
+        }
+    );
+    assert_eq!(
+        song.replay_gain,
+        ReplayGain {
+            track_gain: Some(-654),
+            track_peak: Some(988_547),
+            album_gain: Some(120),
+            album_peak: None,
+        }
+    );
+    assert_eq!(song.replay_gain.track_gain_db(), Some(-6.54));
+    let (_, song) = tree.song_by_mbid("0f6f0f6f-0000-4000-8000-000000000001")?;
+    assert_eq!(tree.song_path(&song)?, paths[1]);
+    // IDs are looked up ignoring case, and stored as found.
+    let stored: Song = tree.get_metadata(&key)?;
+    assert_eq!(
+        stored.musicbrainz.recording_id.as_deref(),
+        Some(recording_id)
+    );
+
+    // Files and their IDs leave the index together.
+    std::fs::remove_file(&paths[0])?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert!(matches!(
+        tree.song_by_mbid(recording_id),
+        Err(Error::NotFound(_))
+    ));
+
+    // Vorbis comments carry the same fields in FLAC files.
+    let mut flac = b"fLaC\x80\0\0\x22".to_vec();
+    flac.extend([0x10, 0x00, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+    flac.extend(((44100u64 << 44) | (1 << 41) | (15 << 36)).to_be_bytes());
+    flac.extend([0; 16]);
+    let path = dir.path().join("lossless.flac");
+    std::fs::write(&path, &flac)?;
+    let mut tag = metaflac::Tag::read_from_path(&path)?;
+    tag.set_vorbis("musicbrainz_trackid", vec![recording_id]);
+    tag.set_vorbis("REPLAYGAIN_TRACK_GAIN", vec!["3.00 dB"]);
+    tag.save()?;
+    let format = AudioFormat::new(Container::Flac, Some(Codec::Flac));
+    let (_, extended) = read_audio_tag(&path, Some(format))?;
+    assert_eq!(
+        extended.musicbrainz.recording_id.as_deref(),
+        Some(recording_id)
+    );
+    assert_eq!(extended.replay_gain.track_gain, Some(300));
+
+    // And in Ogg files, where they're stored with the song like any other.
+    let ogg_dir = tempdir()?;
+    let mut comment_packet = b"\x03vorbis".to_vec();
+    comment_packet.extend(vorbis_comment_data(&[
+        "TITLE=Cold Song",
+        "MusicBrainz_TrackId=0f6f0f6f-0000-4000-8000-000000000002",
+        "REPLAYGAIN_TRACK_GAIN=-1.50 dB",
+    ]));
+    let mut vorbis = ogg_page(0, 0, b"\x01vorbis\0\0\0\0\x02");
+    vorbis.extend(ogg_page(0, 1, &comment_packet));
+    let path = ogg_dir.path().join("cold song.ogg");
+    std::fs::write(&path, &vorbis)?;
+    scan_library(Arc::clone(&tree), ogg_dir.path())?;
+    let (_, song) = tree.song_by_mbid("0f6f0f6f-0000-4000-8000-000000000002")?;
+    assert_eq!(tree.song_path(&song)?, path);
+    assert_eq!(song.tags.title.as_deref(), Some("Cold Song"));
+    assert_eq!(song.replay_gain.track_gain, Some(-150));
+
+    Ok(())
+}
+
+#[test]
+fn test_user_data() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(dir.path())?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let song_key = |index: usize| -> music_cache::Result<Key> {
+        let path = all_tags[index].1.path();
+        Ok(tree.get_song_from_path(path.as_os_str().as_encoded_bytes())?()?.hash_key())
+    };
+    let (first, second, third) = (song_key(0)?, song_key(1)?, song_key(2)?);
+    let album_key = all_tags[0].0.hash_key();
+
+    assert_eq!(tree.user_data(&first)?, UserData::default());
+    for _ in 0..3 {
+        tree.record_play(&first)?;
+    }
+    // Plays need telling apart by time.
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let played = tree.record_play(&second)?;
+    assert_eq!(played.play_count, 1);
+    assert!(played
+        .last_played()
+        .is_some_and(|time| time <= SystemTime::now()));
+    assert_eq!(tree.record_skip(&third)?.skip_count, 1);
+    assert_eq!(tree.set_rating(&first, Some(5))?.rating, Some(5));
+    assert!(matches!(
+        tree.set_rating(&first, Some(MAX_RATING + 1)),
+        Err(Error::InvalidRating(_))
+    ));
+    assert_eq!(tree.set_rating(&first, None)?.rating, None);
+    assert!(tree.set_favourite(&album_key, true)?.favourite);
+    assert!(matches!(
+        tree.record_play(&Song::arbitrary().hash_key()),
+        Err(Error::NotFound(_))
+    ));
+
+    let keys = |entries: Vec<(Key, UserData)>| -> Vec<Key> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(keys(tree.most_played(10)?), [first.clone(), second.clone()]);
+    assert_eq!(keys(tree.most_played(1)?), vec![first.clone()]);
+    assert_eq!(
+        keys(tree.recently_played(10)?),
+        [second.clone(), first.clone()]
+    );
+
+    // Rescans leave user data alone, even for songs whose files changed.
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    write_tags_to_path(
+        &all_tags[0].1.path(),
+        &all_tags[0].0,
+        &SongTags::arbitrary(),
+    )?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(tree.user_data(&first)?.play_count, 3);
+    assert!(tree.user_data(&album_key)?.favourite);
+
+    // A song that goes is left out of the rankings, but gets its history back when it returns.
+    let second_path = all_tags[1].1.path();
+    let second_file = std::fs::read(&second_path)?;
+    std::fs::remove_file(&second_path)?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(keys(tree.most_played(10)?), vec![first.clone()]);
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    std::fs::write(&second_path, second_file)?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(tree.user_data(&second)?.play_count, 1);
+    assert_eq!(keys(tree.most_played(10)?), [first, second]);
+
+    Ok(())
+}
+
+#[test]
+fn test_playlists() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(dir.path())?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let paths: Vec<_> = all_tags.iter().map(|(_, song)| song.path()).collect();
+    let song_key = |index: usize| -> music_cache::Result<Key> {
+        let path = paths[index].as_os_str().as_encoded_bytes();
+        Ok(tree.get_song_from_path(path)?()?.hash_key())
+    };
+    let (first, second, third) = (song_key(0)?, song_key(1)?, song_key(2)?);
+    let keys =
+        |songs: Vec<(Key, Song)>| -> Vec<Key> { songs.into_iter().map(|(key, _)| key).collect() };
+
+    let mixtape = tree.create_playlist("mixtape")?;
+    let created = tree.playlist(&mixtape)?;
+    assert_eq!(created.name, "mixtape");
+    assert!(created.song_keys.is_empty());
+    tree.insert_into_playlist(&mixtape, 0, &[first.clone(), third.clone()])?;
+    tree.insert_into_playlist(&mixtape, 1, std::slice::from_ref(&second))?;
+    tree.insert_into_playlist(&mixtape, 3, std::slice::from_ref(&first))?;
+    assert_eq!(
+        keys(tree.playlist_songs(&mixtape)?),
+        [first.clone(), second.clone(), third.clone(), first.clone()]
+    );
+    tree.move_in_playlist(&mixtape, 2, 0)?;
+    tree.remove_from_playlist(&mixtape, 3)?;
+    assert_eq!(
+        keys(tree.playlist_songs(&mixtape)?),
+        [third.clone(), first.clone(), second.clone()]
+    );
+    assert!(matches!(
+        tree.remove_from_playlist(&mixtape, 3),
+        Err(Error::InvalidIndex(3))
+    ));
+    assert!(matches!(
+        tree.move_in_playlist(&mixtape, 0, 3),
+        Err(Error::InvalidIndex(3))
+    ));
+    assert!(matches!(
+        tree.insert_into_playlist(&mixtape, 0, &[Song::arbitrary().hash_key()]),
+        Err(Error::NotFound(_))
+    ));
+    assert!(matches!(tree.playlist(&first), Err(Error::NotFound(_))));
+
+    tree.rename_playlist(&mixtape, "Mixtape")?;
+    let renamed = tree.playlist(&mixtape)?;
+    assert_eq!(renamed.name, "Mixtape");
+    assert_eq!(renamed.created_ms, created.created_ms);
+    assert!(renamed.modified_ms >= created.modified_ms);
+    let empty = tree.create_playlist("b-sides")?;
+    let names = |playlists: Vec<(Key, Playlist)>| -> Vec<String> {
+        playlists
+            .into_iter()
+            .map(|(_, playlist)| playlist.name)
+            .collect()
+    };
+    assert_eq!(names(tree.playlists()?), ["b-sides", "Mixtape"]);
+
+    // Both formats come back as they went out.
+    let playlist_dir = tempdir()?;
+    for name in ["mixtape.m3u8", "mixtape.pls"] {
+        let path = playlist_dir.path().join(name);
+        assert_eq!(tree.export_playlist(&mixtape, &path)?, 3);
+        let (imported, unresolved) = tree.import_playlist(&path)?;
+        assert!(unresolved.is_empty());
+        assert_eq!(tree.playlist(&imported)?.name, "mixtape");
+        assert_eq!(
+            keys(tree.playlist_songs(&imported)?),
+            [third.clone(), first.clone(), second.clone()]
+        );
+        tree.delete_playlist(&imported)?;
+    }
+    assert!(matches!(
+        tree.export_playlist(&mixtape, &playlist_dir.path().join("mixtape.txt")),
+        Err(Error::UnknownPlaylistFormat(_))
+    ));
+
+    // Entries relative to the playlist, file URIs, and files that aren't in the library.
+    let relative = pathdiff(&paths[1], playlist_dir.path());
+    let uri = format!("file://{}", paths[0].to_string_lossy().replace(' ', "%20"));
+    let hand_written = playlist_dir.path().join("hand written.m3u");
+    std::fs::write(
+        &hand_written,
+        format!("#EXTM3U\n#EXTINF:-1,Somebody - Something\n{relative}\n\n{uri}\nnowhere.mp3\n"),
+    )?;
+    let (imported, unresolved) = tree.import_playlist(&hand_written)?;
+    assert_eq!(
+        keys(tree.playlist_songs(&imported)?),
+        [second.clone(), first.clone()]
+    );
+    assert_eq!(unresolved, vec![playlist_dir.path().join("nowhere.mp3")]);
+
+    // A song that goes keeps its place, and shows up again when it returns.
+    let first_file = std::fs::read(&paths[0])?;
+    std::fs::remove_file(&paths[0])?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(tree.playlist(&mixtape)?.song_keys.len(), 3);
+    assert_eq!(
+        keys(tree.playlist_songs(&mixtape)?),
+        [third.clone(), second.clone()]
+    );
+    let export_path = playlist_dir.path().join("gap.m3u8");
+    assert_eq!(tree.export_playlist(&mixtape, &export_path)?, 2);
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    std::fs::write(&paths[0], first_file)?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(keys(tree.playlist_songs(&mixtape)?), [third, first, second]);
+
+    tree.delete_playlist(&empty)?;
+    assert!(matches!(
+        tree.delete_playlist(&empty),
+        Err(Error::NotFound(_))
+    ));
+    assert_eq!(names(tree.playlists()?), ["hand written", "Mixtape"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_song_queries() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(4).generate_file_structure(dir.path())?;
+    let paths: Vec<_> = all_tags.iter().map(|(_, song)| song.path()).collect();
+    let album = |title: &str, year: u16, genre: &str| AlbumTags {
+        artist: Some("Various".to_string()),
+        title: Some(title.to_string()),
+        year: Some(year),
+        genre: Some(genre.to_string()),
+        total_discs: None,
+    };
+    let song = |title: &str, track: u16, genre: &str| SongTags {
+        title: Some(title.to_string()),
+        track_number: Some(track),
+        genre: Some(genre.to_string()),
+        ..SongTags::default()
+    };
+    let nineties = album("Nineties", 1995, "Rock");
+    write_tags_to_path(&paths[0], &nineties, &song("Alpha", 1, "Rock"))?;
+    write_tags_to_path(&paths[1], &nineties, &song("Beta", 2, "Rock"))?;
+    write_tags_to_path(
+        &paths[2],
+        &album("Noughties", 2005, "Rock"),
+        &song("Gamma", 1, "Rock"),
+    )?;
+    write_tags_to_path(
+        &paths[3],
+        &album("Nineties Jazz", 1992, "Jazz"),
+        &song("Delta", 1, "Jazz"),
+    )?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let song_key = |index: usize| -> music_cache::Result<Key> {
+        let path = paths[index].as_os_str().as_encoded_bytes();
+        Ok(tree.get_song_from_path(path)?()?.hash_key())
+    };
+    let (alpha, beta, gamma, delta) = (song_key(0)?, song_key(1)?, song_key(2)?, song_key(3)?);
+    tree.set_rating(&alpha, Some(5))?;
+    tree.set_rating(&beta, Some(4))?;
+    tree.set_rating(&gamma, Some(5))?;
+    tree.record_play(&alpha)?;
+    tree.set_favourite(&delta, true)?;
+
+    let query = |query: &str| -> music_cache::Result<Vec<Key>> {
+        let songs = tree.query_songs(&SongQuery::parse(query)?)?;
+        Ok(songs.into_iter().map(|(key, _)| key).collect())
+    };
+    assert_eq!(
+        query("year = 1990..1999 and genre = rock and rating >= 4 and not days_since_played < 30")?,
+        vec![beta.clone()]
+    );
+    assert_eq!(
+        query("GENRE = Rock sort by rating desc, title desc")?,
+        [gamma.clone(), alpha.clone(), beta.clone()]
+    );
+    assert_eq!(
+        query(r#"title ~ "ALP" or (album = "nineties jazz")"#)?,
+        [alpha.clone(), delta.clone()]
+    );
+    assert_eq!(
+        query("sort by year limit 2")?,
+        [delta.clone(), alpha.clone()]
+    );
+    assert_eq!(query("favourite")?, vec![delta.clone()]);
+    assert_eq!(
+        query("not favourite and plays = 0")?,
+        [beta.clone(), gamma.clone()]
+    );
+    assert_eq!(query("")?.len(), 4);
+
+    assert_eq!(
+        SongQuery::parse("favourite sort by plays desc limit 5")?,
+        SongQuery {
+            filter: Some(Filter::Condition(Condition::Flag(SongField::Favourite))),
+            sort: vec![SortKey {
+                field: SongField::Plays,
+                descending: true,
+            }],
+            limit: Some(5),
+            ..SongQuery::default()
+        }
+    );
+    for (source, expected_position) in [
+        ("yeer = 1", 0),
+        ("year > rock", 7),
+        ("title < a", 6),
+        ("(year = 1", 9),
+        ("title = \"open", 8),
+        ("year = 1 sort title", 14),
+        ("year = 1 limit", 14),
+        (&"(".repeat(100_000), 256),
+        (&"not ".repeat(100_000), 1024),
+    ] {
+        assert!(
+            matches!(
+                SongQuery::parse(source),
+                Err(Error::InvalidQuery { position, .. }) if position == expected_position
+            ),
+            "{source}"
+        );
+    }
+
+    let nested = format!("{}year = 1{}", "(".repeat(255), ")".repeat(255));
+    assert!(SongQuery::parse(&nested).is_ok());
+
+    let rock = tree.create_smart_playlist("Rock", "genre = rock sort by title")?;
+    assert_eq!(
+        tree.smart_playlist(&rock)?.query,
+        "genre = rock sort by title"
+    );
+    let keys =
+        |songs: Vec<(Key, Song)>| -> Vec<Key> { songs.into_iter().map(|(key, _)| key).collect() };
+    assert_eq!(
+        keys(tree.smart_playlist_songs(&rock)?),
+        [alpha.clone(), beta.clone(), gamma.clone()]
+    );
+    assert!(matches!(
+        tree.create_smart_playlist("Broken", "genre rock"),
+        Err(Error::InvalidQuery { .. })
+    ));
+    assert!(matches!(
+        tree.update_smart_playlist(&rock, "Rock", "genre rock"),
+        Err(Error::InvalidQuery { .. })
+    ));
+    let favourites = tree.create_smart_playlist("favourites", "favourite")?;
+    tree.update_smart_playlist(&rock, "Jazz", "genre = jazz")?;
+    assert_eq!(keys(tree.smart_playlist_songs(&rock)?), vec![delta]);
+    let names: Vec<String> = tree
+        .smart_playlists()?
+        .into_iter()
+        .map(|(_, playlist)| playlist.name)
+        .collect();
+    assert_eq!(names, ["favourites", "Jazz"]);
+    tree.delete_smart_playlist(&favourites)?;
+    assert!(matches!(
+        tree.smart_playlist(&favourites),
+        Err(Error::NotFound(_))
+    ));
+    let static_playlist = tree.create_playlist("static")?;
+    assert!(matches!(
+        tree.smart_playlist(&static_playlist),
+        Err(Error::NotFound(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_query_builder() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(4).generate_file_structure(dir.path())?;
+    let paths: Vec<_> = all_tags.iter().map(|(_, song)| song.path()).collect();
+    let album = |title: &str, year: u16| AlbumTags {
+        artist: Some("Various".to_string()),
+        title: Some(title.to_string()),
+        year: Some(year),
+        genre: Some("Rock".to_string()),
+        total_discs: None,
+    };
+    let song = |title: &str, track: u16| SongTags {
+        title: Some(title.to_string()),
+        track_number: Some(track),
+        ..SongTags::default()
+    };
+    let first = album("First", 1990);
+    let second = album("Second", 2000);
+    write_tags_to_path(&paths[0], &first, &song("Alpha", 1))?;
+    write_tags_to_path(&paths[1], &first, &song("Beta", 2))?;
+    write_tags_to_path(&paths[2], &first, &song("Gamma", 3))?;
+    write_tags_to_path(&paths[3], &second, &song("Delta", 1))?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let song_key = |index: usize| -> music_cache::Result<Key> {
+        let path = paths[index].as_os_str().as_encoded_bytes();
+        Ok(tree.get_song_from_path(path)?()?.hash_key())
+    };
+    let (alpha, beta, gamma, delta) = (song_key(0)?, song_key(1)?, song_key(2)?, song_key(3)?);
+    tree.set_favourite(&beta, true)?;
+
+    let query = SongQuery::new()
+        .filter(Filter::is(SongField::Album, "first"))
+        .filter(!Filter::flag(SongField::Favourite))
+        .sort_by_descending(SongField::Title);
+    assert_eq!(
+        query,
+        SongQuery::parse("album = first and not favourite sort by title desc")?
+    );
+    assert_eq!(
+        tree.query_song_keys(&query)?,
+        [gamma.clone(), alpha.clone()]
+    );
+    let either = Filter::is(SongField::Title, "delta").or(Filter::range(SongField::Track, 2, 2));
+    assert_eq!(
+        tree.query_song_keys(&SongQuery::new().filter(either))?,
+        [beta.clone(), delta.clone()]
+    );
+
+    // Pages by offset and by cursor agree.
+    let by_title = SongQuery::new().sort_by(SongField::Title);
+    assert_eq!(
+        tree.query_song_keys(&by_title.clone().limit(2))?,
+        [alpha.clone(), beta.clone()]
+    );
+    assert_eq!(
+        tree.query_song_keys(&SongQuery::parse("sort by title limit 2 offset 2")?)?,
+        [delta.clone(), gamma.clone()]
+    );
+    assert_eq!(
+        tree.query_song_keys(&by_title.clone().after(beta.clone()).limit(1))?,
+        vec![delta.clone()]
+    );
+    assert_eq!(
+        tree.query_song_keys(&by_title.clone().after(beta.clone()).offset(1))?,
+        vec![gamma.clone()]
+    );
+    assert!(tree
+        .query_song_keys(&by_title.clone().offset(9))?
+        .is_empty());
+    let songs = tree.query_songs(&by_title)?;
+    assert_eq!(songs[0].1.tags.title.as_deref(), Some("Alpha"));
+
+    let albums = tree.query_albums(&AlbumQuery::parse("sort by track_count desc")?)?;
+    let titles: Vec<_> = albums
+        .iter()
+        .map(|(_, tags)| tags.title.clone().unwrap_or_default())
+        .collect();
+    assert_eq!(titles, ["First", "Second"]);
+    let (first_key, second_key) = (albums[0].0.clone(), albums[1].0.clone());
+    let recent = AlbumQuery::new()
+        .filter(Filter::compare(AlbumField::Year, NumberOp::Ge, 1995))
+        .filter(Filter::compare(AlbumField::DaysSinceAdded, NumberOp::Lt, 1));
+    assert_eq!(tree.query_album_keys(&recent)?, vec![second_key.clone()]);
+    assert_eq!(
+        tree.query_album_keys(&AlbumQuery::new().after(first_key.clone()))?,
+        vec![second_key.clone()]
+    );
+    assert!(matches!(
+        AlbumQuery::parse("album = first"),
+        Err(Error::InvalidQuery { position: 0, .. })
+    ));
+    assert!(matches!(
+        tree.query_song_keys(&SongQuery::new().after(first_key.clone())),
+        Err(Error::NotFound(_))
+    ));
+
+    // The date a song was added survives it changing.
+    let added = tree.date_added(&alpha)?;
+    assert!(added.is_some());
+    assert!(tree.date_added(&first_key)?.is_some());
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    write_tags_to_path(&paths[0], &first, &song("Alpha Prime", 1))?;
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(tree.date_added(&alpha)?, added);
+    assert_eq!(
+        tree.query_song_keys(&SongQuery::parse("days_since_added = 0 sort by title")?)?,
+        [alpha, beta, delta, gamma]
+    );
+
+    Ok(())
+}
+
+// How `path` is reached from `base`, both being absolute.
+fn pathdiff(path: &Path, base: &Path) -> String {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let ups = base.components().count() - common;
+    let rest: Vec<_> = path.components().skip(common).collect();
+    std::iter::repeat_n("..".to_string(), ups)
+        .chain(
+            rest.iter()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned()),
+        )
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[test]
+fn test_format_detection() -> Result {
+    let dir = tempdir()?;
+    let album_tags = AlbumTags::arbitrary();
+    let song_tags = SongTags::arbitrary();
+    // Extensions are matched whatever their case, and the contents decide the format.
+    write_tags_to_path(&dir.path().join("LOUD.MP3"), &album_tags, &song_tags)?;
+    write_tags_to_path(&dir.path().join("misnamed.flac"), &album_tags, &song_tags)?;
+    let mut wav = b"RIFF\x24\0\0\0WAVEfmt \x10\0\0\0\x01\0\x02\0".to_vec();
+    wav.resize(44, 0);
+    std::fs::write(dir.path().join("untagged.wav"), &wav)?;
+    std::fs::write(dir.path().join("untagged.mp3"), dummy_audio(8))?;
+    let mut opus = b"OggS\0\x02".to_vec();
+    opus.resize(26, 0);
+    opus.extend(b"\x01\x13OpusHead");
+    std::fs::write(dir.path().join("voice.opus"), &opus)?;
+    std::fs::write(dir.path().join("notes.txt"), b"not music")?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(report.added, 5, "{:?}", report.failures);
+
+    let format_of = |name: &str| -> music_cache::Result<Option<AudioFormat>> {
+        let path = dir.path().join(name);
+        Ok(tree.get_song_from_path(path.as_os_str().as_encoded_bytes())?()?.format)
+    };
+    let mp3 = AudioFormat::new(Container::Mpeg, Some(Codec::Mp3));
+    assert_eq!(format_of("LOUD.MP3")?, Some(mp3));
+    assert_eq!(format_of("misnamed.flac")?, Some(mp3));
+    assert_eq!(format_of("untagged.mp3")?, Some(mp3));
+    assert_eq!(
+        format_of("untagged.wav")?,
+        Some(AudioFormat::new(Container::Wav, Some(Codec::Pcm)))
+    );
+    assert_eq!(
+        format_of("voice.opus")?,
+        Some(AudioFormat::new(Container::Ogg, Some(Codec::Opus)))
+    );
+    let path = dir.path().join("misnamed.flac");
+    let song = tree.get_song_from_path(path.as_os_str().as_encoded_bytes())?()?;
+    assert_eq!(song.tags, song_tags);
+    let path = dir.path().join("untagged.mp3");
+    let song = tree.get_song_from_path(path.as_os_str().as_encoded_bytes())?()?;
+    assert_eq!(song.tags, SongTags::default());
+
+    // A narrower extension set leaves the rest out, and added detectors take precedence.
+    struct EverythingIsWma;
+    impl FormatDetector for EverythingIsWma {
+        fn detect(&self, _: &[u8]) -> Option<AudioFormat> {
+            Some(AudioFormat::new(Container::Asf, Some(Codec::Wma)))
+        }
+    }
+    let formats = AudioFormats::default()
+        .with_extensions(["Mp3"])
+        .with_detector(Arc::new(EverythingIsWma));
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    let options = ScanOptions::new().with_formats(formats);
+    let report = scan_library_with_options(Arc::clone(&tree), dir.path(), &options)?;
+    assert_eq!(report.added, 2);
+    let mut paths = Vec::new();
+    for song in tree.scan_songs() {
+        let song = song?;
+        assert_eq!(
+            song.format,
+            Some(AudioFormat::new(Container::Asf, Some(Codec::Wma)))
+        );
+        assert_eq!(song.tags, SongTags::default());
+        paths.push(song.path());
+    }
+    paths.sort();
+    assert_eq!(paths, [Path::new("LOUD.MP3"), Path::new("untagged.mp3")]);
+
+    Ok(())
+}
+
+// A comment packet body: the vendor string and then each NAME=value comment, length first.
+fn vorbis_comment_data(comments: &[&str]) -> Vec<u8> {
+    let mut data = 6u32.to_le_bytes().to_vec();
+    data.extend(b"vendor");
+    data.extend((comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        data.extend((comment.len() as u32).to_le_bytes());
+        data.extend(comment.as_bytes());
+    }
+    data
+}
+
+fn utf16_data(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain([0])
+        .flat_map(|unit| unit.to_le_bytes())
+        .collect()
+}
+
+fn asf_object(guid: [u8; 16], data: &[u8]) -> Vec<u8> {
+    let mut object = guid.to_vec();
+    object.extend((24 + data.len() as u64).to_le_bytes());
+    object.extend(data);
+    object
+}
+
+#[test]
+fn test_ogg_and_asf_tags() -> Result {
+    let dir = tempdir()?;
+    // Long enough that the comment packet takes more than one segment.
+    let long_comment = format!("COMMENT={}", "la ".repeat(100));
+    let mut comment_packet = b"\x03vorbis".to_vec();
+    comment_packet.extend(vorbis_comment_data(&[
+        "TITLE=Cold Song",
+        "artist=Klaus Nomi",
+        "ALBUM=Klaus Nomi",
+        "TRACKNUMBER=7",
+        "DATE=1981",
+        &long_comment,
+    ]));
+    comment_packet.push(1);
+    let mut vorbis = ogg_page(0, 0, b"\x01vorbis\0\0\0\0\x02");
+    vorbis.extend(ogg_page(0, 1, &comment_packet));
+    std::fs::write(dir.path().join("cold song.ogg"), &vorbis)?;
+
+    let mut tags_packet = b"OpusTags".to_vec();
+    tags_packet.extend(vorbis_comment_data(&[
+        "TITLE=Total Eclipse",
+        "ARTIST=Klaus Nomi",
+        "ALBUM=Simple Man",
+    ]));
+    let mut opus = ogg_page(0, 0, b"OpusHead\x01\x02");
+    opus.extend(ogg_page(0, 1, &tags_packet));
+    std::fs::write(dir.path().join("total eclipse.opus"), &opus)?;
+
+    // Title and artist, then the lengths and strings for copyright, description and rating.
+    let title = utf16_data("Lightning Strikes");
+    let author = utf16_data("Klaus Nomi");
+    let mut description = Vec::new();
+    for len in [title.len(), author.len(), 0, 0, 0] {
+        description.extend((len as u16).to_le_bytes());
+    }
+    description.extend(&title);
+    description.extend(&author);
+    let mut attributes = 2u16.to_le_bytes().to_vec();
+    let album_name = utf16_data("WM/AlbumTitle");
+    let album = utf16_data("Simple Man");
+    attributes.extend((album_name.len() as u16).to_le_bytes());
+    attributes.extend(&album_name);
+    attributes.extend(0u16.to_le_bytes());
+    attributes.extend((album.len() as u16).to_le_bytes());
+    attributes.extend(&album);
+    let track_name = utf16_data("WM/TrackNumber");
+    attributes.extend((track_name.len() as u16).to_le_bytes());
+    attributes.extend(&track_name);
+    attributes.extend(3u16.to_le_bytes());
+    attributes.extend(4u16.to_le_bytes());
+    attributes.extend(4u32.to_le_bytes());
+    let mut objects = asf_object(
+        [
+            0x33, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62,
+            0xCE, 0x6C,
+        ],
+        &description,
+    );
+    objects.extend(asf_object(
+        [
+            0x40, 0xA4, 0xD0, 0xD2, 0x07, 0xE3, 0xD2, 0x11, 0x97, 0xF0, 0x00, 0xA0, 0xC9, 0x5E,
+            0xA8, 0x50,
+        ],
+        &attributes,
+    ));
+    let mut wma = vec![
+        0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE,
+        0x6C,
+    ];
+    wma.extend((30 + objects.len() as u64).to_le_bytes());
+    wma.extend(2u32.to_le_bytes());
+    wma.extend([1, 2]);
+    wma.extend(objects);
+    std::fs::write(dir.path().join("lightning strikes.wma"), &wma)?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(report.added, 3, "{:?}", report.failures);
+
+    let song_at = |name: &str| -> music_cache::Result<Song> {
+        let path = dir.path().join(name);
+        tree.get_song_from_path(path.as_os_str().as_encoded_bytes())?()
+    };
+    let song = song_at("cold song.ogg")?;
+    assert_eq!(song.tags.title.as_deref(), Some("Cold Song"));
+    assert_eq!(song.tags.artist.as_deref(), Some("Klaus Nomi"));
+    assert_eq!(song.tags.track_number, Some(7));
+    assert_eq!(
+        song.tags.comment.as_deref(),
+        Some(&long_comment["COMMENT=".len()..])
+    );
+    let song = song_at("total eclipse.opus")?;
+    assert_eq!(song.tags.title.as_deref(), Some("Total Eclipse"));
+    let song = song_at("lightning strikes.wma")?;
+    assert_eq!(song.tags.title.as_deref(), Some("Lightning Strikes"));
+    assert_eq!(song.tags.artist.as_deref(), Some("Klaus Nomi"));
+    assert_eq!(song.tags.track_number, Some(4));
+
+    // Rather than all three landing in one untitled album.
+    let mut albums: Vec<_> = tree
+        .scan_albums()
+        .map(|album| album.map(|album| (album.tags.title, album.tags.year, album.songs.len())))
+        .collect::<music_cache::Result<_>>()?;
+    albums.sort();
+    assert_eq!(
+        albums,
+        [
+            (Some("Klaus Nomi".to_string()), Some(1981), 1),
+            (Some("Simple Man".to_string()), None, 2),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_scan_report() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(4).generate_file_structure(dir.path())?;
+    // Not really an mp3, so its tags can't be read.
+    let junk_path = dir.path().join("junk.mp3");
+    std::fs::write(&junk_path, b"not an mp3")?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.added, report.updated, report.removed), (4, 0, 0));
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].path, junk_path);
+    assert_eq!(report.failures[0].phase, ScanPhase::ReadTags);
+    assert!(matches!(report.failures[0].error, Error::TagRead { .. }));
+
+    // File timestamps come from a coarser clock than SystemTime::now, so give them a moment to pass it.
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let (album_tags, updated_song) = &all_tags[0];
+    write_tags_to_path(&updated_song.path(), album_tags, &updated_song.tags)?;
+    let (_, removed_song) = &all_tags[1];
+    std::fs::remove_file(removed_song.path())?;
+
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(
+        (report.added, report.updated, report.removed, report.skipped),
+        (0, 1, 1, 2)
+    );
+    assert_eq!(report.failures.len(), 1);
+
+    assert!(matches!(
+        scan_library(Arc::clone(&tree), &dir.path().join("missing")),
+        Err(Error::Io { .. })
+    ));
+    assert_eq!(tree.scan_songs().count(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_relocate_library_root() -> Result {
+    let mount = tempdir()?;
+    let dir = mount.path().join("music");
+    std::fs::create_dir(&dir)?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(&dir)?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    tree.set_library_root("music", &dir)?;
+    scan_library(Arc::clone(&tree), &dir)?;
+
+    let keys: Vec<Key> = all_tags
+        .iter()
+        .map(|(_, song)| path_hash_key(&tree, &song.path()))
+        .collect();
+    let stored: Song = tree.get_metadata(&keys[0])?;
+    assert_eq!(stored.root, "music");
+    assert_eq!(stored.path(), Path::new("0").join("0.mp3"));
+
+    // Moving the library and pointing its root at the new location keeps every song as it was.
+    let new_dir = mount.path().join("new-mount");
+    std::fs::rename(&dir, &new_dir)?;
+    tree.set_library_root("music", &new_dir)?;
+    let report = scan_library(Arc::clone(&tree), &new_dir)?;
+    assert_eq!(
+        (report.added, report.moved, report.removed, report.skipped),
+        (0, 0, 0, 3)
+    );
+    assert_eq!(tree.library_roots()?.len(), 1);
+
+    for ((_, song), key) in all_tags.iter().zip(&keys) {
+        let new_path = new_dir.join(song.path().strip_prefix(&dir)?);
+        let found = tree.get_song_from_path(new_path.as_os_str().as_encoded_bytes())?()?;
+        assert_eq!(found.tags, song.tags);
+        assert_eq!(found.hash_key(), *key);
+        assert_eq!(tree.song_path(&found)?, new_path);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_multiple_library_roots() -> Result {
+    let internal = tempdir()?;
+    let internal_tags = SkeletonFileTree {
+        dirs: vec![single_album_file_tree(2), single_album_file_tree(2)],
+        files: 0,
+    }
+    .generate_file_structure(internal.path())?;
+    let nas = tempdir()?;
+    single_album_file_tree(3).generate_file_structure(nas.path())?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    tree.set_library_root("internal", internal.path())?;
+    tree.set_library_root("nas", nas.path())?;
+    let mut names: Vec<String> = tree.library_roots()?.into_iter().map(|r| r.name).collect();
+    names.sort();
+    assert_eq!(names, ["internal", "nas"]);
+
+    // Scanning one root doesn't sweep away the other's songs.
+    assert_eq!(scan_library_root(Arc::clone(&tree), "internal")?.added, 4);
+    assert_eq!(scan_library_root(Arc::clone(&tree), "nas")?.added, 3);
+    let report = scan_library_root(Arc::clone(&tree), "internal")?;
+    assert_eq!((report.removed, report.skipped), (0, 4));
+    assert_eq!(tree.scan_songs().count(), 7);
+    assert!(tree.get_last_scan_time("nas")? > SystemTime::UNIX_EPOCH);
+
+    // Scanning a directory inside a root only looks for songs beneath it.
+    std::fs::remove_file(internal_tags[0].1.path())?;
+    let report = scan_library(Arc::clone(&tree), &internal.path().join("1"))?;
+    assert_eq!((report.removed, report.skipped), (0, 2));
+    let report = scan_library_root(Arc::clone(&tree), "internal")?;
+    assert_eq!(report.removed, 1);
+
+    // Roots can't nest, whether they're added directly or by scanning a directory holding one.
+    assert!(matches!(
+        tree.set_library_root("inner", &internal.path().join("1")),
+        Err(Error::OverlappingRoot { root, .. }) if root == "internal"
+    ));
+    assert!(matches!(
+        scan_library(Arc::clone(&tree), internal.path().parent().unwrap()),
+        Err(Error::OverlappingRoot { .. })
+    ));
+    assert_eq!(tree.library_roots()?.len(), 2);
+    assert_eq!(tree.scan_songs().count(), 6);
+
+    tree.remove_library_root("nas")?;
+    assert_eq!(tree.library_roots()?.len(), 1);
+    assert_eq!(tree.scan_songs().count(), 3);
+    assert_eq!(tree.get_last_scan_time("nas")?, SystemTime::UNIX_EPOCH);
+    assert!(matches!(
+        scan_library_root(Arc::clone(&tree), "nas"),
+        Err(Error::NotFound(_))
+    ));
+
+    Ok(())
+}
+
+// The key a song stored at `path` gets when nothing else has claimed it.
+fn path_hash_key(tree: &sled::Db, path: &Path) -> Key {
+    let (root, relpath) = root_for_path(tree, path).unwrap().unwrap();
+    song_hash_key(&root.name, &relpath)
+}
+
+#[test]
+fn test_scan_moved_files() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(dir.path())?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let key_of = |song: &Song| path_hash_key(&tree, &song.path());
+
+    // Moving the album and retagging a song along the way doesn't change what the songs are.
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let album_dir = dir.path().join("moved");
+    std::fs::rename(dir.path().join("0"), &album_dir)?;
+    let moved_path = |song: &Song| album_dir.join(song.path().file_name().unwrap());
+    let (album_tags, first_song) = &all_tags[0];
+    let mut retagged = first_song.tags.clone();
+    retagged.title = Some("Retagged after moving".to_string());
+    write_tags_to_path(&moved_path(first_song), album_tags, &retagged)?;
+
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(
+        (report.added, report.moved, report.removed),
+        (0, 3, 0),
+        "{report:?}"
+    );
+    for (_, song) in &all_tags {
+        let stored: Song = tree.get_metadata(&key_of(song))?;
+        assert_eq!(tree.song_path(&stored)?, moved_path(song));
+        let found = tree.get_song_from_path(moved_path(song).as_os_str().as_encoded_bytes())?()?;
+        assert_eq!(found, stored);
+    }
+    let stored: Song = tree.get_metadata(&key_of(first_song))?;
+    assert_eq!(stored.tags.title, retagged.title);
+    let album: Album = tree.get_metadata(&album_tags.hash_key())?;
+    assert_eq!(album.songs.len(), 3);
+
+    // Nothing is left at the old paths, and a rescan leaves the moved songs alone.
+    assert!(tree.get_song_from_path(&first_song.relpath)?().is_err());
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.added, report.moved, report.skipped), (0, 0, 3));
+
+    // A new file at an old path is a different song from the one that moved away.
+    std::fs::create_dir(dir.path().join("0"))?;
+    let new_tags = SongTags::arbitrary();
+    write_tags_to_path(&first_song.path(), album_tags, &new_tags)?;
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.added, report.moved), (1, 0));
+    let new_song = tree.get_song_from_path(&first_song.relpath)?()?;
+    assert_eq!(new_song.tags, new_tags);
+    let stored: Song = tree.get_metadata(&key_of(first_song))?;
+    assert_eq!(stored.tags.title, retagged.title);
+    assert_eq!(tree.scan_songs().count(), 4);
+
+    Ok(())
+}
+
+fn riff_chunk(id: &[u8; 4], body: &[u8], big_endian: bool) -> Vec<u8> {
+    let len = body.len() as u32;
+    let mut chunk = id.to_vec();
+    chunk.extend(if big_endian {
+        len.to_be_bytes()
+    } else {
+        len.to_le_bytes()
+    });
+    chunk.extend(body);
+    chunk.resize(chunk.len() + body.len() % 2, 0);
+    chunk
+}
+
+fn ogg_page(granule: u64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+    let mut page = b"OggS\0\0".to_vec();
+    page.extend(granule.to_le_bytes());
+    page.extend([1, 0, 0, 0]);
+    page.extend(sequence.to_le_bytes());
+    // The checksum covers the whole page, so it changes along with the sequence number.
+    page.extend(sequence.to_be_bytes());
+    let mut segments = vec![255; packet.len() / 255];
+    segments.push((packet.len() % 255) as u8);
+    page.push(segments.len() as u8);
+    page.extend(segments);
+    page.extend(packet);
+    page
+}
+
+#[test]
+fn test_fingerprint_ignores_container_tags() -> Result {
+    let dir = tempdir()?;
+    let fingerprint_of = |name: &str, bytes: &[u8]| -> std::io::Result<Option<u64>> {
+        let path = dir.path().join(name);
+        std::fs::write(&path, bytes)?;
+        audio_fingerprint(&path)
+    };
+    let samples: Vec<u8> = (0..4001).map(|_| rand::random()).collect();
+    let other_samples: Vec<u8> = (0..4001).map(|_| rand::random()).collect();
+
+    // WAV and AIFF tags live in chunks of their own, before or after the samples.
+    let wav = |info: &[u8], samples: &[u8], id3: &[u8]| {
+        let mut chunks = riff_chunk(b"fmt ", &[0; 16], false);
+        chunks.extend(riff_chunk(b"LIST", info, false));
+        chunks.extend(riff_chunk(b"data", samples, false));
+        chunks.extend(riff_chunk(b"id3 ", id3, false));
+        riff_chunk(b"RIFF", &[b"WAVE".as_slice(), &chunks].concat(), false)
+    };
+    let wav_fingerprint = fingerprint_of("a.wav", &wav(b"INFO", &samples, b""))?;
+    assert!(wav_fingerprint.is_some());
+    let retagged = wav(b"INFOINAM\x05\0\0\0Title", &samples, b"ID3\x04");
+    assert_eq!(fingerprint_of("a.wav", &retagged)?, wav_fingerprint);
+    let other = wav(b"INFO", &other_samples, b"");
+    assert_ne!(fingerprint_of("a.wav", &other)?, wav_fingerprint);
+
+    let aiff = |id3: &[u8], samples: &[u8]| {
+        let mut chunks = riff_chunk(b"COMM", &[0; 18], true);
+        chunks.extend(riff_chunk(b"ID3 ", id3, true));
+        chunks.extend(riff_chunk(b"SSND", samples, true));
+        riff_chunk(b"FORM", &[b"AIFF".as_slice(), &chunks].concat(), true)
+    };
+    let aiff_fingerprint = fingerprint_of("a.aiff", &aiff(b"", &samples))?;
+    assert!(aiff_fingerprint.is_some());
+    assert_eq!(
+        fingerprint_of("a.aiff", &aiff(b"ID3\x04\0", &samples))?,
+        aiff_fingerprint
+    );
+    assert_ne!(
+        fingerprint_of("a.aiff", &aiff(b"", &other_samples))?,
+        aiff_fingerprint
+    );
+
+    // A comment long enough to need another page renumbers every page after it.
+    let ogg = |comments: &[&[u8]], samples: &[u8]| {
+        let mut pages = ogg_page(0, 0, b"\x01vorbis");
+        for comment in comments {
+            pages.extend(ogg_page(0, pages.len() as u32, comment));
+        }
+        let sequence = comments.len() as u32 + 1;
+        for (i, packet) in samples.chunks(1000).enumerate() {
+            pages.extend(ogg_page(1024 * (i as u64 + 1), sequence + i as u32, packet));
+        }
+        pages
+    };
+    let ogg_fingerprint = fingerprint_of("a.ogg", &ogg(&[b"\x03vorbis"], &samples))?;
+    assert!(ogg_fingerprint.is_some());
+    let long_comment = [b"\x03vorbis".as_slice(), &[b'x'; 300]];
+    assert_eq!(
+        fingerprint_of("a.ogg", &ogg(&long_comment, &samples))?,
+        ogg_fingerprint
+    );
+    assert_ne!(
+        fingerprint_of("a.ogg", &ogg(&[b"\x03vorbis"], &other_samples))?,
+        ogg_fingerprint
+    );
+
+    // MP4 box sizes are read from the file, and one running past the end is given up on.
+    for large_size in [u64::MAX, u64::MAX - 8, 1 << 40] {
+        let mut mp4 = [&[0, 0, 0, 16][..], b"ftypM4A ", &[0; 4]].concat();
+        mp4.extend([&[0, 0, 0, 1][..], b"free", &large_size.to_be_bytes()].concat());
+        mp4.extend([&[0, 0, 0, 8][..], b"mdat"].concat());
+        mp4.extend(&samples);
+        assert!(fingerprint_of("a.m4a", &mp4)?.is_some());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_hash_scheme() -> Result {
+    // The hash is pinned, so these only change along with HASH_SCHEME.
+    assert_eq!(KeyHasher::new().finish(), 15130871412783076140);
+    let mut hasher = KeyHasher::new();
+    hasher.write_str("music");
+    hasher.write_option_u16(Some(1999));
+    hasher.write_option_str(None);
+    assert_eq!(hasher.finish(), 18238019485733928148);
+    assert_eq!(
+        format!("{:?}", song_hash_key("music", b"Artist/Album/01.mp3")),
+        "Key { tag: 0, id: 1398493147223556580 }"
+    );
+    let tags = album_tags(Some("Artist"), "Album", Some(1999));
+    assert_eq!(
+        format!("{:?}", tags.hash_key()),
+        "Key { tag: 1, id: 14467459294565488651 }"
+    );
+    assert_ne!(
+        album_tags(Some("X"), "", None).hash_key(),
+        AlbumTags {
+            title: Some("X".to_string()),
+            ..album_tags(None, "", None)
+        }
+        .hash_key()
+    );
+
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(dir.path())?;
+    let (album_tags, first_song) = &all_tags[0];
+    let song_tags = SongTags {
+        title: Some("Rekeyed".to_string()),
+        ..first_song.tags.clone()
+    };
+    write_tags_to_path(&first_song.path(), album_tags, &song_tags)?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    assert!(!upgrade_hash_scheme(&tree)?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+
+    // A moved song keeps the key of its old path until the db is rekeyed.
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let album_dir = dir.path().join("moved");
+    std::fs::rename(dir.path().join("0"), &album_dir)?;
+    let moved_path = album_dir.join(first_song.path().file_name().unwrap());
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let (old_key, _) = song_at_path(&tree, moved_path.as_os_str().as_encoded_bytes())?;
+    let new_key = path_hash_key(&tree, &moved_path);
+    assert_ne!(old_key, new_key);
+    tree.set_rating(&old_key, Some(4))?;
+    let playlist = tree.create_playlist("moved")?;
+    tree.insert_into_playlist(&playlist, 0, std::slice::from_ref(&old_key))?;
+    let added = tree.date_added(&old_key)?;
+    assert!(!upgrade_hash_scheme(&tree)?);
+
+    // Marking the keys as coming from another scheme rekeys everything on the next upgrade.
+    tree.insert(hash_scheme_key(), 0u32.to_be_bytes())?;
+    assert!(upgrade_hash_scheme(&tree)?);
+    assert!(!upgrade_hash_scheme(&tree)?);
+    assert!(matches!(
+        tree.get_metadata(&old_key) as music_cache::Result<Song>,
+        Err(Error::NotFound(_))
+    ));
+    let (key, song) = song_at_path(&tree, moved_path.as_os_str().as_encoded_bytes())?;
+    assert_eq!(key, new_key);
+    assert_eq!(song.fingerprint, None);
+    assert_eq!(tree.user_data(&new_key)?.rating, Some(4));
+    assert_eq!(tree.date_added(&new_key)?, added);
+    assert_eq!(
+        tree.playlist_songs(&playlist)?,
+        vec![(new_key.clone(), song.clone())]
+    );
+    let album: Album = tree.get_metadata(&album_tags.hash_key())?;
+    assert_eq!(album.songs.len(), 3);
+    assert_eq!(tree.search("rekeyed", 10)?.songs, vec![new_key.clone()]);
+    let artist_key = artist_hash_key(album_tags.artist.as_deref());
+    let albums = tree.albums_for_artist(&artist_key)?;
+    assert_eq!(albums.len(), 1);
+    assert_eq!(albums[0].0, album_tags.hash_key());
+
+    // Dropping the fingerprints made the next scan re-read every file, and nothing moves.
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(
+        (report.added, report.moved, report.removed, report.skipped),
+        (0, 0, 0, 0),
+        "{report:?}"
+    );
+    let song: Song = tree.get_metadata(&new_key)?;
+    assert!(song.fingerprint.is_some());
+    assert_eq!(tree.scan_songs().count(), 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_portable_keys() -> Result {
+    let key = Key::new(KeyType::Song, 0x0102_0304_0506_0708);
+    assert_eq!(key.as_ref(), [0, 1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(Key::try_from(key.as_ref())?, key);
+    assert_eq!(key.key_type(), Some(KeyType::Song));
+    assert!(Key::new(KeyType::Song, 255).as_ref() < Key::new(KeyType::Song, 256).as_ref());
+    assert!(matches!(
+        Key::try_from(&key.as_ref()[1..]),
+        Err(Error::InvalidKey)
+    ));
+    assert!(matches!(
+        Key::try_from(&[255, 0, 0, 0, 0, 0, 0, 0, 0][..]),
+        Err(Error::InvalidKey)
+    ));
+
+    let dir = tempdir()?;
+    single_album_file_tree(2).generate_file_structure(dir.path())?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    let before = SystemTime::now();
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let root = &tree.library_roots()?[0].name;
+    let scanned = tree.get(last_scan_time_key(root))?.unwrap();
+    assert_eq!(scanned.len(), 12);
+    let scan_time = tree.get_last_scan_time(root)?;
+    assert!(before <= scan_time && scan_time <= SystemTime::now());
+
+    // Playlists from dbs written in native byte order keep their ids.
+    tree.create_playlist("first")?;
+    let playlist = tree.create_playlist("second")?;
+    let mut legacy_key = playlist.as_ref().to_vec();
+    let id = u64::from_be_bytes(legacy_key[1..].try_into()?);
+    legacy_key[1..].copy_from_slice(&id.to_ne_bytes());
+    let bytes = tree.remove(&playlist)?.unwrap();
+    tree.insert(legacy_key, bytes)?;
+    tree.insert(hash_scheme_key(), 1u32.to_be_bytes())?;
+    assert!(upgrade_hash_scheme(&tree)?);
+    assert_eq!(tree.playlist(&playlist)?.name, "second");
+    assert_eq!(tree.playlists()?.len(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn test_playlists_before_first_scan() -> Result {
+    let dir = tempdir()?;
+    single_album_file_tree(2).generate_file_structure(dir.path())?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    tree.create_playlist("first")?;
+    let playlist = tree.create_playlist("second")?;
+    assert_eq!(
+        tree.get(hash_scheme_key())?.unwrap().as_ref(),
+        HASH_SCHEME.to_be_bytes()
+    );
+    scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!(tree.playlist(&playlist)?.name, "second");
+
+    // Without a scheme recorded, generated ids are still taken as written.
+    tree.remove(hash_scheme_key())?;
+    assert!(upgrade_hash_scheme(&tree)?);
+    assert_eq!(tree.playlist(&playlist)?.name, "second");
+    assert_eq!(tree.playlists()?.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn test_schema_versioning() -> Result {
+    let db_dir = tempdir()?;
+    let tree = sled::open(db_dir.path())?;
+    let mut song = Song::new(SongTags::default(), b"Artist/Album/01.mp3");
+    song.tags.title = Some("Untagged".to_string());
+    let song_key = song.hash_key();
+    let album_tags = album_tags(Some("Artist"), "Album", Some(1999));
+    let album = BasicStoredAlbum {
+        tags: BasicAlbumTags::from(&album_tags),
+        song_keys: vec![(None, *song_key.to_byte_key())],
+    };
+
+    // Records from before formats were tagged still read, and are tagged by the upgrade.
+    tree.insert(hash_scheme_key(), HASH_SCHEME.to_be_bytes())?;
+    tree.insert(&song_key, bitcode::encode(&BasicSong::from(&song)))?;
+    tree.insert(album_tags.hash_key(), bitcode::encode(&album))?;
+    let stored: Album = tree.get_metadata(&album_tags.hash_key())?;
+    assert_eq!(stored.songs, vec![song.clone()]);
+    assert!(upgrade_db(&tree)?);
+    assert!(!upgrade_db(&tree)?);
+    assert_eq!(
+        tree.get(schema_version_key())?.unwrap().as_ref(),
+        SCHEMA_VERSION.to_be_bytes()
+    );
+    assert_eq!(tree.get(&song_key)?.unwrap()[0], SONG_FORMAT);
+    assert_eq!(tree.get(album_tags.hash_key())?.unwrap()[0], ALBUM_FORMAT);
+    let stored: Album = tree.get_metadata(&album_tags.hash_key())?;
+    assert_eq!(stored.songs, vec![song.clone()]);
+
+    // A newer build's records and dbs are refused rather than misread.
+    let mut newer = vec![SONG_FORMAT + 1];
+    newer.extend(bitcode::encode(&song));
+    tree.insert(&song_key, newer)?;
+    assert!(matches!(
+        tree.get_metadata(&song_key) as music_cache::Result<Song>,
+        Err(Error::DatabaseTooNew { found, supported }) if found == SONG_FORMAT as u32 + 1
+            && supported == SONG_FORMAT as u32
+    ));
+    tree.insert(schema_version_key(), (SCHEMA_VERSION + 1).to_be_bytes())?;
+    assert!(matches!(
+        upgrade_db(&tree),
+        Err(Error::DatabaseTooNew { found, supported }) if found == SCHEMA_VERSION + 1
+            && supported == SCHEMA_VERSION
+    ));
+
+    // New dbs start out current.
+    let db_dir = tempdir()?;
+    let tree = sled::open(db_dir.path())?;
+    assert!(!upgrade_db(&tree)?);
+    assert_eq!(
+        tree.get(schema_version_key())?.unwrap().as_ref(),
+        SCHEMA_VERSION.to_be_bytes()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_file_stamps() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(dir.path())?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    scan_library(Arc::clone(&tree), dir.path())?;
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.updated, report.skipped), (0, 3), "{report:?}");
+
+    // A file replaced by one with an old modification time is still re-read.
+    let (album_tags, first_song) = &all_tags[0];
+    let path = first_song.path();
+    let modified = std::fs::metadata(&path)?.modified()?;
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let title = format!(
+        "{} replaced",
+        first_song.tags.title.as_deref().unwrap_or_default()
+    );
+    let song_tags = SongTags {
+        title: Some(title.clone()),
+        ..first_song.tags.clone()
+    };
+    let replacement = dir.path().join("replacement.tmp");
+    std::fs::copy(&path, &replacement)?;
+    write_tags_to_path(&replacement, album_tags, &song_tags)?;
+    std::fs::rename(&replacement, &path)?;
+    std::fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(modified)?;
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.updated, report.skipped), (1, 2), "{report:?}");
+    let (_, song) = song_at_path(&tree, path.as_os_str().as_encoded_bytes())?;
+    assert_eq!(song.tags.title, Some(title));
+    let stamp = song.file.unwrap();
+    assert_eq!(stamp.size, std::fs::metadata(&path)?.len());
+    // Only scans checking content read files in full to hash them.
+    assert!(stamp.content_hash.is_none());
+
+    // Stamps without a hash look changed to the first scan that checks content.
+    let options = ScanOptions::new().with_content_check(true);
+    let report = scan_library_with_options(Arc::clone(&tree), dir.path(), &options)?;
+    assert_eq!((report.updated, report.skipped), (3, 0), "{report:?}");
+    let (_, song) = song_at_path(&tree, path.as_os_str().as_encoded_bytes())?;
+    assert!(song.file.unwrap().content_hash.is_some());
+
+    // Bytes changed in place with the modification time put back are only caught by checking content.
+    let mut bytes = std::fs::read(&path)?;
+    *bytes.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&path, bytes)?;
+    std::fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(modified)?;
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.updated, report.skipped), (0, 3), "{report:?}");
+    let report = scan_library_with_options(Arc::clone(&tree), dir.path(), &options)?;
+    assert_eq!((report.updated, report.skipped), (1, 2), "{report:?}");
+    let report = scan_library_with_options(Arc::clone(&tree), dir.path(), &options)?;
+    assert_eq!((report.updated, report.skipped), (0, 3), "{report:?}");
+
+    // A file that's only moved on disk, as by a restore, is checked by its content and kept.
+    let replace_with_copy = |flip_last_byte: bool| -> std::io::Result<()> {
+        let mut bytes = std::fs::read(&path)?;
+        if flip_last_byte {
+            *bytes.last_mut().unwrap() ^= 0xff;
+        }
+        std::fs::write(&replacement, bytes)?;
+        std::fs::rename(&replacement, &path)?;
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified)
+    };
+    replace_with_copy(false)?;
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.updated, report.skipped), (0, 3), "{report:?}");
+    let (_, song) = song_at_path(&tree, path.as_os_str().as_encoded_bytes())?;
+    let stamp = song.file.unwrap();
+    assert!(stamp.same_file(&FileStamp::from_metadata(&std::fs::metadata(&path)?)));
+    assert!(stamp.content_hash.is_some());
+    replace_with_copy(true)?;
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.updated, report.skipped), (1, 2), "{report:?}");
+
+    // Changing the grouping forgets every stamp.
+    tree.set_album_grouping(AlbumGrouping::Directory)?;
+    let report = scan_library(Arc::clone(&tree), dir.path())?;
+    assert_eq!((report.updated, report.skipped), (3, 0), "{report:?}");
+
+    Ok(())
+}
+
+type StoredLibrary = (Vec<Song>, Vec<(Key, AlbumTags)>, Vec<(Key, Artist)>);
+
+fn stored_library<S: Store>(tree: &S) -> music_cache::Result<StoredLibrary> {
+    Ok((
+        tree.scan_songs().collect::<music_cache::Result<_>>()?,
+        tree.scan_album_tags_sorted()?,
+        tree.scan_artists_sorted()?,
+    ))
+}
+
+#[test]
+fn test_memory_store() -> Result {
+    let dir = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(dir.path())?;
+    let db_dir = tempdir()?;
+    let sled_tree = Arc::new(sled::open(db_dir.path())?);
+    let memory = Arc::new(MemoryStore::new());
+    scan_library(Arc::clone(&sled_tree), dir.path())?;
+    let report = scan_library(Arc::clone(&memory), dir.path())?;
+    assert_eq!((report.added, report.skipped), (3, 0), "{report:?}");
+
+    // Either store ends up holding the same library.
+    assert_eq!(stored_library(&*memory)?, stored_library(&*sled_tree)?);
+    let word = all_tags[0].1.tags.title.as_deref().unwrap_or_default();
+    assert_eq!(memory.search(word, 10)?, sled_tree.search(word, 10)?);
+    let report = scan_library(Arc::clone(&memory), dir.path())?;
+    assert_eq!((report.updated, report.skipped), (0, 3), "{report:?}");
+
+    // Side trees and generated ids work the same.
+    let paths: Vec<_> = all_tags.iter().map(|(_, song)| song.path()).collect();
+    let (first, _) = song_at_path(&memory, paths[0].as_os_str().as_encoded_bytes())?;
+    assert_eq!(memory.record_play(&first)?.play_count, 1);
+    assert!(memory.date_added(&first)?.is_some());
+    let mixtape = memory.create_playlist("mixtape")?;
+    let other = memory.create_playlist("other")?;
+    assert_ne!(mixtape, other);
+    memory.insert_into_playlist(&mixtape, 0, std::slice::from_ref(&first))?;
+    assert_eq!(memory.playlist_songs(&mixtape)?.len(), 1);
+
+    // Rekeying rewrites every tree at once.
+    memory.insert(hash_scheme_key(), 1u32.to_be_bytes())?;
+    assert!(upgrade_db(&memory)?);
+    assert_eq!(memory.user_data(&first)?.play_count, 1);
+    assert_eq!(memory.playlists()?.len(), 2);
+    assert_eq!(memory.scan_songs().count(), 3);
+
+    std::fs::remove_file(&paths[0])?;
+    let report = scan_library(Arc::clone(&memory), dir.path())?;
+    assert_eq!(report.removed, 1, "{report:?}");
+    assert_eq!(memory.scan_songs().count(), 2);
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct ChangeRecorder {
+    added: Mutex<Vec<Key>>,
+    updated: Mutex<Vec<Key>>,
+    removed: Mutex<Vec<Key>>,
+    moved: Mutex<Vec<Key>>,
+}
+
+impl LibraryObserver for ChangeRecorder {
+    fn on_change(&self, change: &LibraryChange) {
+        match change {
+            LibraryChange::SongAdded(key) => self.added.lock().unwrap().push((*key).clone()),
+            LibraryChange::SongUpdated(key) => self.updated.lock().unwrap().push((*key).clone()),
+            LibraryChange::SongRemoved(key) => self.removed.lock().unwrap().push((*key).clone()),
+            LibraryChange::SongMoved(key) => self.moved.lock().unwrap().push((*key).clone()),
+            LibraryChange::Error(failure) => panic!("Unexpected watch failure: {failure:?}"),
+        }
+    }
+}
+
+// Watch events arrive asynchronously, so poll for the expected state rather than sleeping a fixed time.
+fn wait_for(mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+    while std::time::Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    condition()
+}
+
+#[test]
+fn test_watch_library() -> Result {
+    let dir = tempdir()?;
+    let staging = tempdir()?;
+    let all_tags = single_album_file_tree(3).generate_file_structure(staging.path())?;
+
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+
+    let watcher = LibraryWatcher::start(
+        Arc::clone(&tree),
+        dir.path(),
+        std::time::Duration::from_millis(100),
+    )?;
+    let recorder = Arc::new(ChangeRecorder::default());
+    watcher.subscribe(recorder.clone());
+
+    // Moving a finished album in only raises an event for its directory.
+    let album_dir = dir.path().join("album");
+    std::fs::rename(staging.path().join("0"), &album_dir)?;
+    assert!(wait_for(|| recorder.added.lock().unwrap().len() == 3));
+    let moved_path = |song: &Song| album_dir.join(song.path().file_name().unwrap());
+    let (album_tags, first_song) = &all_tags[0];
+    let first_key = path_hash_key(&tree, &moved_path(first_song));
+    let album: Album = tree.get_metadata(&album_tags.hash_key())?;
+    assert_eq!(album.songs.len(), 3);
+
+    // Retagging into another album takes the song out of its old one.
+    let mut retagged = first_song.tags.clone();
+    retagged.title = Some("Retagged while watching".to_string());
+    let other_album = AlbumTags {
+        title: Some("Another album".to_string()),
+        ..album_tags.clone()
+    };
+    write_tags_to_path(&moved_path(first_song), &other_album, &retagged)?;
+    let updated = &recorder.updated;
+    assert!(wait_for(|| updated.lock().unwrap().contains(&first_key)));
+    let stored: Song = tree.get_metadata(&first_key)?;
+    assert_eq!(stored.tags.title, retagged.title);
+    assert_eq!(tree.search("retagged", 10)?.songs, vec![first_key.clone()]);
+    let album: Album = tree.get_metadata(&album_tags.hash_key())?;
+    assert_eq!(album.songs.len(), 2);
+    let album: Album = tree.get_metadata(&other_album.hash_key())?;
+    assert_eq!(album.songs, vec![stored]);
+
+    // A rename within the library keeps the song's key.
+    let renamed_path = album_dir.join("renamed.mp3");
+    std::fs::rename(moved_path(first_song), &renamed_path)?;
+    let moved = &recorder.moved;
+    assert!(wait_for(|| moved.lock().unwrap().contains(&first_key)));
+    let stored: Song = tree.get_metadata(&first_key)?;
+    assert_eq!(tree.song_path(&stored)?, renamed_path);
+    assert_eq!(recorder.removed.lock().unwrap().len(), 0);
+
+    std::fs::remove_file(&renamed_path)?;
+    let removed = &recorder.removed;
+    assert!(wait_for(|| removed.lock().unwrap().contains(&first_key)));
+    let missing: music_cache::Result<Song> = tree.get_metadata(&first_key);
+    assert!(matches!(missing, Err(Error::NotFound(_))));
+
+    std::fs::remove_dir_all(&album_dir)?;
+    assert!(wait_for(|| removed.lock().unwrap().len() == 3));
+    assert_eq!(tree.scan_songs().count(), 0);
+    assert_eq!(tree.scan_albums().count(), 0);
+
+    watcher.stop();
+    Ok(())
+}
+
+#[test]
+fn test_watch_library_formats() -> Result {
+    let dir = tempdir()?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+
+    let formats = AudioFormats::default().with_extensions(["song"]);
+    let watcher = LibraryWatcher::start_with_formats(
+        Arc::clone(&tree),
+        dir.path(),
+        std::time::Duration::from_millis(100),
+        formats,
+    )?;
+    let recorder = Arc::new(ChangeRecorder::default());
+    watcher.subscribe(recorder.clone());
+
+    // Only extensions the watcher was given are loaded.
+    let album_tags = AlbumTags::arbitrary();
+    let song_tags = SongTags::arbitrary();
+    write_tags_to_path(&dir.path().join("ignored.mp3"), &album_tags, &song_tags)?;
+    write_tags_to_path(&dir.path().join("kept.song"), &album_tags, &song_tags)?;
+    assert!(wait_for(|| recorder.added.lock().unwrap().len() == 1));
+    let kept = dir.path().join("kept.song");
+    let song = tree.get_song_from_path(kept.as_os_str().as_encoded_bytes())?()?;
+    assert_eq!(song.tags, song_tags);
+    assert_eq!(tree.scan_songs().count(), 1);
+
+    watcher.stop();
+    Ok(())
+}
+
+#[test]
+fn test_watch_library_reconciles_albums() -> Result {
+    let dir = tempdir()?;
+    let db_dir = tempdir()?;
+    let tree = Arc::new(sled::open(db_dir.path())?);
+    tree.set_album_grouping(AlbumGrouping::NormalizedTags)?;
+
+    let watcher = LibraryWatcher::start(
+        Arc::clone(&tree),
+        dir.path(),
+        std::time::Duration::from_millis(100),
+    )?;
+    let recorder = Arc::new(ChangeRecorder::default());
+    watcher.subscribe(recorder.clone());
+
+    // One track is missing its album artist, so its normalized key differs from the other's.
+    let files = [
+        ("1.mp3", album_tags(Some("The Beatles"), "Abbey Road", None)),
+        ("2.mp3", album_tags(None, "Abbey Road", Some(1969))),
+    ];
+    for (name, tags) in &files {
+        let path = dir.path().join(name);
+        std::fs::write(&path, dummy_audio(2))?;
+        write_tags_to_path(&path, tags, &SongTags::default())?;
+    }
+    assert!(wait_for(|| recorder.added.lock().unwrap().len() == 2));
+    assert!(wait_for(|| tree.scan_albums().count() == 1));
+    let album = tree.scan_albums().next().unwrap()?;
+    assert_eq!(album.songs.len(), 2);
+    assert_eq!(album.tags.artist.as_deref(), Some("The Beatles"));
+    assert_eq!(album.tags.year, Some(1969));
+
+    watcher.stop();
+    Ok(())
+}